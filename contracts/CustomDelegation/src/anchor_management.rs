@@ -0,0 +1,697 @@
+//! Changes to the devices and the metadata of existing anchors.
+//!
+//! Every change reads the anchor record, modifies its devices or metadata and writes the record
+//! back, so the delegations stored with the anchor are kept. Since devices and metadata are stored
+//! in the same entry, a change is rejected with [DeviceError::EntrySizeLimitExceeded] if the
+//! candid encoding of the whole record no longer fits.
+
+use std::collections::HashMap;
+
+use candid::Principal;
+
+use crate::storage::record_storage::RecordStorage;
+use crate::storage::StorageError;
+use crate::types::{
+    AnchorInfo, AnchorRecord, DeviceData, DeviceError, DeviceKey, DeviceRegistrationInfo,
+    IdentityAnchorInfo, MetadataEntry, Timestamp, UserNumber,
+};
+
+/// The last usage of a device is only written if the stored one is at least this old, so that
+/// not every call rewrites the entry of the anchor.
+pub const DEVICE_USAGE_WRITE_INTERVAL_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// Time between a controller scheduling the deletion of an anchor (see [schedule_deletion]) and
+/// the deletion, during which the devices of the anchor can still cancel it.
+pub const ANCHOR_DELETION_GRACE_PERIOD_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+/// Returns the devices and the metadata of the given anchor.
+pub fn lookup<S: RecordStorage + ?Sized>(
+    storage: &S,
+    user_number: UserNumber,
+) -> Result<AnchorInfo, DeviceError> {
+    let anchor = read(storage, user_number)?;
+    Ok(AnchorInfo {
+        devices: anchor.devices,
+        metadata: anchor.metadata,
+    })
+}
+
+/// Returns the devices and the metadata of the given anchor together with the state of its device
+/// registration mode, if any, so that an anchor can be managed with a single call.
+pub fn anchor_info<S: RecordStorage + ?Sized>(
+    storage: &S,
+    user_number: UserNumber,
+    device_registration: Option<DeviceRegistrationInfo>,
+) -> Result<IdentityAnchorInfo, DeviceError> {
+    let anchor = read(storage, user_number)?;
+    Ok(IdentityAnchorInfo {
+        devices: anchor.devices,
+        device_registration,
+        metadata: anchor.metadata,
+    })
+}
+
+/// Replaces the anchor level metadata of the given anchor.
+pub fn replace_metadata<S: RecordStorage + ?Sized>(
+    storage: &mut S,
+    user_number: UserNumber,
+    metadata: HashMap<String, MetadataEntry>,
+) -> Result<(), DeviceError> {
+    let mut anchor = read(storage, user_number)?;
+    anchor.metadata = Some(metadata);
+    write(storage, user_number, &anchor)
+}
+
+/// Adds a device to the given anchor, rejecting a public key the anchor already has. The new
+/// device has not been used yet.
+pub fn add<S: RecordStorage + ?Sized>(
+    storage: &mut S,
+    user_number: UserNumber,
+    mut device: DeviceData,
+) -> Result<(), DeviceError> {
+    device.last_usage_timestamp = None;
+    let mut anchor = read(storage, user_number)?;
+    if anchor.devices.iter().any(|d| d.pubkey == device.pubkey) {
+        return Err(DeviceError::DuplicateDevice);
+    }
+    anchor.devices.push(device);
+    write(storage, user_number, &anchor)
+}
+
+/// Removes a device from the given anchor on behalf of `caller`. The last device cannot be
+/// removed, as the anchor could no longer be authenticated afterwards.
+pub fn remove<S: RecordStorage + ?Sized>(
+    storage: &mut S,
+    user_number: UserNumber,
+    caller: Principal,
+    device_key: DeviceKey,
+) -> Result<(), DeviceError> {
+    let mut anchor = read(storage, user_number)?;
+    let index = authorize_device_mutation(&anchor, caller, &device_key)?;
+    if anchor.devices.len() == 1 {
+        return Err(DeviceError::LastDevice);
+    }
+    anchor.devices.remove(index);
+    write(storage, user_number, &anchor)
+}
+
+/// Replaces a device of the given anchor in place on behalf of `caller`. The new device may keep
+/// the public key of the replaced one, but must not have the key of any other device. The last
+/// usage is kept if the public key does not change.
+pub fn replace<S: RecordStorage + ?Sized>(
+    storage: &mut S,
+    user_number: UserNumber,
+    caller: Principal,
+    device_key: DeviceKey,
+    mut device: DeviceData,
+) -> Result<(), DeviceError> {
+    let mut anchor = read(storage, user_number)?;
+    let index = authorize_device_mutation(&anchor, caller, &device_key)?;
+    if device.pubkey != device_key && anchor.devices.iter().any(|d| d.pubkey == device.pubkey) {
+        return Err(DeviceError::DuplicateDevice);
+    }
+    device.last_usage_timestamp = if device.pubkey == device_key {
+        anchor.devices[index].last_usage_timestamp
+    } else {
+        None
+    };
+    anchor.devices[index] = device;
+    write(storage, user_number, &anchor)
+}
+
+/// Returns the index of the device with the given key if `caller` may remove or replace it, which
+/// any device of the anchor may do unless the device is protected: protected devices (e.g.
+/// recovery phrases) can only be changed by themselves.
+fn authorize_device_mutation(
+    anchor: &AnchorRecord,
+    caller: Principal,
+    device_key: &DeviceKey,
+) -> Result<usize, DeviceError> {
+    let index = anchor
+        .devices
+        .iter()
+        .position(|d| d.pubkey == *device_key)
+        .ok_or(DeviceError::DeviceNotFound)?;
+    if anchor.devices[index].is_protected() && caller != Principal::self_authenticating(device_key)
+    {
+        return Err(DeviceError::ProtectedDevice);
+    }
+    Ok(index)
+}
+
+/// Records that the device with the given key authenticated a call at time `now`. The anchor is
+/// only written if the last usage recorded before is older than [DEVICE_USAGE_WRITE_INTERVAL_NS].
+///
+/// Returns whether the anchor was written.
+pub fn record_device_usage<S: RecordStorage + ?Sized>(
+    storage: &mut S,
+    user_number: UserNumber,
+    device_key: &DeviceKey,
+    now: Timestamp,
+) -> Result<bool, DeviceError> {
+    let mut anchor = read(storage, user_number)?;
+    let device = anchor
+        .devices
+        .iter_mut()
+        .find(|d| d.pubkey == *device_key)
+        .ok_or(DeviceError::DeviceNotFound)?;
+    if let Some(last_usage) = device.last_usage_timestamp {
+        if now.saturating_sub(last_usage) < DEVICE_USAGE_WRITE_INTERVAL_NS {
+            return Ok(false);
+        }
+    }
+    device.last_usage_timestamp = Some(now);
+    write(storage, user_number, &anchor)?;
+    Ok(true)
+}
+
+/// Deletes the given anchor, see [RecordStorage::delete_anchor].
+pub fn delete<S: RecordStorage + ?Sized>(
+    storage: &mut S,
+    user_number: UserNumber,
+) -> Result<(), DeviceError> {
+    storage
+        .delete_anchor(user_number)
+        .map_err(|err| DeviceError::StorageError(err.to_string()))
+}
+
+/// Schedules the deletion of the given anchor by a controller at [ANCHOR_DELETION_GRACE_PERIOD_NS]
+/// after `now`. A deletion that is already scheduled is not postponed.
+///
+/// Returns the time from which the deletion is allowed.
+pub fn schedule_deletion(
+    scheduled: &mut Vec<(UserNumber, Timestamp)>,
+    user_number: UserNumber,
+    now: Timestamp,
+) -> Timestamp {
+    if let Some((_, due)) = scheduled
+        .iter()
+        .find(|(scheduled, _)| *scheduled == user_number)
+    {
+        return *due;
+    }
+    let due = now.saturating_add(ANCHOR_DELETION_GRACE_PERIOD_NS);
+    scheduled.push((user_number, due));
+    due
+}
+
+/// Returns true if the deletion of the given anchor was scheduled and its grace period has passed
+/// at `now`.
+pub fn deletion_due(
+    scheduled: &[(UserNumber, Timestamp)],
+    user_number: UserNumber,
+    now: Timestamp,
+) -> bool {
+    scheduled
+        .iter()
+        .any(|(scheduled, due)| *scheduled == user_number && *due <= now)
+}
+
+/// Removes the scheduled deletion of the given anchor, if any. Returns whether one was removed.
+pub fn cancel_deletion(
+    scheduled: &mut Vec<(UserNumber, Timestamp)>,
+    user_number: UserNumber,
+) -> bool {
+    let len = scheduled.len();
+    scheduled.retain(|(scheduled, _)| *scheduled != user_number);
+    scheduled.len() != len
+}
+
+fn read<S: RecordStorage + ?Sized>(
+    storage: &S,
+    user_number: UserNumber,
+) -> Result<AnchorRecord, DeviceError> {
+    storage
+        .read_anchor(user_number)
+        .map_err(|err| DeviceError::StorageError(err.to_string()))
+}
+
+fn write<S: RecordStorage + ?Sized>(
+    storage: &mut S,
+    user_number: UserNumber,
+    anchor: &AnchorRecord,
+) -> Result<(), DeviceError> {
+    storage
+        .write_anchor(user_number, anchor)
+        .map_err(|err| match err {
+            StorageError::EntrySizeLimitExceeded(length) => DeviceError::EntrySizeLimitExceeded {
+                length: length as u64,
+            },
+            err => DeviceError::StorageError(err.to_string()),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+
+    use crate::storage::Storage;
+    use crate::testing::{device, VectorMemory};
+    use crate::types::{DeviceProtection, KeyType, Purpose};
+
+    use super::*;
+
+    const RANGE: (u64, u64) = (10_000, 10_010);
+
+    fn protected_device(key: u8) -> DeviceData {
+        DeviceData {
+            purpose: Purpose::Recovery,
+            key_type: KeyType::SeedPhrase,
+            protection: Some(DeviceProtection::Protected),
+            ..device(key)
+        }
+    }
+
+    fn principal(key: u8) -> Principal {
+        Principal::self_authenticating(device(key).pubkey)
+    }
+
+    fn storage_with_anchor() -> Storage<VectorMemory> {
+        let mut storage = Storage::new(RANGE, VectorMemory::new()).unwrap();
+        let anchor = AnchorRecord {
+            devices: vec![device(1)],
+            delegations: Some(vec![]),
+            metadata: None,
+        };
+        storage.write_anchor(RANGE.0, &anchor).unwrap();
+        storage
+    }
+
+    #[test]
+    fn should_add_replace_and_remove_devices() {
+        let mut storage = storage_with_anchor();
+        add(&mut storage, RANGE.0, device(2)).unwrap();
+        assert_eq!(
+            lookup(&storage, RANGE.0).unwrap().devices,
+            vec![device(1), device(2)]
+        );
+
+        replace(
+            &mut storage,
+            RANGE.0,
+            principal(1),
+            device(1).pubkey,
+            device(3),
+        )
+        .unwrap();
+        assert_eq!(
+            lookup(&storage, RANGE.0).unwrap().devices,
+            vec![device(3), device(2)]
+        );
+
+        remove(&mut storage, RANGE.0, principal(1), device(3).pubkey).unwrap();
+        assert_eq!(lookup(&storage, RANGE.0).unwrap().devices, vec![device(2)]);
+        assert_eq!(
+            storage.read_anchor(RANGE.0).unwrap().delegations,
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn should_not_remove_last_device() {
+        let mut storage = storage_with_anchor();
+        assert_eq!(
+            remove(&mut storage, RANGE.0, principal(1), device(1).pubkey),
+            Err(DeviceError::LastDevice)
+        );
+        assert_eq!(
+            remove(&mut storage, RANGE.0, principal(1), device(2).pubkey),
+            Err(DeviceError::DeviceNotFound)
+        );
+        assert_eq!(lookup(&storage, RANGE.0).unwrap().devices, vec![device(1)]);
+    }
+
+    #[test]
+    fn should_reject_duplicate_public_keys() {
+        let mut storage = storage_with_anchor();
+        let mut duplicate = device(1);
+        duplicate.alias = "other alias".to_string();
+        assert_eq!(
+            add(&mut storage, RANGE.0, duplicate.clone()),
+            Err(DeviceError::DuplicateDevice)
+        );
+
+        add(&mut storage, RANGE.0, device(2)).unwrap();
+        assert_eq!(
+            replace(
+                &mut storage,
+                RANGE.0,
+                principal(1),
+                device(2).pubkey,
+                duplicate.clone()
+            ),
+            Err(DeviceError::DuplicateDevice)
+        );
+        // keeping the public key of the replaced device is fine
+        replace(
+            &mut storage,
+            RANGE.0,
+            principal(1),
+            device(1).pubkey,
+            duplicate.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            lookup(&storage, RANGE.0).unwrap().devices,
+            vec![duplicate, device(2)]
+        );
+    }
+
+    #[test]
+    fn should_return_error_if_entry_size_limit_is_exceeded() {
+        let mut storage = storage_with_anchor();
+        let mut large = device(2);
+        large.alias = "a".repeat(5000);
+        assert!(matches!(
+            add(&mut storage, RANGE.0, large),
+            Err(DeviceError::EntrySizeLimitExceeded { length }) if length > 5000
+        ));
+        assert_eq!(lookup(&storage, RANGE.0).unwrap().devices, vec![device(1)]);
+    }
+
+    #[test]
+    fn should_return_error_for_unknown_anchor() {
+        let mut storage = storage_with_anchor();
+        assert!(matches!(
+            add(&mut storage, RANGE.1 + 1, device(2)),
+            Err(DeviceError::StorageError(_))
+        ));
+    }
+
+    #[test]
+    fn should_let_any_device_change_unprotected_devices() {
+        let mut storage = storage_with_anchor();
+        add(&mut storage, RANGE.0, device(2)).unwrap();
+        add(&mut storage, RANGE.0, device(3)).unwrap();
+
+        // by another device
+        replace(
+            &mut storage,
+            RANGE.0,
+            principal(1),
+            device(2).pubkey,
+            device(4),
+        )
+        .unwrap();
+        remove(&mut storage, RANGE.0, principal(1), device(4).pubkey).unwrap();
+        // by the device itself
+        remove(&mut storage, RANGE.0, principal(3), device(3).pubkey).unwrap();
+        assert_eq!(lookup(&storage, RANGE.0).unwrap().devices, vec![device(1)]);
+    }
+
+    #[test]
+    fn should_only_let_protected_devices_change_themselves() {
+        let mut storage = storage_with_anchor();
+        add(&mut storage, RANGE.0, protected_device(2)).unwrap();
+
+        assert_eq!(
+            remove(&mut storage, RANGE.0, principal(1), device(2).pubkey),
+            Err(DeviceError::ProtectedDevice)
+        );
+        assert_eq!(
+            replace(
+                &mut storage,
+                RANGE.0,
+                principal(1),
+                device(2).pubkey,
+                device(3)
+            ),
+            Err(DeviceError::ProtectedDevice)
+        );
+        assert_eq!(
+            lookup(&storage, RANGE.0).unwrap().devices,
+            vec![device(1), protected_device(2)]
+        );
+
+        replace(
+            &mut storage,
+            RANGE.0,
+            principal(2),
+            device(2).pubkey,
+            protected_device(3),
+        )
+        .unwrap();
+        remove(&mut storage, RANGE.0, principal(3), device(3).pubkey).unwrap();
+        assert_eq!(lookup(&storage, RANGE.0).unwrap().devices, vec![device(1)]);
+    }
+
+    #[test]
+    fn should_not_remove_last_device_regardless_of_protection() {
+        let mut storage = storage_with_anchor();
+        add(&mut storage, RANGE.0, protected_device(2)).unwrap();
+        remove(&mut storage, RANGE.0, principal(1), device(1).pubkey).unwrap();
+
+        // the protected device is left, which only it may change
+        assert_eq!(
+            remove(&mut storage, RANGE.0, principal(1), device(2).pubkey),
+            Err(DeviceError::ProtectedDevice)
+        );
+        assert_eq!(
+            remove(&mut storage, RANGE.0, principal(2), device(2).pubkey),
+            Err(DeviceError::LastDevice)
+        );
+        // replacing keeps the number of devices, so it is allowed
+        replace(
+            &mut storage,
+            RANGE.0,
+            principal(2),
+            device(2).pubkey,
+            device(5),
+        )
+        .unwrap();
+        assert_eq!(lookup(&storage, RANGE.0).unwrap().devices, vec![device(5)]);
+    }
+
+    #[test]
+    fn should_store_device_and_anchor_metadata() {
+        let mut storage = storage_with_anchor();
+        let metadata = HashMap::from([
+            (
+                "origin".to_string(),
+                MetadataEntry::String("https://example.com".to_string()),
+            ),
+            (
+                "attestation".to_string(),
+                MetadataEntry::Map(HashMap::from([(
+                    "signature".to_string(),
+                    MetadataEntry::Bytes(ByteBuf::from(vec![1, 2, 3])),
+                )])),
+            ),
+        ]);
+        let with_metadata = DeviceData {
+            metadata: Some(metadata.clone()),
+            ..device(2)
+        };
+        add(&mut storage, RANGE.0, with_metadata.clone()).unwrap();
+        replace_metadata(&mut storage, RANGE.0, metadata.clone()).unwrap();
+
+        assert_eq!(
+            lookup(&storage, RANGE.0).unwrap(),
+            AnchorInfo {
+                devices: vec![device(1), with_metadata],
+                metadata: Some(metadata),
+            }
+        );
+    }
+
+    #[test]
+    fn should_accept_metadata_exactly_filling_the_entry() {
+        let mut storage = storage_with_anchor();
+        let limit = storage.candid_entry_size_limit();
+        let metadata = |len: usize| {
+            HashMap::from([(
+                "attestation".to_string(),
+                MetadataEntry::Bytes(ByteBuf::from(vec![0; len])),
+            )])
+        };
+        let mut anchor = storage.read_anchor(RANGE.0).unwrap();
+        anchor.metadata = Some(metadata(1000));
+        let len = 1000 + limit - candid::encode_one(&anchor).unwrap().len();
+
+        replace_metadata(&mut storage, RANGE.0, metadata(len)).unwrap();
+        assert_eq!(
+            lookup(&storage, RANGE.0).unwrap().metadata,
+            Some(metadata(len))
+        );
+        // the reported length is the one of the encoded record, not the one of the metadata
+        assert_eq!(
+            replace_metadata(&mut storage, RANGE.0, metadata(len + 1)),
+            Err(DeviceError::EntrySizeLimitExceeded {
+                length: limit as u64 + 1
+            })
+        );
+        assert_eq!(
+            lookup(&storage, RANGE.0).unwrap().metadata,
+            Some(metadata(len))
+        );
+    }
+
+    #[test]
+    fn should_only_record_device_usage_once_per_interval() {
+        let mut storage = storage_with_anchor();
+        let key = device(1).pubkey;
+        let last_usage = |storage: &Storage<VectorMemory>| {
+            lookup(storage, RANGE.0).unwrap().devices[0].last_usage_timestamp
+        };
+
+        assert_eq!(
+            record_device_usage(&mut storage, RANGE.0, &key, 1_000),
+            Ok(true)
+        );
+        assert_eq!(last_usage(&storage), Some(1_000));
+
+        // a second authentication within the interval does not write the anchor
+        let within = 1_000 + DEVICE_USAGE_WRITE_INTERVAL_NS - 1;
+        assert_eq!(
+            record_device_usage(&mut storage, RANGE.0, &key, within),
+            Ok(false)
+        );
+        assert_eq!(last_usage(&storage), Some(1_000));
+
+        let after = 1_000 + DEVICE_USAGE_WRITE_INTERVAL_NS;
+        assert_eq!(
+            record_device_usage(&mut storage, RANGE.0, &key, after),
+            Ok(true)
+        );
+        assert_eq!(last_usage(&storage), Some(after));
+
+        assert_eq!(
+            record_device_usage(&mut storage, RANGE.0, &device(2).pubkey, after),
+            Err(DeviceError::DeviceNotFound)
+        );
+    }
+
+    #[test]
+    fn should_not_take_last_usage_from_callers() {
+        let mut storage = storage_with_anchor();
+        record_device_usage(&mut storage, RANGE.0, &device(1).pubkey, 1_000).unwrap();
+        let used = |key| DeviceData {
+            last_usage_timestamp: Some(5_000),
+            ..device(key)
+        };
+
+        add(&mut storage, RANGE.0, used(2)).unwrap();
+        replace(
+            &mut storage,
+            RANGE.0,
+            principal(1),
+            device(1).pubkey,
+            used(1),
+        )
+        .unwrap();
+        replace(
+            &mut storage,
+            RANGE.0,
+            principal(1),
+            device(2).pubkey,
+            used(3),
+        )
+        .unwrap();
+        let timestamps: Vec<_> = lookup(&storage, RANGE.0)
+            .unwrap()
+            .devices
+            .into_iter()
+            .map(|d| d.last_usage_timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![Some(1_000), None]);
+    }
+
+    #[test]
+    fn should_delete_anchor() {
+        let mut storage = storage_with_anchor();
+        delete(&mut storage, RANGE.0).unwrap();
+        assert!(matches!(
+            lookup(&storage, RANGE.0),
+            Err(DeviceError::StorageError(_))
+        ));
+        assert!(matches!(
+            add(&mut storage, RANGE.0, device(2)),
+            Err(DeviceError::StorageError(_))
+        ));
+        assert!(matches!(
+            delete(&mut storage, RANGE.0),
+            Err(DeviceError::StorageError(_))
+        ));
+    }
+
+    #[test]
+    fn should_allow_scheduled_deletion_after_grace_period() {
+        let mut scheduled = vec![];
+        let due = schedule_deletion(&mut scheduled, RANGE.0, 1_000);
+        assert_eq!(due, 1_000 + ANCHOR_DELETION_GRACE_PERIOD_NS);
+        // scheduling again does not postpone the deletion
+        assert_eq!(schedule_deletion(&mut scheduled, RANGE.0, 2_000), due);
+
+        assert!(!deletion_due(&scheduled, RANGE.0, due - 1));
+        assert!(deletion_due(&scheduled, RANGE.0, due));
+        assert!(!deletion_due(&scheduled, RANGE.0 + 1, due));
+
+        assert!(cancel_deletion(&mut scheduled, RANGE.0));
+        assert!(!cancel_deletion(&mut scheduled, RANGE.0));
+        assert!(!deletion_due(&scheduled, RANGE.0, due));
+    }
+
+    #[test]
+    fn should_combine_anchor_info_of_individual_endpoints() {
+        let mut storage = storage_with_anchor();
+        let assert_consistent = |storage: &Storage<VectorMemory>| {
+            let info = anchor_info(storage, RANGE.0, None).unwrap();
+            let AnchorInfo { devices, metadata } = lookup(storage, RANGE.0).unwrap();
+            assert_eq!(info.devices, devices);
+            assert_eq!(info.metadata, metadata);
+            assert_eq!(info.device_registration, None);
+        };
+        assert_consistent(&storage);
+
+        add(&mut storage, RANGE.0, protected_device(2)).unwrap();
+        assert_consistent(&storage);
+        record_device_usage(&mut storage, RANGE.0, &device(1).pubkey, 1_000).unwrap();
+        assert_consistent(&storage);
+        replace_metadata(
+            &mut storage,
+            RANGE.0,
+            HashMap::from([(
+                "origin".to_string(),
+                MetadataEntry::String("https://example.com".to_string()),
+            )]),
+        )
+        .unwrap();
+        assert_consistent(&storage);
+        replace(
+            &mut storage,
+            RANGE.0,
+            principal(1),
+            device(1).pubkey,
+            device(3),
+        )
+        .unwrap();
+        assert_consistent(&storage);
+        remove(
+            &mut storage,
+            RANGE.0,
+            principal(2),
+            protected_device(2).pubkey,
+        )
+        .unwrap();
+        assert_consistent(&storage);
+
+        let info = anchor_info(&storage, RANGE.0, None).unwrap();
+        assert_eq!(info.devices, vec![device(3)]);
+        assert!(info.metadata.unwrap().contains_key("origin"));
+
+        // the device registration state is passed through
+        let registration = DeviceRegistrationInfo {
+            expiration: 1_000,
+            tentative_device: Some(device(4)),
+        };
+        assert_eq!(
+            anchor_info(&storage, RANGE.0, Some(registration.clone()))
+                .unwrap()
+                .device_registration,
+            Some(registration)
+        );
+        assert!(matches!(
+            anchor_info(&storage, RANGE.0 + 1, None),
+            Err(DeviceError::StorageError(_))
+        ));
+    }
+}
@@ -0,0 +1,580 @@
+//! Audit log of the anchor operations, pushed to an archive canister.
+//!
+//! If an archive is configured in the persistent state (see [ArchiveConfig]), every mutating
+//! operation appends an [ArchiveEntry] to the [ArchiveBuffer], which the heartbeat drains by calling
+//! `write_entries` of the archive with up to `max_entries_per_call` entries. Before the first push,
+//! the module hash of the archive is checked against the expected one. Failed pushes are retried
+//! with exponential backoff.
+//!
+//! Up to [SPILL_THRESHOLD] entries are kept in memory, and carried in the persistent state across
+//! upgrades. Beyond that, they are spilled to stable memory (see
+//! [RecordStorage::write_archive_buffer]), which holds the oldest entries. If more than
+//! [MAX_SPILLED_ENTRIES] entries are waiting, the oldest ones are dropped, which the archive
+//! notices from the gap in the sequence numbers.
+//!
+//! Entries are identified by their sequence number: once the archive acknowledges a batch, all
+//! entries up to its last sequence number are removed, so no entry is pushed twice.
+
+use std::collections::VecDeque;
+
+use candid::Principal;
+use ic_cdk::api::call::call;
+use ic_cdk::api::management_canister::main::{canister_status, CanisterIdRecord};
+use ic_cdk::api::time;
+use serde_bytes::ByteBuf;
+
+use crate::{secs_to_nanos, state};
+use crate::storage::record_storage::RecordStorage;
+use crate::storage::StorageError;
+use crate::types::{Anchor, ArchiveConfig, ArchiveEntry, Entry, Operation, Timestamp, UserNumber};
+
+/// Number of entries kept in memory before they are spilled to stable memory.
+pub const SPILL_THRESHOLD: usize = 1_000;
+/// Maximum number of entries waiting to be pushed, the oldest entries beyond it are dropped.
+pub const MAX_SPILLED_ENTRIES: usize = 10_000;
+/// Time to wait before retrying a failed push, doubled with every further failure.
+pub const INITIAL_BACKOFF_NS: u64 = secs_to_nanos(1);
+/// Maximum time to wait before retrying a failed push.
+pub const MAX_BACKOFF_NS: u64 = secs_to_nanos(60 * 60);
+
+/// The entries waiting to be pushed to the archive: the entries spilled to stable memory, followed
+/// by the entries in memory.
+#[derive(Default)]
+pub struct ArchiveBuffer {
+    entries: VecDeque<ArchiveEntry>,
+    // number of entries spilled to stable memory
+    spilled: usize,
+    // sequence number of the last entry of the push in flight, if any
+    in_flight: Option<u64>,
+    // failed pushes since the last successful one
+    failures: u32,
+    retry_at: Timestamp,
+    // whether the module hash of the archive has been checked
+    module_hash_checked: bool,
+}
+
+impl ArchiveBuffer {
+    /// Returns the number of entries waiting to be pushed.
+    pub fn len(&self) -> usize {
+        self.spilled + self.entries.len()
+    }
+
+    /// Appends an entry, spilling the entries in memory if there are more than [SPILL_THRESHOLD].
+    pub fn append<S: RecordStorage + ?Sized>(
+        &mut self,
+        storage: &mut S,
+        entry: ArchiveEntry,
+    ) -> Result<(), StorageError> {
+        self.entries.push_back(entry);
+        if self.entries.len() > SPILL_THRESHOLD {
+            self.spill(storage)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the entries in memory to stable memory. If an error is returned, the entries remain
+    /// in memory.
+    pub fn spill<S: RecordStorage + ?Sized>(
+        &mut self,
+        storage: &mut S,
+    ) -> Result<(), StorageError> {
+        if self.entries.is_empty() {
+            return Ok(());
+        }
+        let mut spilled = self.read_spilled(storage)?;
+        spilled.extend(self.entries.iter().cloned());
+        let excess = spilled.len().saturating_sub(MAX_SPILLED_ENTRIES);
+        spilled.drain(..excess);
+        storage.write_archive_buffer(&spilled)?;
+        self.entries.clear();
+        self.spilled = spilled.len();
+        Ok(())
+    }
+
+    /// Removes the entries in memory, to be carried in the persistent state across an upgrade.
+    pub fn take_entries(&mut self) -> Vec<ArchiveEntry> {
+        self.entries.drain(..).collect()
+    }
+
+    /// Recovers the entries spilled to stable memory and re-enqueues the `entries` taken before an
+    /// upgrade, skipping the entries already spilled.
+    pub fn restore<S: RecordStorage + ?Sized>(
+        &mut self,
+        storage: &mut S,
+        entries: Vec<ArchiveEntry>,
+    ) -> Result<(), StorageError> {
+        let spilled = storage.read_archive_buffer()?;
+        self.spilled = spilled.len();
+        let mut last_sequence_number = spilled.last().map(|entry| entry.sequence_number);
+        for entry in entries {
+            if last_sequence_number.is_none_or(|last| entry.sequence_number > last) {
+                last_sequence_number = Some(entry.sequence_number);
+                self.entries.push_back(entry);
+            }
+        }
+        if self.entries.len() > SPILL_THRESHOLD {
+            self.spill(storage)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the oldest `max_entries` entries to be pushed at `now`, unless a push is in flight
+    /// or the backoff of the last failed push has not elapsed.
+    pub fn next_batch<S: RecordStorage + ?Sized>(
+        &mut self,
+        storage: &S,
+        max_entries: usize,
+        now: Timestamp,
+    ) -> Result<Option<Vec<ArchiveEntry>>, StorageError> {
+        if self.in_flight.is_some() || now < self.retry_at {
+            return Ok(None);
+        }
+        let batch: Vec<ArchiveEntry> = if self.spilled > 0 {
+            let mut spilled = self.read_spilled(storage)?;
+            spilled.truncate(max_entries);
+            spilled
+        } else {
+            self.entries.iter().take(max_entries).cloned().collect()
+        };
+        self.in_flight = batch.last().map(|entry| entry.sequence_number);
+        Ok(Some(batch).filter(|batch| !batch.is_empty()))
+    }
+
+    /// Records the result of the push of the last batch: the pushed entries are removed, or the
+    /// push is retried after the backoff.
+    pub fn on_pushed<S: RecordStorage + ?Sized>(
+        &mut self,
+        storage: &mut S,
+        result: Result<(), String>,
+        now: Timestamp,
+    ) -> Result<(), StorageError> {
+        let Some(last_pushed) = self.in_flight.take() else {
+            return Ok(());
+        };
+        if result.is_err() {
+            self.failures += 1;
+            self.retry_at = now.saturating_add(backoff(self.failures));
+            return Ok(());
+        }
+        self.failures = 0;
+        self.retry_at = 0;
+        if self.spilled > 0 {
+            let mut spilled = self.read_spilled(storage)?;
+            spilled.retain(|entry| entry.sequence_number > last_pushed);
+            if spilled.len() != self.spilled {
+                storage.write_archive_buffer(&spilled)?;
+                self.spilled = spilled.len();
+            }
+        }
+        while matches!(self.entries.front(), Some(entry) if entry.sequence_number <= last_pushed) {
+            self.entries.pop_front();
+        }
+        Ok(())
+    }
+
+    fn read_spilled<S: RecordStorage + ?Sized>(
+        &self,
+        storage: &S,
+    ) -> Result<Vec<ArchiveEntry>, StorageError> {
+        if self.spilled == 0 {
+            return Ok(vec![]);
+        }
+        storage.read_archive_buffer()
+    }
+}
+
+/// Time to wait before retrying after the given number of consecutive failed pushes.
+pub fn backoff(failures: u32) -> u64 {
+    INITIAL_BACKOFF_NS
+        .checked_shl(failures.saturating_sub(1))
+        .filter(|backoff| *backoff <= MAX_BACKOFF_NS)
+        .unwrap_or(MAX_BACKOFF_NS)
+}
+
+/// Creates the archive entry of an operation on the given anchor.
+pub fn archive_entry(
+    anchor: Anchor,
+    operation: Operation,
+    caller: Principal,
+    timestamp: Timestamp,
+    sequence_number: u64,
+) -> ArchiveEntry {
+    let entry = Entry {
+        anchor,
+        operation,
+        timestamp,
+        caller,
+        sequence_number,
+    };
+    ArchiveEntry {
+        anchor,
+        timestamp,
+        sequence_number,
+        entry: ByteBuf::from(candid::encode_one(entry).expect("failed to encode archive entry")),
+    }
+}
+
+/// Appends an operation on the given anchor to the audit log, if an archive is configured.
+///
+/// Failing to buffer the entry (because it cannot be spilled) must not fail the operation, the
+/// archive notices the gap in the sequence numbers.
+pub fn log_operation(user_number: UserNumber, operation: Operation, caller: Principal) {
+    let sequence_number = state::persistent_state_mut(|persistent_state| {
+        persistent_state.archive_config.as_ref()?;
+        let sequence_number = persistent_state.archive_sequence_number.unwrap_or(0);
+        persistent_state.archive_sequence_number = Some(sequence_number + 1);
+        Some(sequence_number)
+    });
+    let Some(sequence_number) = sequence_number else {
+        return;
+    };
+    let entry = archive_entry(user_number, operation, caller, time(), sequence_number);
+    let _ = state::archive_buffer_and_storage_mut(|buffer, storage| buffer.append(storage, entry));
+}
+
+/// Pushes the next batch of entries to the archive, if any. Called by the heartbeat.
+pub fn push_entries() {
+    let Some(config) =
+        state::persistent_state(|persistent_state| persistent_state.archive_config.clone())
+    else {
+        return;
+    };
+    let (batch, module_hash_checked) = state::archive_buffer_and_storage_mut(|buffer, storage| {
+        (
+            buffer.next_batch(storage, config.max_entries_per_call.max(1) as usize, time()),
+            buffer.module_hash_checked,
+        )
+    });
+    let batch = match batch {
+        Ok(Some(batch)) => batch,
+        _ => return,
+    };
+    ic_cdk::spawn(async move {
+        let result = push(&config, batch, module_hash_checked).await;
+        state::archive_buffer_and_storage_mut(|buffer, storage| {
+            buffer.module_hash_checked |= result.is_ok();
+            let _ = buffer.on_pushed(storage, result, time());
+        });
+    });
+}
+
+async fn push(
+    config: &ArchiveConfig,
+    entries: Vec<ArchiveEntry>,
+    module_hash_checked: bool,
+) -> Result<(), String> {
+    if !module_hash_checked {
+        let (status,) = canister_status(CanisterIdRecord {
+            canister_id: config.archive_canister,
+        })
+        .await
+        .map_err(|(code, msg)| format!("failed to get archive status: {:?} {}", code, msg))?;
+        check_module_hash(&config.expected_module_hash, status.module_hash.as_deref())?;
+    }
+    call(config.archive_canister, "write_entries", (entries,))
+        .await
+        .map_err(|(code, msg)| format!("failed to push archive entries: {:?} {}", code, msg))
+}
+
+/// Checks that the archive runs the expected module.
+pub fn check_module_hash(expected: &[u8; 32], actual: Option<&[u8]>) -> Result<(), String> {
+    match actual {
+        Some(actual) if actual == expected => Ok(()),
+        Some(actual) => Err(format!(
+            "archive module hash {} does not match the expected {}",
+            hex::encode(actual),
+            hex::encode(expected)
+        )),
+        None => Err("archive canister is empty".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::PersistentState;
+    use crate::storage::Storage;
+    use crate::testing::VectorMemory;
+    use crate::types::PublicKey;
+
+    use super::*;
+
+    const RANGE: (UserNumber, UserNumber) = (10_000, 10_100);
+    const BATCH_SIZE: usize = 10;
+
+    /// Archive canister accepting the pushed entries unless it is set to fail.
+    #[derive(Default)]
+    struct MockArchive {
+        failures_left: u32,
+        entries: Vec<ArchiveEntry>,
+    }
+
+    impl MockArchive {
+        fn write_entries(&mut self, entries: Vec<ArchiveEntry>) -> Result<(), String> {
+            if self.failures_left > 0 {
+                self.failures_left -= 1;
+                return Err("archive unavailable".to_string());
+            }
+            self.entries.extend(entries);
+            Ok(())
+        }
+    }
+
+    fn entry(sequence_number: u64) -> ArchiveEntry {
+        archive_entry(
+            RANGE.0,
+            Operation::RemoveDevice {
+                device: PublicKey::from(sequence_number.to_le_bytes().to_vec()),
+            },
+            Principal::anonymous(),
+            sequence_number,
+            sequence_number,
+        )
+    }
+
+    fn storage() -> Storage<VectorMemory> {
+        Storage::new(RANGE, VectorMemory::default()).unwrap()
+    }
+
+    /// Pushes the next batch to the archive, like the heartbeat. Returns whether a batch was pushed.
+    fn heartbeat(
+        buffer: &mut ArchiveBuffer,
+        storage: &mut Storage<VectorMemory>,
+        archive: &mut MockArchive,
+        now: Timestamp,
+    ) -> bool {
+        match buffer.next_batch(storage, BATCH_SIZE, now).unwrap() {
+            Some(batch) => {
+                let result = archive.write_entries(batch);
+                buffer.on_pushed(storage, result, now).unwrap();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn sequence_numbers(entries: &[ArchiveEntry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.sequence_number).collect()
+    }
+
+    #[test]
+    fn should_push_entries_in_batches() {
+        let mut storage = storage();
+        let mut buffer = ArchiveBuffer::default();
+        let mut archive = MockArchive::default();
+        for i in 0..25 {
+            buffer.append(&mut storage, entry(i)).unwrap();
+        }
+
+        while heartbeat(&mut buffer, &mut storage, &mut archive, 0) {}
+        assert_eq!(buffer.len(), 0);
+        assert_eq!(
+            sequence_numbers(&archive.entries),
+            (0..25).collect::<Vec<_>>()
+        );
+
+        let decoded: Entry = candid::decode_one(&archive.entries[3].entry).unwrap();
+        assert_eq!(decoded.sequence_number, 3);
+        assert_eq!(decoded.anchor, RANGE.0);
+    }
+
+    #[test]
+    fn should_retry_failed_pushes_with_backoff() {
+        let mut storage = storage();
+        let mut buffer = ArchiveBuffer::default();
+        let mut archive = MockArchive {
+            failures_left: 2,
+            ..MockArchive::default()
+        };
+        buffer.append(&mut storage, entry(0)).unwrap();
+
+        assert!(heartbeat(&mut buffer, &mut storage, &mut archive, 0));
+        assert!(!heartbeat(
+            &mut buffer,
+            &mut storage,
+            &mut archive,
+            INITIAL_BACKOFF_NS - 1
+        ));
+        assert!(heartbeat(
+            &mut buffer,
+            &mut storage,
+            &mut archive,
+            INITIAL_BACKOFF_NS
+        ));
+        // the backoff doubles
+        let now = INITIAL_BACKOFF_NS;
+        assert!(!heartbeat(
+            &mut buffer,
+            &mut storage,
+            &mut archive,
+            now + 2 * INITIAL_BACKOFF_NS - 1
+        ));
+        assert_eq!(buffer.len(), 1);
+        assert!(heartbeat(
+            &mut buffer,
+            &mut storage,
+            &mut archive,
+            now + 2 * INITIAL_BACKOFF_NS
+        ));
+
+        assert_eq!(buffer.len(), 0);
+        assert_eq!(sequence_numbers(&archive.entries), vec![0]);
+        // a successful push resets the backoff
+        buffer.append(&mut storage, entry(1)).unwrap();
+        assert!(heartbeat(
+            &mut buffer,
+            &mut storage,
+            &mut archive,
+            now + 2 * INITIAL_BACKOFF_NS
+        ));
+        assert_eq!(sequence_numbers(&archive.entries), vec![0, 1]);
+    }
+
+    #[test]
+    fn should_cap_backoff() {
+        assert_eq!(backoff(1), INITIAL_BACKOFF_NS);
+        assert_eq!(backoff(3), 4 * INITIAL_BACKOFF_NS);
+        assert_eq!(backoff(20), MAX_BACKOFF_NS);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF_NS);
+    }
+
+    #[test]
+    fn should_not_push_while_a_push_is_in_flight() {
+        let mut storage = storage();
+        let mut buffer = ArchiveBuffer::default();
+        buffer.append(&mut storage, entry(0)).unwrap();
+
+        let batch = buffer.next_batch(&storage, BATCH_SIZE, 0).unwrap().unwrap();
+        assert_eq!(sequence_numbers(&batch), vec![0]);
+        buffer.append(&mut storage, entry(1)).unwrap();
+        assert_eq!(buffer.next_batch(&storage, BATCH_SIZE, 0).unwrap(), None);
+
+        buffer.on_pushed(&mut storage, Ok(()), 0).unwrap();
+        let batch = buffer.next_batch(&storage, BATCH_SIZE, 0).unwrap().unwrap();
+        assert_eq!(sequence_numbers(&batch), vec![1]);
+    }
+
+    #[test]
+    fn should_spill_entries_beyond_threshold_to_stable_memory() {
+        let memory = VectorMemory::default();
+        let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+        storage.flush();
+        let mut buffer = ArchiveBuffer::default();
+        let count = SPILL_THRESHOLD as u64 + 5;
+        for i in 0..count {
+            buffer.append(&mut storage, entry(i)).unwrap();
+        }
+        assert_eq!(buffer.spilled, SPILL_THRESHOLD + 1);
+        assert_eq!(buffer.entries.len(), 4);
+
+        // a push in flight while the remaining entries are spilled
+        let batch = buffer.next_batch(&storage, BATCH_SIZE, 0).unwrap().unwrap();
+        assert_eq!(
+            sequence_numbers(&batch),
+            (0..BATCH_SIZE as u64).collect::<Vec<_>>()
+        );
+        buffer.spill(&mut storage).unwrap();
+        buffer.on_pushed(&mut storage, Ok(()), 0).unwrap();
+        assert_eq!(buffer.len(), count as usize - BATCH_SIZE);
+
+        // the spilled entries survive an upgrade
+        let mut storage = Storage::try_from_memory(memory).unwrap().unwrap();
+        let mut buffer = ArchiveBuffer::default();
+        buffer.restore(&mut storage, vec![]).unwrap();
+        assert_eq!(buffer.len(), count as usize - BATCH_SIZE);
+
+        let mut archive = MockArchive::default();
+        while heartbeat(&mut buffer, &mut storage, &mut archive, 0) {}
+        assert_eq!(buffer.len(), 0);
+        assert_eq!(
+            sequence_numbers(&archive.entries),
+            (BATCH_SIZE as u64..count).collect::<Vec<_>>()
+        );
+        assert_eq!(storage.read_archive_buffer().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn should_carry_entries_in_persistent_state_across_upgrades() {
+        let memory = VectorMemory::default();
+        let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+        storage.flush();
+        let mut buffer = ArchiveBuffer::default();
+        let mut archive = MockArchive::default();
+        let count = SPILL_THRESHOLD as u64 + 5;
+        for i in 0..count {
+            buffer.append(&mut storage, entry(i)).unwrap();
+        }
+        assert!(heartbeat(&mut buffer, &mut storage, &mut archive, 0));
+
+        // pre_upgrade
+        let persistent_state = PersistentState {
+            archive_entries: Some(buffer.take_entries()),
+            ..PersistentState::default()
+        };
+        storage.write_persistent_state(&persistent_state).unwrap();
+
+        // post_upgrade
+        let mut storage = Storage::try_from_memory(memory).unwrap().unwrap();
+        let persistent_state = storage.read_persistent_state().unwrap();
+        let mut buffer = ArchiveBuffer::default();
+        buffer
+            .restore(
+                &mut storage,
+                persistent_state.archive_entries.unwrap_or_default(),
+            )
+            .unwrap();
+        assert_eq!(buffer.len(), count as usize - BATCH_SIZE);
+
+        while heartbeat(&mut buffer, &mut storage, &mut archive, 0) {}
+        assert_eq!(buffer.len(), 0);
+        assert_eq!(
+            sequence_numbers(&archive.entries),
+            (0..count).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn should_not_restore_entries_already_spilled() {
+        let mut storage = storage();
+        let mut buffer = ArchiveBuffer::default();
+        for i in 0..5 {
+            buffer.append(&mut storage, entry(i)).unwrap();
+        }
+        buffer.spill(&mut storage).unwrap();
+
+        let mut buffer = ArchiveBuffer::default();
+        buffer
+            .restore(&mut storage, (3..8).map(entry).collect())
+            .unwrap();
+        assert_eq!(buffer.len(), 8);
+
+        let mut archive = MockArchive::default();
+        while heartbeat(&mut buffer, &mut storage, &mut archive, 0) {}
+        assert_eq!(
+            sequence_numbers(&archive.entries),
+            (0..8).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn should_drop_oldest_entries_beyond_max_spilled_entries() {
+        let mut storage = storage();
+        let mut buffer = ArchiveBuffer::default();
+        let count = (MAX_SPILLED_ENTRIES + SPILL_THRESHOLD) as u64;
+        for i in 0..count {
+            buffer.append(&mut storage, entry(i)).unwrap();
+        }
+        buffer.spill(&mut storage).unwrap();
+
+        assert_eq!(buffer.len(), MAX_SPILLED_ENTRIES);
+        let batch = buffer.next_batch(&storage, BATCH_SIZE, 0).unwrap().unwrap();
+        assert_eq!(batch[0].sequence_number, SPILL_THRESHOLD as u64);
+    }
+
+    #[test]
+    fn should_check_archive_module_hash() {
+        let expected = [1; 32];
+        assert_eq!(check_module_hash(&expected, Some(&[1; 32])), Ok(()));
+        assert!(check_module_hash(&expected, Some(&[2; 32])).is_err());
+        assert!(check_module_hash(&expected, None).is_err());
+    }
+}
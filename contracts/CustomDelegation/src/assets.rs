@@ -0,0 +1,387 @@
+//! HTTP responses certified with version 2 of the response verification of the HTTP gateway
+//! protocol, so that clients can check that `/metrics` and `/status` are served by the canister.
+//!
+//! Queries cannot change the certified data, so the responses are computed in update calls (see
+//! [update_certified_responses]) and served as is by `http_request`. Their hashes are certified
+//! under [LABEL_ASSETS] next to the revoked delegations and the signatures of the delegations (see
+//! [crate::delegation::update_root_hash]):
+//!
+//! ```text
+//! http_expr/<path segment>/<$>/<expression hash>/""/<response hash> -> ""
+//! ```
+//!
+//! All responses are certified with [CERTIFICATE_EXPRESSION]: the request is not certified, the
+//! status, the body and the `Content-Type` header of the response are.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ic_certified_map::{AsHashTree, Hash, HashTree};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+
+use crate::{LABEL_ASSETS, LABEL_REVOKED, LABEL_SIG, metrics, state};
+use crate::deps::hash::{self, Value};
+use crate::deps::http::{HeaderField, HttpResponse};
+use crate::deps::signature_map::SignatureMap;
+use crate::state::{AssetHashes, Assets};
+use crate::types::Timestamp;
+
+pub const METRICS_PATH: &str = "/metrics";
+pub const STATUS_PATH: &str = "/status";
+
+/// Expression certifying the status, the body and the `Content-Type` header of a response.
+pub const CERTIFICATE_EXPRESSION: &str = "default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{certified_response_headers:ResponseHeaderList{headers:[\"content-type\"]}}}})";
+const CERTIFIED_HEADERS: [&str; 2] = ["content-type", "ic-certificateexpression"];
+const IC_CERTIFICATE_HEADER: &str = "IC-Certificate";
+const IC_CERTIFICATE_EXPRESSION_HEADER: &str = "IC-CertificateExpression";
+// label of the exact path match in the tree
+const EXACT_MATCH: &[u8] = b"<$>";
+// request hash of the responses whose request is not certified
+const NO_REQUEST_HASH: &[u8] = b"";
+
+/// Hashes certifying the response of a path, see the tree layout above.
+pub struct ResponseHashes {
+    expr_hash: Hash,
+    response_hash: Hash,
+}
+
+impl AsHashTree for ResponseHashes {
+    fn root_hash(&self) -> Hash {
+        self.as_hash_tree().reconstruct()
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        use ic_certified_map::labeled;
+        labeled(
+            EXACT_MATCH,
+            labeled(
+                &self.expr_hash,
+                labeled(
+                    NO_REQUEST_HASH,
+                    labeled(&self.response_hash, HashTree::Leaf(Cow::from(&b""[..]))),
+                ),
+            ),
+        )
+    }
+}
+
+/// Recomputes the certified responses with timestamp `now`. Must be followed by an update of the
+/// certified data, see [crate::delegation::update_root_hash].
+pub fn update_certified_responses(now: Timestamp) {
+    let stats = metrics::collect();
+    state::assets_and_hashes_mut(|assets, asset_hashes| {
+        certify(
+            assets,
+            asset_hashes,
+            METRICS_PATH,
+            "text/plain; version=0.0.4",
+            metrics::encode(&stats, now),
+        );
+        certify(
+            assets,
+            asset_hashes,
+            STATUS_PATH,
+            "application/json",
+            metrics::encode_status(&stats),
+        );
+    })
+}
+
+/// Adds the response with the given body to the assets, replacing the previous one of the path.
+/// The path must consist of a single segment, e.g. `/status`.
+pub fn certify(
+    assets: &mut Assets,
+    asset_hashes: &mut AssetHashes,
+    path: &'static str,
+    content_type: &str,
+    body: Vec<u8>,
+) {
+    let headers = vec![
+        ("Content-Type".to_string(), content_type.to_string()),
+        (
+            IC_CERTIFICATE_EXPRESSION_HEADER.to_string(),
+            CERTIFICATE_EXPRESSION.to_string(),
+        ),
+    ];
+    asset_hashes.insert(
+        path_segment(path),
+        ResponseHashes {
+            expr_hash: hash::hash_string(CERTIFICATE_EXPRESSION),
+            response_hash: response_hash(200, &headers, &body),
+        },
+    );
+    assets.insert(path, (headers, body));
+}
+
+/// Returns the certified response of the given path, if any.
+///
+/// The `IC-Certificate` header is only added if the `certificate` of the certified data is
+/// available, i.e. in query calls.
+pub fn http_response(path: &str, certificate: Option<Vec<u8>>) -> Option<HttpResponse> {
+    let (mut headers, body) = state::assets(|assets| assets.get(path).cloned())?;
+    if let Some(certificate) = certificate {
+        let header = state::certified_trees(|asset_hashes, revocations, sigs| {
+            ic_certificate_header(
+                asset_hashes,
+                &revocations.root_hash(),
+                sigs,
+                path,
+                &certificate,
+            )
+        });
+        headers.push((IC_CERTIFICATE_HEADER.to_string(), header));
+    }
+    Some(HttpResponse {
+        status_code: 200,
+        headers,
+        body: ByteBuf::from(body),
+        upgrade: None,
+        streaming_strategy: None,
+    })
+}
+
+/// Returns the witness of the response of the given path, with the revoked delegations (given by
+/// their root hash) and the signatures pruned.
+pub fn response_tree<'a>(
+    asset_hashes: &'a AssetHashes,
+    revocations_hash: &Hash,
+    sigs: &SignatureMap,
+    path: &str,
+) -> HashTree<'a> {
+    use ic_certified_map::{fork, fork_hash, labeled, labeled_hash};
+    fork(
+        labeled(
+            LABEL_ASSETS,
+            asset_hashes.witness(path_segment(path).as_bytes()),
+        ),
+        HashTree::Pruned(fork_hash(
+            &labeled_hash(LABEL_REVOKED, revocations_hash),
+            &labeled_hash(LABEL_SIG, &sigs.root_hash()),
+        )),
+    )
+}
+
+/// Computes the hash of a response with the given status, headers and body, of which only the
+/// certified headers count.
+pub fn response_hash(status_code: u16, headers: &[HeaderField], body: &[u8]) -> Hash {
+    let mut certified = HashMap::new();
+    for (name, value) in headers {
+        let name = name.to_ascii_lowercase();
+        if CERTIFIED_HEADERS.contains(&name.as_str()) {
+            certified.insert(name, Value::String(value));
+        }
+    }
+    certified.insert(
+        ":ic-cert-status".to_string(),
+        Value::U64(status_code as u64),
+    );
+    let mut bytes = hash::hash_of_map(certified).to_vec();
+    bytes.extend_from_slice(&hash::hash_bytes(body));
+    hash::hash_bytes(bytes)
+}
+
+fn ic_certificate_header(
+    asset_hashes: &AssetHashes,
+    revocations_hash: &Hash,
+    sigs: &SignatureMap,
+    path: &str,
+    certificate: &[u8],
+) -> String {
+    let mut tree = serde_cbor::ser::Serializer::new(Vec::new());
+    tree.self_describe().unwrap();
+    response_tree(asset_hashes, revocations_hash, sigs, path)
+        .serialize(&mut tree)
+        .unwrap();
+    let expr_path = serde_cbor::to_vec(&[
+        std::str::from_utf8(LABEL_ASSETS).unwrap(),
+        path_segment(path),
+        std::str::from_utf8(EXACT_MATCH).unwrap(),
+    ])
+    .unwrap();
+    format!(
+        "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+        BASE64.encode(certificate),
+        BASE64.encode(tree.into_inner()),
+        BASE64.encode(expr_path)
+    )
+}
+
+fn path_segment(path: &str) -> &str {
+    path.strip_prefix('/').unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: Timestamp = 1_650_000_000_123_000_000;
+    const CERTIFICATE: [u8; 3] = [1, 2, 3];
+
+    /// Looks up the subtree at the given path, e.g. the witness of a response.
+    fn lookup<'a>(tree: &'a HashTree<'a>, path: &[&[u8]]) -> Option<&'a HashTree<'a>> {
+        match (path.split_first(), tree) {
+            (None, _) => Some(tree),
+            (Some(_), HashTree::Fork(forks)) => {
+                lookup(&forks.0, path).or_else(|| lookup(&forks.1, path))
+            }
+            (Some((label, rest)), HashTree::Labeled(l, subtree)) if l == label => {
+                lookup(subtree, rest)
+            }
+            _ => None,
+        }
+    }
+
+    fn header<'a>(response: &'a HttpResponse, name: &str) -> &'a str {
+        &response
+            .headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .unwrap()
+            .1
+    }
+
+    /// Splits the `IC-Certificate` header into its decoded fields.
+    fn ic_certificate_fields(header: &str) -> HashMap<&str, Vec<u8>> {
+        header
+            .split(", ")
+            .filter_map(|field| {
+                let (name, value) = field.split_once('=')?;
+                let value = value.strip_prefix(':')?.strip_suffix(':')?;
+                Some((name, BASE64.decode(value).unwrap()))
+            })
+            .collect()
+    }
+
+    fn is_certified(tree: &HashTree, path: &str, response_hash: &Hash) -> bool {
+        let expr_hash = hash::hash_string(CERTIFICATE_EXPRESSION);
+        let leaf = lookup(
+            tree,
+            &[
+                LABEL_ASSETS,
+                path_segment(path).as_bytes(),
+                EXACT_MATCH,
+                &expr_hash,
+                NO_REQUEST_HASH,
+                response_hash,
+            ],
+        );
+        matches!(leaf, Some(HashTree::Leaf(value)) if value.is_empty())
+    }
+
+    #[test]
+    fn should_serve_certified_responses() {
+        state::storage_mut(|storage| {
+            storage.allocate_anchor().unwrap();
+        });
+        update_certified_responses(NOW);
+
+        for path in [METRICS_PATH, STATUS_PATH] {
+            let response = http_response(path, Some(CERTIFICATE.to_vec())).unwrap();
+            assert_eq!(response.status_code, 200);
+            assert_eq!(
+                header(&response, IC_CERTIFICATE_EXPRESSION_HEADER),
+                CERTIFICATE_EXPRESSION
+            );
+
+            let certificate = header(&response, IC_CERTIFICATE_HEADER);
+            assert!(certificate.ends_with(", version=2"));
+            let fields = ic_certificate_fields(certificate);
+            assert_eq!(fields["certificate"], CERTIFICATE);
+            assert_eq!(
+                serde_cbor::from_slice::<Vec<String>>(&fields["expr_path"]).unwrap(),
+                vec!["http_expr", path_segment(path), "<$>"]
+            );
+
+            state::certified_trees(|asset_hashes, revocations, sigs| {
+                let tree = response_tree(asset_hashes, &revocations.root_hash(), sigs, path);
+                let mut cbor = serde_cbor::ser::Serializer::new(Vec::new());
+                cbor.self_describe().unwrap();
+                tree.serialize(&mut cbor).unwrap();
+                assert_eq!(fields["tree"], cbor.into_inner());
+
+                let served_hash = response_hash(200, &response.headers, &response.body);
+                assert!(is_certified(&tree, path, &served_hash));
+            });
+        }
+
+        let status = http_response(STATUS_PATH, None).unwrap();
+        assert_eq!(header(&status, "Content-Type"), "application/json");
+        assert!(std::str::from_utf8(&status.body)
+            .unwrap()
+            .contains("\"users_registered\":1"));
+        assert!(status
+            .headers
+            .iter()
+            .all(|(name, _)| name != IC_CERTIFICATE_HEADER));
+    }
+
+    #[test]
+    fn should_replace_response_of_recertified_path() {
+        let mut assets = Assets::default();
+        let mut asset_hashes = AssetHashes::new();
+        let sigs = SignatureMap::default();
+        certify(
+            &mut assets,
+            &mut asset_hashes,
+            METRICS_PATH,
+            "text/plain",
+            b"old".to_vec(),
+        );
+        certify(
+            &mut assets,
+            &mut asset_hashes,
+            STATUS_PATH,
+            "text/plain",
+            b"{}".to_vec(),
+        );
+        let old_hash = response_hash(200, &assets[METRICS_PATH].0, b"old");
+
+        certify(
+            &mut assets,
+            &mut asset_hashes,
+            METRICS_PATH,
+            "text/plain",
+            b"new".to_vec(),
+        );
+        let new_hash = response_hash(200, &assets[METRICS_PATH].0, b"new");
+        assert_eq!(assets[METRICS_PATH].1, b"new");
+
+        let revocations_hash = [0; 32];
+        let tree = response_tree(&asset_hashes, &revocations_hash, &sigs, METRICS_PATH);
+        assert!(is_certified(&tree, METRICS_PATH, &new_hash));
+        assert!(!is_certified(&tree, METRICS_PATH, &old_hash));
+        let tree = response_tree(&asset_hashes, &revocations_hash, &sigs, STATUS_PATH);
+        let status_hash = response_hash(200, &assets[STATUS_PATH].0, b"{}");
+        assert!(is_certified(&tree, STATUS_PATH, &status_hash));
+    }
+
+    #[test]
+    fn should_only_hash_certified_headers() {
+        let headers = vec![("Content-Type".to_string(), "text/plain".to_string())];
+        let mut with_other_headers = headers.clone();
+        with_other_headers.push((IC_CERTIFICATE_HEADER.to_string(), "ignored".to_string()));
+        with_other_headers.push(("Cache-Control".to_string(), "no-cache".to_string()));
+        assert_eq!(
+            response_hash(200, &headers, b"body"),
+            response_hash(200, &with_other_headers, b"body")
+        );
+
+        let other_content_type = vec![("content-type".to_string(), "text/html".to_string())];
+        assert_ne!(
+            response_hash(200, &headers, b"body"),
+            response_hash(200, &other_content_type, b"body")
+        );
+        assert_ne!(
+            response_hash(200, &headers, b"body"),
+            response_hash(404, &headers, b"body")
+        );
+    }
+
+    #[test]
+    fn should_not_serve_uncertified_paths() {
+        assert!(http_response("/other", Some(CERTIFICATE.to_vec())).is_none());
+    }
+}
@@ -0,0 +1,225 @@
+//! Proof-of-work challenges that have to be solved to register an anchor, so that bots cannot
+//! exhaust the anchor range for free.
+//!
+//! A solution of a challenge is any string `chars` such that the SHA-256 hash of the challenge key
+//! followed by `chars` starts with `difficulty` zero bits.
+
+use std::collections::HashMap;
+
+use sha2::{Digest, Sha256};
+
+use crate::secs_to_nanos;
+use crate::storage::Salt;
+use crate::types::{Challenge, ChallengeAttempt, ChallengeKey, Timestamp};
+
+/// Maximum number of challenges that can be waiting for a solution at the same time.
+pub const MAX_INFLIGHT_CHALLENGES: usize = 500;
+/// Time after which a challenge can no longer be solved.
+pub const CHALLENGE_EXPIRATION_PERIOD_NS: u64 = secs_to_nanos(5 * 60);
+/// Number of leading zero bits required by default, i.e. about a million hashes per registration.
+pub const DEFAULT_CHALLENGE_DIFFICULTY: u8 = 20;
+
+/// The challenges waiting for a solution.
+pub struct Challenges {
+    difficulty: u8,
+    // expiration of the challenges by key
+    inflight: HashMap<ChallengeKey, Timestamp>,
+    // number of challenges created so far, making the keys unique
+    created: u64,
+}
+
+impl Default for Challenges {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHALLENGE_DIFFICULTY)
+    }
+}
+
+impl Challenges {
+    pub fn new(difficulty: u8) -> Self {
+        Self {
+            difficulty,
+            inflight: HashMap::new(),
+            created: 0,
+        }
+    }
+
+    /// Creates a new challenge, pruning the expired ones first. The key is derived from the
+    /// secret `salt`, so that challenges cannot be predicted and solved in advance.
+    ///
+    /// Returns `None` if [MAX_INFLIGHT_CHALLENGES] challenges are still waiting for a solution.
+    pub fn create(&mut self, salt: &Salt, now: Timestamp) -> Option<Challenge> {
+        self.inflight.retain(|_, expiration| *expiration > now);
+        if self.inflight.len() >= MAX_INFLIGHT_CHALLENGES {
+            return None;
+        }
+
+        self.created += 1;
+        let mut hasher = Sha256::new();
+        hasher.update(salt);
+        hasher.update(now.to_le_bytes());
+        hasher.update(self.created.to_le_bytes());
+        let challenge_key = hex::encode(hasher.finalize());
+        self.inflight.insert(
+            challenge_key.clone(),
+            now.saturating_add(CHALLENGE_EXPIRATION_PERIOD_NS),
+        );
+        Some(Challenge {
+            challenge_key,
+            difficulty: self.difficulty,
+        })
+    }
+
+    /// Returns whether the attempt solves a challenge that has not expired yet.
+    ///
+    /// Every challenge can only be attempted once, whether the attempt is correct or not, so that
+    /// a solution cannot be used to register more than one anchor.
+    pub fn verify(&mut self, attempt: &ChallengeAttempt, now: Timestamp) -> bool {
+        match self.inflight.remove(&attempt.key) {
+            Some(expiration) if expiration > now => {
+                is_solution(&attempt.key, &attempt.chars, self.difficulty)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Returns whether `chars` solves the challenge with the given key and difficulty.
+pub fn is_solution(key: &str, chars: &str, difficulty: u8) -> bool {
+    let hash = Sha256::new()
+        .chain_update(key)
+        .chain_update(chars)
+        .finalize();
+    let mut zero_bits = 0;
+    for byte in hash {
+        zero_bits += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    zero_bits >= difficulty as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SALT: Salt = [7; 32];
+    const DIFFICULTY: u8 = 8;
+
+    fn solve(challenge: &Challenge) -> ChallengeAttempt {
+        let chars = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|chars| is_solution(&challenge.challenge_key, chars, challenge.difficulty))
+            .unwrap();
+        ChallengeAttempt {
+            chars,
+            key: challenge.challenge_key.clone(),
+        }
+    }
+
+    fn wrong_attempt(challenge: &Challenge) -> ChallengeAttempt {
+        let chars = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|chars| !is_solution(&challenge.challenge_key, chars, challenge.difficulty))
+            .unwrap();
+        ChallengeAttempt {
+            chars,
+            key: challenge.challenge_key.clone(),
+        }
+    }
+
+    #[test]
+    fn should_accept_solution_once() {
+        let mut challenges = Challenges::new(DIFFICULTY);
+        let challenge = challenges.create(&SALT, 0).unwrap();
+        assert_eq!(challenge.difficulty, DIFFICULTY);
+
+        let attempt = solve(&challenge);
+        assert!(challenges.verify(&attempt, 1));
+        assert!(!challenges.verify(&attempt, 1));
+        assert_eq!(challenges.inflight.len(), 0);
+    }
+
+    #[test]
+    fn should_create_unique_challenges() {
+        let mut challenges = Challenges::new(DIFFICULTY);
+        let first = challenges.create(&SALT, 0).unwrap();
+        let second = challenges.create(&SALT, 0).unwrap();
+        assert_ne!(first.challenge_key, second.challenge_key);
+        assert_ne!(
+            Challenges::new(DIFFICULTY)
+                .create(&[8; 32], 0)
+                .unwrap()
+                .challenge_key,
+            first.challenge_key
+        );
+    }
+
+    #[test]
+    fn should_reject_wrong_solution() {
+        let mut challenges = Challenges::new(DIFFICULTY);
+        let challenge = challenges.create(&SALT, 0).unwrap();
+
+        assert!(!challenges.verify(&wrong_attempt(&challenge), 1));
+        // the challenge cannot be attempted again
+        assert!(!challenges.verify(&solve(&challenge), 1));
+
+        let other = challenges.create(&SALT, 0).unwrap();
+        let attempt = ChallengeAttempt {
+            key: other.challenge_key,
+            ..solve(&challenge)
+        };
+        assert!(!challenges.verify(&attempt, 1));
+    }
+
+    #[test]
+    fn should_reject_expired_challenge() {
+        let mut challenges = Challenges::new(DIFFICULTY);
+        let challenge = challenges.create(&SALT, 0).unwrap();
+        assert!(!challenges.verify(&solve(&challenge), CHALLENGE_EXPIRATION_PERIOD_NS));
+    }
+
+    #[test]
+    fn should_prune_expired_challenges_on_create() {
+        let mut challenges = Challenges::new(DIFFICULTY);
+        challenges.create(&SALT, 0).unwrap();
+        challenges.create(&SALT, 1).unwrap();
+        assert_eq!(challenges.inflight.len(), 2);
+
+        challenges
+            .create(&SALT, CHALLENGE_EXPIRATION_PERIOD_NS)
+            .unwrap();
+        assert_eq!(challenges.inflight.len(), 2);
+        challenges
+            .create(&SALT, CHALLENGE_EXPIRATION_PERIOD_NS + 1)
+            .unwrap();
+        assert_eq!(challenges.inflight.len(), 2);
+    }
+
+    #[test]
+    fn should_cap_inflight_challenges() {
+        let mut challenges = Challenges::new(DIFFICULTY);
+        for _ in 0..MAX_INFLIGHT_CHALLENGES {
+            challenges.create(&SALT, 0).unwrap();
+        }
+        assert!(challenges.create(&SALT, 0).is_none());
+
+        // solving a challenge makes room for a new one
+        let challenge = challenges.inflight.keys().next().unwrap().clone();
+        challenges.verify(
+            &ChallengeAttempt {
+                chars: String::new(),
+                key: challenge,
+            },
+            0,
+        );
+        assert!(challenges.create(&SALT, 0).is_some());
+        assert!(challenges.create(&SALT, 0).is_none());
+
+        // as does the expiry of the challenges
+        assert!(challenges
+            .create(&SALT, CHALLENGE_EXPIRATION_PERIOD_NS)
+            .is_some());
+        assert_eq!(challenges.inflight.len(), 1);
+    }
+}
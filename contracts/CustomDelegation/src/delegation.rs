@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Read;
 
 use candid::Principal;
@@ -6,50 +6,72 @@ use ic_cdk::{id, trap};
 use ic_cdk::api::{data_certificate, set_certified_data, time};
 use ic_certified_map::{Hash, HashTree};
 use ic_certified_map::AsHashTree;
+use ic_stable_structures::Memory;
 use serde::Serialize;
 use serde_bytes::ByteBuf;
 
-use crate::{LABEL_ASSETS, LABEL_SIG, secs_to_nanos, state, update_root_hash};
+use crate::{LABEL_ASSETS, LABEL_REVOKED, LABEL_SIG, secs_to_nanos, state, update_root_hash};
+use crate::assets;
 use crate::deps::hash;
 use crate::deps::signature_map::SignatureMap;
-use crate::state::AssetHashes;
+use crate::metrics;
+use crate::state::{AssetHashes, PersistentState};
+use crate::storage::Salt;
+use crate::storage::revocations::{RevocationKey, RevocationList};
 use crate::types::{
-    Delegation, FrontendHostname, GetDelegationResponse, PublicKey, SessionKey, SignedDelegation,
-    Timestamp, UserKey, UserNumber,
+    Delegation, DelegationRequest, FrontendHostname, GetDelegationResponse, PublicKey,
+    RevocationStatus, SessionKey, SignedDelegation, Timestamp, UserKey, UserNumber,
 };
 
 // 30 mins
 const DEFAULT_EXPIRATION_PERIOD_NS: u64 = secs_to_nanos(30 * 60);
 // 30 days
 const MAX_EXPIRATION_PERIOD_NS: u64 = secs_to_nanos(30 * 24 * 60 * 60);
+// 90 days, no configured maximum time to live may exceed it
+const MAX_EXPIRATION_PERIOD_HARD_CAP_NS: u64 = secs_to_nanos(90 * 24 * 60 * 60);
 
 // 1 min
 const DEFAULT_SIGNATURE_EXPIRATION_PERIOD_NS: u64 = secs_to_nanos(60);
 
+const MAX_DELEGATION_TARGETS: usize = 1_000;
+
+const MAX_DELEGATION_BATCH_SIZE: usize = 10;
+
+const MAX_REVOCATIONS_TO_PRUNE: usize = 100;
+
+// the length of a derivation origin is mixed into the seed as a single byte
+const MAX_DERIVATION_ORIGIN_LEN: usize = u8::MAX as usize;
+
+/// Prepares a delegation expiring after `max_time_to_live` (30 minutes by default), capped by the
+/// maximum time to live configured for the `frontend` (if any), see [delegation_ttl]. The capped
+/// expiration is the one signed and returned.
 pub async fn prepare_delegation(
     seed: Hash, // public key sha256 hash
     session_key: SessionKey,
     max_time_to_live: Option<u64>,
+    targets: Option<Vec<Principal>>,
+    frontend: Option<&FrontendHostname>,
 ) -> (UserKey, Timestamp) {
     // must be called before the first await because it requires caller()
 
+    check_targets(targets.as_deref()).unwrap_or_else(|err| trap(&err));
     prune_expired_signatures();
 
-    let delta = u64::min(
-        max_time_to_live.unwrap_or(DEFAULT_EXPIRATION_PERIOD_NS),
-        MAX_EXPIRATION_PERIOD_NS,
-    );
+    let delta = state::persistent_state(|persistent_state| {
+        delegation_ttl(persistent_state, frontend, max_time_to_live)
+    });
     let expiration = (time() as u64).saturating_add(delta);
 
     state::signature_map_mut(|sigs| {
-        add_signature(sigs, session_key, seed, expiration);
+        add_signature(sigs, session_key, seed, expiration, targets);
     });
-    update_root_hash();
+    // counted before updating the root hash so that the certified metrics include it
     state::usage_metrics_mut(|metrics| {
         metrics.delegation_counter += 1;
     });
+    update_root_hash();
     (
-        ByteBuf::from(der_encode_canister_sig_key(seed.to_vec())),
+        ByteBuf::from(der_encode_canister_sig_key(id(), seed.to_vec())),
         expiration,
     )
 }
@@ -58,46 +80,335 @@ pub fn get_delegation(
     seed: Hash,
     session_key: SessionKey,
     expiration: Timestamp,
+    targets: Option<Vec<Principal>>,
 ) -> GetDelegationResponse {
-    state::asset_hashes_and_sigs(|asset_hashes, sigs| {
-        match get_signature(asset_hashes, sigs, session_key.clone(), seed, expiration) {
-            Some(signature) => GetDelegationResponse::SignedDelegation(SignedDelegation {
-                delegation: Delegation {
-                    pubkey: session_key,
-                    expiration,
-                    targets: None,
-                },
-                signature: ByteBuf::from(signature),
+    let delegation = Delegation {
+        pubkey: session_key,
+        expiration,
+        targets,
+    };
+    state::certified_trees(|asset_hashes, revocations, sigs| {
+        let tree = unrevoked_signature_tree(
+            asset_hashes,
+            revocations,
+            sigs,
+            &delegation,
+            seed,
+            time() as u64,
+        );
+        match tree {
+            Some(tree) => GetDelegationResponse::SignedDelegation(SignedDelegation {
+                delegation,
+                signature: ByteBuf::from(get_signature(tree)),
             }),
             None => GetDelegationResponse::NoSuchDelegation,
         }
     })
 }
 
-pub fn get_principal(user_number: UserNumber, frontend: FrontendHostname) -> Principal {
-    let seed = calculate_seed(user_number, &frontend);
-    let public_key = der_encode_canister_sig_key(seed.to_vec());
+/// Revokes the delegations of the given anchor on the given frontend for `session_key`: their
+/// signatures are no longer returned by [get_anchor_delegation] and [is_delegation_revoked]
+/// reports them as revoked. Signatures returned before stay valid until the delegations expire,
+/// so relying parties have to check for revocations themselves.
+///
+/// A revocation expires after [MAX_EXPIRATION_PERIOD_HARD_CAP_NS], once all the delegations signed
+/// before it have expired as well. Revocations are certified, see [is_delegation_revoked].
+pub fn revoke_delegation(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    session_key: SessionKey,
+    derivation_origin: Option<String>,
+) {
+    trap_if_derivation_origin_not_allowed(derivation_origin.as_deref());
+    let seed = calculate_seed(user_number, &frontend, derivation_origin.as_deref());
+    let now = time() as u64;
+    state::revocations_mut(|revocations| {
+        revocations.insert(
+            revocation_key(seed, &session_key),
+            now.saturating_add(MAX_EXPIRATION_PERIOD_HARD_CAP_NS),
+            now,
+        )
+    })
+    .unwrap_or_else(|err| trap(&err));
+    update_root_hash();
+}
+
+/// Returns whether the delegations of the given user key (as returned by [prepare_delegation]) to
+/// `session_key` have been revoked with [revoke_delegation], together with the witness of the
+/// revocation (or of its absence) under [LABEL_REVOKED] and, in query calls, the certificate.
+///
+/// A user key of another canister has no seed, its status is the one of a seed of zeros, which is
+/// never revoked.
+pub fn is_delegation_revoked(user_key: &UserKey, session_key: &SessionKey) -> RevocationStatus {
+    let key = revocation_key(
+        seed_of_user_key(id(), user_key).unwrap_or_default(),
+        session_key,
+    );
+    let now = time() as u64;
+    state::certified_trees(|asset_hashes, revocations, sigs| {
+        let expires_at = revocations.expiration(&key);
+        let tree = revocation_tree(asset_hashes, revocations, sigs, &key);
+        let mut witness = serde_cbor::ser::Serializer::new(Vec::new());
+        witness.self_describe().unwrap();
+        tree.serialize(&mut witness).unwrap();
+        RevocationStatus {
+            revoked: revocations.is_revoked(&key, now),
+            expires_at,
+            certificate: data_certificate().map(ByteBuf::from),
+            witness: ByteBuf::from(witness.into_inner()),
+        }
+    })
+}
+
+/// Removes a batch of expired revocations, called in the heartbeat. Queries cannot persist
+/// changes, so [get_delegation] only treats expired revocations as absent and leaves them to the
+/// heartbeat; until then they stay certified with their expiration.
+pub fn prune_expired_revocations() {
+    let num_pruned = state::revocations_mut(|revocations| {
+        revocations.prune_batch(time() as u64, MAX_REVOCATIONS_TO_PRUNE)
+    });
+    if num_pruned > 0 {
+        update_root_hash();
+    }
+}
+
+/// Prepares a delegation for the given anchor on the given frontend. The delegation is signed with a
+/// seed derived from the salt, the anchor, the frontend hostname and the derivation origin (if any).
+pub async fn prepare_anchor_delegation(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    session_key: SessionKey,
+    max_time_to_live: Option<u64>,
+    targets: Option<Vec<Principal>>,
+    derivation_origin: Option<String>,
+) -> (UserKey, Timestamp) {
+    trap_if_derivation_origin_not_allowed(derivation_origin.as_deref());
+    state::ensure_salt_set().await;
+    let seed = calculate_seed(user_number, &frontend, derivation_origin.as_deref());
+    // counted before preparing the delegation so that the certified metrics include it
+    state::usage_metrics_mut(|metrics| metrics::count_anchor_delegation(metrics, &frontend));
+    prepare_delegation(
+        seed,
+        session_key,
+        max_time_to_live,
+        targets,
+        Some(&frontend),
+    )
+    .await
+}
+
+/// Prepares a batch of at most [MAX_DELEGATION_BATCH_SIZE] delegations for the given anchor, each
+/// as if prepared by [prepare_anchor_delegation] without derivation origin. The signatures are
+/// certified together, and either all of them are added or, if an entry is invalid, none.
+pub async fn prepare_anchor_delegations(
+    user_number: UserNumber,
+    delegations: Vec<DelegationRequest>,
+) -> Vec<(UserKey, Timestamp)> {
+    state::ensure_salt_set().await;
+    prune_expired_signatures();
+
+    let frontends: Vec<FrontendHostname> = delegations
+        .iter()
+        .map(|(frontend, ..)| frontend.clone())
+        .collect();
+    let salt = state::salt();
+    let prepared = state::persistent_state(|persistent_state| {
+        state::signature_map_mut(|sigs| {
+            add_delegation_signatures(
+                sigs,
+                persistent_state,
+                &salt,
+                user_number,
+                delegations,
+                time() as u64,
+            )
+        })
+    })
+    .unwrap_or_else(|err| trap(&err));
+    // counted before updating the root hash so that the certified metrics include them
+    state::usage_metrics_mut(|metrics| {
+        for frontend in &frontends {
+            metrics::count_anchor_delegation(metrics, frontend);
+        }
+        metrics.delegation_counter += prepared.len() as u64;
+    });
+    update_root_hash();
+    prepared
+        .into_iter()
+        .map(|(seed, delegation)| {
+            (
+                ByteBuf::from(der_encode_canister_sig_key(id(), seed.to_vec())),
+                delegation.expiration,
+            )
+        })
+        .collect()
+}
+
+pub fn get_anchor_delegation(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    session_key: SessionKey,
+    expiration: Timestamp,
+    targets: Option<Vec<Principal>>,
+    derivation_origin: Option<String>,
+) -> GetDelegationResponse {
+    trap_if_derivation_origin_not_allowed(derivation_origin.as_deref());
+    let seed = calculate_seed(user_number, &frontend, derivation_origin.as_deref());
+    get_delegation(seed, session_key, expiration, targets)
+}
+
+/// Returns the principal of the delegations prepared for the given anchor on the given frontend.
+/// Traps if the salt has not been set yet.
+pub fn get_principal(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    derivation_origin: Option<String>,
+) -> Principal {
+    trap_if_derivation_origin_not_allowed(derivation_origin.as_deref());
+    anchor_principal(
+        id(),
+        &state::salt(),
+        user_number,
+        &frontend,
+        derivation_origin.as_deref(),
+    )
+}
+
+fn trap_if_derivation_origin_not_allowed(derivation_origin: Option<&str>) {
+    state::persistent_state(|persistent_state| {
+        check_derivation_origin(
+            persistent_state
+                .derivation_origins
+                .as_deref()
+                .unwrap_or_default(),
+            derivation_origin,
+        )
+    })
+    .unwrap_or_else(|err| trap(&err));
+}
+
+/// Returns the time to live of a delegation on `frontend` (if any): the `requested` one (or the
+/// default) capped by the override configured for the frontend, or else by the configured maximum
+/// (30 days by default). No time to live exceeds [MAX_EXPIRATION_PERIOD_HARD_CAP_NS].
+fn delegation_ttl(
+    persistent_state: &PersistentState,
+    frontend: Option<&FrontendHostname>,
+    requested: Option<u64>,
+) -> u64 {
+    let origin_ttl = frontend.and_then(|frontend| {
+        persistent_state
+            .origin_delegation_ttls
+            .iter()
+            .flatten()
+            .find(|(origin, _)| origin == frontend)
+            .map(|(_, ttl)| *ttl)
+    });
+    let max_ttl = origin_ttl
+        .or(persistent_state.max_delegation_ttl)
+        .unwrap_or(MAX_EXPIRATION_PERIOD_NS)
+        .min(MAX_EXPIRATION_PERIOD_HARD_CAP_NS);
+    requested
+        .unwrap_or(DEFAULT_EXPIRATION_PERIOD_NS)
+        .min(max_ttl)
+}
+
+/// Checks that a configured maximum time to live does not exceed
+/// [MAX_EXPIRATION_PERIOD_HARD_CAP_NS].
+pub fn check_max_delegation_ttl(ttl: u64) -> Result<(), String> {
+    if ttl > MAX_EXPIRATION_PERIOD_HARD_CAP_NS {
+        return Err(format!(
+            "maximum delegation time to live {} ns exceeds the hard cap of {} ns",
+            ttl, MAX_EXPIRATION_PERIOD_HARD_CAP_NS
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that the derivation origins to allow are at most [MAX_DERIVATION_ORIGIN_LEN] bytes long,
+/// so that the length prefixes in their seeds are unambiguous (see [calculate_seed_with_salt]).
+pub fn check_derivation_origins(origins: &[String]) -> Result<(), String> {
+    match origins
+        .iter()
+        .find(|origin| origin.len() > MAX_DERIVATION_ORIGIN_LEN)
+    {
+        Some(origin) => Err(format!(
+            "derivation origin {} exceeds the maximum length of {} bytes",
+            origin, MAX_DERIVATION_ORIGIN_LEN
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Checks that the derivation origin (if any) is one of the `allowed` origins.
+fn check_derivation_origin(
+    allowed: &[String],
+    derivation_origin: Option<&str>,
+) -> Result<(), String> {
+    match derivation_origin {
+        Some(origin) if !allowed.iter().any(|allowed| allowed == origin) => {
+            Err(format!("derivation origin {} is not allowed", origin))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn anchor_principal(
+    canister_id: Principal,
+    salt: &Salt,
+    user_number: UserNumber,
+    frontend: &FrontendHostname,
+    derivation_origin: Option<&str>,
+) -> Principal {
+    let seed = calculate_seed_with_salt(salt, user_number, frontend, derivation_origin);
+    let public_key = der_encode_canister_sig_key(canister_id, seed.to_vec());
     Principal::self_authenticating(&public_key)
 }
 
+/// Recomputes the certified HTTP responses (see [assets]) and certifies them together with the
+/// revoked delegations and the signature map.
 pub fn update_root_hash() {
+    assets::update_certified_responses(time());
+    state::certified_trees(|asset_hashes, revocations, sigs| {
+        set_certified_data(&root_hash(asset_hashes, &revocations.root_hash(), sigs)[..]);
+    })
+}
+
+/// Computes the certified data, i.e. the root hash of the tree combining the certified HTTP
+/// responses, the revoked delegations (given by their root hash) and the signatures.
+pub fn root_hash(asset_hashes: &AssetHashes, revocations_hash: &Hash, sigs: &SignatureMap) -> Hash {
     use ic_certified_map::{fork_hash, labeled_hash};
-    state::asset_hashes_and_sigs(|asset_hashes, sigs| {
-        let prefixed_root_hash = fork_hash(
-            // NB: Labels added in lexicographic order
-            &labeled_hash(LABEL_ASSETS, &asset_hashes.root_hash()),
+    fork_hash(
+        // NB: Labels added in lexicographic order
+        &labeled_hash(LABEL_ASSETS, &asset_hashes.root_hash()),
+        &fork_hash(
+            &labeled_hash(LABEL_REVOKED, revocations_hash),
             &labeled_hash(LABEL_SIG, &sigs.root_hash()),
-        );
-        set_certified_data(&prefixed_root_hash[..]);
-    })
+        ),
+    )
 }
 
-fn calculate_seed(user_number: UserNumber, frontend: &FrontendHostname) -> Hash {
-    let salt = state::salt();
+fn calculate_seed(
+    user_number: UserNumber,
+    frontend: &FrontendHostname,
+    derivation_origin: Option<&str>,
+) -> Hash {
+    calculate_seed_with_salt(&state::salt(), user_number, frontend, derivation_origin)
+}
 
+/// Returns the seed of the delegations of the MetaMask account with the given address.
+pub fn calculate_metamask_seed(address: &[u8]) -> Hash {
+    hash::hash_bytes(address)
+}
+
+fn calculate_seed_with_salt(
+    salt: &Salt,
+    user_number: UserNumber,
+    frontend: &FrontendHostname,
+    derivation_origin: Option<&str>,
+) -> Hash {
     let mut blob: Vec<u8> = vec![];
     blob.push(salt.len() as u8);
-    blob.extend_from_slice(&salt);
+    blob.extend_from_slice(salt);
 
     let user_number_str = user_number.to_string();
     let user_number_blob = user_number_str.bytes();
@@ -107,11 +418,17 @@ fn calculate_seed(user_number: UserNumber, frontend: &FrontendHostname) -> Hash
     blob.push(frontend.bytes().len() as u8);
     blob.extend(frontend.bytes());
 
+    // appended only if present to keep the seeds derived without derivation origin
+    if let Some(origin) = derivation_origin {
+        blob.push(origin.len() as u8);
+        blob.extend(origin.bytes());
+    }
+
     hash::hash_bytes(blob)
 }
 
-fn der_encode_canister_sig_key(seed: Vec<u8>) -> Vec<u8> {
-    let my_canister_id: Vec<u8> = id().as_ref().to_vec();
+fn der_encode_canister_sig_key(canister_id: Principal, seed: Vec<u8>) -> Vec<u8> {
+    let my_canister_id: Vec<u8> = canister_id.as_ref().to_vec();
     let mut bitstring: Vec<u8> = vec![];
     bitstring.push(my_canister_id.len() as u8);
     bitstring.extend(my_canister_id);
@@ -134,6 +451,42 @@ fn der_encode_canister_sig_key(seed: Vec<u8>) -> Vec<u8> {
     der
 }
 
+/// Checks that a batch holds at most [MAX_DELEGATION_BATCH_SIZE] delegations with valid targets.
+fn check_delegation_batch(delegations: &[DelegationRequest]) -> Result<(), String> {
+    if delegations.len() > MAX_DELEGATION_BATCH_SIZE {
+        return Err(format!(
+            "at most {} delegations can be prepared at once, got {}",
+            MAX_DELEGATION_BATCH_SIZE,
+            delegations.len()
+        ));
+    }
+    delegations
+        .iter()
+        .try_for_each(|(_, _, _, targets)| check_targets(targets.as_deref()))
+}
+
+/// Checks that a delegation is restricted to at most [MAX_DELEGATION_TARGETS] distinct canisters.
+fn check_targets(targets: Option<&[Principal]>) -> Result<(), String> {
+    let targets = match targets {
+        Some(targets) => targets,
+        None => return Ok(()),
+    };
+    if targets.len() > MAX_DELEGATION_TARGETS {
+        return Err(format!(
+            "at most {} delegation targets are allowed, got {}",
+            MAX_DELEGATION_TARGETS,
+            targets.len()
+        ));
+    }
+    let mut seen = HashSet::with_capacity(targets.len());
+    for target in targets {
+        if !seen.insert(target) {
+            return Err(format!("duplicate delegation target {}", target));
+        }
+    }
+    Ok(())
+}
+
 fn delegation_signature_msg_hash(d: &Delegation) -> Hash {
     use hash::Value;
 
@@ -151,21 +504,78 @@ fn delegation_signature_msg_hash(d: &Delegation) -> Hash {
     hash::hash_with_domain(b"ic-request-auth-delegation", &map_hash)
 }
 
-fn get_signature(
-    asset_hashes: &AssetHashes,
-    sigs: &SignatureMap,
-    pk: PublicKey,
-    seed: Hash,
-    expiration: Timestamp,
-) -> Option<Vec<u8>> {
+fn get_signature(tree: HashTree) -> Vec<u8> {
     let certificate = data_certificate().unwrap_or_else(|| {
         trap("data certificate is only available in query calls");
     });
-    let msg_hash = delegation_signature_msg_hash(&Delegation {
-        pubkey: pk,
-        expiration,
-        targets: None,
-    });
+
+    #[derive(Serialize)]
+    struct Sig<'a> {
+        certificate: ByteBuf,
+        tree: HashTree<'a>,
+    }
+
+    let sig = Sig {
+        certificate: ByteBuf::from(certificate),
+        tree,
+    };
+
+    let mut cbor = serde_cbor::ser::Serializer::new(Vec::new());
+    cbor.self_describe().unwrap();
+    sig.serialize(&mut cbor).unwrap();
+    cbor.into_inner()
+}
+
+/// Like [signature_tree], but returns `None` if the delegation has been revoked, see
+/// [revoke_delegation].
+fn unrevoked_signature_tree<'a, M: Memory + Clone>(
+    asset_hashes: &AssetHashes,
+    revocations: &RevocationList<M>,
+    sigs: &'a SignatureMap,
+    delegation: &Delegation,
+    seed: Hash,
+    now: Timestamp,
+) -> Option<HashTree<'a>> {
+    if revocations.is_revoked(&revocation_key(seed, &delegation.pubkey), now) {
+        return None;
+    }
+    signature_tree(
+        asset_hashes,
+        &revocations.root_hash(),
+        sigs,
+        delegation,
+        seed,
+    )
+}
+
+/// Returns the witness of the revocation identified by `key` (or of its absence), with the HTTP
+/// responses and the signatures pruned.
+fn revocation_tree<'a, M: Memory + Clone>(
+    asset_hashes: &AssetHashes,
+    revocations: &'a RevocationList<M>,
+    sigs: &SignatureMap,
+    key: &RevocationKey,
+) -> HashTree<'a> {
+    use ic_certified_map::{fork, labeled, labeled_hash};
+    fork(
+        HashTree::Pruned(labeled_hash(LABEL_ASSETS, &asset_hashes.root_hash())),
+        fork(
+            labeled(LABEL_REVOKED, revocations.witness(key)),
+            HashTree::Pruned(labeled_hash(LABEL_SIG, &sigs.root_hash())),
+        ),
+    )
+}
+
+/// Returns the witness of the signature of the delegation, with the HTTP responses and the
+/// revoked delegations (given by their root hash) pruned.
+fn signature_tree<'a>(
+    asset_hashes: &AssetHashes,
+    revocations_hash: &Hash,
+    sigs: &'a SignatureMap,
+    delegation: &Delegation,
+    seed: Hash,
+) -> Option<HashTree<'a>> {
+    let msg_hash = delegation_signature_msg_hash(delegation);
     let witness = sigs.witness(hash::hash_bytes(seed), msg_hash)?;
 
     let witness_hash = witness.reconstruct();
@@ -178,51 +588,622 @@ fn get_signature(
         ));
     }
 
-    let tree = ic_certified_map::fork(
+    Some(ic_certified_map::fork(
         HashTree::Pruned(ic_certified_map::labeled_hash(
             LABEL_ASSETS,
             &asset_hashes.root_hash(),
         )),
-        ic_certified_map::labeled(&LABEL_SIG[..], witness),
-    );
-
-    #[derive(Serialize)]
-    struct Sig<'a> {
-        certificate: ByteBuf,
-        tree: HashTree<'a>,
-    }
-
-    let sig = Sig {
-        certificate: ByteBuf::from(certificate),
-        tree,
-    };
-
-    let mut cbor = serde_cbor::ser::Serializer::new(Vec::new());
-    cbor.self_describe().unwrap();
-    sig.serialize(&mut cbor).unwrap();
-    Some(cbor.into_inner())
+        ic_certified_map::fork(
+            HashTree::Pruned(ic_certified_map::labeled_hash(
+                LABEL_REVOKED,
+                revocations_hash,
+            )),
+            ic_certified_map::labeled(&LABEL_SIG[..], witness),
+        ),
+    ))
 }
 
-fn add_signature(sigs: &mut SignatureMap, pk: PublicKey, seed: Hash, expiration: Timestamp) {
-    let msg_hash = delegation_signature_msg_hash(&Delegation {
+fn add_signature(
+    sigs: &mut SignatureMap,
+    pk: PublicKey,
+    seed: Hash,
+    expiration: Timestamp,
+    targets: Option<Vec<Principal>>,
+) {
+    let delegation = Delegation {
         pubkey: pk,
         expiration,
-        targets: None,
-    });
-    let expires_at = (time() as u64).saturating_add(DEFAULT_SIGNATURE_EXPIRATION_PERIOD_NS);
+        targets,
+    };
+    put_signature(sigs, &delegation, seed, time() as u64);
+}
+
+fn put_signature(sigs: &mut SignatureMap, delegation: &Delegation, seed: Hash, now: Timestamp) {
+    let msg_hash = delegation_signature_msg_hash(delegation);
+    let expires_at = now.saturating_add(DEFAULT_SIGNATURE_EXPIRATION_PERIOD_NS);
     sigs.put(hash::hash_bytes(seed), msg_hash, expires_at);
 }
 
+/// Adds the signatures of a batch of delegations for the given anchor, returning the seed and the
+/// signed delegation of each entry. The whole batch is checked first so that no signature is added
+/// if an entry is invalid.
+fn add_delegation_signatures(
+    sigs: &mut SignatureMap,
+    persistent_state: &PersistentState,
+    salt: &Salt,
+    user_number: UserNumber,
+    delegations: Vec<DelegationRequest>,
+    now: Timestamp,
+) -> Result<Vec<(Hash, Delegation)>, String> {
+    check_delegation_batch(&delegations)?;
+    let prepared: Vec<(Hash, Delegation)> = delegations
+        .into_iter()
+        .map(|(frontend, session_key, max_time_to_live, targets)| {
+            let seed = calculate_seed_with_salt(salt, user_number, &frontend, None);
+            let ttl = delegation_ttl(persistent_state, Some(&frontend), max_time_to_live);
+            let delegation = Delegation {
+                pubkey: session_key,
+                expiration: now.saturating_add(ttl),
+                targets,
+            };
+            (seed, delegation)
+        })
+        .collect();
+    for (seed, delegation) in &prepared {
+        put_signature(sigs, delegation, *seed, now);
+    }
+    Ok(prepared)
+}
+
+/// Returns the key identifying the revoked delegations with the given seed to `session_key`.
+fn revocation_key(seed: Hash, session_key: &[u8]) -> RevocationKey {
+    RevocationKey::new(hash::hash_bytes(seed), hash::hash_bytes(session_key))
+}
+
+/// Returns the seed of a user key encoded by [der_encode_canister_sig_key] for `canister_id`.
+fn seed_of_user_key(canister_id: Principal, user_key: &[u8]) -> Option<Hash> {
+    let seed_start = user_key.len().checked_sub(std::mem::size_of::<Hash>())?;
+    let seed: Hash = user_key[seed_start..].try_into().ok()?;
+    (der_encode_canister_sig_key(canister_id, seed.to_vec()) == user_key).then_some(seed)
+}
+
 /// Removes a batch of expired signatures from the signature map.
 ///
 /// This function is supposed to piggy back on update calls to
 /// amortize the cost of tree pruning.  Each operation on the signature map
-/// will prune at most MAX_SIGS_TO_PRUNE other signatures.
+/// will prune at most MAX_SIGS_TO_PRUNE other signatures (unless configured otherwise in the
+/// persistent state).
 pub fn prune_expired_signatures() {
     const MAX_SIGS_TO_PRUNE: usize = 10;
+    let max_to_prune =
+        state::persistent_state(|persistent_state| persistent_state.max_signatures_to_prune)
+            .map_or(MAX_SIGS_TO_PRUNE, |max| max as usize);
     let num_pruned =
-        state::signature_map_mut(|sigs| sigs.prune_expired(time() as u64, MAX_SIGS_TO_PRUNE));
+        state::signature_map_mut(|sigs| sigs.prune_expired(time() as u64, max_to_prune));
     if num_pruned > 0 {
         update_root_hash();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Assets;
+    use hex_literal::hex;
+    use ic_stable_structures::VectorMemory;
+
+    fn sample_delegation(targets: Option<Vec<Principal>>) -> Delegation {
+        Delegation {
+            pubkey: ByteBuf::from(vec![1; 32]),
+            expiration: 1_650_000_000_000_000_000,
+            targets,
+        }
+    }
+
+    fn sha256(data: &[u8]) -> Hash {
+        <[u8; 32]>::from(<sha2::Sha256 as sha2::Digest>::digest(data))
+    }
+
+    /// Computes the message signed for a delegation as the IC interface specification defines it
+    /// (sections "Authentication" and "Representation-independent hashing"), from the hashes of
+    /// the encoded fields of the delegation, without [hash::hash_of_map].
+    fn spec_delegation_hash(fields: &[(&str, Hash)]) -> Hash {
+        let mut field_hashes: Vec<Vec<u8>> = fields
+            .iter()
+            .map(|(name, value_hash)| [sha256(name.as_bytes()), *value_hash].concat())
+            .collect();
+        field_hashes.sort();
+        let map_hash = sha256(&field_hashes.concat());
+        sha256(&[&b"\x1Aic-request-auth-delegation"[..], &map_hash].concat())
+    }
+
+    #[test]
+    fn delegation_hash_without_targets() {
+        assert_eq!(
+            delegation_signature_msg_hash(&sample_delegation(None)),
+            spec_delegation_hash(&[
+                ("pubkey", sha256(&[1; 32])),
+                // LEB128 of 1_650_000_000_000_000_000
+                ("expiration", sha256(&hex!("808094bba0c8fef216"))),
+            ])
+        );
+    }
+
+    #[test]
+    fn should_certify_delegations_and_http_responses_under_one_root() {
+        let delegation = sample_delegation(None);
+        let seed = [7; 32];
+        let mut sigs = SignatureMap::default();
+        sigs.put(
+            hash::hash_bytes(seed),
+            delegation_signature_msg_hash(&delegation),
+            1,
+        );
+        let mut assets = Assets::default();
+        let mut asset_hashes = AssetHashes::new();
+        assets::certify(
+            &mut assets,
+            &mut asset_hashes,
+            "/status",
+            "application/json",
+            b"{}".to_vec(),
+        );
+
+        let mut revocations = RevocationList::init(VectorMemory::default(), 0);
+        revocations
+            .insert(revocation_key([8; 32], &delegation.pubkey), 2, 1)
+            .unwrap();
+        let revocations_hash = revocations.root_hash();
+
+        let root = root_hash(&asset_hashes, &revocations_hash, &sigs);
+        let delegation_witness =
+            signature_tree(&asset_hashes, &revocations_hash, &sigs, &delegation, seed).unwrap();
+        assert_eq!(delegation_witness.reconstruct(), root);
+        let response_witness =
+            assets::response_tree(&asset_hashes, &revocations_hash, &sigs, "/status");
+        assert_eq!(response_witness.reconstruct(), root);
+        let revocation_witness = revocation_tree(
+            &asset_hashes,
+            &revocations,
+            &sigs,
+            &revocation_key(seed, &delegation.pubkey),
+        );
+        assert_eq!(revocation_witness.reconstruct(), root);
+
+        assert!(signature_tree(
+            &asset_hashes,
+            &revocations_hash,
+            &sigs,
+            &sample_delegation(Some(vec![])),
+            seed
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn delegation_hash_with_targets() {
+        let targets = vec![Principal::management_canister(), Principal::anonymous()];
+        assert_eq!(
+            delegation_signature_msg_hash(&sample_delegation(Some(targets))),
+            spec_delegation_hash(&[
+                ("pubkey", sha256(&[1; 32])),
+                // LEB128 of 1_650_000_000_000_000_000
+                ("expiration", sha256(&hex!("808094bba0c8fef216"))),
+                // an array of blobs hashes to the hash of the concatenated hashes of the blobs, the
+                // management canister id being empty and the anonymous principal being 0x04
+                ("targets", sha256(&[sha256(&[]), sha256(&[0x04])].concat())),
+            ])
+        );
+    }
+
+    #[test]
+    fn anchor_principal_should_match_delegation_user_key() {
+        let canister_id = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+        let salt = [7; 32];
+        let frontend = "https://example.com".to_string();
+
+        // the user key returned by prepare_delegation for the seed of the anchor
+        let seed = calculate_seed_with_salt(&salt, 10_000, &frontend, None);
+        let user_key = der_encode_canister_sig_key(canister_id, seed.to_vec());
+
+        assert_eq!(
+            anchor_principal(canister_id, &salt, 10_000, &frontend, None),
+            Principal::self_authenticating(&user_key)
+        );
+        assert_ne!(
+            anchor_principal(canister_id, &salt, 10_001, &frontend, None),
+            Principal::self_authenticating(&user_key)
+        );
+        assert_ne!(
+            anchor_principal(
+                canister_id,
+                &salt,
+                10_000,
+                &"https://other.com".to_string(),
+                None
+            ),
+            Principal::self_authenticating(&user_key)
+        );
+        assert_ne!(
+            anchor_principal(canister_id, &[8; 32], 10_000, &frontend, None),
+            Principal::self_authenticating(&user_key)
+        );
+    }
+
+    #[test]
+    fn should_derive_different_principals_per_derivation_origin() {
+        let canister_id = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+        let frontend = "https://example.com".to_string();
+        let principal = |origin| anchor_principal(canister_id, &[7; 32], 10_000, &frontend, origin);
+
+        assert_ne!(principal(None), principal(Some("https://app1.example.com")));
+        assert_ne!(principal(None), principal(Some("https://app2.example.com")));
+        assert_ne!(
+            principal(Some("https://app1.example.com")),
+            principal(Some("https://app2.example.com"))
+        );
+    }
+
+    #[test]
+    fn should_only_allow_listed_derivation_origins() {
+        let allowed = vec!["https://app1.example.com".to_string()];
+        assert!(check_derivation_origin(&allowed, None).is_ok());
+        assert!(check_derivation_origin(&allowed, Some("https://app1.example.com")).is_ok());
+        assert!(check_derivation_origin(&allowed, Some("https://app2.example.com")).is_err());
+        assert!(check_derivation_origin(&[], Some("https://app1.example.com")).is_err());
+    }
+
+    #[test]
+    fn should_reject_too_long_derivation_origins() {
+        let origin = |len: usize| format!("https://{}", "a".repeat(len - "https://".len()));
+        assert!(check_derivation_origins(&[origin(MAX_DERIVATION_ORIGIN_LEN)]).is_ok());
+        assert!(
+            check_derivation_origins(&[origin(10), origin(MAX_DERIVATION_ORIGIN_LEN + 1)]).is_err()
+        );
+    }
+
+    #[test]
+    fn should_accept_distinct_targets() {
+        assert!(check_targets(None).is_ok());
+        let targets: Vec<Principal> = (0..MAX_DELEGATION_TARGETS as u32)
+            .map(|i| Principal::from_slice(&i.to_le_bytes()))
+            .collect();
+        assert!(check_targets(Some(&targets)).is_ok());
+    }
+
+    #[test]
+    fn should_reject_too_many_targets() {
+        let targets: Vec<Principal> = (0..=MAX_DELEGATION_TARGETS as u32)
+            .map(|i| Principal::from_slice(&i.to_le_bytes()))
+            .collect();
+        assert!(check_targets(Some(&targets)).is_err());
+    }
+
+    #[test]
+    fn should_reject_duplicate_targets() {
+        let targets = vec![
+            Principal::anonymous(),
+            Principal::management_canister(),
+            Principal::anonymous(),
+        ];
+        assert!(check_targets(Some(&targets)).is_err());
+    }
+
+    fn state_with_ttls(
+        max_delegation_ttl: Option<u64>,
+        origin_delegation_ttls: Vec<(FrontendHostname, u64)>,
+    ) -> PersistentState {
+        PersistentState {
+            max_delegation_ttl,
+            origin_delegation_ttls: Some(origin_delegation_ttls),
+            ..PersistentState::default()
+        }
+    }
+
+    #[test]
+    fn should_clamp_requested_ttl_to_maximum() {
+        let state = state_with_ttls(None, vec![]);
+        assert_eq!(
+            delegation_ttl(&state, None, None),
+            DEFAULT_EXPIRATION_PERIOD_NS
+        );
+        assert_eq!(
+            delegation_ttl(&state, None, Some(u64::MAX)),
+            MAX_EXPIRATION_PERIOD_NS
+        );
+
+        let state = state_with_ttls(Some(secs_to_nanos(60)), vec![]);
+        assert_eq!(
+            delegation_ttl(&state, None, Some(secs_to_nanos(30))),
+            secs_to_nanos(30)
+        );
+        assert_eq!(delegation_ttl(&state, None, None), secs_to_nanos(60));
+
+        // states configured before the hard cap are capped as well
+        let state = state_with_ttls(Some(u64::MAX), vec![]);
+        assert_eq!(
+            delegation_ttl(&state, None, Some(u64::MAX)),
+            MAX_EXPIRATION_PERIOD_HARD_CAP_NS
+        );
+    }
+
+    #[test]
+    fn should_apply_origin_ttl_override() {
+        let frontend = "https://app.example.com".to_string();
+        let state = state_with_ttls(
+            Some(secs_to_nanos(60)),
+            vec![(frontend.clone(), secs_to_nanos(3_600))],
+        );
+        assert_eq!(
+            delegation_ttl(&state, Some(&frontend), Some(u64::MAX)),
+            secs_to_nanos(3_600)
+        );
+        assert_eq!(
+            delegation_ttl(
+                &state,
+                Some(&"https://other.example.com".to_string()),
+                Some(u64::MAX)
+            ),
+            secs_to_nanos(60)
+        );
+        assert_eq!(
+            delegation_ttl(&state, None, Some(u64::MAX)),
+            secs_to_nanos(60)
+        );
+    }
+
+    #[test]
+    fn should_prefer_shorter_origin_override_over_request() {
+        let frontend = "https://app.example.com".to_string();
+        let state = state_with_ttls(None, vec![(frontend.clone(), secs_to_nanos(10))]);
+        assert_eq!(
+            delegation_ttl(&state, Some(&frontend), None),
+            secs_to_nanos(10)
+        );
+        assert_eq!(
+            delegation_ttl(&state, Some(&frontend), Some(secs_to_nanos(20))),
+            secs_to_nanos(10)
+        );
+        assert_eq!(
+            delegation_ttl(&state, Some(&frontend), Some(secs_to_nanos(5))),
+            secs_to_nanos(5)
+        );
+    }
+
+    #[test]
+    fn should_reject_maximum_ttl_above_hard_cap() {
+        assert!(check_max_delegation_ttl(MAX_EXPIRATION_PERIOD_HARD_CAP_NS).is_ok());
+        assert!(check_max_delegation_ttl(MAX_EXPIRATION_PERIOD_HARD_CAP_NS + 1).is_err());
+    }
+
+    fn delegation_request(frontend: &str, key: u8) -> DelegationRequest {
+        (
+            frontend.to_string(),
+            ByteBuf::from(vec![key; 32]),
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn should_validate_each_delegation_of_a_batch_independently() {
+        let canister_id = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+        let salt = [7; 32];
+        let frontend = "https://app.example.com".to_string();
+        let state = state_with_ttls(None, vec![(frontend.clone(), secs_to_nanos(60))]);
+        let mut sigs = SignatureMap::default();
+        let batch = vec![
+            delegation_request(&frontend, 1),
+            delegation_request("https://other.example.com", 2),
+            (
+                frontend.clone(),
+                ByteBuf::from(vec![3; 32]),
+                Some(secs_to_nanos(30)),
+                Some(vec![Principal::management_canister()]),
+            ),
+        ];
+
+        let prepared =
+            add_delegation_signatures(&mut sigs, &state, &salt, 10_000, batch, 1_000).unwrap();
+        assert_eq!(prepared.len(), 3);
+        assert_eq!(sigs.len(), 3);
+        assert_eq!(prepared[0].1.expiration, 1_000 + secs_to_nanos(60));
+        assert_eq!(
+            prepared[1].1.expiration,
+            1_000 + DEFAULT_EXPIRATION_PERIOD_NS
+        );
+        assert_eq!(prepared[2].1.expiration, 1_000 + secs_to_nanos(30));
+
+        let asset_hashes = AssetHashes::new();
+        let revocations_hash = RevocationList::init(VectorMemory::default(), 0).root_hash();
+        let root = root_hash(&asset_hashes, &revocations_hash, &sigs);
+        for (seed, delegation) in &prepared {
+            let witness =
+                signature_tree(&asset_hashes, &revocations_hash, &sigs, delegation, *seed).unwrap();
+            assert_eq!(witness.reconstruct(), root);
+        }
+        // the seeds are those of the anchor on the respective frontends
+        let user_key = der_encode_canister_sig_key(canister_id, prepared[1].0.to_vec());
+        assert_eq!(
+            anchor_principal(
+                canister_id,
+                &salt,
+                10_000,
+                &"https://other.example.com".to_string(),
+                None
+            ),
+            Principal::self_authenticating(&user_key)
+        );
+        // a delegation is only signed for the seed it was prepared with
+        assert!(signature_tree(
+            &asset_hashes,
+            &revocations_hash,
+            &sigs,
+            &prepared[0].1,
+            prepared[1].0
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn should_not_add_any_signature_of_an_invalid_batch() {
+        let state = PersistentState::default();
+        let mut sigs = SignatureMap::default();
+
+        let too_large = (0..=MAX_DELEGATION_BATCH_SIZE as u8)
+            .map(|key| delegation_request("https://app.example.com", key))
+            .collect();
+        assert!(
+            add_delegation_signatures(&mut sigs, &state, &[7; 32], 10_000, too_large, 1_000)
+                .is_err()
+        );
+
+        let duplicate_targets = vec![
+            delegation_request("https://app.example.com", 1),
+            (
+                "https://app.example.com".to_string(),
+                ByteBuf::from(vec![2; 32]),
+                None,
+                Some(vec![Principal::anonymous(), Principal::anonymous()]),
+            ),
+        ];
+        assert!(add_delegation_signatures(
+            &mut sigs,
+            &state,
+            &[7; 32],
+            10_000,
+            duplicate_targets,
+            1_000
+        )
+        .is_err());
+        assert_eq!(sigs.len(), 0);
+
+        let full = (0..MAX_DELEGATION_BATCH_SIZE as u8)
+            .map(|key| delegation_request("https://app.example.com", key))
+            .collect();
+        add_delegation_signatures(&mut sigs, &state, &[7; 32], 10_000, full, 1_000).unwrap();
+        assert_eq!(sigs.len(), MAX_DELEGATION_BATCH_SIZE);
+    }
+
+    #[test]
+    fn should_refuse_signature_of_delegation_revoked_before_get_delegation() {
+        let delegation = sample_delegation(None);
+        let seed = [7; 32];
+        let mut sigs = SignatureMap::default();
+        put_signature(&mut sigs, &delegation, seed, 1_000);
+        let asset_hashes = AssetHashes::new();
+        let mut revocations = RevocationList::init(VectorMemory::default(), 0);
+
+        revocations
+            .insert(revocation_key(seed, &delegation.pubkey), 4_000, 2_000)
+            .unwrap();
+        assert!(unrevoked_signature_tree(
+            &asset_hashes,
+            &revocations,
+            &sigs,
+            &delegation,
+            seed,
+            3_000
+        )
+        .is_none());
+        // the signature itself is still certified, under the same root as the revocation
+        let root = root_hash(&asset_hashes, &revocations.root_hash(), &sigs);
+        let tree = signature_tree(
+            &asset_hashes,
+            &revocations.root_hash(),
+            &sigs,
+            &delegation,
+            seed,
+        )
+        .unwrap();
+        assert_eq!(tree.reconstruct(), root);
+        let key = revocation_key(seed, &delegation.pubkey);
+        let tree = revocation_tree(&asset_hashes, &revocations, &sigs, &key);
+        assert_eq!(tree.reconstruct(), root);
+
+        // other session keys and seeds are not affected
+        let other_delegation = Delegation {
+            pubkey: ByteBuf::from(vec![2; 32]),
+            ..sample_delegation(None)
+        };
+        put_signature(&mut sigs, &other_delegation, seed, 1_000);
+        put_signature(&mut sigs, &delegation, [8; 32], 1_000);
+        assert!(unrevoked_signature_tree(
+            &asset_hashes,
+            &revocations,
+            &sigs,
+            &other_delegation,
+            seed,
+            3_000
+        )
+        .is_some());
+        assert!(unrevoked_signature_tree(
+            &asset_hashes,
+            &revocations,
+            &sigs,
+            &delegation,
+            [8; 32],
+            3_000
+        )
+        .is_some());
+        // nor is the delegation once the revocation has expired
+        assert!(unrevoked_signature_tree(
+            &asset_hashes,
+            &revocations,
+            &sigs,
+            &delegation,
+            seed,
+            4_000
+        )
+        .is_some());
+    }
+
+    #[test]
+    fn should_report_delegation_revoked_after_get_delegation() {
+        let canister_id = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+        let delegation = sample_delegation(None);
+        let seed = [7; 32];
+        let mut sigs = SignatureMap::default();
+        put_signature(&mut sigs, &delegation, seed, 1_000);
+        let asset_hashes = AssetHashes::new();
+        let mut revocations = RevocationList::init(VectorMemory::default(), 0);
+
+        assert!(unrevoked_signature_tree(
+            &asset_hashes,
+            &revocations,
+            &sigs,
+            &delegation,
+            seed,
+            2_000
+        )
+        .is_some());
+        let key = revocation_key(seed, &delegation.pubkey);
+        assert!(!revocations.is_revoked(&key, 2_000));
+
+        revocations.insert(key, 5_000, 3_000).unwrap();
+        let user_key = der_encode_canister_sig_key(canister_id, seed.to_vec());
+        let revoked_seed = seed_of_user_key(canister_id, &user_key).unwrap();
+        assert!(revocations.is_revoked(&revocation_key(revoked_seed, &delegation.pubkey), 4_000));
+
+        // user keys of other canisters and malformed keys have no seed
+        assert_eq!(
+            seed_of_user_key(Principal::management_canister(), &user_key),
+            None
+        );
+        assert_eq!(seed_of_user_key(canister_id, &user_key[1..]), None);
+        assert_eq!(seed_of_user_key(canister_id, &[1; 16]), None);
+    }
+
+    #[test]
+    fn should_derive_metamask_seed_from_address() {
+        let address = hex!("5aaeb6053f3e94c9b9a09f33669435e7ef1beaed");
+        let seed = calculate_metamask_seed(&address);
+
+        assert_ne!(seed, calculate_metamask_seed(&[1; 20]));
+        // the seed MetaMask accounts always had, so that their principals do not change
+        assert_eq!(
+            seed,
+            <[u8; 32]>::from(<sha2::Sha256 as sha2::Digest>::digest(address))
+        );
+    }
+}
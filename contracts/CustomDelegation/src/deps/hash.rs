@@ -263,4 +263,20 @@ mod tests {
             hex!("6c0b2ae49718f6995c02ac5700c9c789d7b7862a0d53e6d40a73f1fcd2f70189")
         );
     }
+
+    #[test]
+    fn message_id_request_reference() {
+        // the example request of the IC interface specification (section "Request ids")
+        let mut request = HashMap::new();
+        request.insert("request_type", Value::String("call"));
+        request.insert("sender", Value::Bytes(&[0x04][..]));
+        request.insert("ingress_expiry", Value::U64(1_685_570_400_000_000_000));
+        request.insert("canister_id", Value::Bytes(&hex!("00000000000004D2")[..]));
+        request.insert("method_name", Value::String("hello"));
+        request.insert("arg", Value::Bytes(b"DIDL\x00\xFD*"));
+        assert_eq!(
+            hash_of_map(request),
+            hex!("1d1091364d6bb8a6c16b203ee75467d59ead468f523eb058880ae8ec80e2b101")
+        );
+    }
 }
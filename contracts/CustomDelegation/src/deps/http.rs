@@ -1,6 +1,9 @@
 use candid::{CandidType, Deserialize};
+use ic_cdk::api::data_certificate;
 use serde_bytes::ByteBuf;
 
+use crate::assets;
+
 pub type HeaderField = (String, String);
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -50,7 +53,7 @@ pub fn http_request(req: HttpRequest) -> HttpResponse {
             upgrade: Some(true),
             streaming_strategy: None,
         },
-        _ => {
+        path => assets::http_response(path, data_certificate()).unwrap_or_else(|| {
             let headers = vec![(
                 "Content-Type".to_string(),
                 "text/plain; version=0.0.4".to_string(),
@@ -62,6 +65,6 @@ pub fn http_request(req: HttpRequest) -> HttpResponse {
                 upgrade: Some(true),
                 streaming_strategy: None,
             }
-        }
+        }),
     }
 }
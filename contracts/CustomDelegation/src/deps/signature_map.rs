@@ -80,8 +80,9 @@ impl SignatureMap {
                     return num_pruned;
                 }
             }
-            if let Some(expiration) = self.expiration_queue.pop() {
-                self.delete(expiration.seed_hash, expiration.msg_hash);
+            match self.expiration_queue.pop() {
+                Some(expiration) => self.delete(expiration.seed_hash, expiration.msg_hash),
+                None => break,
             }
             num_pruned += 1;
         }
@@ -93,10 +94,6 @@ impl SignatureMap {
         self.expiration_queue.len()
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.expiration_queue.is_empty()
-    }
-
     pub fn root_hash(&self) -> Hash {
         self.certified_map.root_hash()
     }
@@ -109,3 +106,48 @@ impl SignatureMap {
         Some(witness)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: u64) -> Hash {
+        let mut hash = [0; 32];
+        hash[..8].copy_from_slice(&n.to_le_bytes());
+        hash
+    }
+
+    #[test]
+    fn should_shrink_when_pruning_expired_signatures() {
+        const NUM_SIGNATURES: u64 = 10_000;
+        let mut map = SignatureMap::default();
+        for i in 0..NUM_SIGNATURES {
+            // staggered expiries, inserted out of order
+            let expires_at = (i * 7_919) % NUM_SIGNATURES;
+            map.put(hash(i % 100), hash(i), expires_at);
+        }
+        assert_eq!(map.len(), NUM_SIGNATURES as usize);
+
+        let mut previous_len = map.len();
+        let mut now = 0;
+        while map.len() > 0 {
+            now += 500;
+            assert_eq!(map.prune_expired(now, 100), previous_len - map.len());
+            assert!(map.len() < previous_len);
+            // nothing that has not expired yet is pruned
+            assert!(map.len() as u64 >= NUM_SIGNATURES.saturating_sub(now + 1));
+            previous_len = map.len();
+        }
+        assert_eq!(map.root_hash(), SignatureMap::default().root_hash());
+    }
+
+    #[test]
+    fn should_not_count_pruning_an_empty_map() {
+        let mut map = SignatureMap::default();
+        assert_eq!(map.prune_expired(u64::MAX, 10), 0);
+
+        map.put(hash(1), hash(1), 5);
+        assert_eq!(map.prune_expired(10, 10), 1);
+        assert_eq!(map.len(), 0);
+    }
+}
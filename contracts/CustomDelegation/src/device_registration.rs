@@ -0,0 +1,340 @@
+//! Device registration mode for adding a device from a browser that cannot use any of the existing
+//! devices of an anchor ("add device from another browser").
+//!
+//! An existing device enters the registration mode of its anchor, which lasts for
+//! [REGISTRATION_MODE_DURATION_NS]. While it lasts, the new browser can add a single device
+//! tentatively and receives a verification code, which the user then enters on the existing device.
+//! Only then the device is added to the anchor. After [MAX_VERIFICATION_ATTEMPTS] wrong codes, the
+//! registration mode ends.
+
+use std::collections::HashMap;
+
+use crate::secs_to_nanos;
+use crate::types::{
+    AddTentativeDeviceResponse, DeviceData, DeviceRegistrationInfo, DeviceVerificationCode,
+    FailedAttemptsCounter, Timestamp, UserNumber, VerifyTentativeDeviceResponse,
+};
+
+/// Time after which the device registration mode of an anchor ends.
+pub const REGISTRATION_MODE_DURATION_NS: u64 = secs_to_nanos(15 * 60);
+/// Number of wrong verification codes after which the device registration mode ends.
+pub const MAX_VERIFICATION_ATTEMPTS: FailedAttemptsCounter = 3;
+/// Maximum number of anchors that can be in device registration mode at the same time.
+pub const MAX_ANCHORS_IN_REGISTRATION_MODE: usize = 10_000;
+
+struct TentativeDevice {
+    device: DeviceData,
+    verification_code: DeviceVerificationCode,
+    failed_attempts: FailedAttemptsCounter,
+}
+
+struct RegistrationMode {
+    expiration: Timestamp,
+    tentative_device: Option<TentativeDevice>,
+}
+
+/// The anchors in device registration mode, NOT persisted across upgrades.
+#[derive(Default)]
+pub struct DeviceRegistrations {
+    anchors: HashMap<UserNumber, RegistrationMode>,
+}
+
+impl DeviceRegistrations {
+    /// Starts (or restarts) the device registration mode of the given anchor, pruning the expired
+    /// registration modes first, and returns its expiration.
+    ///
+    /// Returns `None` if [MAX_ANCHORS_IN_REGISTRATION_MODE] other anchors are in registration mode.
+    pub fn enter(&mut self, user_number: UserNumber, now: Timestamp) -> Option<Timestamp> {
+        self.anchors.retain(|_, mode| mode.expiration > now);
+        if !self.anchors.contains_key(&user_number)
+            && self.anchors.len() >= MAX_ANCHORS_IN_REGISTRATION_MODE
+        {
+            return None;
+        }
+        let expiration = now.saturating_add(REGISTRATION_MODE_DURATION_NS);
+        self.anchors.insert(
+            user_number,
+            RegistrationMode {
+                expiration,
+                tentative_device: None,
+            },
+        );
+        Some(expiration)
+    }
+
+    /// Ends the device registration mode of the given anchor, dropping its tentative device.
+    pub fn exit(&mut self, user_number: UserNumber) {
+        self.anchors.remove(&user_number);
+    }
+
+    /// Adds the device tentatively to the given anchor, to be verified with `verification_code`.
+    pub fn add_tentative_device(
+        &mut self,
+        user_number: UserNumber,
+        device: DeviceData,
+        verification_code: DeviceVerificationCode,
+        now: Timestamp,
+    ) -> AddTentativeDeviceResponse {
+        let Some(mode) = self.active_mode_mut(user_number, now) else {
+            return AddTentativeDeviceResponse::DeviceRegistrationModeOff;
+        };
+        if mode.tentative_device.is_some() {
+            return AddTentativeDeviceResponse::AnotherDeviceTentativelyAdded;
+        }
+        mode.tentative_device = Some(TentativeDevice {
+            device,
+            verification_code: verification_code.clone(),
+            failed_attempts: 0,
+        });
+        AddTentativeDeviceResponse::AddedTentatively {
+            verification_code,
+            device_registration_timeout: mode.expiration,
+        }
+    }
+
+    /// Checks the verification code of the tentative device of the given anchor. If it matches,
+    /// the registration mode ends and the device is returned to be added to the anchor. Otherwise
+    /// the response to return is the error.
+    pub fn verify_tentative_device(
+        &mut self,
+        user_number: UserNumber,
+        verification_code: &str,
+        now: Timestamp,
+    ) -> Result<DeviceData, VerifyTentativeDeviceResponse> {
+        let Some(mode) = self.active_mode_mut(user_number, now) else {
+            return Err(VerifyTentativeDeviceResponse::DeviceRegistrationModeOff);
+        };
+        let Some(tentative_device) = mode.tentative_device.as_mut() else {
+            return Err(VerifyTentativeDeviceResponse::NoDeviceToVerify);
+        };
+        if tentative_device.verification_code != verification_code {
+            tentative_device.failed_attempts += 1;
+            let retries_left = MAX_VERIFICATION_ATTEMPTS - tentative_device.failed_attempts;
+            if retries_left == 0 {
+                self.exit(user_number);
+            }
+            return Err(VerifyTentativeDeviceResponse::WrongCode { retries_left });
+        }
+        let mode = self
+            .anchors
+            .remove(&user_number)
+            .expect("bug: registration mode vanished");
+        Ok(mode
+            .tentative_device
+            .expect("bug: tentative device vanished")
+            .device)
+    }
+
+    /// Returns the expiration and the tentative device of the registration mode of the given
+    /// anchor, if it is in registration mode.
+    pub fn info(&self, user_number: UserNumber, now: Timestamp) -> Option<DeviceRegistrationInfo> {
+        self.anchors
+            .get(&user_number)
+            .filter(|mode| mode.expiration > now)
+            .map(|mode| DeviceRegistrationInfo {
+                expiration: mode.expiration,
+                tentative_device: mode
+                    .tentative_device
+                    .as_ref()
+                    .map(|tentative_device| tentative_device.device.clone()),
+            })
+    }
+
+    fn active_mode_mut(
+        &mut self,
+        user_number: UserNumber,
+        now: Timestamp,
+    ) -> Option<&mut RegistrationMode> {
+        if self
+            .anchors
+            .get(&user_number)
+            .is_some_and(|mode| mode.expiration <= now)
+        {
+            self.anchors.remove(&user_number);
+        }
+        self.anchors.get_mut(&user_number)
+    }
+}
+
+/// Derives a 6 digit verification code from the randomness returned by `raw_rand`.
+pub fn verification_code(randomness: &[u8]) -> DeviceVerificationCode {
+    let mut bytes = [0; 8];
+    let len = randomness.len().min(8);
+    bytes[..len].copy_from_slice(&randomness[..len]);
+    format!("{:06}", u64::from_le_bytes(bytes) % 1_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::device;
+
+    use super::*;
+
+    const ANCHOR: UserNumber = 10_000;
+
+    fn code(digits: &str) -> DeviceVerificationCode {
+        digits.to_string()
+    }
+
+    #[test]
+    fn should_add_verified_device() {
+        let mut registrations = DeviceRegistrations::default();
+        assert_eq!(registrations.info(ANCHOR, 0), None);
+        let expiration = registrations.enter(ANCHOR, 1_000).unwrap();
+        assert_eq!(expiration, 1_000 + REGISTRATION_MODE_DURATION_NS);
+        assert_eq!(
+            registrations.info(ANCHOR, 2_000),
+            Some(DeviceRegistrationInfo {
+                expiration,
+                tentative_device: None,
+            })
+        );
+
+        assert!(matches!(
+            registrations.add_tentative_device(ANCHOR, device(1), code("123456"), 2_000),
+            AddTentativeDeviceResponse::AddedTentatively {
+                verification_code,
+                device_registration_timeout,
+            } if verification_code == "123456" && device_registration_timeout == expiration
+        ));
+        assert_eq!(
+            registrations.info(ANCHOR, 3_000).unwrap().tentative_device,
+            Some(device(1))
+        );
+
+        assert_eq!(
+            registrations
+                .verify_tentative_device(ANCHOR, "123456", 3_000)
+                .unwrap(),
+            device(1)
+        );
+        // the registration mode ends once the device is verified
+        assert_eq!(registrations.info(ANCHOR, 3_000), None);
+        assert!(matches!(
+            registrations.verify_tentative_device(ANCHOR, "123456", 3_000),
+            Err(VerifyTentativeDeviceResponse::DeviceRegistrationModeOff)
+        ));
+    }
+
+    #[test]
+    fn should_reject_devices_outside_of_registration_mode() {
+        let mut registrations = DeviceRegistrations::default();
+        assert!(matches!(
+            registrations.add_tentative_device(ANCHOR, device(1), code("123456"), 0),
+            AddTentativeDeviceResponse::DeviceRegistrationModeOff
+        ));
+        assert!(matches!(
+            registrations.verify_tentative_device(ANCHOR, "123456", 0),
+            Err(VerifyTentativeDeviceResponse::DeviceRegistrationModeOff)
+        ));
+
+        registrations.enter(ANCHOR, 0).unwrap();
+        registrations.exit(ANCHOR);
+        assert!(matches!(
+            registrations.add_tentative_device(ANCHOR, device(1), code("123456"), 0),
+            AddTentativeDeviceResponse::DeviceRegistrationModeOff
+        ));
+
+        // the registration mode of other anchors does not count
+        registrations.enter(ANCHOR + 1, 0).unwrap();
+        assert!(matches!(
+            registrations.add_tentative_device(ANCHOR, device(1), code("123456"), 0),
+            AddTentativeDeviceResponse::DeviceRegistrationModeOff
+        ));
+    }
+
+    #[test]
+    fn should_end_registration_mode_on_expiration() {
+        let mut registrations = DeviceRegistrations::default();
+        let expiration = registrations.enter(ANCHOR, 0).unwrap();
+        assert!(matches!(
+            registrations.add_tentative_device(ANCHOR, device(1), code("123456"), expiration - 1),
+            AddTentativeDeviceResponse::AddedTentatively { .. }
+        ));
+        assert_eq!(registrations.info(ANCHOR, expiration), None);
+        assert!(matches!(
+            registrations.verify_tentative_device(ANCHOR, "123456", expiration),
+            Err(VerifyTentativeDeviceResponse::DeviceRegistrationModeOff)
+        ));
+
+        let expiration = registrations.enter(ANCHOR, expiration).unwrap();
+        assert!(matches!(
+            registrations.add_tentative_device(ANCHOR, device(1), code("123456"), expiration),
+            AddTentativeDeviceResponse::DeviceRegistrationModeOff
+        ));
+    }
+
+    #[test]
+    fn should_only_add_one_tentative_device() {
+        let mut registrations = DeviceRegistrations::default();
+        registrations.enter(ANCHOR, 0).unwrap();
+        assert!(matches!(
+            registrations.verify_tentative_device(ANCHOR, "123456", 0),
+            Err(VerifyTentativeDeviceResponse::NoDeviceToVerify)
+        ));
+        registrations.add_tentative_device(ANCHOR, device(1), code("123456"), 0);
+        assert!(matches!(
+            registrations.add_tentative_device(ANCHOR, device(2), code("654321"), 0),
+            AddTentativeDeviceResponse::AnotherDeviceTentativelyAdded
+        ));
+        // the code of the second device does not verify anything
+        assert!(matches!(
+            registrations.verify_tentative_device(ANCHOR, "654321", 0),
+            Err(VerifyTentativeDeviceResponse::WrongCode { retries_left: 2 })
+        ));
+        assert_eq!(
+            registrations
+                .verify_tentative_device(ANCHOR, "123456", 0)
+                .unwrap(),
+            device(1)
+        );
+    }
+
+    #[test]
+    fn should_end_registration_mode_after_max_wrong_codes() {
+        let mut registrations = DeviceRegistrations::default();
+        registrations.enter(ANCHOR, 0).unwrap();
+        registrations.add_tentative_device(ANCHOR, device(1), code("123456"), 0);
+        for retries_left in (0..MAX_VERIFICATION_ATTEMPTS).rev() {
+            assert!(matches!(
+                registrations.verify_tentative_device(ANCHOR, "000000", 0),
+                Err(VerifyTentativeDeviceResponse::WrongCode { retries_left: left })
+                    if left == retries_left
+            ));
+        }
+        assert_eq!(registrations.info(ANCHOR, 0), None);
+        assert!(matches!(
+            registrations.verify_tentative_device(ANCHOR, "123456", 0),
+            Err(VerifyTentativeDeviceResponse::DeviceRegistrationModeOff)
+        ));
+    }
+
+    #[test]
+    fn should_limit_anchors_in_registration_mode() {
+        let mut registrations = DeviceRegistrations::default();
+        for user_number in 0..MAX_ANCHORS_IN_REGISTRATION_MODE as u64 {
+            registrations.enter(user_number, 0).unwrap();
+        }
+        assert_eq!(
+            registrations.enter(MAX_ANCHORS_IN_REGISTRATION_MODE as u64, 0),
+            None
+        );
+        // anchors in registration mode can restart it
+        assert!(registrations.enter(0, 1).is_some());
+        // expired registration modes are pruned
+        assert!(registrations
+            .enter(
+                MAX_ANCHORS_IN_REGISTRATION_MODE as u64,
+                REGISTRATION_MODE_DURATION_NS
+            )
+            .is_some());
+    }
+
+    #[test]
+    fn should_derive_six_digit_verification_codes() {
+        assert_eq!(verification_code(&[0; 32]), "000000");
+        assert_eq!(verification_code(&[0xff; 32]), "551615");
+        assert_eq!(verification_code(&[]), "000000");
+        assert_eq!(verification_code(&[7]), "000007");
+    }
+}
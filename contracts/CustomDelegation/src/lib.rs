@@ -1,19 +1,41 @@
+use std::collections::HashMap;
+
 use candid::{candid_method, CandidType, Principal};
 use ic_cdk::{call, caller, query, trap, update};
-use ic_cdk_macros::init;
+use ic_cdk::api::time;
+use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade};
 use ic_certified_map::{AsHashTree, Hash};
 use serde::Deserialize;
 use serde_bytes::ByteBuf;
-use sha2::{Digest, Sha256};
 
-use types::{GetDelegationResponse, SessionKey, Timestamp, UserKey};
+use types::{
+    AddTentativeDeviceResponse, AnchorInfo, AnchorRecord, Challenge, ChallengeAttempt,
+    CredentialId, DelegationRequest, DeviceData, DeviceError, DeviceKey, DeviceVerificationCode,
+    FrontendHostname, GetDelegationResponse, IdentityAnchorInfo, InternetIdentityInit,
+    InternetIdentityStats, MetadataEntry, Operation, RateLimitConfig, RegisterResponse,
+    RevocationStatus, SessionKey, Timestamp, UserKey, UserNumber, VerifyTentativeDeviceResponse,
+};
 
 use crate::delegation::update_root_hash;
+use crate::deps::http::{HttpRequest, HttpResponse};
+use crate::rate_limit::{RateLimitExceeded, TokenBucket};
+use crate::storage::{CompactionReport, VerifyReport};
+use crate::storage::record_storage::RecordStorage;
 
+mod anchor_management;
+mod archive;
+mod assets;
+mod challenge;
 mod delegation;
 mod deps;
+mod device_registration;
+mod metrics;
+mod rate_limit;
 mod state;
 mod storage;
+mod temp_keys;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod types;
 
 #[derive(CandidType, Deserialize)]
@@ -25,19 +47,36 @@ const fn secs_to_nanos(secs: u64) -> u64 {
     secs * 1_000_000_000
 }
 
-const LABEL_ASSETS: &[u8] = b"http_assets";
+// label of the certified HTTP responses, see [assets]
+const LABEL_ASSETS: &[u8] = b"http_expr";
+// label of the certified revoked delegations, see [storage::revocations]
+const LABEL_REVOKED: &[u8] = b"revoked";
 const LABEL_SIG: &[u8] = b"sig";
 const METAMASK_CID: &str = "sp7ew-3yaaa-aaaak-qbtua-cai";
+const MAX_ANCHORS_PER_QUERY: usize = 500;
+/// Number of anchors indexed per heartbeat while the credential index is rebuilt.
+const CREDENTIAL_INDEX_REBUILD_BATCH: usize = 1_000;
 
+/// Prepares a delegation for the MetaMask account that signed the session key. The seed is derived
+/// from the address of the account, see [delegation::calculate_metamask_seed].
 #[update]
 #[candid_method]
 async fn prepare_delegation(
     max_time_to_live: Option<u64>,
     sig: CustomSignature,
+    targets: Option<Vec<Principal>>,
 ) -> (UserKey, Timestamp) {
-    let mut seed = [0x00u8; 32];
-    let mut session_key = vec![];
+    // before the signature check, which calls another canister
+    trap_if_delegation_rate_limited();
+    let (address, session_key) = verify_custom_signature(sig).await;
+    state::ensure_salt_set().await;
+    let seed = delegation::calculate_metamask_seed(&address);
+    delegation::prepare_delegation(seed, session_key, max_time_to_live, targets, None).await
+}
 
+/// Verifies the signature of the session key, which has to be the caller, and returns the address
+/// of the signing account together with the session key.
+async fn verify_custom_signature(sig: CustomSignature) -> (Vec<u8>, SessionKey) {
     match sig {
         CustomSignature::MetaMask(address, key, sig) => {
             let res: Result<(bool,), _> = call(
@@ -49,21 +88,13 @@ async fn prepare_delegation(
             if !res.unwrap().0 {
                 trap("Failed to verify signature")
             }
-            session_key = hex::decode(key).unwrap().try_into().unwrap();
-            if caller() != Principal::self_authenticating(session_key.clone()) {
+            let session_key = hex::decode(key).unwrap();
+            if caller() != Principal::self_authenticating(&session_key) {
                 trap("Invalid Session Key")
             }
-            let mut hasher = Sha256::new();
-            hasher.update(hex::decode(address).unwrap());
-            // You should add a salt value here that is not known to third parties
-            // otherwise the identity generated from this canister will have security vulnerabilities.
-            // hasher.update([salt value]);
-            let result = hasher.finalize().to_vec();
-            seed = result.try_into().unwrap();
+            (hex::decode(address).unwrap(), ByteBuf::from(session_key))
         }
     }
-
-    delegation::prepare_delegation(seed.clone(), ByteBuf::from(session_key), max_time_to_live).await
 }
 
 #[query]
@@ -72,15 +103,878 @@ fn get_delegation(
     seed: Hash,
     session_key: SessionKey,
     expiration: Timestamp,
+    targets: Option<Vec<Principal>>,
 ) -> GetDelegationResponse {
     // self auth
     if caller() != Principal::self_authenticating(&session_key) {
         trap("Invalid Session Key")
     }
-    delegation::get_delegation(seed.try_into().unwrap(), session_key, expiration)
+    delegation::get_delegation(seed.try_into().unwrap(), session_key, expiration, targets)
+}
+
+#[update]
+#[candid_method]
+async fn prepare_anchor_delegation(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    session_key: SessionKey,
+    max_time_to_live: Option<u64>,
+    targets: Option<Vec<Principal>>,
+    derivation_origin: Option<String>,
+) -> (UserKey, Timestamp) {
+    authenticate_and_record_usage(user_number);
+    trap_if_delegation_rate_limited();
+    // logged once the delegation is prepared, like in prepare_delegations
+    let caller = caller();
+    let prepared = delegation::prepare_anchor_delegation(
+        user_number,
+        frontend.clone(),
+        session_key,
+        max_time_to_live,
+        targets,
+        derivation_origin,
+    )
+    .await;
+    archive::log_operation(
+        user_number,
+        Operation::PrepareDelegation { frontend },
+        caller,
+    );
+    prepared
+}
+
+/// Prepares up to 10 delegations for the given anchor at once, see [prepare_anchor_delegation].
+/// The signatures are certified together and either all delegations are prepared or none. A batch
+/// counts as a single call towards the delegation rate limit.
+#[update]
+#[candid_method]
+async fn prepare_delegations(
+    user_number: UserNumber,
+    delegations: Vec<DelegationRequest>,
+) -> Vec<(UserKey, Timestamp)> {
+    authenticate_and_record_usage(user_number);
+    trap_if_delegation_rate_limited();
+    // logged once the batch is prepared, so that invalid batches are not logged
+    let caller = caller();
+    let frontends: Vec<FrontendHostname> = delegations
+        .iter()
+        .map(|(frontend, ..)| frontend.clone())
+        .collect();
+    let prepared = delegation::prepare_anchor_delegations(user_number, delegations).await;
+    for frontend in frontends {
+        archive::log_operation(
+            user_number,
+            Operation::PrepareDelegation { frontend },
+            caller,
+        );
+    }
+    prepared
+}
+
+#[query]
+#[candid_method(query)]
+fn get_anchor_delegation(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    session_key: SessionKey,
+    expiration: Timestamp,
+    targets: Option<Vec<Principal>>,
+    derivation_origin: Option<String>,
+) -> GetDelegationResponse {
+    trap_if_not_authenticated(user_number);
+    delegation::get_anchor_delegation(
+        user_number,
+        frontend,
+        session_key,
+        expiration,
+        targets,
+        derivation_origin,
+    )
+}
+
+/// Revokes the delegations prepared for the given anchor on the given frontend to `session_key`,
+/// see [delegation::revoke_delegation].
+#[update]
+#[candid_method]
+fn revoke_delegation(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    session_key: SessionKey,
+    derivation_origin: Option<String>,
+) {
+    authenticate_and_record_usage(user_number);
+    delegation::revoke_delegation(user_number, frontend, session_key, derivation_origin);
+}
+
+/// Returns whether the delegations of `user_key` (as returned by [prepare_anchor_delegation]) to
+/// `session_key` have been revoked with [revoke_delegation]. Signed delegations are verified
+/// without consulting this canister, so relying parties have to call this method to learn about
+/// revocations. Query calls return the certificate and the witness of the revocation (or of its
+/// absence) under `revoked`, so that the response can be verified like a signature; calls from
+/// other canisters are executed in replicated mode and certified by consensus.
+#[query]
+#[candid_method(query)]
+fn is_delegation_revoked(user_key: UserKey, session_key: SessionKey) -> RevocationStatus {
+    delegation::is_delegation_revoked(&user_key, &session_key)
+}
+
+/// Returns the principal the given anchor gets on the given frontend, i.e. the principal of the
+/// delegations prepared with [prepare_anchor_delegation].
+#[query]
+#[candid_method(query)]
+fn get_principal(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    derivation_origin: Option<String>,
+) -> Principal {
+    trap_if_not_authenticated(user_number);
+    delegation::get_principal(user_number, frontend, derivation_origin)
+}
+
+/// Creates a challenge that has to be solved to [register] an anchor.
+#[update]
+#[candid_method]
+async fn create_challenge() -> Challenge {
+    state::ensure_salt_set().await;
+    let salt = state::salt();
+    state::challenges_mut(|challenges| challenges.create(&salt, time()))
+        .unwrap_or_else(|| trap("too many challenges waiting for a solution, try again later"))
+}
+
+/// Registers a new anchor with the given device, which has to be the caller. Unless disabled at
+/// install time, the caller has to solve a challenge created by [create_challenge] first.
+///
+/// Registrations are subject to the registration rate limit, if any. Only calls that register an
+/// anchor take a token, so that bogus calls and calls to a full canister cannot use up the limit.
+///
+/// If a `temp_key` is given, it authenticates calls for the new anchor on behalf of the device for
+/// a few minutes, see [temp_keys].
+#[update]
+#[candid_method]
+fn register(
+    device: DeviceData,
+    challenge_attempt: ChallengeAttempt,
+    temp_key: Option<SessionKey>,
+) -> RegisterResponse {
+    if caller() != Principal::self_authenticating(&device.pubkey) {
+        trap(&format!("{} could not be authenticated", caller()))
+    }
+    let rate_limited = state::persistent_state_mut(|persistent_state| {
+        persistent_state
+            .registration_rate_limit
+            .as_mut()
+            .map_or(Ok(()), |bucket| bucket.check(time()))
+    });
+    if let Err(RateLimitExceeded { retry_after_ns }) = rate_limited {
+        return RegisterResponse::RateLimitExceeded { retry_after_ns };
+    }
+    let challenge_disabled = state::persistent_state(|persistent_state| {
+        persistent_state
+            .disable_registration_challenge
+            .unwrap_or(false)
+    });
+    if !challenge_disabled
+        && !state::challenges_mut(|challenges| challenges.verify(&challenge_attempt, time()))
+    {
+        return RegisterResponse::BadChallenge;
+    }
+
+    let device_key = device.pubkey.clone();
+    let operation = Operation::RegisterAnchor {
+        device: device.clone().into(),
+    };
+    let anchor = AnchorRecord {
+        devices: vec![DeviceData {
+            last_usage_timestamp: None,
+            ..device
+        }],
+        delegations: None,
+        metadata: None,
+    };
+    let response = state::indexed_storage_mut(|storage| match storage.allocate_anchor() {
+        Some(user_number) => {
+            storage
+                .write_anchor(user_number, &anchor)
+                .unwrap_or_else(|err| trap(&err.to_string()));
+            RegisterResponse::Registered { user_number }
+        }
+        None => RegisterResponse::CanisterFull,
+    });
+    if let RegisterResponse::Registered { user_number } = response {
+        // a token is left, it was checked above in the same call
+        state::persistent_state_mut(|persistent_state| {
+            if let Some(bucket) = persistent_state.registration_rate_limit.as_mut() {
+                bucket.take();
+            }
+        });
+        archive::log_operation(user_number, operation, caller());
+        if let Some(temp_key) = temp_key {
+            add_temp_key(user_number, device_key, &temp_key);
+        }
+        // the certified metrics include the number of registered anchors
+        update_root_hash();
+    }
+    response
+}
+
+/// Returns the devices and the metadata of the given anchor.
+#[query]
+#[candid_method(query)]
+fn lookup(user_number: UserNumber) -> Result<AnchorInfo, DeviceError> {
+    trap_if_not_authenticated(user_number);
+    state::storage(|storage| anchor_management::lookup(storage, user_number))
+}
+
+/// Returns the devices and the metadata of the given anchor together with the state of its device
+/// registration mode. Only the devices of the anchor may call this.
+#[query]
+#[candid_method(query)]
+fn get_anchor_info(user_number: UserNumber) -> Result<IdentityAnchorInfo, DeviceError> {
+    trap_if_not_authenticated(user_number);
+    let device_registration =
+        state::device_registrations(|registrations| registrations.info(user_number, time()));
+    state::storage(|storage| {
+        anchor_management::anchor_info(storage, user_number, device_registration)
+    })
+}
+
+/// Returns the anchor holding the device with the given WebAuthn credential ID if the caller is
+/// that device, see [storage::credential_index]. Traps while the index is being rebuilt.
+#[query]
+#[candid_method(query)]
+fn lookup_anchor_by_credential(credential_id: CredentialId) -> Option<UserNumber> {
+    state::credential_index_and_storage_mut(|index, storage| {
+        if !index.is_complete() {
+            trap("the credential index is being rebuilt, try again later");
+        }
+        index.lookup(storage, &credential_id, caller())
+    })
+}
+
+/// Adds a device to the given anchor. If a `temp_key` is given, it authenticates calls for the
+/// anchor on behalf of the new device for a few minutes, see [temp_keys].
+#[update]
+#[candid_method]
+fn add(
+    user_number: UserNumber,
+    device: DeviceData,
+    temp_key: Option<SessionKey>,
+) -> Result<(), DeviceError> {
+    authenticate_and_record_usage(user_number);
+    let device_key = device.pubkey.clone();
+    let operation = Operation::AddDevice {
+        device: device.clone().into(),
+    };
+    state::indexed_storage_mut(|storage| anchor_management::add(storage, user_number, device))?;
+    archive::log_operation(user_number, operation, caller());
+    if let Some(temp_key) = temp_key {
+        add_temp_key(user_number, device_key, &temp_key);
+    }
+    Ok(())
+}
+
+/// Starts the device registration mode of the given anchor, in which a device can be added from a
+/// browser that cannot use any of its devices, see [device_registration]. Returns the time at
+/// which the registration mode ends.
+#[update]
+#[candid_method]
+fn enter_device_registration_mode(user_number: UserNumber) -> Timestamp {
+    authenticate_and_record_usage(user_number);
+    state::device_registrations_mut(|registrations| registrations.enter(user_number, time()))
+        .unwrap_or_else(|| trap("too many anchors are in device registration mode"))
+}
+
+/// Ends the device registration mode of the given anchor, dropping its tentative device.
+#[update]
+#[candid_method]
+fn exit_device_registration_mode(user_number: UserNumber) {
+    authenticate_and_record_usage(user_number);
+    state::device_registrations_mut(|registrations| registrations.exit(user_number));
+}
+
+/// Adds the device tentatively to the given anchor while it is in device registration mode. The
+/// device is only added to the anchor once one of its devices calls [verify_tentative_device] with
+/// the returned verification code. Anyone can call this.
+#[update]
+#[candid_method]
+async fn add_tentative_device(
+    user_number: UserNumber,
+    device: DeviceData,
+) -> AddTentativeDeviceResponse {
+    // no need to wait for randomness if the device would be rejected anyway
+    if state::device_registrations(|registrations| registrations.info(user_number, time()))
+        .is_none()
+    {
+        return AddTentativeDeviceResponse::DeviceRegistrationModeOff;
+    }
+    let randomness: Vec<u8> = match call(Principal::management_canister(), "raw_rand", ()).await {
+        Ok((randomness,)) => randomness,
+        Err((_, err)) => trap(&format!("failed to get randomness: {}", err)),
+    };
+    let verification_code = device_registration::verification_code(&randomness);
+    state::device_registrations_mut(|registrations| {
+        registrations.add_tentative_device(user_number, device, verification_code, time())
+    })
+}
+
+/// Adds the tentative device of the given anchor if the verification code matches, see
+/// [add_tentative_device]. Only the devices of the anchor may call this.
+#[update]
+#[candid_method]
+fn verify_tentative_device(
+    user_number: UserNumber,
+    verification_code: DeviceVerificationCode,
+) -> Result<VerifyTentativeDeviceResponse, DeviceError> {
+    authenticate_and_record_usage(user_number);
+    let device = match state::device_registrations_mut(|registrations| {
+        registrations.verify_tentative_device(user_number, &verification_code, time())
+    }) {
+        Ok(device) => device,
+        Err(response) => return Ok(response),
+    };
+    let operation = Operation::AddDevice {
+        device: device.clone().into(),
+    };
+    state::indexed_storage_mut(|storage| anchor_management::add(storage, user_number, device))?;
+    archive::log_operation(user_number, operation, caller());
+    Ok(VerifyTentativeDeviceResponse::Verified)
+}
+
+/// Removes a device from the given anchor, which must keep at least one device. Protected devices
+/// can only be removed by themselves.
+#[update]
+#[candid_method]
+fn remove(user_number: UserNumber, device_key: DeviceKey) -> Result<(), DeviceError> {
+    authenticate_and_record_usage(user_number);
+    state::indexed_storage_mut(|storage| {
+        anchor_management::remove(storage, user_number, caller(), device_key.clone())
+    })?;
+    archive::log_operation(
+        user_number,
+        Operation::RemoveDevice {
+            device: device_key.clone(),
+        },
+        caller(),
+    );
+    state::temp_keys_mut(|temp_keys| temp_keys.remove_device(user_number, &device_key));
+    Ok(())
+}
+
+/// Replaces the device with the given key of the given anchor. Protected devices can only be
+/// replaced by themselves.
+#[update]
+#[candid_method]
+fn replace(
+    user_number: UserNumber,
+    device_key: DeviceKey,
+    device: DeviceData,
+) -> Result<(), DeviceError> {
+    authenticate_and_record_usage(user_number);
+    let new_device_key = device.pubkey.clone();
+    let operation = Operation::AddDevice {
+        device: device.clone().into(),
+    };
+    state::indexed_storage_mut(|storage| {
+        anchor_management::replace(storage, user_number, caller(), device_key.clone(), device)
+    })?;
+    // logged as the removal of the old device followed by the addition of the new one
+    archive::log_operation(
+        user_number,
+        Operation::RemoveDevice {
+            device: device_key.clone(),
+        },
+        caller(),
+    );
+    archive::log_operation(user_number, operation, caller());
+    if new_device_key != device_key {
+        state::temp_keys_mut(|temp_keys| temp_keys.remove_device(user_number, &device_key));
+    }
+    Ok(())
+}
+
+/// Replaces the anchor level metadata of the given anchor.
+#[update]
+#[candid_method]
+fn anchor_metadata_replace(
+    user_number: UserNumber,
+    metadata: HashMap<String, MetadataEntry>,
+) -> Result<(), DeviceError> {
+    authenticate_and_record_usage(user_number);
+    state::storage_mut(|storage| {
+        anchor_management::replace_metadata(storage, user_number, metadata)
+    })
+}
+
+/// Deletes the given anchor, see [storage::Storage::delete_anchor]. The devices of the anchor can
+/// delete it at any time, controllers only once the grace period of a deletion scheduled with
+/// [schedule_anchor_deletion] has passed.
+#[update]
+#[candid_method]
+fn delete_anchor(user_number: UserNumber) -> Result<(), DeviceError> {
+    let deletion_due = state::persistent_state(|persistent_state| {
+        anchor_management::deletion_due(
+            persistent_state
+                .scheduled_anchor_deletions
+                .as_deref()
+                .unwrap_or_default(),
+            user_number,
+            time(),
+        )
+    });
+    if !(deletion_due && state::is_admin()) {
+        trap_if_not_authenticated(user_number);
+    }
+    state::indexed_storage_mut(|storage| anchor_management::delete(storage, user_number))?;
+    state::device_registrations_mut(|registrations| registrations.exit(user_number));
+    state::persistent_state_mut(|persistent_state| {
+        anchor_management::cancel_deletion(
+            persistent_state
+                .scheduled_anchor_deletions
+                .get_or_insert_with(Vec::new),
+            user_number,
+        )
+    });
+    // the certified metrics include the number of deleted anchors
+    update_root_hash();
+    Ok(())
+}
+
+/// Schedules the deletion of the given anchor by a controller, see [delete_anchor]. Returns the
+/// time from which the anchor can be deleted.
+#[update]
+#[candid_method]
+fn schedule_anchor_deletion(user_number: UserNumber) -> Timestamp {
+    trap_if_not_admin();
+    state::storage(|storage| storage.read_anchor(user_number))
+        .unwrap_or_else(|err| trap(&err.to_string()));
+    state::persistent_state_mut(|persistent_state| {
+        anchor_management::schedule_deletion(
+            persistent_state
+                .scheduled_anchor_deletions
+                .get_or_insert_with(Vec::new),
+            user_number,
+            time(),
+        )
+    })
+}
+
+/// Cancels the deletion of the given anchor scheduled by a controller.
+#[update]
+#[candid_method]
+fn cancel_anchor_deletion(user_number: UserNumber) {
+    authenticate_and_record_usage(user_number);
+    state::persistent_state_mut(|persistent_state| {
+        anchor_management::cancel_deletion(
+            persistent_state
+                .scheduled_anchor_deletions
+                .get_or_insert_with(Vec::new),
+            user_number,
+        )
+    });
+}
+
+/// Sets the derivation origins that may be passed to [prepare_anchor_delegation]. Traps if an
+/// origin is longer than 255 bytes.
+#[update]
+#[candid_method]
+fn set_derivation_origins(origins: Vec<String>) {
+    trap_if_not_admin();
+    delegation::check_derivation_origins(&origins).unwrap_or_else(|err| trap(&err));
+    state::persistent_state_mut(|persistent_state| {
+        persistent_state.derivation_origins = Some(origins);
+    });
+}
+
+/// Sets the maximum time to live of the delegations prepared for the given frontend, overriding the
+/// one configured at install or upgrade. `None` removes the override. Traps if the time to live
+/// exceeds the hard cap of 90 days.
+#[update]
+#[candid_method]
+fn set_origin_ttl(origin: FrontendHostname, ttl: Option<u64>) {
+    trap_if_not_admin();
+    trap_if_max_delegation_ttl_too_large(ttl);
+    state::persistent_state_mut(|persistent_state| {
+        let ttls = persistent_state
+            .origin_delegation_ttls
+            .get_or_insert_with(Vec::new);
+        ttls.retain(|(existing, _)| *existing != origin);
+        if let Some(ttl) = ttl {
+            ttls.push((origin, ttl));
+        }
+    });
+}
+
+#[update]
+#[candid_method]
+fn extend_identity_range(new_hi: UserNumber) {
+    trap_if_not_admin();
+    state::fixed_slot_storage_mut(|storage| storage.extend_range(new_hi))
+        .unwrap_or_else(|err| trap(&err.to_string()));
+}
+
+/// Converts the next `batch_size` anchors from the vec<device> to the candid anchor record layout
+/// and returns the number of anchors that still need to be converted.
+#[update]
+#[candid_method]
+fn migrate_anchors(batch_size: u32) -> u32 {
+    trap_if_not_admin();
+    state::fixed_slot_storage_mut(|storage| {
+        storage.set_migration_batch_size(batch_size);
+        storage.migrate_batch()
+    })
+    .map(|progress| progress.remaining)
+    .unwrap_or_else(|err| trap(&err.to_string()))
+}
+
+/// Re-encodes up to `batch` anchors starting with the `start_record`-th anchor to drop the bytes of
+/// fields that are no longer used, see [storage::Storage::reencode_anchors]. Returns the record to
+/// continue with, which equals the number of registered anchors once all have been processed.
+#[update]
+#[candid_method]
+fn recompress_anchors(start_record: u32, batch: u32) -> u32 {
+    trap_if_not_admin();
+    state::fixed_slot_storage_mut(|storage| storage.reencode_anchors(start_record, batch))
+        .unwrap_or_else(|err| trap(&err.to_string()))
+}
+
+/// Deallocates the anchors at the end of the range that have never been written, see
+/// [storage::Storage::compact], and removes the entries left for them in the credential index.
+#[update]
+#[candid_method]
+fn compact_anchors() -> CompactionReport {
+    trap_if_not_admin();
+    let report = state::credential_index_and_fixed_slot_storage_mut(|index, storage| {
+        let report = storage.compact()?;
+        if report.reclaimed_records > 0 {
+            let (id_range_lo, _) = storage.assigned_user_number_range();
+            index.remove_anchors_from(id_range_lo + storage.user_count() as u64);
+        }
+        Ok::<_, storage::StorageError>(report)
+    })
+    .unwrap_or_else(|err| trap(&err.to_string()));
+    // the certified metrics include the number of registered anchors
+    update_root_hash();
+    report
+}
+
+/// Starts growing the entry size of all anchors to `new_entry_size`, see
+/// [storage::Storage::start_entry_size_migration]. The entries are moved with [migrate_entry_size].
+#[update]
+#[candid_method]
+fn start_entry_size_migration(new_entry_size: u16) {
+    trap_if_not_admin();
+    state::fixed_slot_storage_mut(|storage| storage.start_entry_size_migration(new_entry_size))
+        .unwrap_or_else(|err| trap(&err.to_string()));
+}
+
+/// Moves the entries of the next `batch_size` anchors to their location for the new entry size and
+/// returns the number of anchors that still need to be moved.
+#[update]
+#[candid_method]
+fn migrate_entry_size(batch_size: u32) -> u32 {
+    trap_if_not_admin();
+    state::fixed_slot_storage_mut(|storage| storage.migrate_entry_size(batch_size))
+}
+
+/// Returns the anchor records of the given user numbers (at most [MAX_ANCHORS_PER_QUERY]).
+#[query]
+#[candid_method(query)]
+fn get_anchors(user_numbers: Vec<UserNumber>) -> Vec<Result<AnchorRecord, String>> {
+    trap_if_not_admin();
+    if user_numbers.len() > MAX_ANCHORS_PER_QUERY {
+        trap(&format!(
+            "at most {} anchors can be read at once",
+            MAX_ANCHORS_PER_QUERY
+        ));
+    }
+    state::storage(|storage| storage.read_anchors(&user_numbers))
+        .into_iter()
+        .map(|result| result.map_err(|err| err.to_string()))
+        .collect()
+}
+
+/// Decodes the records of all registered anchors and reports the ones that cannot be decoded, see
+/// [storage::Storage::verify_all]. Meant as a health check after an upgrade.
+#[query]
+#[candid_method(query)]
+fn verify_anchors() -> VerifyReport {
+    trap_if_not_admin();
+    state::fixed_slot_storage(|storage| storage.verify_all())
+}
+
+/// Returns up to `limit` anchors starting at the given user number, to be imported into another
+/// canister with [import_anchors].
+#[query]
+#[candid_method(query)]
+fn export_anchor_range(start: UserNumber, limit: u16) -> Vec<(UserNumber, AnchorRecord)> {
+    trap_if_not_admin();
+    state::fixed_slot_storage(|storage| storage.export_anchor_range(start, limit))
+        .unwrap_or_else(|err| trap(&err.to_string()))
+}
+
+/// Imports anchors returned by [export_anchor_range], which must continue right after the
+/// anchors registered in this canister. Nothing is imported if any of the anchors cannot be.
+#[update]
+#[candid_method]
+fn import_anchors(anchors: Vec<(UserNumber, AnchorRecord)>) {
+    trap_if_not_admin();
+    let first_user_number = anchors.first().map(|(user_number, _)| *user_number);
+    state::credential_index_and_fixed_slot_storage_mut(|index, storage| {
+        storage.import_anchors(anchors)?;
+        if let Some(user_number) = first_user_number {
+            index.rebuild_from(user_number);
+        }
+        Ok::<_, storage::StorageError>(())
+    })
+    .unwrap_or_else(|err| trap(&err.to_string()));
+    // the certified metrics include the number of registered anchors
+    update_root_hash();
+}
+
+/// Prefixes all anchor records written from now on with a record version, see
+/// [storage::Storage::enable_record_versions]. Cannot be undone.
+#[update]
+#[candid_method]
+fn enable_record_versions() {
+    trap_if_not_admin();
+    state::fixed_slot_storage_mut(|storage| storage.enable_record_versions())
+        .unwrap_or_else(|err| trap(&err.to_string()));
+}
+
+/// Enables or disables [restore_anchors], see [storage::Storage::set_backup_mode].
+#[update]
+#[candid_method]
+fn set_backup_mode(enabled: bool) {
+    trap_if_not_admin();
+    state::fixed_slot_storage_mut(|storage| storage.set_backup_mode(enabled));
+}
+
+/// Returns the raw entries of up to `count` anchors starting with the `offset`-th anchor, see
+/// [storage::Storage::backup_anchors].
+#[query]
+#[candid_method(query)]
+fn backup_anchors(offset: u64, count: u32) -> ByteBuf {
+    trap_if_not_admin();
+    state::fixed_slot_storage(|storage| storage.backup_anchors(record_offset(offset), count))
+        .map(ByteBuf::from)
+        .unwrap_or_else(|err| trap(&err.to_string()))
+}
+
+/// Writes back raw entries returned by [backup_anchors], starting with the `offset`-th anchor.
+/// Only allowed in backup mode, see [set_backup_mode].
+#[update]
+#[candid_method]
+fn restore_anchors(offset: u64, bytes: ByteBuf) {
+    trap_if_not_admin();
+    // the restored anchors are indexed again, see [storage::credential_index]
+    state::credential_index_and_fixed_slot_storage_mut(|index, storage| {
+        storage.restore_anchors(record_offset(offset), &bytes)?;
+        index.rebuild_from(storage.assigned_user_number_range().0 + offset);
+        Ok::<_, storage::StorageError>(())
+    })
+    .unwrap_or_else(|err| trap(&err.to_string()));
+    // the certified metrics include the number of registered anchors
+    update_root_hash();
+}
+
+fn record_offset(offset: u64) -> u32 {
+    u32::try_from(offset).unwrap_or_else(|_| trap(&format!("bad anchor offset {}", offset)))
+}
+
+/// Returns the metrics of the canister, which are also served in the Prometheus text format at
+/// `/metrics`.
+#[query]
+#[candid_method(query)]
+fn stats() -> InternetIdentityStats {
+    metrics::collect()
+}
+
+#[query]
+#[candid_method(query)]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    deps::http::http_request(req)
+}
+
+/// Traps unless the caller is authenticated with one of the devices of the given anchor, or with
+/// a temp key standing in for one of them (see [temp_keys]).
+/// Returns the key of the device of the given anchor the caller authenticates with.
+fn trap_if_not_authenticated(user_number: UserNumber) -> DeviceKey {
+    let anchor = state::storage(|storage| storage.read_anchor(user_number))
+        .unwrap_or_else(|err| trap(&err.to_string()));
+    let caller = caller();
+    let temp_key_device =
+        state::temp_keys(|temp_keys| temp_keys.device_key(user_number, caller, time()).cloned());
+    match anchor.devices.into_iter().find(|device| {
+        caller == Principal::self_authenticating(&device.pubkey)
+            || Some(&device.pubkey) == temp_key_device.as_ref()
+    }) {
+        Some(device) => device.pubkey,
+        None => trap(&format!("{} could not be authenticated", caller)),
+    }
+}
+
+/// Adds a temp key for the given device. Failing to add it (because too many temp keys are valid)
+/// must not fail the call, the client then authenticates with the device itself.
+fn add_temp_key(user_number: UserNumber, device_key: DeviceKey, temp_key: &SessionKey) {
+    let _ =
+        state::temp_keys_mut(|temp_keys| temp_keys.add(user_number, device_key, temp_key, time()));
+}
+
+/// Like [trap_if_not_authenticated], but also records the usage of the device. Only for update
+/// calls, since queries cannot persist the usage.
+fn authenticate_and_record_usage(user_number: UserNumber) {
+    let device_key = trap_if_not_authenticated(user_number);
+    // failing to record the usage (e.g. because the entry is full) must not fail the call
+    let _ = state::storage_mut(|storage| {
+        anchor_management::record_device_usage(storage, user_number, &device_key, time())
+    });
+}
+
+/// Takes a token from the delegation rate limit, if any. Traps if the limit is exceeded since the
+/// response of the delegation calls is fixed by the delegation protocol.
+fn trap_if_delegation_rate_limited() {
+    let rate_limited = state::persistent_state_mut(|persistent_state| {
+        persistent_state
+            .delegation_rate_limit
+            .as_mut()
+            .map_or(Ok(()), |bucket| bucket.try_take(time()))
+    });
+    if let Err(RateLimitExceeded { retry_after_ns }) = rate_limited {
+        trap(&format!(
+            "delegation rate limit exceeded, retry in {} ns",
+            retry_after_ns
+        ))
+    }
+}
+
+fn apply_rate_limit(bucket: &mut Option<TokenBucket>, config: RateLimitConfig) {
+    match bucket {
+        Some(bucket) => bucket.reconfigure(config, time()),
+        None => *bucket = Some(TokenBucket::new(config, time())),
+    }
+}
+
+fn trap_if_max_delegation_ttl_too_large(max_delegation_ttl: Option<u64>) {
+    if let Some(ttl) = max_delegation_ttl {
+        delegation::check_max_delegation_ttl(ttl).unwrap_or_else(|err| trap(&err));
+    }
+}
+
+fn trap_if_not_admin() {
+    if !state::is_admin() {
+        trap(&format!(
+            "{} is not authorized to call this method",
+            caller()
+        ))
+    }
 }
 
 #[init]
-fn init() {
+fn init(maybe_arg: Option<InternetIdentityInit>) {
+    let (
+        range,
+        entry_size,
+        max_delegation_ttl,
+        max_signatures_to_prune,
+        disable_registration_challenge,
+        registration_rate_limit,
+        delegation_rate_limit,
+        archive_config,
+        compress_anchor_records,
+        anchor_storage_layout,
+        stable_memory_reserve,
+    ) = maybe_arg
+        .map(|arg| {
+            (
+                arg.assigned_user_number_range,
+                arg.entry_size,
+                arg.max_delegation_ttl,
+                arg.max_signatures_to_prune,
+                arg.disable_registration_challenge,
+                arg.registration_rate_limit,
+                arg.delegation_rate_limit,
+                arg.archive_config,
+                arg.compress_anchor_records,
+                arg.anchor_storage_layout,
+                arg.stable_memory_reserve,
+            )
+        })
+        .unwrap_or_default();
+    trap_if_max_delegation_ttl_too_large(max_delegation_ttl);
+    state::init_new(
+        range,
+        entry_size,
+        stable_memory_reserve,
+        anchor_storage_layout.unwrap_or_default(),
+    );
+    if compress_anchor_records.unwrap_or(false) {
+        state::fixed_slot_storage_mut(|storage| storage.set_compression(true));
+    }
+    state::persistent_state_mut(|persistent_state| {
+        persistent_state.max_delegation_ttl = max_delegation_ttl;
+        persistent_state.max_signatures_to_prune = max_signatures_to_prune;
+        persistent_state.disable_registration_challenge = disable_registration_challenge;
+        persistent_state.registration_rate_limit =
+            registration_rate_limit.map(|config| TokenBucket::new(config, time()));
+        persistent_state.delegation_rate_limit =
+            delegation_rate_limit.map(|config| TokenBucket::new(config, time()));
+        persistent_state.archive_config = archive_config;
+    });
     update_root_hash();
 }
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    // the archive entries not pushed yet survive the upgrade in the persistent state
+    let archive_entries = state::archive_buffer_mut(|buffer| buffer.take_entries());
+    state::persistent_state_mut(|persistent_state| {
+        persistent_state.archive_entries = Some(archive_entries);
+    });
+    state::save_persistent_state();
+}
+
+/// Restores the state saved in [pre_upgrade]. Of the optional argument, the maximum delegation time
+/// to live, the rate limits and the archive config are applied (if set), the other fields only take
+/// effect on install. A changed rate limit keeps the tokens left in its bucket. The module hash of
+/// the archive is checked again before the next push, since the archive buffer starts afresh.
+#[post_upgrade]
+fn post_upgrade(maybe_arg: Option<InternetIdentityInit>) {
+    state::initialize_from_stable_memory();
+    state::load_persistent_state();
+    if let Some(arg) = maybe_arg {
+        trap_if_max_delegation_ttl_too_large(arg.max_delegation_ttl);
+        state::persistent_state_mut(|persistent_state| {
+            if let Some(max_delegation_ttl) = arg.max_delegation_ttl {
+                persistent_state.max_delegation_ttl = Some(max_delegation_ttl);
+            }
+            if let Some(config) = arg.registration_rate_limit {
+                apply_rate_limit(&mut persistent_state.registration_rate_limit, config);
+            }
+            if let Some(config) = arg.delegation_rate_limit {
+                apply_rate_limit(&mut persistent_state.delegation_rate_limit, config);
+            }
+            if let Some(archive_config) = arg.archive_config {
+                persistent_state.archive_config = Some(archive_config);
+            }
+        });
+    }
+    let archive_entries = state::persistent_state_mut(|persistent_state| {
+        persistent_state.archive_entries.take().unwrap_or_default()
+    });
+    state::archive_buffer_and_storage_mut(|buffer, storage| {
+        buffer.restore(storage, archive_entries)
+    })
+    .unwrap_or_else(|err| trap(&format!("failed to restore archive entries: {}", err)));
+    update_root_hash();
+}
+
+/// Pushes the entries of the audit log to the archive (see [archive]), prunes expired revocations
+/// and rebuilds the credential index if needed.
+#[heartbeat]
+fn heartbeat() {
+    archive::push_entries();
+    delegation::prune_expired_revocations();
+    state::credential_index_and_storage_mut(|index, storage| {
+        index.rebuild_batch(storage, CREDENTIAL_INDEX_REBUILD_BATCH)
+    });
+}
@@ -0,0 +1,351 @@
+//! Metrics of the canister, available as candid from the `stats` query and in the Prometheus text
+//! format (encoded with the `ic-metrics-encoder` crate) from `/metrics`, with the main ones as JSON
+//! from `/status`. The HTTP responses are certified, see [crate::assets].
+//!
+//! Anchor delegations are counted by frontend hostname, which is chosen by the callers. To keep
+//! the metrics small, only the first [MAX_FRONTEND_LABELS] frontends (with hostnames of at most
+//! [MAX_FRONTEND_LABEL_LENGTH] bytes) get their own counter, all others are counted as
+//! [OTHER_FRONTENDS_LABEL].
+
+use ic_metrics_encoder::MetricsEncoder;
+
+use crate::state::{self, AnchorStorage, UsageMetrics};
+use crate::types::{
+    AnchorStorageLayout, ArchiveInfo, FrontendHostname, InternetIdentityStats, MigrationState,
+    Timestamp,
+};
+
+/// Maximum number of frontends anchor delegations are counted for separately.
+pub const MAX_FRONTEND_LABELS: usize = 32;
+/// Maximum length of a frontend hostname anchor delegations are counted for separately.
+pub const MAX_FRONTEND_LABEL_LENGTH: usize = 100;
+/// Frontend label of the anchor delegations of all other frontends.
+pub const OTHER_FRONTENDS_LABEL: &str = "other";
+
+#[cfg(target_arch = "wasm32")]
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// Collects the current metrics.
+pub fn collect() -> InternetIdentityStats {
+    let (canister_creation_cycles_cost, archive_info) =
+        state::persistent_state(|persistent_state| {
+            (
+                persistent_state.canister_creation_cycles_cost,
+                ArchiveInfo {
+                    archive_canister: persistent_state
+                        .archive_config
+                        .as_ref()
+                        .map(|config| config.archive_canister),
+                    expected_wasm_hash: persistent_state
+                        .archive_config
+                        .as_ref()
+                        .map(|config| config.expected_module_hash),
+                },
+            )
+        });
+    let signature_map_size = state::signature_map(|sigs| sigs.len() as u64);
+    let (delegations_prepared, anchor_delegations_by_frontend) = state::usage_metrics(|metrics| {
+        (
+            metrics.delegation_counter,
+            metrics
+                .anchor_delegations_by_frontend
+                .iter()
+                .map(|(frontend, count)| (frontend.clone(), *count))
+                .collect(),
+        )
+    });
+    let buffered_archive_entries = state::archive_buffer(|buffer| buffer.len() as u64);
+    let layout_stats = state::anchor_storage(layout_stats);
+    state::storage(|storage| InternetIdentityStats {
+        assigned_user_number_range: storage.assigned_user_number_range(),
+        users_registered: storage.user_count() as u64,
+        archive_info,
+        canister_creation_cycles_cost,
+        storage_layout_version: layout_stats.version,
+        layout_migration_state: layout_stats.migration_state,
+        max_entry_size: layout_stats.max_entry_size,
+        signature_map_size,
+        stable_memory_pages: layout_stats.stable_memory_pages,
+        heap_memory_bytes: heap_memory_bytes(),
+        last_upgrade_timestamp: state::last_upgrade_timestamp(),
+        delegations_prepared,
+        anchor_delegations_by_frontend,
+        buffered_archive_entries,
+        live_anchors: (storage.user_count() - storage.deleted_count()) as u64,
+        deleted_anchors: storage.deleted_count() as u64,
+        reencoding_freed_bytes: layout_stats.reencoding_freed_bytes,
+        available_reserve_bytes: layout_stats.available_reserve_bytes,
+        anchor_storage_layout: layout_stats.layout,
+    })
+}
+
+/// Metrics that depend on the layout of the anchor records.
+struct LayoutStats {
+    layout: AnchorStorageLayout,
+    version: u8,
+    migration_state: Option<MigrationState>,
+    max_entry_size: u16,
+    stable_memory_pages: u64,
+    reencoding_freed_bytes: u64,
+    available_reserve_bytes: u64,
+}
+
+fn layout_stats(storage: &AnchorStorage) -> LayoutStats {
+    match storage {
+        AnchorStorage::FixedSlots(storage) => LayoutStats {
+            layout: AnchorStorageLayout::FixedSlots,
+            version: storage.version(),
+            migration_state: Some(storage.layout_migration_state()),
+            max_entry_size: storage.max_entry_size(),
+            stable_memory_pages: storage.memory_stats().total_allocated_pages,
+            reencoding_freed_bytes: storage.reencoding_freed_bytes(),
+            available_reserve_bytes: storage.available_reserve(),
+        },
+        // records are never re-encoded and there is no stable memory reserve
+        AnchorStorage::Map(storage) => LayoutStats {
+            layout: AnchorStorageLayout::Map,
+            version: storage.version(),
+            migration_state: None,
+            max_entry_size: storage.max_record_size().min(u16::MAX as u32) as u16,
+            stable_memory_pages: storage.total_allocated_pages(),
+            reencoding_freed_bytes: 0,
+            available_reserve_bytes: 0,
+        },
+    }
+}
+
+/// Counts an anchor delegation prepared for the given frontend.
+pub fn count_anchor_delegation(metrics: &mut UsageMetrics, frontend: &FrontendHostname) {
+    let counters = &mut metrics.anchor_delegations_by_frontend;
+    let label = if counters.contains_key(frontend)
+        || (counters.len() < MAX_FRONTEND_LABELS && frontend.len() <= MAX_FRONTEND_LABEL_LENGTH)
+    {
+        frontend.as_str()
+    } else {
+        OTHER_FRONTENDS_LABEL
+    };
+    *counters.entry(label.to_string()).or_insert(0) += 1;
+}
+
+/// Encodes the metrics in the Prometheus text exposition format, with timestamp `now`.
+pub fn encode(stats: &InternetIdentityStats, now: Timestamp) -> Vec<u8> {
+    let mut encoder = MetricsEncoder::new(Vec::with_capacity(1024), (now / 1_000_000) as i64);
+    encode_metrics(&mut encoder, stats).expect("writing to a vec cannot fail");
+    encoder.into_inner()
+}
+
+/// Encodes the main metrics as the JSON object served at `/status`.
+pub fn encode_status(stats: &InternetIdentityStats) -> Vec<u8> {
+    format!(
+        "{{\"assigned_user_number_range\":[{},{}],\"users_registered\":{},\"storage_layout_version\":{},\"signature_map_size\":{},\"last_upgrade_timestamp_ns\":{}}}",
+        stats.assigned_user_number_range.0,
+        stats.assigned_user_number_range.1,
+        stats.users_registered,
+        stats.storage_layout_version,
+        stats.signature_map_size,
+        stats.last_upgrade_timestamp
+    )
+    .into_bytes()
+}
+
+fn encode_metrics(
+    w: &mut MetricsEncoder<Vec<u8>>,
+    stats: &InternetIdentityStats,
+) -> std::io::Result<()> {
+    w.encode_gauge(
+        "users_registered",
+        stats.users_registered as f64,
+        "Number of registered anchors, including the deleted ones.",
+    )?;
+    w.encode_gauge(
+        "live_anchors",
+        stats.live_anchors as f64,
+        "Number of registered anchors that have not been deleted.",
+    )?;
+    w.encode_gauge(
+        "deleted_anchors",
+        stats.deleted_anchors as f64,
+        "Number of deleted anchors.",
+    )?;
+    w.encode_gauge(
+        "storage_layout_version",
+        stats.storage_layout_version as f64,
+        "Version of the stable memory layout.",
+    )?;
+    w.encode_gauge(
+        "stable_memory_pages",
+        stats.stable_memory_pages as f64,
+        "Number of allocated stable memory pages of 64 KiB.",
+    )?;
+    w.encode_gauge(
+        "heap_memory_bytes",
+        stats.heap_memory_bytes as f64,
+        "Size of the heap memory in bytes.",
+    )?;
+    w.encode_gauge(
+        "signature_map_size",
+        stats.signature_map_size as f64,
+        "Number of signatures in the signature map.",
+    )?;
+    w.encode_gauge(
+        "last_upgrade_timestamp_ns",
+        stats.last_upgrade_timestamp as f64,
+        "Time of the last upgrade in nanoseconds since the epoch.",
+    )?;
+    w.encode_gauge(
+        "available_reserve_bytes",
+        stats.available_reserve_bytes as f64,
+        "Number of bytes of the stable memory reserve below the stable memory limit.",
+    )?;
+    w.encode_gauge(
+        "buffered_archive_entries",
+        stats.buffered_archive_entries as f64,
+        "Number of audit log entries waiting to be pushed to the archive.",
+    )?;
+    w.encode_counter(
+        "delegations_prepared",
+        stats.delegations_prepared as f64,
+        "Number of delegations prepared since the last upgrade.",
+    )?;
+    w.encode_counter(
+        "reencoding_freed_bytes",
+        stats.reencoding_freed_bytes as f64,
+        "Number of entry bytes freed by re-encoding anchors since the last upgrade.",
+    )?;
+    let mut anchor_delegations = w.counter_vec(
+        "anchor_delegations_prepared",
+        "Number of anchor delegations prepared since the last upgrade by frontend.",
+    )?;
+    for (frontend, count) in &stats.anchor_delegations_by_frontend {
+        anchor_delegations = anchor_delegations.value(&[("frontend", frontend)], *count as f64)?;
+    }
+    Ok(())
+}
+
+fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const NOW: Timestamp = 1_650_000_000_123_000_000;
+
+    /// Parses the samples of a Prometheus text body, checking that every metric has a type and
+    /// every sample the timestamp [NOW].
+    fn parse_samples(body: &[u8]) -> HashMap<String, f64> {
+        let mut types = HashMap::new();
+        let mut samples = HashMap::new();
+        for line in std::str::from_utf8(body).unwrap().lines() {
+            if let Some(declaration) = line.strip_prefix("# TYPE ") {
+                let (name, metric_type) = declaration.split_once(' ').unwrap();
+                types.insert(name.to_string(), metric_type.to_string());
+            } else if !line.starts_with('#') {
+                let (sample, timestamp) = line.rsplit_once(' ').unwrap();
+                let (sample, value) = sample.rsplit_once(' ').unwrap();
+                let name = sample.split('{').next().unwrap();
+                assert!(types.contains_key(name), "missing type of {}", name);
+                assert_eq!(timestamp, (NOW / 1_000_000).to_string());
+                samples.insert(sample.to_string(), value.parse().unwrap());
+            }
+        }
+        samples
+    }
+
+    fn stats() -> InternetIdentityStats {
+        InternetIdentityStats {
+            assigned_user_number_range: (10_000, 20_000),
+            users_registered: 42,
+            archive_info: ArchiveInfo {
+                archive_canister: None,
+                expected_wasm_hash: None,
+            },
+            canister_creation_cycles_cost: 0,
+            storage_layout_version: 11,
+            layout_migration_state: None,
+            max_entry_size: 4096,
+            signature_map_size: 7,
+            stable_memory_pages: 3,
+            heap_memory_bytes: 1 << 20,
+            last_upgrade_timestamp: 1_620_328_630_192_441_513,
+            delegations_prepared: 12,
+            anchor_delegations_by_frontend: vec![
+                ("app.example.com".to_string(), 10),
+                ("evil\"}\n".to_string(), 2),
+            ],
+            buffered_archive_entries: 5,
+            live_anchors: 40,
+            deleted_anchors: 2,
+            reencoding_freed_bytes: 1_024,
+            available_reserve_bytes: 800,
+            anchor_storage_layout: AnchorStorageLayout::FixedSlots,
+        }
+    }
+
+    #[test]
+    fn should_encode_metrics() {
+        let samples = parse_samples(&encode(&stats(), NOW));
+        let expected = [
+            ("users_registered", 42u64),
+            ("live_anchors", 40),
+            ("deleted_anchors", 2),
+            ("storage_layout_version", 11),
+            ("stable_memory_pages", 3),
+            ("heap_memory_bytes", 1 << 20),
+            ("signature_map_size", 7),
+            ("last_upgrade_timestamp_ns", 1_620_328_630_192_441_513),
+            ("available_reserve_bytes", 800),
+            ("buffered_archive_entries", 5),
+            ("delegations_prepared", 12),
+            ("reencoding_freed_bytes", 1_024),
+            (
+                "anchor_delegations_prepared{frontend=\"app.example.com\"}",
+                10,
+            ),
+            ("anchor_delegations_prepared{frontend=\"evil\\\"}\\n\"}", 2),
+        ];
+        assert_eq!(
+            samples,
+            expected
+                .iter()
+                .map(|(sample, value)| (sample.to_string(), *value as f64))
+                .collect()
+        );
+    }
+
+    #[test]
+    fn should_cap_frontend_labels() {
+        let mut metrics = UsageMetrics::default();
+        count_anchor_delegation(&mut metrics, &"a".repeat(MAX_FRONTEND_LABEL_LENGTH + 1));
+        for i in 0..2 * MAX_FRONTEND_LABELS {
+            count_anchor_delegation(&mut metrics, &format!("frontend{}.example.com", i));
+        }
+        count_anchor_delegation(&mut metrics, &"frontend0.example.com".to_string());
+
+        let counters = &metrics.anchor_delegations_by_frontend;
+        assert_eq!(counters.len(), MAX_FRONTEND_LABELS);
+        assert_eq!(counters["frontend0.example.com"], 2);
+        assert_eq!(
+            counters[OTHER_FRONTENDS_LABEL],
+            MAX_FRONTEND_LABELS as u64 + 2
+        );
+    }
+
+    #[test]
+    fn should_encode_status() {
+        assert_eq!(
+            std::str::from_utf8(&encode_status(&stats())).unwrap(),
+            "{\"assigned_user_number_range\":[10000,20000],\"users_registered\":42,\"storage_layout_version\":11,\"signature_map_size\":7,\"last_upgrade_timestamp_ns\":1620328630192441513}"
+        );
+    }
+}
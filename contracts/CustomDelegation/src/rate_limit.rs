@@ -0,0 +1,213 @@
+//! Token buckets limiting the rate of calls that are expensive for the canister, such as
+//! registrations, so that a single client cannot burn its cycles.
+//!
+//! A bucket holds up to `max_tokens` tokens and every call takes one. A token is added every
+//! `time_per_token_ns` nanoseconds, so bursts of `max_tokens` calls are possible while the
+//! sustained rate is one call per `time_per_token_ns`.
+
+use candid::{CandidType, Deserialize};
+
+use crate::types::{RateLimitConfig, Timestamp};
+
+/// A rate limit and the tokens currently left, kept in the persistent state so that an upgrade
+/// does not refill the bucket.
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub struct TokenBucket {
+    pub config: RateLimitConfig,
+    pub tokens: u64,
+    // time the last token was added, or the bucket was last seen full
+    pub last_refill: Timestamp,
+}
+
+/// The bucket is empty, the next token is added in `retry_after_ns` nanoseconds.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RateLimitExceeded {
+    pub retry_after_ns: u64,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(config: RateLimitConfig, now: Timestamp) -> Self {
+        Self {
+            tokens: config.max_tokens,
+            config,
+            last_refill: now,
+        }
+    }
+
+    /// Takes a token, adding the tokens accrued since the last call first.
+    pub fn try_take(&mut self, now: Timestamp) -> Result<(), RateLimitExceeded> {
+        self.check(now)?;
+        self.take();
+        Ok(())
+    }
+
+    /// Takes a token left according to a preceding [TokenBucket::check].
+    pub fn take(&mut self) {
+        self.tokens = self.tokens.saturating_sub(1);
+    }
+
+    /// Checks that a token is left without taking it, adding the tokens accrued since the last call
+    /// first.
+    pub fn check(&mut self, now: Timestamp) -> Result<(), RateLimitExceeded> {
+        self.refill(now);
+        if self.tokens == 0 {
+            return Err(RateLimitExceeded {
+                retry_after_ns: self
+                    .last_refill
+                    .saturating_add(self.config.time_per_token_ns)
+                    .saturating_sub(now),
+            });
+        }
+        Ok(())
+    }
+
+    /// Replaces the rate limit, keeping the tokens left up to the new `max_tokens`.
+    pub fn reconfigure(&mut self, config: RateLimitConfig, now: Timestamp) {
+        self.refill(now);
+        self.tokens = self.tokens.min(config.max_tokens);
+        self.config = config;
+    }
+
+    fn refill(&mut self, now: Timestamp) {
+        if self.tokens >= self.config.max_tokens || self.config.time_per_token_ns == 0 {
+            self.tokens = self.config.max_tokens;
+            self.last_refill = now;
+            return;
+        }
+        let new_tokens = now.saturating_sub(self.last_refill) / self.config.time_per_token_ns;
+        if new_tokens >= self.config.max_tokens - self.tokens {
+            self.tokens = self.config.max_tokens;
+            self.last_refill = now;
+        } else {
+            // keep the time elapsed since the last token, so that it counts towards the next one
+            self.tokens += new_tokens;
+            self.last_refill += new_tokens * self.config.time_per_token_ns;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIME_PER_TOKEN: u64 = 1_000;
+
+    fn bucket(max_tokens: u64) -> TokenBucket {
+        TokenBucket::new(
+            RateLimitConfig {
+                max_tokens,
+                time_per_token_ns: TIME_PER_TOKEN,
+            },
+            0,
+        )
+    }
+
+    #[test]
+    fn should_allow_bursts_up_to_max_tokens() {
+        let mut bucket = bucket(3);
+        for _ in 0..3 {
+            assert_eq!(bucket.try_take(10), Ok(()));
+        }
+        assert_eq!(
+            bucket.try_take(10),
+            Err(RateLimitExceeded {
+                retry_after_ns: TIME_PER_TOKEN
+            })
+        );
+    }
+
+    #[test]
+    fn should_refill_over_time() {
+        let mut bucket = bucket(2);
+        bucket.try_take(0).unwrap();
+        bucket.try_take(0).unwrap();
+        assert_eq!(
+            bucket.try_take(400),
+            Err(RateLimitExceeded {
+                retry_after_ns: 600
+            })
+        );
+
+        assert_eq!(bucket.try_take(TIME_PER_TOKEN), Ok(()));
+        assert!(bucket.try_take(TIME_PER_TOKEN).is_err());
+
+        // the time elapsed since the last token counts towards the next one
+        assert_eq!(bucket.try_take(2 * TIME_PER_TOKEN + 500), Ok(()));
+        assert_eq!(
+            bucket.try_take(2 * TIME_PER_TOKEN + 500),
+            Err(RateLimitExceeded {
+                retry_after_ns: 500
+            })
+        );
+        assert_eq!(bucket.try_take(3 * TIME_PER_TOKEN), Ok(()));
+    }
+
+    #[test]
+    fn should_not_refill_beyond_max_tokens() {
+        let mut bucket = bucket(2);
+        bucket.try_take(0).unwrap();
+
+        let now = 100 * TIME_PER_TOKEN;
+        assert_eq!(bucket.try_take(now), Ok(()));
+        assert_eq!(bucket.try_take(now), Ok(()));
+        assert_eq!(
+            bucket.try_take(now),
+            Err(RateLimitExceeded {
+                retry_after_ns: TIME_PER_TOKEN
+            })
+        );
+    }
+
+    #[test]
+    fn should_keep_tokens_left_when_reconfigured() {
+        let mut bucket = bucket(3);
+        bucket.try_take(0).unwrap();
+        bucket.try_take(0).unwrap();
+
+        bucket.reconfigure(
+            RateLimitConfig {
+                max_tokens: 5,
+                time_per_token_ns: 2 * TIME_PER_TOKEN,
+            },
+            0,
+        );
+        assert_eq!(bucket.try_take(0), Ok(()));
+        assert_eq!(
+            bucket.try_take(0),
+            Err(RateLimitExceeded {
+                retry_after_ns: 2 * TIME_PER_TOKEN
+            })
+        );
+
+        bucket.reconfigure(
+            RateLimitConfig {
+                max_tokens: 1,
+                time_per_token_ns: TIME_PER_TOKEN,
+            },
+            100 * TIME_PER_TOKEN,
+        );
+        assert_eq!(bucket.tokens, 1);
+    }
+
+    #[test]
+    fn should_not_take_token_when_checking() {
+        let mut bucket = bucket(1);
+        assert_eq!(bucket.check(0), Ok(()));
+        assert_eq!(bucket.check(0), Ok(()));
+        bucket.take();
+        assert_eq!(
+            bucket.check(0),
+            Err(RateLimitExceeded {
+                retry_after_ns: TIME_PER_TOKEN
+            })
+        );
+    }
+
+    #[test]
+    fn should_reject_all_calls_without_tokens() {
+        let mut bucket = bucket(0);
+        assert!(bucket.try_take(0).is_err());
+        assert!(bucket.try_take(100 * TIME_PER_TOKEN).is_err());
+    }
+}
@@ -1,20 +1,39 @@
 use std::cell::{Cell, RefCell};
 use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::future::Future;
 
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::{call, caller, trap};
 use ic_cdk::api::time;
-use ic_certified_map::{Hash, RbTree};
-use ic_stable_structures::DefaultMemoryImpl;
+use ic_certified_map::RbTree;
+use ic_stable_structures::{DefaultMemoryImpl, Memory};
+use ic_stable_structures::memory_manager::VirtualMemory;
 use regex::internal::Input;
 
+use crate::archive::ArchiveBuffer;
+use crate::assets::ResponseHashes;
+use crate::challenge::Challenges;
 use crate::deps::http::HeaderField;
 use crate::deps::signature_map::SignatureMap;
-use crate::storage::{DEFAULT_RANGE_SIZE, Salt, Storage};
-use crate::types::{Timestamp, UserNumber};
-
-pub type Assets = HashMap<&'static str, (Vec<HeaderField>, &'static [u8])>;
-pub type AssetHashes = RbTree<&'static str, Hash>;
+use crate::device_registration::DeviceRegistrations;
+use crate::rate_limit::TokenBucket;
+use crate::storage::{
+    DEFAULT_ENTRY_SIZE, DEFAULT_RANGE_SIZE, HeaderError, LegacyRevocation, PersistentStateError,
+    Region, Salt, Storage, StorageBuilder, max_range_size, max_range_size_with_reserve,
+};
+use crate::storage::credential_index::{CredentialIndex, IndexedStorage};
+use crate::storage::record_storage::{MapStorage, RecordStorage};
+use crate::storage::revocations::{RevocationKey, RevocationList};
+use crate::temp_keys::TempKeys;
+use crate::types::{
+    AnchorStorageLayout, ArchiveConfig, ArchiveEntry, FrontendHostname, Timestamp, UserNumber,
+};
+
+// certified HTTP responses by path, see crate::assets
+pub type Assets = HashMap<&'static str, (Vec<HeaderField>, Vec<u8>)>;
+pub type AssetHashes = RbTree<&'static str, ResponseHashes>;
+
+const FIRST_USER_ID: UserNumber = 10_000;
 
 thread_local! {
     static STATE: State = State::default();
@@ -25,17 +44,166 @@ thread_local! {
 pub struct UsageMetrics {
     // number of prepare_delegation calls since last upgrade
     pub delegation_counter: u64,
+    // number of prepare_anchor_delegation calls since last upgrade by frontend, bounded by
+    // crate::metrics::MAX_FRONTEND_LABELS
+    pub anchor_delegations_by_frontend: BTreeMap<FrontendHostname, u64>,
 }
 
+// Fields added after the first persistent state version are optional, so that the states written
+// by earlier versions decode into this struct. Only changes that cannot be decoded that way require
+// a new persistent state version.
 #[derive(Clone, Default, CandidType, Deserialize, Eq, PartialEq, Debug)]
 pub struct PersistentState {
     // Information related to the archive
     // Amount of cycles that need to be attached when II creates a canister
     pub canister_creation_cycles_cost: u64,
+    // Maximum time to live of delegations in nanoseconds, 30 days if not set
+    pub max_delegation_ttl: Option<u64>,
+    // Maximum number of expired signatures pruned per delegation, 10 if not set
+    pub max_signatures_to_prune: Option<u64>,
+    // Derivation origins that may be mixed into the seeds of anchor delegations
+    pub derivation_origins: Option<Vec<String>>,
+    // Whether anchors can be registered without solving a challenge (e.g. in test environments)
+    pub disable_registration_challenge: Option<bool>,
+    // Rate limit of registrations, unlimited if not set
+    pub registration_rate_limit: Option<TokenBucket>,
+    // Rate limit of delegation preparations, unlimited if not set
+    pub delegation_rate_limit: Option<TokenBucket>,
+    // Archive canister the audit log is pushed to, no audit log is kept if not set
+    pub archive_config: Option<ArchiveConfig>,
+    // Sequence number of the next archive entry
+    pub archive_sequence_number: Option<u64>,
+    // Archive entries buffered in memory when the canister was upgraded, see [crate::archive]
+    pub archive_entries: Option<Vec<ArchiveEntry>>,
+    // Anchors scheduled for deletion by a controller with the time the deletion becomes allowed,
+    // see [crate::anchor_management::ANCHOR_DELETION_GRACE_PERIOD_NS]
+    pub scheduled_anchor_deletions: Option<Vec<(UserNumber, Timestamp)>>,
+    // Maximum time to live of delegations for specific frontends in nanoseconds, overriding
+    // max_delegation_ttl, see [crate::delegation::delegation_ttl]
+    pub origin_delegation_ttls: Option<Vec<(FrontendHostname, u64)>>,
+    // Revoked delegations kept here by persistent state versions 11 and 12, moved to their own
+    // memory on load (see [load_persistent_state]) and never written again
+    pub revoked_delegations: Option<Vec<LegacyRevocation>>,
+}
+
+/// Anchor records in the layout chosen at install time, see [AnchorStorageLayout].
+pub enum AnchorStorage {
+    FixedSlots(Storage<DefaultMemoryImpl>),
+    Map(MapStorage<DefaultMemoryImpl>),
+}
+
+impl AnchorStorage {
+    /// Loads the storage of either layout from `memory`, `None` if the memory is empty.
+    fn from_memory(memory: DefaultMemoryImpl) -> Option<Self> {
+        match MapStorage::from_memory(memory.clone()) {
+            Ok(storage) => storage.map(Self::Map),
+            // not a memory manager, i.e. the fixed-slot layout
+            Err(HeaderError::InvalidMagic(_)) => Storage::from_memory(memory).map(Self::FixedSlots),
+            Err(err) => trap(&err.to_string()),
+        }
+    }
+
+    fn records(&self) -> &dyn RecordStorage {
+        match self {
+            Self::FixedSlots(storage) => storage,
+            Self::Map(storage) => storage,
+        }
+    }
+
+    fn records_mut(&mut self) -> &mut dyn RecordStorage {
+        match self {
+            Self::FixedSlots(storage) => storage,
+            Self::Map(storage) => storage,
+        }
+    }
+
+    fn fixed_slots(&self) -> &Storage<DefaultMemoryImpl> {
+        match self {
+            Self::FixedSlots(storage) => storage,
+            Self::Map(_) => trap_unless_fixed_slots(),
+        }
+    }
+
+    fn fixed_slots_mut(&mut self) -> &mut Storage<DefaultMemoryImpl> {
+        match self {
+            Self::FixedSlots(storage) => storage,
+            Self::Map(_) => trap_unless_fixed_slots(),
+        }
+    }
+
+    /// Returns the tag of the region of the credential index, see [CredentialIndex::init].
+    fn credential_index_tag(&self) -> u64 {
+        // the regions of either layout never move
+        0
+    }
+
+    fn credential_index_memory(&self) -> RegionMemory {
+        match self {
+            Self::FixedSlots(storage) => {
+                RegionMemory::FixedSlots(storage.credential_index_memory())
+            }
+            Self::Map(storage) => RegionMemory::Map(storage.credential_index_memory()),
+        }
+    }
+
+    /// Returns the tag of the region of the revoked delegations, see [RevocationList::init].
+    fn revocations_tag(&self) -> u64 {
+        // the regions of either layout never move
+        0
+    }
+
+    fn revocations_memory(&self) -> RegionMemory {
+        match self {
+            Self::FixedSlots(storage) => RegionMemory::FixedSlots(storage.revocations_memory()),
+            Self::Map(storage) => RegionMemory::Map(storage.revocations_memory()),
+        }
+    }
+}
+
+fn trap_unless_fixed_slots() -> ! {
+    trap("not supported by the map layout of the anchor records")
+}
+
+/// Region of the credential index or the revoked delegations, which depends on the layout of the
+/// anchor records.
+#[derive(Clone)]
+pub enum RegionMemory {
+    FixedSlots(Region<DefaultMemoryImpl>),
+    Map(VirtualMemory<DefaultMemoryImpl>),
+}
+
+impl Memory for RegionMemory {
+    fn size(&self) -> u64 {
+        match self {
+            Self::FixedSlots(memory) => memory.size(),
+            Self::Map(memory) => memory.size(),
+        }
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        match self {
+            Self::FixedSlots(memory) => memory.grow(pages),
+            Self::Map(memory) => memory.grow(pages),
+        }
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        match self {
+            Self::FixedSlots(memory) => memory.read(offset, dst),
+            Self::Map(memory) => memory.read(offset, dst),
+        }
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        match self {
+            Self::FixedSlots(memory) => memory.write(offset, src),
+            Self::Map(memory) => memory.write(offset, src),
+        }
+    }
 }
 
 struct State {
-    storage: RefCell<Storage<DefaultMemoryImpl>>,
+    storage: RefCell<AnchorStorage>,
     sigs: RefCell<SignatureMap>,
     asset_hashes: RefCell<AssetHashes>,
     last_upgrade_timestamp: Cell<Timestamp>,
@@ -49,21 +217,37 @@ struct State {
     // Cache of the archive status (to make unwanted calls to deploy_archive cheap to dismiss).
     admins: RefCell<BTreeSet<Principal>>,
     authed_public_key: RefCell<BTreeMap<String, Vec<u8>>>,
+    // registration challenges waiting for a solution, NOT persisted across upgrades
+    challenges: RefCell<Challenges>,
+    // temp keys standing in for devices, NOT persisted across upgrades
+    temp_keys: RefCell<TempKeys>,
+    // anchors in device registration mode, NOT persisted across upgrades
+    device_registrations: RefCell<DeviceRegistrations>,
+    // audit log entries waiting to be pushed to the archive, carried in the persistent state across
+    // upgrades
+    archive_buffer: RefCell<ArchiveBuffer>,
+    // index from credential IDs to anchors in stable memory, loaded on first use, see
+    // [credential_index_and_storage_mut]
+    credential_index: RefCell<Option<CredentialIndex<RegionMemory>>>,
+    // revoked delegations, loaded from their region on first use, see [revocations_mut]
+    revocations: RefCell<Option<RevocationList<RegionMemory>>>,
 }
 
 impl Default for State {
     fn default() -> Self {
-        const FIRST_USER_ID: UserNumber = 10_000;
         Self {
-            storage: RefCell::new(Storage::new(
-                (
-                    FIRST_USER_ID,
-                    FIRST_USER_ID.saturating_add(DEFAULT_RANGE_SIZE),
-                ),
-                DefaultMemoryImpl::default(),
+            storage: RefCell::new(AnchorStorage::FixedSlots(
+                Storage::new(
+                    (
+                        FIRST_USER_ID,
+                        FIRST_USER_ID.saturating_add(DEFAULT_RANGE_SIZE),
+                    ),
+                    DefaultMemoryImpl::default(),
+                )
+                .unwrap_or_else(|err| trap(&err.to_string())),
             )),
             sigs: RefCell::new(SignatureMap::default()),
-            asset_hashes: RefCell::new(AssetHashes::default()),
+            asset_hashes: RefCell::new(AssetHashes::new()),
             last_upgrade_timestamp: Cell::new(0),
             usage_metrics: RefCell::new(UsageMetrics::default()),
             persistent_state: RefCell::new(PersistentState::default()),
@@ -72,35 +256,65 @@ impl Default for State {
             )
             .unwrap()])),
             authed_public_key: RefCell::new(BTreeMap::new()),
+            challenges: RefCell::new(Challenges::default()),
+            temp_keys: RefCell::new(TempKeys::default()),
+            device_registrations: RefCell::new(DeviceRegistrations::default()),
+            archive_buffer: RefCell::new(ArchiveBuffer::default()),
+            credential_index: RefCell::new(None),
+            revocations: RefCell::new(None),
         }
     }
 }
 
 // Checks if salt is empty and calls `init_salt` to set it.
 pub async fn ensure_salt_set() {
-    let salt = STATE.with(|s| s.storage.borrow().salt().cloned());
+    ensure_salt_set_with(raw_rand).await
+}
+
+/// Like [ensure_salt_set], but gets the randomness from `raw_rand`.
+async fn ensure_salt_set_with<F: Future<Output = Result<Vec<u8>, String>>>(
+    raw_rand: impl FnOnce() -> F,
+) {
+    let salt = STATE.with(|s| s.storage.borrow().records().salt().cloned());
     if salt.is_none() {
-        init_salt().await;
+        init_salt(raw_rand).await;
     }
 
     STATE.with(|s| {
-        if s.storage.borrow().salt().is_none() {
+        if s.storage.borrow().records().salt().is_none() {
             trap("Salt is not set. Try calling init_salt() to set it");
         }
     });
 }
 
-pub async fn init_salt() {
+/// Sets the salt to the randomness returned by `raw_rand`.
+async fn init_salt<F: Future<Output = Result<Vec<u8>, String>>>(raw_rand: impl FnOnce() -> F) {
     STATE.with(|s| {
-        if s.storage.borrow().salt().is_some() {
+        if s.storage.borrow().records().salt().is_some() {
             trap("Salt already set");
         }
     });
 
-    let res: Vec<u8> = match call(Principal::management_canister(), "raw_rand", ()).await {
-        Ok((res,)) => res,
-        Err((_, err)) => trap(&format!("failed to get salt: {}", err)),
-    };
+    // a failed call traps, so the salt is requested again on the next call
+    let res = raw_rand()
+        .await
+        .unwrap_or_else(|err| trap(&format!("failed to get salt: {}", err)));
+    set_salt_from_randomness(res);
+}
+
+/// Calls `raw_rand` of the management canister.
+async fn raw_rand() -> Result<Vec<u8>, String> {
+    call(Principal::management_canister(), "raw_rand", ())
+        .await
+        .map(|(res,): (Vec<u8>,)| res)
+        .map_err(|(_, err)| err)
+}
+
+/// Stores the randomness returned by `raw_rand` as the salt.
+///
+/// Concurrent calls to `init_salt` may all be waiting for `raw_rand`: only the first response is
+/// stored, so the salt never changes once set.
+fn set_salt_from_randomness(res: Vec<u8>) {
     let salt: Salt = res[..].try_into().unwrap_or_else(|_| {
         trap(&format!(
             "expected raw randomness to be of length 32, got {}",
@@ -109,8 +323,7 @@ pub async fn init_salt() {
     });
 
     STATE.with(|s| {
-        let mut store = s.storage.borrow_mut();
-        store.update_salt(salt); // update_salt() traps if salt has already been set
+        s.storage.borrow_mut().records_mut().set_salt_if_empty(salt);
     });
 }
 
@@ -131,26 +344,77 @@ pub fn check_if_public_key_authed(public_key: String) -> Option<Vec<u8>> {
 
 pub fn salt() -> [u8; 32] {
     STATE
-        .with(|s| s.storage.borrow().salt().cloned())
+        .with(|s| s.storage.borrow().records().salt().cloned())
         .unwrap_or_else(|| trap("Salt is not set. Try calling init_salt() to set it"))
 }
 
 pub fn set_salt(salt: [u8; 32]) {
     STATE.with(|s| {
-        let mut store = s.storage.borrow_mut();
-        store.update_salt(salt);
+        let mut storage = s.storage.borrow_mut();
+        storage
+            .fixed_slots_mut()
+            .update_salt(salt)
+            .unwrap_or_else(|err| trap(&err.to_string()));
     })
 }
 
+/// Initializes the storage of a freshly installed canister with the given layout. The entry size
+/// is the maximum size of the records of the map layout, the map layout has no stable memory
+/// reserve.
+///
+/// If no range is given, the largest range that fits the entry size and reserve is assigned. The
+/// range of the map layout is not limited by the entry size, but the same range is assigned.
+pub fn init_new(
+    range: Option<(UserNumber, UserNumber)>,
+    entry_size: Option<u16>,
+    reserve: Option<u64>,
+    layout: AnchorStorageLayout,
+) {
+    let entry_size = entry_size.unwrap_or(DEFAULT_ENTRY_SIZE);
+    let max_size = reserve.map_or(max_range_size(entry_size), |reserve| {
+        max_range_size_with_reserve(entry_size, reserve)
+    });
+    let (id_range_lo, id_range_hi) =
+        range.unwrap_or((FIRST_USER_ID, FIRST_USER_ID.saturating_add(max_size)));
+    let storage = match layout {
+        AnchorStorageLayout::FixedSlots => {
+            let mut builder = StorageBuilder::new()
+                .range(id_range_lo, id_range_hi)
+                .entry_size(entry_size);
+            if let Some(reserve) = reserve {
+                builder = builder.stable_memory_reserve(reserve);
+            }
+            let mut storage = builder.build().unwrap_or_else(|err| trap(&err.to_string()));
+            storage.flush();
+            AnchorStorage::FixedSlots(storage)
+        }
+        AnchorStorageLayout::Map => AnchorStorage::Map(
+            MapStorage::new(
+                (id_range_lo, id_range_hi),
+                DefaultMemoryImpl::default(),
+                entry_size as u32,
+            )
+            .unwrap_or_else(|err| trap(&err.to_string())),
+        ),
+    };
+    STATE.with(|s| {
+        s.storage.replace(storage);
+        s.credential_index.replace(None);
+        s.revocations.replace(None);
+    });
+}
+
 pub fn initialize_from_stable_memory() {
     STATE.with(|s| {
         s.last_upgrade_timestamp.set(time() as u64);
-        match Storage::from_memory(DefaultMemoryImpl::default()) {
+        match AnchorStorage::from_memory(DefaultMemoryImpl::default()) {
             Some(storage) => {
                 s.storage.replace(storage);
+                s.credential_index.replace(None);
+                s.revocations.replace(None);
             }
             None => {
-                s.storage.borrow_mut().flush();
+                s.storage.borrow_mut().fixed_slots_mut().flush();
             }
         }
     });
@@ -158,17 +422,36 @@ pub fn initialize_from_stable_memory() {
 
 pub fn save_persistent_state() {
     STATE.with(|s| {
-        s.storage
+        if let Err(err) = s
+            .storage
             .borrow_mut()
-            .write_persistent_state(&s.persistent_state.borrow());
+            .records_mut()
+            .write_persistent_state(&s.persistent_state.borrow())
+        {
+            trap(&format!("failed to save persistent state! Err: {:?}", err))
+        }
     })
 }
 
+/// Loads the persistent state saved by [save_persistent_state]. The revoked delegations kept in the
+/// persistent state by earlier versions are moved to the revocation list.
 pub fn load_persistent_state() {
     STATE.with(|s| {
         let storage = s.storage.borrow();
-        match storage.read_persistent_state() {
-            Ok(loaded_state) => *s.persistent_state.borrow_mut() = loaded_state,
+        match storage.records().read_persistent_state() {
+            Ok(mut loaded_state) => {
+                let revoked_delegations = loaded_state.revoked_delegations.take();
+                *s.persistent_state.borrow_mut() = loaded_state;
+                let mut revocations = s.revocations.borrow_mut();
+                let revocations = load_revocations(&storage, &mut revocations);
+                for (seed_hash, key_hash, expires_at) in revoked_delegations.unwrap_or_default() {
+                    revocations
+                        .insert(RevocationKey::new(seed_hash, key_hash), expires_at, time())
+                        .unwrap_or_else(|err| trap(&err));
+                }
+            }
+            // not saved by the canister this one is upgraded from
+            Err(PersistentStateError::NotFound) => {}
             Err(err) => trap(&format!(
                 "failed to recover persistent state! Err: {:?}",
                 err
@@ -194,8 +477,20 @@ pub fn assets_and_hashes_mut<R>(f: impl FnOnce(&mut Assets, &mut AssetHashes) ->
     })
 }
 
-pub fn asset_hashes_and_sigs<R>(f: impl FnOnce(&AssetHashes, &SignatureMap) -> R) -> R {
-    STATE.with(|s| f(&*s.asset_hashes.borrow(), &*s.sigs.borrow()))
+/// Calls `f` with the certified HTTP responses, the revoked delegations and the signatures, which
+/// are certified together, see [crate::delegation::root_hash].
+pub fn certified_trees<R>(
+    f: impl FnOnce(&AssetHashes, &RevocationList<RegionMemory>, &SignatureMap) -> R,
+) -> R {
+    STATE.with(|s| {
+        let storage = s.storage.borrow();
+        let mut revocations = s.revocations.borrow_mut();
+        f(
+            &s.asset_hashes.borrow(),
+            load_revocations(&storage, &mut revocations),
+            &s.sigs.borrow(),
+        )
+    })
 }
 
 pub fn signature_map<R>(f: impl FnOnce(&SignatureMap) -> R) -> R {
@@ -206,12 +501,129 @@ pub fn signature_map_mut<R>(f: impl FnOnce(&mut SignatureMap) -> R) -> R {
     STATE.with(|s| f(&mut *s.sigs.borrow_mut()))
 }
 
-pub fn storage<R>(f: impl FnOnce(&Storage<DefaultMemoryImpl>) -> R) -> R {
-    STATE.with(|s| f(&*s.storage.borrow()))
+/// Calls `f` with the anchor records of either layout.
+pub fn storage<R>(f: impl FnOnce(&dyn RecordStorage) -> R) -> R {
+    STATE.with(|s| f(s.storage.borrow().records()))
+}
+
+/// Like [storage], but for changes. Anchor writes that may change the devices should go through
+/// [indexed_storage_mut] instead.
+pub fn storage_mut<R>(f: impl FnOnce(&mut dyn RecordStorage) -> R) -> R {
+    STATE.with(|s| f(s.storage.borrow_mut().records_mut()))
 }
 
-pub fn storage_mut<R>(f: impl FnOnce(&mut Storage<DefaultMemoryImpl>) -> R) -> R {
-    STATE.with(|s| f(&mut *s.storage.borrow_mut()))
+/// Calls `f` with the storage of whichever layout the anchor records use, e.g. for metrics that
+/// depend on the layout.
+pub fn anchor_storage<R>(f: impl FnOnce(&AnchorStorage) -> R) -> R {
+    STATE.with(|s| f(&s.storage.borrow()))
+}
+
+/// Calls `f` with the storage of the fixed-slot layout, for the features only it supports. Traps if
+/// the anchor records use the map layout.
+pub fn fixed_slot_storage<R>(f: impl FnOnce(&Storage<DefaultMemoryImpl>) -> R) -> R {
+    STATE.with(|s| f(s.storage.borrow().fixed_slots()))
+}
+
+/// Like [fixed_slot_storage], but for changes.
+pub fn fixed_slot_storage_mut<R>(f: impl FnOnce(&mut Storage<DefaultMemoryImpl>) -> R) -> R {
+    STATE.with(|s| f(s.storage.borrow_mut().fixed_slots_mut()))
+}
+
+pub fn challenges_mut<R>(f: impl FnOnce(&mut Challenges) -> R) -> R {
+    STATE.with(|s| f(&mut s.challenges.borrow_mut()))
+}
+
+pub fn temp_keys<R>(f: impl FnOnce(&TempKeys) -> R) -> R {
+    STATE.with(|s| f(&s.temp_keys.borrow()))
+}
+
+pub fn temp_keys_mut<R>(f: impl FnOnce(&mut TempKeys) -> R) -> R {
+    STATE.with(|s| f(&mut s.temp_keys.borrow_mut()))
+}
+
+pub fn device_registrations<R>(f: impl FnOnce(&DeviceRegistrations) -> R) -> R {
+    STATE.with(|s| f(&s.device_registrations.borrow()))
+}
+
+pub fn device_registrations_mut<R>(f: impl FnOnce(&mut DeviceRegistrations) -> R) -> R {
+    STATE.with(|s| f(&mut s.device_registrations.borrow_mut()))
+}
+
+pub fn archive_buffer<R>(f: impl FnOnce(&ArchiveBuffer) -> R) -> R {
+    STATE.with(|s| f(&s.archive_buffer.borrow()))
+}
+
+pub fn archive_buffer_mut<R>(f: impl FnOnce(&mut ArchiveBuffer) -> R) -> R {
+    STATE.with(|s| f(&mut s.archive_buffer.borrow_mut()))
+}
+
+pub fn archive_buffer_and_storage_mut<R>(
+    f: impl FnOnce(&mut ArchiveBuffer, &mut dyn RecordStorage) -> R,
+) -> R {
+    STATE.with(|s| {
+        f(
+            &mut s.archive_buffer.borrow_mut(),
+            s.storage.borrow_mut().records_mut(),
+        )
+    })
+}
+
+/// Calls `f` with the credential index and the storage. The index is loaded from its region on
+/// first use.
+pub fn credential_index_and_storage_mut<R>(
+    f: impl FnOnce(&mut CredentialIndex<RegionMemory>, &mut dyn RecordStorage) -> R,
+) -> R {
+    with_credential_index(|index, storage| f(index, storage.records_mut()))
+}
+
+/// Like [credential_index_and_storage_mut], but with the storage of the fixed-slot layout, see
+/// [fixed_slot_storage].
+pub fn credential_index_and_fixed_slot_storage_mut<R>(
+    f: impl FnOnce(&mut CredentialIndex<RegionMemory>, &mut Storage<DefaultMemoryImpl>) -> R,
+) -> R {
+    with_credential_index(|index, storage| f(index, storage.fixed_slots_mut()))
+}
+
+fn with_credential_index<R>(
+    f: impl FnOnce(&mut CredentialIndex<RegionMemory>, &mut AnchorStorage) -> R,
+) -> R {
+    STATE.with(|s| {
+        let mut storage = s.storage.borrow_mut();
+        let mut index = s.credential_index.borrow_mut();
+        let index = index.get_or_insert_with(|| {
+            CredentialIndex::init(
+                storage.credential_index_memory(),
+                storage.credential_index_tag(),
+            )
+        });
+        f(index, &mut storage)
+    })
+}
+
+/// Like [storage_mut], but keeps the credential index up to date with the anchors written, see
+/// [IndexedStorage].
+pub fn indexed_storage_mut<R>(
+    f: impl FnOnce(&mut IndexedStorage<'_, dyn RecordStorage + '_, RegionMemory>) -> R,
+) -> R {
+    credential_index_and_storage_mut(|index, storage| f(&mut IndexedStorage::new(storage, index)))
+}
+
+/// Calls `f` with the revoked delegations, see [crate::delegation::revoke_delegation].
+pub fn revocations_mut<R>(f: impl FnOnce(&mut RevocationList<RegionMemory>) -> R) -> R {
+    STATE.with(|s| {
+        let storage = s.storage.borrow();
+        f(load_revocations(&storage, &mut s.revocations.borrow_mut()))
+    })
+}
+
+/// Loads the revoked delegations from their region on first use.
+fn load_revocations<'a>(
+    storage: &AnchorStorage,
+    revocations: &'a mut Option<RevocationList<RegionMemory>>,
+) -> &'a mut RevocationList<RegionMemory> {
+    revocations.get_or_insert_with(|| {
+        RevocationList::init(storage.revocations_memory(), storage.revocations_tag())
+    })
 }
 
 pub fn usage_metrics<R>(f: impl FnOnce(&UsageMetrics) -> R) -> R {
@@ -233,3 +645,179 @@ pub fn persistent_state<R>(f: impl FnOnce(&PersistentState) -> R) -> R {
 pub fn persistent_state_mut<R>(f: impl FnOnce(&mut PersistentState) -> R) -> R {
     STATE.with(|s| f(&mut *s.persistent_state.borrow_mut()))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::pin::pin;
+    use std::task::Poll;
+
+    use super::*;
+    use crate::storage::record_storage::tests::{CONFORMANCE_CHECKS, RANGE};
+    use crate::testing::{block_on, poll_once, MockManagementCanister};
+
+    fn stored_salt() -> Option<Salt> {
+        STATE.with(|s| s.storage.borrow().records().salt().cloned())
+    }
+
+    #[test]
+    fn should_only_persist_first_salt() {
+        assert_eq!(stored_salt(), None);
+        let management_canister =
+            MockManagementCanister::new(vec![Ok(vec![1; 32]), Ok(vec![2; 32])]);
+
+        // two calls waiting for raw_rand at the same time
+        let mut first = pin!(ensure_salt_set_with(|| management_canister.raw_rand()));
+        let mut second = pin!(ensure_salt_set_with(|| management_canister.raw_rand()));
+        assert!(poll_once(first.as_mut()).is_pending());
+        assert!(poll_once(second.as_mut()).is_pending());
+        assert_eq!(poll_once(first.as_mut()), Poll::Ready(()));
+        assert_eq!(poll_once(second.as_mut()), Poll::Ready(()));
+
+        assert_eq!(management_canister.calls(), 2);
+        assert_eq!(salt(), [1; 32]);
+
+        // once set, the salt is neither requested nor replaced anymore
+        block_on(ensure_salt_set_with(|| management_canister.raw_rand()));
+        assert_eq!(management_canister.calls(), 2);
+        assert_eq!(salt(), [1; 32]);
+    }
+
+    #[test]
+    fn should_retry_salt_initialization_after_failed_raw_rand() {
+        let management_canister = MockManagementCanister::new(vec![
+            Err("the management canister is busy".to_string()),
+            Ok(vec![3; 32]),
+        ]);
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            block_on(ensure_salt_set_with(|| management_canister.raw_rand()))
+        }));
+        assert!(result.is_err());
+        assert_eq!(stored_salt(), None);
+
+        block_on(ensure_salt_set_with(|| management_canister.raw_rand()));
+        assert_eq!(management_canister.calls(), 2);
+        assert_eq!(salt(), [3; 32]);
+    }
+
+    #[test]
+    fn canister_storage_should_conform_with_either_layout() {
+        for layout in [AnchorStorageLayout::FixedSlots, AnchorStorageLayout::Map] {
+            for check in CONFORMANCE_CHECKS {
+                init_new(Some(RANGE), Some(4096), None, layout);
+                storage_mut(check);
+                init_new(Some(RANGE), Some(4096), None, layout);
+                indexed_storage_mut(|storage| check(storage));
+            }
+        }
+    }
+
+    #[test]
+    fn should_load_anchor_storage_of_either_layout() {
+        assert!(AnchorStorage::from_memory(DefaultMemoryImpl::default()).is_none());
+
+        let memory = DefaultMemoryImpl::default();
+        Storage::new(RANGE, memory.clone())
+            .unwrap()
+            .set_salt_if_empty([1; 32]);
+        let storage = AnchorStorage::from_memory(memory).unwrap();
+        assert!(matches!(storage, AnchorStorage::FixedSlots(_)));
+        assert_eq!(storage.records().salt(), Some(&[1; 32]));
+
+        let memory = DefaultMemoryImpl::default();
+        MapStorage::new(RANGE, memory.clone(), 4096)
+            .unwrap()
+            .set_salt_if_empty([2; 32]);
+        let storage = AnchorStorage::from_memory(memory).unwrap();
+        assert!(matches!(storage, AnchorStorage::Map(_)));
+        assert_eq!(storage.records().salt(), Some(&[2; 32]));
+    }
+
+    #[test]
+    fn should_keep_stable_memory_small_after_init_and_heartbeat() {
+        init_new(None, None, None, AnchorStorageLayout::FixedSlots);
+        // the heartbeat rebuilds the credential index
+        assert!(credential_index_and_storage_mut(
+            |index, storage| index.rebuild_batch(storage, 100)
+        ));
+        // the pages of the header and the memory manager, followed by the bucket of the index
+        let pages = fixed_slot_storage(|storage| storage.memory_stats().total_allocated_pages);
+        assert_eq!(pages, 2 + 1024);
+    }
+
+    #[test]
+    fn should_assign_larger_range_with_smaller_reserve() {
+        init_new(None, None, None, AnchorStorageLayout::FixedSlots);
+        let (id_range_lo, id_range_hi) =
+            fixed_slot_storage(|storage| storage.assigned_user_number_range());
+        assert_eq!(id_range_hi - id_range_lo, DEFAULT_RANGE_SIZE);
+
+        let reserve = 100 * 1024 * 1024;
+        init_new(None, None, Some(reserve), AnchorStorageLayout::FixedSlots);
+        let (id_range_lo, id_range_hi) = fixed_slot_storage(|storage| {
+            assert_eq!(storage.stable_memory_reserve(), reserve);
+            storage.assigned_user_number_range()
+        });
+        assert!(id_range_hi - id_range_lo > DEFAULT_RANGE_SIZE);
+    }
+
+    #[test]
+    fn should_only_offer_fixed_slot_features_with_fixed_slot_layout() {
+        init_new(Some(RANGE), None, None, AnchorStorageLayout::FixedSlots);
+        fixed_slot_storage_mut(|storage| storage.set_compression(true));
+
+        init_new(Some(RANGE), None, None, AnchorStorageLayout::Map);
+        let result =
+            catch_unwind(|| fixed_slot_storage_mut(|storage| storage.set_compression(true)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn should_keep_revocations_of_either_layout() {
+        let key = RevocationKey::new([1; 32], [2; 32]);
+        for layout in [AnchorStorageLayout::FixedSlots, AnchorStorageLayout::Map] {
+            init_new(Some(RANGE), None, None, layout);
+            revocations_mut(|revocations| revocations.insert(key.clone(), 2_000, 1_000)).unwrap();
+            assert!(revocations_mut(
+                |revocations| revocations.is_revoked(&key, 1_500)
+            ));
+        }
+
+        // the region of the fixed slot layout does not move if the anchor range is extended
+        init_new(Some(RANGE), None, None, AnchorStorageLayout::FixedSlots);
+        revocations_mut(|revocations| revocations.insert(key.clone(), 2_000, 1_000)).unwrap();
+        fixed_slot_storage_mut(|storage| storage.extend_range(RANGE.1 + 1_000)).unwrap();
+        STATE.with(|s| s.revocations.replace(None));
+        assert!(revocations_mut(
+            |revocations| revocations.is_revoked(&key, 1_500)
+        ));
+    }
+
+    #[test]
+    fn should_keep_stable_memory_small_when_saving_the_persistent_state() {
+        init_new(None, None, None, AnchorStorageLayout::FixedSlots);
+        let pages = || fixed_slot_storage(|storage| storage.memory_stats().total_allocated_pages);
+        // the pages of the header and the memory manager, followed by a bucket for each slot
+        save_persistent_state();
+        assert_eq!(pages(), 2 + 1024);
+        save_persistent_state();
+        assert_eq!(pages(), 2 + 2 * 1024);
+        save_persistent_state();
+        assert_eq!(pages(), 2 + 2 * 1024);
+    }
+
+    #[test]
+    fn should_keep_stable_memory_small_after_revoking_delegations() {
+        init_new(None, None, None, AnchorStorageLayout::FixedSlots);
+        revocations_mut(|revocations| {
+            revocations
+                .insert(RevocationKey::new([1; 32], [2; 32]), 2_000, 1_000)
+                .unwrap();
+            revocations.prune_batch(3_000, 10);
+        });
+        // the pages of the header and the memory manager, followed by the bucket of the list
+        let pages = fixed_slot_storage(|storage| storage.memory_stats().total_allocated_pages);
+        assert_eq!(pages, 2 + 1024);
+    }
+}
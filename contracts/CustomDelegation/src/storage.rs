@@ -4,9 +4,28 @@
 //! ## Stable Memory Layout
 //!
 //! Variables used below:
-//! * HEADER_SIZE: 66 bytes
+//! * HEADER_SIZE: 161 bytes
 //! * ENTRY_OFFSET: 131 072 bytes = 2 WASM Pages
-//! * Anchor size: 4096 bytes
+//! * Anchor size: 4096 bytes (default, configurable at install time)
+//!
+//! The first WASM page holds the header. The second page holds a [MemoryManager], which hands out
+//! the stable memory from [ENTRY_OFFSET] onwards to the regions of the storage in buckets of
+//! 64 MiB. A region only grows when it is written, so the stable memory grows with the data stored
+//! rather than with the anchor range.
+//!
+//! ```text
+//! Region 1 (anchors):     entries of the anchors, see below
+//! Region 2 (persistent):  slot 0 of the persistent state, see below
+//! Region 3 (archive):     magic "IIAB" | size (8 bytes) | candid encoded archive entries
+//! Region 4 (credentials): credential index, see [credential_index]
+//! Region 5 (revocations): revoked delegations, see [revocations]
+//! Region 6 (persistent):  slot 1 of the persistent state
+//! Region 7 (principals):  length (4 bytes) | candid encoded principal index
+//! ```
+//!
+//! The regions shared with the layout of [record_storage::MapStorage] have the same IDs in both
+//! layouts. Region 0 is left unused, the header is kept in front of the memory manager (see
+//! [Layouts Before Version 16](#layouts-before-version-16)).
 //!
 //! ```text
 //! ------------------------------------------- <- Address 0
@@ -25,151 +44,955 @@
 //! Salt                        ↕ 32 bytes
 //! -------------------------------------------
 //! Entry offset (ENTRY_OFFSET) ↕ 8 bytes
+//! -------------------------------------------
+//! New layout start            ↕ 4 bytes
+//! -------------------------------------------
+//! Migration batch size        ↕ 4 bytes
+//! -------------------------------------------
+//! Header checksum (CRC32)     ↕ 4 bytes
+//! -------------------------------------------
+//! Entry size migration target ↕ 2 bytes
+//! -------------------------------------------
+//! Entry size migration cursor ↕ 4 bytes
+//! -------------------------------------------
+//! Persistent state epoch      ↕ 8 bytes
+//! -------------------------------------------
+//! Flags                       ↕ 4 bytes
+//! -------------------------------------------
+//! Persistent state address    ↕ 8 bytes
+//! -------------------------------------------
+//! Persistent state length     ↕ 8 bytes
+//! -------------------------------------------
+//! Persistent state anchors    ↕ 4 bytes
+//! -------------------------------------------
+//! Previous salt               ↕ 32 bytes
+//! -------------------------------------------
+//! Deleted anchors             ↕ 4 bytes
+//! -------------------------------------------
+//! Stable memory reserve size  ↕ 8 bytes
+//! -------------------------------------------
+//! Persistent state slot       ↕ 1 byte
 //! ------------------------------------------- <- HEADER_SIZE
-//! Reserved space              ↕ (RESERVED_HEADER_BYTES - HEADER_SIZE) bytes
+//! Reserved space              ↕ (512 - HEADER_SIZE) bytes
+//! ------------------------------------------- <- 512
+//! Unused space                ↕ (65 536 - 512) bytes
+//! ------------------------------------------- <- 65 536
+//! Memory manager              ↕ 65 536 bytes
 //! ------------------------------------------- <- ENTRY_OFFSET
+//! Buckets of the regions
+//! ```
+//!
+//! The region of the anchors holds their entries:
+//!
+//! ```text
+//! ------------------------------------------- <- Offset 0
 //! A_0_size                    ↕ 2 bytes
 //! -------------------------------------------
+//! A_0 checksum (CRC32)        ↕ 4 bytes
+//! -------------------------------------------
 //! Candid encoded entry        ↕ A_0_size bytes
 //! -------------------------------------------
-//! Unused space A_0            ↕ (SIZE_MAX - A_0_size - 2) bytes
-//! ------------------------------------------- <- A_1_offset = ENTRY_OFFSET + (A_1 - A_0) * SIZE_MAX  ┬
-//! A_1_size                    ↕ 2 bytes                                                              │
-//! -------------------------------------------                                                        │
-//! Candid encoded entry        ↕ A_1_size bytes                                            anchor A_1 │
-//! -------------------------------------------                                                        │
-//! Unused space A_1            ↕ (SIZE_MAX - A_1_size - 2) bytes                                      │
-//! -------------------------------------------                                                        ┴
+//! Unused space A_0            ↕ (SIZE_MAX - A_0_size - 6) bytes
+//! ------------------------------------------- <- A_1_offset = (A_1 - A_0) * SIZE_MAX  ┬
+//! A_1_size                    ↕ 2 bytes                                             │
+//! -------------------------------------------                                       │
+//! A_1 checksum (CRC32)        ↕ 4 bytes                                             │
+//! -------------------------------------------                                       │
+//! Candid encoded entry        ↕ A_1_size bytes                           anchor A_1 │
+//! -------------------------------------------                                       │
+//! Unused space A_1            ↕ (SIZE_MAX - A_1_size - 6) bytes                     │
+//! -------------------------------------------                                       ┴
 //! ...
-//! ------------------------------------------- <- A_MAX_offset = ENTRY_OFFSET + (A_MAX - A_0) * SIZE_MAX
+//! ------------------------------------------- <- A_MAX_offset = (A_MAX - A_0) * SIZE_MAX
 //! A_MAX_size                  ↕ 2 bytes
 //! -------------------------------------------
-//! Candid encoded entry        ↕ A_MAX_size bytes
+//! A_MAX checksum (CRC32)      ↕ 4 bytes
 //! -------------------------------------------
-//! Unused space A_MAX          ↕ (SIZE_MAX - A_MAX_size - 2) bytes
+//! Candid encoded entry        ↕ A_MAX_size bytes
 //! -------------------------------------------
-//! Unallocated space           ↕ STABLE_MEMORY_RESERVE bytes
+//! Unused space A_MAX          ↕ (SIZE_MAX - A_MAX_size - 6) bytes
 //! -------------------------------------------
 //! ```
 //!
+//! Header fields are only ever appended, each with a new layout version (see
+//! `SUPPORTED_LAYOUT_VERSIONS`). The fields a header of an older layout version did not have are read
+//! as zero, and the header is upgraded to the current layout version when it is read. A rollback
+//! to a release that does not know the new fields thus fails on the unsupported layout version
+//! instead of silently dropping them.
+//!
+//! The most significant bit of the entry size marks entries that carry a checksum. Entries
+//! written before layout version 7 have no checksum: the candid encoded entry directly follows
+//! the size. They are still readable and get a checksum the next time they are written.
+//!
+//! If record versions are enabled (see [Storage::enable_record_versions]), the second most
+//! significant bit of the entry size marks entries that carry a record version byte directly
+//! following the size. The checksum then covers the record version as well. Entries without
+//! record version have record version 0.
+//!
+//! Deleted anchors (see [Storage::delete_anchor]) are marked by the size 0xFFFF followed by zeros.
+//! No actual entry has this size: it would have both flags set and a length exceeding the entry.
+//! The records of deleted anchors stay allocated, so their user numbers are never assigned again.
+//!
+//! If compression is enabled (see [Storage::set_compression]), records are written as the LEB128
+//! encoded length of the candid encoded record followed by the candid compressed with zstd. The
+//! size limit of the entries applies to the compressed record. Compressed records are recognized by
+//! the zstd magic number following the length (candid encodings start with "DIDL"), so they remain
+//! readable if compression is disabled again. Records are only stored compressed if this saves at
+//! least `MIN_COMPRESSION_SAVINGS_PERCENT` percent, so incompressible records stay plain candid.
+//! There is no flag in the entry size for compressed records: both free high bits are taken by the
+//! checksum and record version flags, and entry sizes use the remaining bits.
+//!
+//! The size of the stable memory reserve is chosen when the storage is created (see
+//! [StorageBuilder::stable_memory_reserve]) and recorded in the header. Headers written before it
+//! was configurable hold zero, which stands for the default of `STABLE_MEMORY_RESERVE` bytes. The
+//! anchor range is limited such that its entries, counted from [ENTRY_OFFSET], leave the reserve
+//! below the stable memory limit. The reserve is not a region of its own, it bounds the other
+//! regions instead (see [Storage::available_reserve]). A smaller reserve leaves room for more
+//! anchors, but also for a smaller persistent state.
+//!
+//! The principal index (see [Storage::put_principal_index]) maps principals to their anchors. It is
+//! kept in memory and written as a candid encoded map to its region whenever it changes, followed
+//! by the header. It is only read if the header flag recording it is set.
+//!
+//! For disaster recovery, the raw entries of the anchors can be backed up (see
+//! [Storage::backup_anchors]) and written back to a storage of the same layout while it is in
+//! backup mode (see [Storage::restore_anchors]).
+//!
+//! If hashed placement is enabled, the entry of an anchor is not located by its offset in the
+//! anchor range, but by the SHA-256 hash of the salt and the user number modulo the number of
+//! entries. Collisions are resolved by linear probing, so every entry ends with a tag holding the
+//! user number plus one (zero marks a free entry). The tag takes the last 8 bytes of the entry and
+//! lowers the size limit of the candid encoded records. As the canister still accesses anchors by
+//! their offset, hashed placement can only be enabled in tests.
+//!
+//! The archive region holds the audit log entries spilled by [crate::archive] (see
+//! [Storage::write_archive_buffer]), at most [ARCHIVE_BUFFER_REGION_SIZE] bytes including the
+//! magic and the size.
+//!
+//! The revoked delegations (see [revocations]) and the index from credential IDs to anchors (see
+//! [credential_index]) are maintained outside of the storage in the regions returned by
+//! [Storage::revocations_memory] and [Storage::credential_index_memory].
+//!
 //! ## Persistent State
 //!
 //! In order to keep state across upgrades that is not related to specific anchors (such as archive
-//! information) Internet Identity will serialize the [PersistentState] into the first unused memory
-//! location (after the anchor record of the highest allocated anchor number). The [PersistentState]
-//! will be read in `post_upgrade` after which the data can be safely overwritten by the next anchor
-//! to be registered.
+//! information) Internet Identity will serialize the [PersistentState] into the regions of the
+//! persistent state, which registering anchors or extending the anchor range never touches. Each
+//! of the two regions is a slot holding at most a quarter of the stable memory reserve without the
+//! archive buffer.
+//!
+//! The slots are written in turns. A new state is written to the slot not holding the last state,
+//! and only then the header is updated to record it (its epoch, length and slot). A write that does
+//! not complete therefore leaves the last state intact. If the slot the header records does not
+//! hold the state with the epoch recorded, the previous state is read from the other slot instead.
+//! The first state is written to slot 0.
+//!
+//! The serialized state starts with the magic "IIPS", followed by a version byte, the epoch, the
+//! size and the candid encoded state.
+//!
+//! ## Layouts Before Version 16
 //!
-//! The [PersistentState] is serialized at the end of stable memory to allow for variable sized data
-//! without the risk of running out of space (which might easily happen if the RESERVED_HEADER_BYTES
-//! were used instead).
+//! Before layout version 16, the storage did not use a memory manager: the principal index followed
+//! the header in the first two pages, the entries of the anchors started at [ENTRY_OFFSET], and the
+//! persistent state, the credential index, the revocations and the archive buffer were kept in the
+//! stable memory reserve after the entries of the whole anchor range. Writing any of them grew the
+//! stable memory to nearly its limit.
+//!
+//! Such layouts are migrated when they are read (see [Storage::try_from_memory]): the memory manager
+//! is created in the second page and allocates its first buckets to the anchors, so that their
+//! entries stay where they are. The principal index, the persistent state, the revocations and the
+//! archive buffer are copied to their regions, the credential index is rebuilt from the anchors.
+//! Records of layout versions 3 and 4 that may still be in the vec<device> layout are marked by a
+//! header flag instead of the layout version, see [Storage::migrate_batch].
 
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::fmt;
+use std::io;
 use std::io::{Read, Write};
-use std::ops::RangeInclusive;
+use std::ops::{Range, RangeInclusive};
 
 use candid;
+use candid::{CandidType, Principal};
 use ic_cdk::api::trap;
-use ic_stable_structures::Memory;
+use ic_stable_structures::{DefaultMemoryImpl, GrowFailed, Memory, RestrictedMemory};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::reader::{OutOfBounds, Reader};
 use ic_stable_structures::writer::Writer;
+use ruzstd::decoding::StreamingDecoder;
+use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+use sha2::{Digest, Sha256};
 
 use crate::state::PersistentState;
-use crate::types::UserNumber;
+use crate::types::{AnchorRecord, ArchiveEntry, DeviceData, MigrationState, Timestamp, UserNumber};
+
+pub mod credential_index;
+mod legacy;
+pub mod record_storage;
+pub mod revocations;
+#[cfg(test)]
+mod tests;
 
 // version   0: invalid
 // version 1-2: no longer supported
-// version   3: 4KB anchors layout (current), vec<device> layout
+// version   3: 4KB anchors layout, vec<device> layout
 // version   4: migration from vec<devices> to anchor record in progress
 // version   5: candid anchor record layout
-// version  6+: invalid
-const SUPPORTED_LAYOUT_VERSIONS: RangeInclusive<u8> = 3..=5;
+// version   6: candid anchor record layout with header checksum
+// version   7: candid anchor record layout with header and entry checksums
+// version   8: candid anchor record layout with checksums and persistent state epoch
+// version   9: candid anchor record layout with optional record versions
+// version  10: candid anchor record layout with the persistent state in the reserve
+// version  11: candid anchor record layout with the anchor count of the persistent state
+// version  12: candid anchor record layout with the previous salt
+// version  13: candid anchor record layout with the number of deleted anchors
+// version  14: candid anchor record layout with a configurable stable memory reserve
+// version  15: candid anchor record layout with persistent state slots and further header flags
+// version  16: candid anchor record layout in the regions of a memory manager
+// version 17+: invalid
+const SUPPORTED_LAYOUT_VERSIONS: RangeInclusive<u8> = 3..=16;
+const CURRENT_LAYOUT_VERSION: u8 = 16;
+/// First layout version that protects the header with a checksum.
+const CHECKSUM_LAYOUT_VERSION: u8 = 6;
+/// First layout version that keeps the data in the regions of a memory manager, older layouts are
+/// migrated when they are read, see [legacy].
+const MANAGED_LAYOUT_VERSION: u8 = 16;
+/// Flag in the entry size marking entries that are followed by a checksum.
+const ENTRY_CHECKSUM_FLAG: u16 = 1 << 15;
+const ENTRY_CHECKSUM_SIZE: usize = std::mem::size_of::<u32>();
+/// Flag in the entry size marking entries that start with a record version byte.
+const ENTRY_RECORD_VERSION_FLAG: u16 = 1 << 14;
+/// Entry size marking the entry of a deleted anchor.
+const ENTRY_TOMBSTONE: u16 = 0xFFFF;
+/// Record versions can only be enabled if no entry size can have [ENTRY_RECORD_VERSION_FLAG] set.
+const MAX_RECORD_VERSION_ENTRY_SIZE: u16 = ENTRY_RECORD_VERSION_FLAG;
+/// Record version 0: no record version byte, candid encoded [AnchorRecord]
+/// Record version 1: candid encoded [AnchorRecord]
+const CURRENT_RECORD_VERSION: u8 = 1;
+
+/// Header flag enabling record versions.
+const HEADER_FLAG_RECORD_VERSIONS: u32 = 1 << 0;
+/// Header flag marking a persistent state that is preceded by a version byte.
+const HEADER_FLAG_PERSISTENT_STATE_VERSION: u32 = 1 << 1;
+/// Header flag enabling the compression of the anchor records written.
+const HEADER_FLAG_COMPRESSION: u32 = 1 << 2;
+/// Header flag marking a principal index written after the header.
+const HEADER_FLAG_PRINCIPAL_INDEX: u32 = 1 << 3;
+/// Header flag allowing raw anchor entries to be restored, see [Storage::restore_anchors].
+const HEADER_FLAG_BACKUP_MODE: u32 = 1 << 4;
+/// Header flag marking an upgrade that has not been finalized, see [Storage::begin_upgrade].
+const HEADER_FLAG_UPGRADE_IN_PROGRESS: u32 = 1 << 5;
+/// Header flag placing anchors by their hashed user number, see [Storage::read_by_hashed].
+const HEADER_FLAG_HASHED_PLACEMENT: u32 = 1 << 6;
+/// Header flag marking records that may still be in the vec<device> layout of layout versions 3
+/// and 4, see [Storage::migrate_batch].
+const HEADER_FLAG_LEGACY_RECORDS: u32 = 1 << 7;
+/// Size of the tag at the end of each entry holding the user number plus one (so that zero marks
+/// a free entry) if anchors are placed by their hashed user number.
+const HASHED_PLACEMENT_TAG_SIZE: usize = std::mem::size_of::<u64>();
+/// Maximum number of bytes of the raw anchor entries backed up or restored at once, keeping the
+/// messages carrying them below the limit of 2 MB.
+pub const MAX_BACKUP_CHUNK_SIZE: usize = 3 * 1024 * 1024 / 2;
+
+/// Minimum size reduction (in percent of the candid encoded record) for a record to be stored
+/// compressed.
+const MIN_COMPRESSION_SAVINGS_PERCENT: usize = 10;
+/// Magic number starting every zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// Upper bound of the length of a decompressed record, protecting against corrupted lengths.
+const MAX_DECOMPRESSED_RECORD_SIZE: u64 = 1024 * 1024;
 
 const WASM_PAGE_SIZE: u64 = 65_536;
 
-/// Reserved space for the header before the anchor records start.
-const ENTRY_OFFSET: u64 = 2 * WASM_PAGE_SIZE; // 1 page reserved for II config, 1 for memory manager
-const DEFAULT_ENTRY_SIZE: u16 = 4096;
+/// Reserved space for the header and the memory manager before the buckets of the regions start.
+/// Before layout version 16, the entries of the anchors started here, see [legacy].
+const ENTRY_OFFSET: u64 = 2 * WASM_PAGE_SIZE; // 1 page for the header, 1 for the memory manager
+/// Pages of the stable memory handed to the memory manager: all pages after the header.
+const MEMORY_MANAGER_PAGES: Range<u64> = 1..u64::MAX / WASM_PAGE_SIZE - 1;
+/// Number of pages of the buckets the memory manager allocates to the regions.
+const BUCKET_SIZE_IN_PAGES: u64 = 1024;
+const ANCHORS_MEMORY_ID: MemoryId = MemoryId::new(1);
+/// The two slots of the persistent state, see [Storage::write_persistent_state].
+const PERSISTENT_STATE_MEMORY_IDS: [MemoryId; 2] = [MemoryId::new(2), MemoryId::new(6)];
+const ARCHIVE_BUFFER_MEMORY_ID: MemoryId = MemoryId::new(3);
+const CREDENTIAL_INDEX_MEMORY_ID: MemoryId = MemoryId::new(4);
+const REVOCATIONS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const PRINCIPAL_INDEX_MEMORY_ID: MemoryId = MemoryId::new(7);
+/// Size of the region at the start of the memory reserved for the header and covered by the header
+/// checksum. Unused bytes in this region are zero, so new header fields can be added without
+/// invalidating the checksum.
+const RESERVED_HEADER_BYTES: usize = 512;
+/// Number of bytes of the reserved header region currently used by header fields.
+const HEADER_SIZE: usize = Header::ACTIVE_SLOT.end;
+/// Address of the length of the principal index before layout version 16, which was bounded by
+/// [ENTRY_OFFSET]. The index kept the same limit in its region.
+const PRINCIPAL_INDEX_OFFSET: u64 = RESERVED_HEADER_BYTES as u64;
+const MAX_PRINCIPAL_INDEX_SIZE: u64 = ENTRY_OFFSET - PRINCIPAL_INDEX_OFFSET - 4;
+pub const DEFAULT_ENTRY_SIZE: u16 = 4096;
+/// Bounds for configurable entry sizes. Entry sizes must also be a power of two.
+const MIN_ENTRY_SIZE: u16 = 512;
+const MAX_ENTRY_SIZE: u16 = 32_768;
 const EMPTY_SALT: [u8; 32] = [0; 32];
 const GB: u64 = 1 << 30;
 
 /// In practice, II has 32 GB of stable memory available. But we want to keep the default
 /// user range until the stable memory migration is complete. Thus we keep this value for anchor
 /// range checking for the time being.
+///
+/// This is only the default: storages for canisters with a different stable memory limit can be
+/// configured with [Storage::set_stable_memory_size].
 const STABLE_MEMORY_SIZE: u64 = 32 * GB;
 /// We reserve the last ~800 MB of stable memory for later new features.
+///
+/// This is only the default: storages can be created with a different reserve, see
+/// [StorageBuilder::stable_memory_reserve].
 const STABLE_MEMORY_RESERVE: u64 = 8 * GB / 10;
+/// The smallest stable memory reserve, which leaves as much room for the persistent state as for
+/// the archive buffer at its end.
+const MIN_STABLE_MEMORY_RESERVE: u64 = 2 * ARCHIVE_BUFFER_REGION_SIZE;
 
 const PERSISTENT_STATE_MAGIC: [u8; 4] = *b"IIPS"; // II Persistent State
+/// Persistent state version 0: no version byte, candid encoded [PersistentState]
+/// Persistent state versions 1 to 13: candid encoded [PersistentState], lacking the fields added
+/// later (versions 11 and 12 also hold the revoked delegations, see [revocations])
+/// Persistent state version 14: candid encoded [PersistentState], whose fields added after version
+/// 1 are optional
+///
+/// Since the fields added to [PersistentState] are optional, states lacking them still decode. The
+/// version is only bumped for changes that the decoding of earlier versions cannot handle.
+const CURRENT_PERSISTENT_STATE_VERSION: u8 = 14;
+/// Size of the magic, version, epoch and size preceding the candid encoded persistent state.
+const PERSISTENT_STATE_PREFIX_SIZE: u64 = 4 + 1 + 8 + 8;
+/// Size of the chunks in which the persistent state is written to and read from stable memory.
+const PERSISTENT_STATE_CHUNK_SIZE: usize = 1024 * 1024;
+/// Maximum number of failures listed by [Storage::verify_all].
+pub const MAX_VERIFY_FAILURES: usize = 100;
+/// Default number of anchor writes kept by [Storage::recent_writes].
+const DEFAULT_WRITE_LOG_CAPACITY: usize = 100;
+/// Default limit of the size of the candid encoded persistent state.
+const DEFAULT_MAX_PERSISTENT_STATE_SIZE: u64 = 2 * GB;
+/// Maximum size of the region holding the spilled archive entries, taken from the stable memory
+/// reserve.
+const ARCHIVE_BUFFER_REGION_SIZE: u64 = 16 * 1024 * 1024;
+const ARCHIVE_BUFFER_MAGIC: [u8; 4] = *b"IIAB"; // II Archive Buffer
+const ARCHIVE_BUFFER_PREFIX_SIZE: u64 = 4 + 8;
 
 /// The maximum number of users this canister can store.
-pub const DEFAULT_RANGE_SIZE: u64 =
-    (STABLE_MEMORY_SIZE - ENTRY_OFFSET - STABLE_MEMORY_RESERVE) / DEFAULT_ENTRY_SIZE as u64;
+pub const DEFAULT_RANGE_SIZE: u64 = max_range_size(DEFAULT_ENTRY_SIZE);
+
+/// The maximum number of users this canister can store given the size of a single entry.
+pub const fn max_range_size(entry_size: u16) -> u64 {
+    max_range_size_in(entry_size, STABLE_MEMORY_SIZE, STABLE_MEMORY_RESERVE)
+}
+
+/// The maximum number of users this canister can store given the size of a single entry and of the
+/// stable memory reserve.
+pub const fn max_range_size_with_reserve(entry_size: u16, reserve: u64) -> u64 {
+    max_range_size_in(entry_size, STABLE_MEMORY_SIZE, reserve)
+}
+
+/// The maximum number of users fitting into a stable memory of `stable_memory_size` bytes
+/// together with a reserve of `reserve` bytes.
+const fn max_range_size_in(entry_size: u16, stable_memory_size: u64, reserve: u64) -> u64 {
+    stable_memory_size.saturating_sub(ENTRY_OFFSET + reserve) / entry_size as u64
+}
 
 pub type Salt = [u8; 32];
+/// Revoked delegation kept in the persistent state by earlier versions: the hash of the seed, the
+/// hash of the session key and the time the revocation expires, see [revocations].
+pub type LegacyRevocation = ([u8; 32], [u8; 32], Timestamp);
+
+/// Region of the stable memory handed out by the memory manager, see [Regions].
+pub type Region<M> = VirtualMemory<RestrictedMemory<M>>;
 
 /// Data type responsible for managing user data in stable memory.
-pub struct Storage<M> {
+pub struct Storage<M: Memory> {
     header: Header,
     memory: M,
+    // regions of the memory manager, set up on first use so that creating a storage (or reading an
+    // empty memory) does not write anything, see [Storage::regions]
+    regions: OnceCell<Regions<M>>,
+    max_persistent_state_size: u64,
+    // anchors by principal, see [Storage::put_principal_index]
+    principal_index: BTreeMap<Principal, UserNumber>,
+    // most recent anchor writes, NOT persisted in stable memory, see [Storage::recent_writes]
+    recent_writes: Vec<WriteEvent>,
+    write_log_capacity: usize,
+    // false until the whole header has been written to the memory once, see [Storage::flush]
+    header_written: bool,
+    // bytes freed by [Storage::reencode_anchors], NOT persisted in stable memory
+    reencoding_freed_bytes: u64,
+    // stable memory limit, NOT persisted in stable memory, see [Storage::set_stable_memory_size]
+    stable_memory_size: u64,
 }
 
-#[repr(packed)]
-struct Header {
-    magic: [u8; 3],
-    // version   0: invalid
-    // version 1-2: no longer supported
-    // version   3: 4KB anchors layout (current), vec<device> layout
-    // version   4: migration from vec<devices> to anchor record in progress
-    // version   5: candid anchor record layout
-    // version  6+: invalid
-    version: u8,
-    num_users: u32,
-    id_range_lo: u64,
-    id_range_hi: u64,
+/// The regions of the memory manager following the header, see the
+/// [module documentation](self).
+struct Regions<M: Memory> {
+    anchors: Region<M>,
+    persistent_state: [Region<M>; 2],
+    archive_buffer: Region<M>,
+    credential_index: Region<M>,
+    revocations: Region<M>,
+    principal_index: Region<M>,
+}
+
+impl<M: Memory + Clone> Regions<M> {
+    /// Loads the memory manager from the page following the header, creating it if the page does
+    /// not hold one yet.
+    fn init(memory: M) -> Self {
+        let manager = MemoryManager::init(RestrictedMemory::new(memory, MEMORY_MANAGER_PAGES));
+        Self {
+            anchors: manager.get(ANCHORS_MEMORY_ID),
+            persistent_state: PERSISTENT_STATE_MEMORY_IDS.map(|id| manager.get(id)),
+            archive_buffer: manager.get(ARCHIVE_BUFFER_MEMORY_ID),
+            credential_index: manager.get(CREDENTIAL_INDEX_MEMORY_ID),
+            revocations: manager.get(REVOCATIONS_MEMORY_ID),
+            principal_index: manager.get(PRINCIPAL_INDEX_MEMORY_ID),
+        }
+    }
+
+    /// Returns the number of buckets allocated to the regions, which are the only users of the
+    /// memory manager.
+    fn allocated_buckets(&self) -> u64 {
+        [
+            &self.anchors,
+            &self.persistent_state[0],
+            &self.persistent_state[1],
+            &self.archive_buffer,
+            &self.credential_index,
+            &self.revocations,
+            &self.principal_index,
+        ]
+        .iter()
+        .map(|region| region.size().div_ceil(BUCKET_SIZE_IN_PAGES))
+        .sum()
+    }
+}
+
+/// Anchor write recorded by a [Storage], see [Storage::recent_writes].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WriteEvent {
+    pub user_number: UserNumber,
+    /// Length of the (possibly compressed) candid encoded record.
+    pub byte_len: usize,
+    /// Time of the write, 0 outside of a canister.
+    pub timestamp_ns: u64,
+}
+
+/// Summary of the stable memory usage of a [Storage], see [Storage::memory_stats].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MemoryStats {
+    pub num_users: u32,
+    pub entry_size: u16,
+    /// Bytes occupied by the entries of all allocated anchors.
+    pub bytes_used: u64,
+    /// Bytes of stable memory reserved for the regions other than the anchors.
+    pub bytes_reserved: u64,
+    /// Number of WASM pages currently allocated.
+    pub total_allocated_pages: u64,
+    pub remaining_capacity: u64,
+}
+
+/// Progress of the layout migration, see [Storage::migrate_batch].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MigrationProgress {
+    /// Number of records converted by the last batch.
+    pub migrated: u32,
+    /// Number of records that still need to be converted.
+    pub remaining: u32,
+    pub done: bool,
+}
+
+/// Result of [Storage::verify_all].
+#[derive(Clone, Debug, CandidType, Eq, PartialEq)]
+pub struct VerifyReport {
+    /// Number of records that were decoded successfully.
+    pub ok_count: u32,
+    /// The first [MAX_VERIFY_FAILURES] records that could not be decoded, with the error.
+    pub failures: Vec<(UserNumber, String)>,
+    /// Number of records that could not be decoded, including the ones not listed in `failures`.
+    pub failure_count: u32,
+}
+
+/// Result of [Storage::compact].
+#[derive(Clone, Debug, CandidType, Eq, PartialEq)]
+pub struct CompactionReport {
+    pub reclaimed_records: u32,
+    pub bytes_reclaimed: u64,
+}
+
+/// Builder for a new empty [Storage], validating the configuration like
+/// [Storage::new_with_entry_size] instead of trapping in the canister.
+///
+/// Unless set, the entry size is [DEFAULT_ENTRY_SIZE], the range is the largest range starting at
+/// 0 the entry size and reserve allow, the stable memory reserve is ~800 MB and the memory is a
+/// [DefaultMemoryImpl].
+pub struct StorageBuilder<M> {
+    range: Option<(UserNumber, UserNumber)>,
     entry_size: u16,
-    salt: [u8; 32],
-    first_entry_offset: u64,
-    new_layout_start: u32, // record number of the first entry using the new candid layout
-    migration_batch_size: u32,
+    stable_memory_reserve: u64,
+    memory: M,
 }
 
-impl<M: Memory> Storage<M> {
-    /// Creates a new empty storage that manages the data of users in
-    /// the specified range.
-    pub fn new((id_range_lo, id_range_hi): (UserNumber, UserNumber), memory: M) -> Self {
+impl StorageBuilder<DefaultMemoryImpl> {
+    pub fn new() -> Self {
+        Self {
+            range: None,
+            entry_size: DEFAULT_ENTRY_SIZE,
+            stable_memory_reserve: STABLE_MEMORY_RESERVE,
+            memory: DefaultMemoryImpl::default(),
+        }
+    }
+}
+
+impl Default for StorageBuilder<DefaultMemoryImpl> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory> StorageBuilder<M> {
+    /// Sets the range of user numbers managed by the storage.
+    pub fn range(mut self, id_range_lo: UserNumber, id_range_hi: UserNumber) -> Self {
+        self.range = Some((id_range_lo, id_range_hi));
+        self
+    }
+
+    pub fn entry_size(mut self, entry_size: u16) -> Self {
+        self.entry_size = entry_size;
+        self
+    }
+
+    /// Sets the size of the stable memory reserve following the entries of the range, which holds
+    /// the persistent state and the archive buffer. The size is recorded in the header, so it
+    /// cannot be changed once the storage has been created.
+    pub fn stable_memory_reserve(mut self, bytes: u64) -> Self {
+        self.stable_memory_reserve = bytes;
+        self
+    }
+
+    pub fn memory<N: Memory>(self, memory: N) -> StorageBuilder<N> {
+        StorageBuilder {
+            range: self.range,
+            entry_size: self.entry_size,
+            stable_memory_reserve: self.stable_memory_reserve,
+            memory,
+        }
+    }
+
+    /// Creates the storage, returning an error if the entry size or the reserve is invalid or the
+    /// range is inverted or too large for the entry size and reserve.
+    pub fn build(self) -> Result<Storage<M>, StorageError> {
+        let (entry_size, reserve) = (self.entry_size, self.stable_memory_reserve);
+        let (id_range_lo, id_range_hi) = self
+            .range
+            .unwrap_or((0, max_range_size_with_reserve(entry_size.max(1), reserve)));
+
+        if !entry_size.is_power_of_two() || !(MIN_ENTRY_SIZE..=MAX_ENTRY_SIZE).contains(&entry_size)
+        {
+            return Err(StorageError::InvalidEntrySize(entry_size));
+        }
+
+        if reserve < MIN_STABLE_MEMORY_RESERVE {
+            return Err(StorageError::InvalidReserve(reserve));
+        }
+
         if id_range_hi < id_range_lo {
-            trap(&format!(
-                "improper Identity Anchor range: [{}, {})",
-                id_range_lo, id_range_hi,
-            ));
+            return Err(StorageError::InvalidRange {
+                range: (id_range_lo, id_range_hi),
+            });
         }
 
-        if (id_range_hi - id_range_lo) > DEFAULT_RANGE_SIZE {
-            trap(&format!(
-                "id range [{}, {}) is too large for a single canister (max {} entries)",
-                id_range_lo, id_range_hi, DEFAULT_RANGE_SIZE,
-            ));
+        let max_size = max_range_size_with_reserve(entry_size, reserve);
+        if (id_range_hi - id_range_lo) > max_size {
+            return Err(StorageError::RangeTooLarge {
+                range: (id_range_lo, id_range_hi),
+                max_size,
+            });
         }
 
-        Self {
+        Ok(Storage {
             header: Header {
                 magic: *b"IIC",
-                version: 5,
+                version: CURRENT_LAYOUT_VERSION,
                 num_users: 0,
                 id_range_lo,
                 id_range_hi,
-                entry_size: DEFAULT_ENTRY_SIZE,
+                entry_size,
                 salt: EMPTY_SALT,
                 first_entry_offset: ENTRY_OFFSET,
                 new_layout_start: 0,
                 migration_batch_size: 0,
+                checksum: 0,
+                entry_size_migration_target: 0,
+                entry_size_migration_cursor: 0,
+                persistent_state_epoch: 0,
+                flags: 0,
+                persistent_state_address: 0,
+                persistent_state_length: 0,
+                persistent_state_anchor_count: 0,
+                previous_salt: EMPTY_SALT,
+                deleted_anchors: 0,
+                // the default is recorded as 0 like in headers written before it was configurable
+                stable_memory_reserve: if reserve == STABLE_MEMORY_RESERVE {
+                    0
+                } else {
+                    reserve
+                },
+                active_slot: 0,
             },
-            memory,
+            memory: self.memory,
+            regions: OnceCell::new(),
+            max_persistent_state_size: DEFAULT_MAX_PERSISTENT_STATE_SIZE,
+            principal_index: BTreeMap::new(),
+            recent_writes: vec![],
+            write_log_capacity: DEFAULT_WRITE_LOG_CAPACITY,
+            reencoding_freed_bytes: 0,
+            stable_memory_size: STABLE_MEMORY_SIZE,
+            header_written: false,
+        })
+    }
+}
+
+/// Iterator over the allocated anchors of a [Storage], see [Storage::iter_anchors].
+pub struct AnchorIterator<'a, M: Memory> {
+    storage: &'a Storage<M>,
+    // offset of the next anchor from the start of the anchor range, which is also its record
+    // number unless hashed placement is enabled
+    next_record: u32,
+    buf: Vec<u8>,
+}
+
+impl<M: Memory + Clone> Iterator for AnchorIterator<'_, M> {
+    type Item = (UserNumber, Result<AnchorRecord, StorageError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_record >= self.storage.header.num_users {
+            return None;
+        }
+        let user_number = self.storage.header.id_range_lo + self.next_record as u64;
+        let anchor = if self.storage.hashed_placement_enabled() {
+            self.storage.read_by_hashed(user_number)
+        } else {
+            self.storage.read_entry(self.next_record, &mut self.buf);
+            self.storage.decode_entry(user_number, &self.buf)
+        };
+        self.next_record += 1;
+        Some((user_number, anchor))
+    }
+}
+
+/// A page of anchors returned by [Storage::scan_from].
+#[derive(Debug)]
+pub struct ScanPage {
+    pub anchors: Vec<(UserNumber, Result<AnchorRecord, StorageError>)>,
+    /// User number to pass to the next call of [Storage::scan_from], `None` if the scan reached
+    /// the highest allocated anchor.
+    pub next_cursor: Option<UserNumber>,
+}
+
+struct Header {
+    magic: [u8; 3],
+    // layout version, see [SUPPORTED_LAYOUT_VERSIONS]
+    version: u8,
+    num_users: u32,
+    id_range_lo: u64,
+    id_range_hi: u64,
+    entry_size: u16,
+    salt: [u8; 32],
+    first_entry_offset: u64,
+    new_layout_start: u32, // record number of the first entry using the new candid layout
+    migration_batch_size: u32,
+    checksum: u32, // CRC32 over the header region, see [Header::compute_checksum]
+    // Entry size of an ongoing entry size migration (0 if there is none). Records with a
+    // record number >= entry_size_migration_cursor have already been moved to the new size.
+    entry_size_migration_target: u16,
+    entry_size_migration_cursor: u32,
+    // incremented on every write of the persistent state, 0 if it was never written with an epoch
+    persistent_state_epoch: u64,
+    flags: u32,
+    // location of the persistent state before layout version 16, 0 since, see [legacy]
+    persistent_state_address: u64,
+    persistent_state_length: u64,
+    // number of anchors at the time the persistent state was written before layout version 16
+    persistent_state_anchor_count: u32,
+    // salt replaced by the last salt rotation, see [Storage::rotate_salt]
+    previous_salt: [u8; 32],
+    // number of allocated anchors that have been deleted, see [Storage::delete_anchor]
+    deleted_anchors: u32,
+    // size of the stable memory reserve, 0 for the default, see [Storage::stable_memory_reserve]
+    stable_memory_reserve: u64,
+    // slot of the persistent state (0 or 1), flipped by every write of the persistent state, see
+    // [Storage::write_persistent_state]
+    active_slot: u8,
+}
+
+/// Prints all fields except for the salts, which only show whether they are set, so that the
+/// output can be handed out to operators.
+impl fmt::Debug for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Header")
+            .field("magic", &String::from_utf8_lossy(&self.magic))
+            .field("version", &self.version)
+            .field("num_users", &self.num_users)
+            .field("id_range_lo", &self.id_range_lo)
+            .field("id_range_hi", &self.id_range_hi)
+            .field("entry_size", &self.entry_size)
+            .field("salt_set", &(self.salt != EMPTY_SALT))
+            .field("first_entry_offset", &self.first_entry_offset)
+            .field("new_layout_start", &self.new_layout_start)
+            .field("migration_batch_size", &self.migration_batch_size)
+            .field("checksum", &format_args!("{:#010x}", self.checksum))
+            .field(
+                "entry_size_migration_target",
+                &self.entry_size_migration_target,
+            )
+            .field(
+                "entry_size_migration_cursor",
+                &self.entry_size_migration_cursor,
+            )
+            .field("persistent_state_epoch", &self.persistent_state_epoch)
+            .field("flags", &format_args!("{:#b}", self.flags))
+            .field("persistent_state_address", &self.persistent_state_address)
+            .field("persistent_state_length", &self.persistent_state_length)
+            .field(
+                "persistent_state_anchor_count",
+                &self.persistent_state_anchor_count,
+            )
+            .field("previous_salt_set", &(self.previous_salt != EMPTY_SALT))
+            .field("deleted_anchors", &self.deleted_anchors)
+            .field("stable_memory_reserve", &self.stable_memory_reserve)
+            .field("active_slot", &self.active_slot)
+            .finish()
+    }
+}
+
+impl Header {
+    // Byte ranges of the header fields, see the layout at the top of this module.
+    const MAGIC: Range<usize> = 0..3;
+    const VERSION: Range<usize> = 3..4;
+    const NUM_USERS: Range<usize> = 4..8;
+    const ID_RANGE_LO: Range<usize> = 8..16;
+    const ID_RANGE_HI: Range<usize> = 16..24;
+    const ENTRY_SIZE: Range<usize> = 24..26;
+    const SALT: Range<usize> = 26..58;
+    const FIRST_ENTRY_OFFSET: Range<usize> = 58..66;
+    const NEW_LAYOUT_START: Range<usize> = 66..70;
+    const MIGRATION_BATCH_SIZE: Range<usize> = 70..74;
+    const CHECKSUM: Range<usize> = 74..78;
+    const ENTRY_SIZE_MIGRATION_TARGET: Range<usize> = 78..80;
+    const ENTRY_SIZE_MIGRATION_CURSOR: Range<usize> = 80..84;
+    const PERSISTENT_STATE_EPOCH: Range<usize> = 84..92;
+    const FLAGS: Range<usize> = 92..96;
+    const PERSISTENT_STATE_ADDRESS: Range<usize> = 96..104;
+    const PERSISTENT_STATE_LENGTH: Range<usize> = 104..112;
+    const PERSISTENT_STATE_ANCHOR_COUNT: Range<usize> = 112..116;
+    const PREVIOUS_SALT: Range<usize> = 116..148;
+    const DELETED_ANCHORS: Range<usize> = 148..152;
+    const STABLE_MEMORY_RESERVE: Range<usize> = 152..160;
+    const ACTIVE_SLOT: Range<usize> = 160..161;
+
+    /// Fields written by [Storage::flush] once the header exists in memory. The magic never
+    /// changes and the salts are only written by [Storage::flush_salt].
+    const METADATA_FIELDS: [Range<usize>; 18] = [
+        Self::VERSION,
+        Self::NUM_USERS,
+        Self::ID_RANGE_LO,
+        Self::ID_RANGE_HI,
+        Self::ENTRY_SIZE,
+        Self::FIRST_ENTRY_OFFSET,
+        Self::NEW_LAYOUT_START,
+        Self::MIGRATION_BATCH_SIZE,
+        Self::ENTRY_SIZE_MIGRATION_TARGET,
+        Self::ENTRY_SIZE_MIGRATION_CURSOR,
+        Self::PERSISTENT_STATE_EPOCH,
+        Self::FLAGS,
+        Self::PERSISTENT_STATE_ADDRESS,
+        Self::PERSISTENT_STATE_LENGTH,
+        Self::PERSISTENT_STATE_ANCHOR_COUNT,
+        Self::DELETED_ANCHORS,
+        Self::STABLE_MEMORY_RESERVE,
+        Self::ACTIVE_SLOT,
+    ];
+
+    /// Serializes the header field by field (little endian) into the reserved header region.
+    /// Bytes after [HEADER_SIZE] are zero.
+    fn serialize_header(&self) -> [u8; RESERVED_HEADER_BYTES] {
+        let mut bytes = [0u8; RESERVED_HEADER_BYTES];
+        bytes[Self::MAGIC].copy_from_slice(&self.magic);
+        bytes[Self::VERSION].copy_from_slice(&[self.version]);
+        bytes[Self::NUM_USERS].copy_from_slice(&self.num_users.to_le_bytes());
+        bytes[Self::ID_RANGE_LO].copy_from_slice(&self.id_range_lo.to_le_bytes());
+        bytes[Self::ID_RANGE_HI].copy_from_slice(&self.id_range_hi.to_le_bytes());
+        bytes[Self::ENTRY_SIZE].copy_from_slice(&self.entry_size.to_le_bytes());
+        bytes[Self::SALT].copy_from_slice(&self.salt);
+        bytes[Self::FIRST_ENTRY_OFFSET].copy_from_slice(&self.first_entry_offset.to_le_bytes());
+        bytes[Self::NEW_LAYOUT_START].copy_from_slice(&self.new_layout_start.to_le_bytes());
+        bytes[Self::MIGRATION_BATCH_SIZE].copy_from_slice(&self.migration_batch_size.to_le_bytes());
+        bytes[Self::CHECKSUM].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes[Self::ENTRY_SIZE_MIGRATION_TARGET]
+            .copy_from_slice(&self.entry_size_migration_target.to_le_bytes());
+        bytes[Self::ENTRY_SIZE_MIGRATION_CURSOR]
+            .copy_from_slice(&self.entry_size_migration_cursor.to_le_bytes());
+        bytes[Self::PERSISTENT_STATE_EPOCH]
+            .copy_from_slice(&self.persistent_state_epoch.to_le_bytes());
+        bytes[Self::FLAGS].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[Self::PERSISTENT_STATE_ADDRESS]
+            .copy_from_slice(&self.persistent_state_address.to_le_bytes());
+        bytes[Self::PERSISTENT_STATE_LENGTH]
+            .copy_from_slice(&self.persistent_state_length.to_le_bytes());
+        bytes[Self::PERSISTENT_STATE_ANCHOR_COUNT]
+            .copy_from_slice(&self.persistent_state_anchor_count.to_le_bytes());
+        bytes[Self::PREVIOUS_SALT].copy_from_slice(&self.previous_salt);
+        bytes[Self::DELETED_ANCHORS].copy_from_slice(&self.deleted_anchors.to_le_bytes());
+        bytes[Self::STABLE_MEMORY_RESERVE]
+            .copy_from_slice(&self.stable_memory_reserve.to_le_bytes());
+        bytes[Self::ACTIVE_SLOT].copy_from_slice(&[self.active_slot]);
+        bytes
+    }
+
+    /// Reads the header fields from their offsets in `bytes`, which must hold at least
+    /// [HEADER_SIZE] bytes. The field values are not validated.
+    fn deserialize_header(bytes: &[u8]) -> Result<Header, HeaderError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(HeaderError::TooShort(bytes.len()));
         }
+        Ok(Header {
+            magic: read_field(bytes, Self::MAGIC),
+            version: bytes[Self::VERSION.start],
+            num_users: u32::from_le_bytes(read_field(bytes, Self::NUM_USERS)),
+            id_range_lo: u64::from_le_bytes(read_field(bytes, Self::ID_RANGE_LO)),
+            id_range_hi: u64::from_le_bytes(read_field(bytes, Self::ID_RANGE_HI)),
+            entry_size: u16::from_le_bytes(read_field(bytes, Self::ENTRY_SIZE)),
+            salt: read_field(bytes, Self::SALT),
+            first_entry_offset: u64::from_le_bytes(read_field(bytes, Self::FIRST_ENTRY_OFFSET)),
+            new_layout_start: u32::from_le_bytes(read_field(bytes, Self::NEW_LAYOUT_START)),
+            migration_batch_size: u32::from_le_bytes(read_field(bytes, Self::MIGRATION_BATCH_SIZE)),
+            checksum: u32::from_le_bytes(read_field(bytes, Self::CHECKSUM)),
+            entry_size_migration_target: u16::from_le_bytes(read_field(
+                bytes,
+                Self::ENTRY_SIZE_MIGRATION_TARGET,
+            )),
+            entry_size_migration_cursor: u32::from_le_bytes(read_field(
+                bytes,
+                Self::ENTRY_SIZE_MIGRATION_CURSOR,
+            )),
+            persistent_state_epoch: u64::from_le_bytes(read_field(
+                bytes,
+                Self::PERSISTENT_STATE_EPOCH,
+            )),
+            flags: u32::from_le_bytes(read_field(bytes, Self::FLAGS)),
+            persistent_state_address: u64::from_le_bytes(read_field(
+                bytes,
+                Self::PERSISTENT_STATE_ADDRESS,
+            )),
+            persistent_state_length: u64::from_le_bytes(read_field(
+                bytes,
+                Self::PERSISTENT_STATE_LENGTH,
+            )),
+            persistent_state_anchor_count: u32::from_le_bytes(read_field(
+                bytes,
+                Self::PERSISTENT_STATE_ANCHOR_COUNT,
+            )),
+            previous_salt: read_field(bytes, Self::PREVIOUS_SALT),
+            deleted_anchors: u32::from_le_bytes(read_field(bytes, Self::DELETED_ANCHORS)),
+            stable_memory_reserve: u64::from_le_bytes(read_field(
+                bytes,
+                Self::STABLE_MEMORY_RESERVE,
+            )),
+            active_slot: bytes[Self::ACTIVE_SLOT.start],
+        })
+    }
+
+    /// Resets the fields introduced after the layout version of the header. Older layouts never
+    /// wrote them, so whatever the memory holds at their offsets is not part of the header. The
+    /// flags field exists since layout version 9, older layouts just never set the newer flags.
+    fn clear_newer_fields(&mut self) {
+        if self.version < 11 {
+            self.persistent_state_anchor_count = 0;
+        }
+        if self.version < 12 {
+            self.previous_salt = EMPTY_SALT;
+        }
+        if self.version < 13 {
+            self.deleted_anchors = 0;
+        }
+        if self.version < 14 {
+            self.stable_memory_reserve = 0;
+        }
+        if self.version < 15 {
+            self.active_slot = 0;
+        }
+    }
+
+    /// Reads the header from the start of `memory`, see [Header::deserialize_header].
+    fn read_from<M: Memory>(memory: &M) -> Result<Header, HeaderError> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        memory.read(0, &mut bytes);
+        Self::deserialize_header(&bytes)
+    }
+
+    /// Writes the [HEADER_SIZE] bytes of the serialized header (without the zero padding of the
+    /// reserved region) to `writer`.
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.serialize_header()[..HEADER_SIZE])
+    }
+
+    /// Computes the CRC32 over the zero-padded header region, skipping the checksum field itself.
+    fn compute_checksum(&self) -> u32 {
+        let region = self.serialize_header();
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&region[..Self::CHECKSUM.start]);
+        hasher.update(&region[Self::CHECKSUM.end..]);
+        hasher.finalize()
+    }
+}
+
+/// Checks that `version` is one of the `supported` layout versions.
+fn check_layout_version(version: u8, supported: RangeInclusive<u8>) -> Result<(), HeaderError> {
+    if version < *supported.start() {
+        return Err(HeaderError::VersionTooOld(version));
+    }
+    if !supported.contains(&version) {
+        return Err(HeaderError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+fn read_field<const N: usize>(bytes: &[u8], field: Range<usize>) -> [u8; N] {
+    bytes[field]
+        .try_into()
+        .expect("bug: header field out of bounds")
+}
+
+impl<M: Memory + Clone> Storage<M> {
+    /// Creates a new empty storage that manages the data of users in
+    /// the specified range.
+    pub fn new(range: (UserNumber, UserNumber), memory: M) -> Result<Self, StorageError> {
+        Self::new_with_entry_size(range, memory, DEFAULT_ENTRY_SIZE)
+    }
+
+    /// Creates a new empty storage that manages the data of users in
+    /// the specified range using entries of `entry_size` bytes.
+    ///
+    /// The maximum size of the range depends on the entry size: doubling the
+    /// entry size halves the number of anchors that can be stored.
+    pub fn new_with_entry_size(
+        range: (UserNumber, UserNumber),
+        memory: M,
+        entry_size: u16,
+    ) -> Result<Self, StorageError> {
+        StorageBuilder::new()
+            .range(range.0, range.1)
+            .entry_size(entry_size)
+            .memory(memory)
+            .build()
     }
 
+
     pub fn salt(&self) -> Option<&Salt> {
         if self.header.salt == EMPTY_SALT {
             None
@@ -178,174 +1001,2278 @@ impl<M: Memory> Storage<M> {
         }
     }
 
-    pub fn update_salt(&mut self, salt: Salt) {
+    /// Sets the salt. Fails with [StorageError::HashedPlacementEnabled] if this would change the
+    /// salt the anchors are placed by.
+    pub fn update_salt(&mut self, salt: Salt) -> Result<(), StorageError> {
+        self.check_salt_change(&salt)?;
         self.header.salt = salt;
-        self.flush();
+        self.flush_salt();
+        Ok(())
+    }
+
+    /// Sets the salt unless it has already been set. Returns true if `salt` was stored.
+    pub fn set_salt_if_empty(&mut self, salt: Salt) -> bool {
+        self.salt().is_none() && self.update_salt(salt).is_ok()
+    }
+
+    /// Replaces the salt by `new_salt`, keeping the current salt as the previous salt so that
+    /// delegations derived from it can still be verified during a grace period. Fails like
+    /// [Storage::update_salt].
+    pub fn rotate_salt(&mut self, new_salt: Salt) -> Result<(), StorageError> {
+        self.check_salt_change(&new_salt)?;
+        self.header.previous_salt = self.header.salt;
+        self.header.salt = new_salt;
+        self.flush_salt();
+        Ok(())
+    }
+
+    fn check_salt_change(&self, salt: &Salt) -> Result<(), StorageError> {
+        // the anchors placed by the hash of the old salt could no longer be found
+        if self.hashed_placement_enabled() && self.salt().is_some_and(|old| old != salt) {
+            return Err(StorageError::HashedPlacementEnabled);
+        }
+        Ok(())
+    }
+
+    /// Returns the salt replaced by the last call to [Storage::rotate_salt], if any.
+    pub fn previous_salt(&self) -> Option<&Salt> {
+        if self.header.previous_salt == EMPTY_SALT {
+            None
+        } else {
+            Some(&self.header.previous_salt)
+        }
     }
 
     /// Initializes storage by reading the given memory.
     ///
     /// Returns None if the memory is empty.
-    ///
-    /// Panics if the memory is not empty but cannot be
-    /// decoded.
+    /// Panics if the memory is not empty but cannot be decoded.
     pub fn from_memory(memory: M) -> Option<Self> {
+        Self::try_from_memory(memory).unwrap_or_else(|err| trap(&err.to_string()))
+    }
+
+    /// Initializes storage by reading the given memory without trapping.
+    ///
+    /// Returns `Ok(None)` if the memory is empty and an error if the
+    /// memory is not empty but cannot be decoded.
+    ///
+    /// Headers of layout version 5 do not have a checksum yet. Fields
+    /// introduced after the layout version of the header are read as zero, see
+    /// [Header::clear_newer_fields]. Storages of layout versions before 16 are
+    /// migrated to the regions of the memory manager, see [legacy::migrate].
+    pub fn try_from_memory(memory: M) -> Result<Option<Self>, HeaderError> {
         if memory.size() < 1 {
-            return None;
+            return Ok(None);
         }
 
-        let mut header: Header = unsafe { std::mem::zeroed() };
-
-        unsafe {
-            let slice = std::slice::from_raw_parts_mut(
-                &mut header as *mut _ as *mut u8,
-                std::mem::size_of::<Header>(),
-            );
-            memory.read(0, slice);
-        }
+        let mut header = Header::read_from(&memory)?;
 
         if &header.magic != b"IIC" {
-            trap(&format!(
-                "stable memory header: invalid magic: {:?}",
-                &header.magic,
-            ));
+            return Err(HeaderError::InvalidMagic(header.magic));
+        }
+        check_layout_version(header.version, SUPPORTED_LAYOUT_VERSIONS)?;
+        header.clear_newer_fields();
+        if header.version >= CHECKSUM_LAYOUT_VERSION {
+            let (expected, actual) = (header.checksum, header.compute_checksum());
+            if expected != actual {
+                return Err(HeaderError::ChecksumMismatch { expected, actual });
+            }
+        }
+        if header.first_entry_offset != ENTRY_OFFSET || header.entry_size == 0 {
+            return Err(HeaderError::InconsistentLayout {
+                entry_size: header.entry_size,
+                first_entry_offset: header.first_entry_offset,
+            });
         }
-        if &header.version < SUPPORTED_LAYOUT_VERSIONS.start() {
-            trap(&format!("stable memory layout version {} is no longer supported:\nEither reinstall (wiping stable memory) or migrate using a previous II version", header.version));
+        if header.flags & HEADER_FLAG_UPGRADE_IN_PROGRESS != 0 {
+            return Err(HeaderError::UpgradeNotFinalized);
         }
-        if !SUPPORTED_LAYOUT_VERSIONS.contains(&header.version) {
-            trap(&format!("unsupported header version: {}", header.version));
+        if header.stable_memory_reserve != 0
+            && header.stable_memory_reserve < MIN_STABLE_MEMORY_RESERVE
+        {
+            return Err(HeaderError::InvalidReserve(header.stable_memory_reserve));
         }
+        if header.active_slot > 1 {
+            return Err(HeaderError::InvalidActiveSlot(header.active_slot));
+        }
+
+        let mut storage = Self {
+            header,
+            memory,
+            regions: OnceCell::new(),
+            max_persistent_state_size: DEFAULT_MAX_PERSISTENT_STATE_SIZE,
+            principal_index: BTreeMap::new(),
+            recent_writes: vec![],
+            write_log_capacity: DEFAULT_WRITE_LOG_CAPACITY,
+            reencoding_freed_bytes: 0,
+            stable_memory_size: STABLE_MEMORY_SIZE,
+            header_written: true,
+        };
+        if storage.header.version < MANAGED_LAYOUT_VERSION {
+            legacy::migrate(&mut storage)?;
+        } else if storage.header.flags & HEADER_FLAG_PRINCIPAL_INDEX != 0 {
+            storage.principal_index = read_principal_index(&storage.regions().principal_index, 0)?;
+        }
+        Ok(Some(storage))
+    }
+
+    /// Returns the regions of the memory manager, creating the memory manager on first use.
+    fn regions(&self) -> &Regions<M> {
+        self.regions
+            .get_or_init(|| Regions::init(self.memory.clone()))
+    }
 
-        Some(Self { header, memory })
+    /// Returns the region of the entries of the anchors.
+    fn anchor_memory(&self) -> &Region<M> {
+        &self.regions().anchors
     }
 
     /// Make sure all the required metadata is recorded to stable memory.
+    ///
+    /// The whole header is only written the first time, afterwards the salts are left untouched
+    /// (see [Storage::flush_salt]).
     pub fn flush(&mut self) {
-        let slice = unsafe {
-            std::slice::from_raw_parts(
-                &self.header as *const _ as *const u8,
-                std::mem::size_of::<Header>(),
-            )
-        };
-        let mut writer = Writer::new(&mut self.memory, 0);
+        self.write_header_fields(&Header::METADATA_FIELDS);
+    }
 
-        // this should never fail as this write only requires a memory of size 1
-        writer.write(slice).expect("bug: failed to grow memory");
+    /// Writes only the number of allocated anchors (and the header checksum).
+    fn flush_counters(&mut self) {
+        self.write_header_fields(&[Header::NUM_USERS]);
     }
 
-    pub fn user_count(&self) -> usize {
-        self.header.num_users as usize
+    /// Writes only the current and the previous salt (and the header checksum).
+    fn flush_salt(&mut self) {
+        self.write_header_fields(&[Header::SALT, Header::PREVIOUS_SALT]);
     }
 
-    fn record_address(&self, record_number: u32) -> u64 {
-        self.header.first_entry_offset + record_number as u64 * self.header.entry_size as u64
+    /// Writes the given header fields followed by the checksum, or the whole header if it has not
+    /// been written yet (replacing whatever the memory holds at the offsets of the fields an older
+    /// layout did not have).
+    fn write_header_fields(&mut self, fields: &[Range<usize>]) {
+        self.header.checksum = self.header.compute_checksum();
+        let bytes = self.header.serialize_header();
+
+        // these writes should never fail as they only require a memory of size 1
+        if !self.header_written {
+            self.header
+                .write_to(&mut Writer::new(&mut self.memory, 0))
+                .expect("bug: failed to grow memory");
+            self.header_written = true;
+            return;
+        }
+        for field in fields.iter().cloned() {
+            Writer::new(&mut self.memory, field.start as u64)
+                .write(&bytes[field])
+                .expect("bug: failed to grow memory");
+        }
+        Writer::new(&mut self.memory, Header::CHECKSUM.start as u64)
+            .write(&bytes[Header::CHECKSUM])
+            .expect("bug: failed to grow memory");
     }
 
-    /// The anchor space is divided into two parts:
-    /// * 2 bytes of candid length (u16 little endian)
-    /// * length bytes of encoded candid
+    /// Records that `principal` belongs to the given anchor, replacing its previous anchor (if
+    /// any), and writes the index followed by the header.
     ///
-    /// This function returns the length limit of the candid part.
-    fn candid_entry_size_limit(&self) -> usize {
-        self.header.entry_size as usize - std::mem::size_of::<u16>()
+    /// Fails without changing the index if the encoded index exceeds [MAX_PRINCIPAL_INDEX_SIZE]
+    /// bytes.
+    pub fn put_principal_index(
+        &mut self,
+        principal: Principal,
+        user_number: UserNumber,
+    ) -> Result<(), StorageError> {
+        self.user_number_to_record(user_number)?;
+        let mut principal_index = self.principal_index.clone();
+        principal_index.insert(principal, user_number);
+        self.write_principal_index(principal_index)
     }
 
-    /// Returns the address of the first byte not yet allocated to a user.
-    /// This address exists even if the max user number has been reached, because there is a memory
-    /// reserve at the end of stable memory.
-    fn unused_memory_start(&self) -> u64 {
-        self.record_address(self.header.num_users)
+    /// Writes the given principal index followed by the header. Fails without changing the index
+    /// if the encoded index exceeds [MAX_PRINCIPAL_INDEX_SIZE] bytes or the memory cannot grow.
+    fn write_principal_index(
+        &mut self,
+        principal_index: BTreeMap<Principal, UserNumber>,
+    ) -> Result<(), StorageError> {
+        let encoded =
+            candid::encode_one(&principal_index).map_err(StorageError::SerializationError)?;
+        self.write_principal_index_region(&encoded)?;
+        self.principal_index = principal_index;
+        self.header.flags |= HEADER_FLAG_PRINCIPAL_INDEX;
+        self.flush();
+        Ok(())
     }
 
-    /// Writes the persistent state to stable memory just outside of the space allocated to the highest user number.
-    /// This is only used to _temporarily_ save state during upgrades. It will be overwritten on next user registration.
-    pub fn write_persistent_state(&mut self, state: &PersistentState) {
-        let address = self.unused_memory_start();
+    /// Writes the length and the candid encoded principal index to its region, without updating
+    /// the header.
+    fn write_principal_index_region(&self, encoded: &[u8]) -> Result<(), StorageError> {
+        if encoded.len() as u64 > MAX_PRINCIPAL_INDEX_SIZE {
+            return Err(StorageError::PrincipalIndexFull {
+                size: encoded.len() as u64,
+                max: MAX_PRINCIPAL_INDEX_SIZE,
+            });
+        }
+        let region = &self.regions().principal_index;
+        self.ensure_region_capacity(region, 4 + encoded.len() as u64)?;
+        region.write(0, &(encoded.len() as u32).to_le_bytes());
+        region.write(4, encoded);
+        Ok(())
+    }
 
-        // In practice, candid encoding is infallible. The Result is an artifact of the serde API.
-        let encoded_state = candid::encode_one(state).unwrap();
+    /// Returns the anchor recorded for `principal` by [Storage::put_principal_index], if any.
+    pub fn lookup_by_principal(&self, principal: &Principal) -> Option<UserNumber> {
+        self.principal_index.get(principal).copied()
+    }
 
-        // In practice, for all reasonably sized persistent states (<800MB) the writes are
-        // infallible because we have a stable memory reserve (i.e. growing the memory will succeed).
-        let mut writer = Writer::new(&mut self.memory, address);
-        writer.write(&PERSISTENT_STATE_MAGIC).unwrap();
-        writer
-            .write(&(encoded_state.len() as u64).to_le_bytes())
-            .unwrap();
-        writer.write(&encoded_state).unwrap();
+    /// Returns the number of allocated anchors, including the deleted ones.
+    pub fn user_count(&self) -> usize {
+        self.header.num_users as usize
     }
 
-    /// Reads the persistent state from stable memory just outside of the space allocated to the highest user number.
-    /// This is only used to restore state in `post_upgrade`.
-    pub fn read_persistent_state(&self) -> Result<PersistentState, PersistentStateError> {
-        const WASM_PAGE_SIZE: u64 = 65536;
-        let address = self.unused_memory_start();
+    /// Returns the number of allocated anchors that have been deleted, see
+    /// [Storage::delete_anchor].
+    pub fn deleted_count(&self) -> usize {
+        self.header.deleted_anchors as usize
+    }
 
-        if address > self.memory.size() * WASM_PAGE_SIZE {
-            // the address where the persistent state would be is not allocated yet
-            return Err(PersistentStateError::NotFound);
+    /// Deletes the given allocated anchor: its entry is overwritten with a tombstone and its
+    /// principals are removed from the principal index. Reads of the anchor fail with
+    /// [StorageError::AnchorDeleted] afterwards.
+    ///
+    /// The record stays allocated, so the user number is never assigned to another anchor.
+    pub fn delete_anchor(&mut self, user_number: UserNumber) -> Result<(), StorageError> {
+        let record_number = self.user_number_to_record(user_number)?;
+        if record_number >= self.header.num_users {
+            return Err(StorageError::BadUserNumber(user_number));
         }
-
-        let mut reader = Reader::new(&self.memory, address);
-        let mut magic_buf: [u8; 4] = [0; 4];
-        let bytes_read = reader
-            .read(&mut magic_buf)
-            // if we hit out of bounds here, this means that the persistent state has not been
-            // written at the expected location and thus cannot be found
-            .map_err(|_| PersistentStateError::NotFound)?;
-
-        if bytes_read != 4 || magic_buf != PERSISTENT_STATE_MAGIC {
-            // less than the expected number of bytes were read or the magic does not match
-            // --> this is not the persistent state
-            return Err(PersistentStateError::NotFound);
+        if self.is_deleted(record_number) {
+            return Err(StorageError::AnchorDeleted { user_number });
         }
-
-        let mut size_buf: [u8; 8] = [0; 8];
-        let bytes_read = reader
-            .read(&mut size_buf)
-            .map_err(|err| PersistentStateError::ReadError(err))? as u64;
-
-        // check if we actually read the required amount of data
-        // note: this will only happen if we hit the memory bounds during read
-        if bytes_read != 8 {
-            let max_address = address + 4 + bytes_read;
-            return Err(PersistentStateError::ReadError(OutOfBounds {
-                max_address,
-                attempted_read_address: max_address + 1,
-            }));
-        }
-
-        let size = u64::from_le_bytes(size_buf);
-        let mut data_buf = Vec::new();
-        data_buf.resize(size as usize, 0);
-        let bytes_read = reader
-            .read(data_buf.as_mut_slice())
-            .map_err(|err| PersistentStateError::ReadError(err))? as u64;
-
-        // check if we actually read the required amount of data
-        // note: this will only happen if we hit the memory bounds during read
-        if bytes_read != size {
-            let max_address = address + 4 + 8 + bytes_read;
-            return Err(PersistentStateError::ReadError(OutOfBounds {
-                max_address,
-                attempted_read_address: max_address + 1,
-            }));
+        if self
+            .principal_index
+            .values()
+            .any(|indexed| *indexed == user_number)
+        {
+            let mut principal_index = self.principal_index.clone();
+            principal_index.retain(|_, indexed| *indexed != user_number);
+            self.write_principal_index(principal_index)?;
         }
 
-        candid::decode_one(&data_buf).map_err(|err| PersistentStateError::CandidError(err))
+        let mut entry = vec![0; self.header.entry_size as usize];
+        entry[..2].copy_from_slice(&ENTRY_TOMBSTONE.to_le_bytes());
+        let address = self.checked_record_address(record_number)?;
+        self.ensure_entry_capacity(address + entry.len() as u64)?;
+        self.anchor_memory().write(address, &entry);
+        self.header.deleted_anchors += 1;
+        self.flush();
+        self.record_write(WriteEvent {
+            user_number,
+            byte_len: 0,
+            timestamp_ns: now_ns(),
+        });
+        Ok(())
     }
 
-    pub fn version(&self) -> u8 {
-        self.header.version
+    /// Returns true if the entry of the given record is the tombstone of a deleted anchor.
+    fn is_deleted(&self, record_number: u32) -> bool {
+        let mut len = [0; 2];
+        self.read_entry(record_number, &mut len);
+        u16::from_le_bytes(len) == ENTRY_TOMBSTONE
     }
-}
+
+    /// Returns the number of allocated anchors with a user number in `range`.
+    ///
+    /// Allocated anchors are contiguous from the lower bound of the assigned range, so this does
+    /// not read any memory.
+    pub fn count_anchors_in_range(&self, range: RangeInclusive<UserNumber>) -> u64 {
+        let allocated_lo = self.header.id_range_lo;
+        let allocated_hi = allocated_lo + self.header.num_users as u64; // exclusive
+        let lo = u64::max(*range.start(), allocated_lo);
+        let hi = u64::min(range.end().saturating_add(1), allocated_hi);
+        hi.saturating_sub(lo)
+    }
+
+    /// Returns whether the given anchor has been allocated, without reading any memory.
+    ///
+    /// Note that an allocated anchor may not have been written yet (see [Storage::allocate_anchor]).
+    pub fn anchor_exists(&self, user_number: UserNumber) -> bool {
+        let allocated_lo = self.header.id_range_lo;
+        let allocated_hi = allocated_lo + self.header.num_users as u64; // exclusive
+        (allocated_lo..allocated_hi).contains(&user_number)
+    }
+
+    /// Returns the range of user numbers `[lo, hi)` managed by this storage.
+    pub fn assigned_user_number_range(&self) -> (UserNumber, UserNumber) {
+        (self.header.id_range_lo, self.header.id_range_hi)
+    }
+
+    /// Extends the upper bound of the anchor range to `new_hi`.
+    ///
+    /// The range can only grow and must still fit into stable memory (before the reserve) given
+    /// the entry size.
+    pub fn extend_range(&mut self, new_hi: UserNumber) -> Result<(), StorageError> {
+        let (id_range_lo, id_range_hi) = self.assigned_user_number_range();
+        if new_hi < id_range_hi {
+            return Err(StorageError::RangeShrinkNotAllowed {
+                range: (id_range_lo, id_range_hi),
+                new_hi,
+            });
+        }
+        self.set_range_upper(new_hi)
+    }
+
+    /// Sets the upper bound of the anchor range to `new_hi`.
+    ///
+    /// Contrary to [Storage::extend_range], the range may also shrink as long as all allocated
+    /// anchors remain within the range. The range must still fit into stable memory (before the
+    /// reserve) given the entry size.
+    pub fn set_range_upper(&mut self, new_hi: UserNumber) -> Result<(), StorageError> {
+        let (id_range_lo, id_range_hi) = self.assigned_user_number_range();
+        let num_users = self.header.num_users;
+        if new_hi < id_range_lo + num_users as u64 {
+            return Err(StorageError::RangeShrinkWouldOrphanRecords {
+                range: (id_range_lo, id_range_hi),
+                new_hi,
+                num_users,
+            });
+        }
+
+        // if an entry size migration is ongoing, the range must also fit the new entry size
+        let entry_size = u16::max(
+            self.header.entry_size,
+            self.header.entry_size_migration_target,
+        );
+        let max_size = max_range_size_in(
+            entry_size,
+            self.stable_memory_size,
+            self.stable_memory_reserve(),
+        );
+        if new_hi - id_range_lo > max_size {
+            return Err(StorageError::RangeTooLarge {
+                range: (id_range_lo, new_hi),
+                max_size,
+            });
+        }
+
+        self.header.id_range_hi = new_hi;
+        self.flush();
+        Ok(())
+    }
+
+    /// Returns the size of a single anchor entry in bytes.
+    pub fn max_entry_size(&self) -> u16 {
+        self.header.entry_size
+    }
+
+    /// Returns the number of anchors that can still be allocated.
+    pub fn remaining_capacity(&self) -> u64 {
+        let (id_range_lo, id_range_hi) = self.assigned_user_number_range();
+        (id_range_hi - id_range_lo) - self.header.num_users as u64
+    }
+
+    /// Returns the size of the stable memory reserve following the entries of the anchor range.
+    pub fn stable_memory_reserve(&self) -> u64 {
+        match self.header.stable_memory_reserve {
+            0 => STABLE_MEMORY_RESERVE,
+            reserve => reserve,
+        }
+    }
+
+    /// Returns the fields of the header for debugging, without the salts (see [Header]).
+    pub fn describe(&self) -> String {
+        format!("{:?}", self.header)
+    }
+
+    /// Returns a summary of the stable memory usage.
+    pub fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            num_users: self.header.num_users,
+            entry_size: self.header.entry_size,
+            bytes_used: self.unused_memory_start(),
+            bytes_reserved: self.stable_memory_reserve(),
+            total_allocated_pages: self.memory.size(),
+            remaining_capacity: self.remaining_capacity(),
+        }
+    }
+
+    /// Returns the total length of the stored records of all anchors, i.e. the bytes actually
+    /// used by the (possibly compressed) candid encoded records as opposed to the bytes allocated
+    /// for their entries. Only the length prefix of each entry is read, nothing is decoded.
+    ///
+    /// Deleted anchors and anchors that have not been written yet count as empty. With hashed
+    /// placement, the entries are not related to the allocated anchors and the total is 0.
+    pub fn total_payload_bytes(&self) -> u64 {
+        if self.hashed_placement_enabled() {
+            return 0;
+        }
+        let mut flags = ENTRY_CHECKSUM_FLAG;
+        if self.record_versions_enabled() {
+            flags |= ENTRY_RECORD_VERSION_FLAG;
+        }
+        (0..self.header.num_users)
+            .map(|record_number| {
+                let mut len = [0; 2];
+                self.read_entry(record_number, &mut len);
+                match u16::from_le_bytes(len) {
+                    ENTRY_TOMBSTONE => 0,
+                    len_field => (len_field & !flags) as u64,
+                }
+            })
+            .sum()
+    }
+
+    /// Allocates a fresh anchor.
+    ///
+    /// Returns the allocated user number together with its record number or `None` if the
+    /// anchor range is exhausted. The length prefix of the new entry is zeroed so that stale
+    /// data at its location (such as a previously written [PersistentState]) can never be
+    /// mistaken for an anchor record.
+    pub fn allocate_anchor(&mut self) -> Option<(UserNumber, u32)> {
+        if self.hashed_placement_enabled() {
+            return self.allocate_hashed_anchor().ok();
+        }
+        let record_number = self.allocate_record().ok()?;
+
+        let address = self.record_address(record_number);
+        self.write_anchor_memory(address, &0u16.to_le_bytes());
+
+        Some((
+            self.header.id_range_lo + record_number as u64,
+            record_number,
+        ))
+    }
+
+    /// Increments the number of allocated anchors, returning the record number of the newly
+    /// allocated anchor or [StorageError::CapacityExhausted] if the anchor range is full.
+    ///
+    /// All anchors allocated one at a time are allocated here, so that the number of anchors never
+    /// exceeds the range.
+    fn allocate_record(&mut self) -> Result<u32, StorageError> {
+        let record_number = self.header.num_users;
+        let (id_range_lo, id_range_hi) = self.assigned_user_number_range();
+        match id_range_lo.checked_add(record_number as u64) {
+            Some(user_number) if user_number < id_range_hi => {}
+            _ => {
+                return Err(StorageError::CapacityExhausted {
+                    range: (id_range_lo, id_range_hi),
+                })
+            }
+        }
+        self.header.num_users += 1;
+        self.flush_counters();
+        Ok(record_number)
+    }
+
+    /// Writes the anchor record of the given user number to stable memory.
+    ///
+    /// Writing the record directly following the highest allocated anchor allocates a new anchor,
+    /// i.e. it increments the number of users and flushes the header. Writing any record further
+    /// above would leave a gap and is rejected.
+    ///
+    /// Returns an error if the user number is out of range or if the candid encoded record
+    /// does not fit into a single entry.
+    ///
+    /// Every entry is written together with a checksum of its candid encoded record (and the
+    /// current record version if record versions are enabled). Storage of a layout version without
+    /// entry checksums is upgraded first (unless its records are still to be migrated, see
+    /// [Storage::migrate_batch]), so that the new entry can be read back.
+    pub fn write_anchor(
+        &mut self,
+        user_number: UserNumber,
+        anchor: &AnchorRecord,
+    ) -> Result<(), StorageError> {
+        let buf = self.encode_anchor(anchor)?;
+        self.write_raw_entry(user_number, &buf)
+    }
+
+    /// Returns the length of the encoded record (compressed if compression is enabled) if it fits
+    /// into an entry, without writing anything. Fails like [Storage::write_anchor] otherwise.
+    pub fn would_fit(&self, record: &AnchorRecord) -> Result<usize, StorageError> {
+        let len = self.encode_anchor(record)?.len();
+        if len > self.candid_entry_size_limit() {
+            return Err(StorageError::EntrySizeLimitExceeded(len));
+        }
+        Ok(len)
+    }
+
+    fn encode_anchor(&self, anchor: &AnchorRecord) -> Result<Vec<u8>, StorageError> {
+        let buf = candid::encode_one(anchor).map_err(StorageError::SerializationError)?;
+        if self.compression_enabled() {
+            let compressed = compress_record(&buf);
+            if compressed.len() * 100 <= buf.len() * (100 - MIN_COMPRESSION_SAVINGS_PERCENT) {
+                return Ok(compressed);
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Writes an encoded anchor record (e.g. produced by an external encoder) to the entry of the
+    /// given user number, as a record of the current record version.
+    ///
+    /// Like [Storage::write_anchor], this overwrites existing records in place or allocates the
+    /// next anchor, and fails if `buf` exceeds the candid size limit of the entries.
+    pub fn write_raw_entry(
+        &mut self,
+        user_number: UserNumber,
+        buf: &[u8],
+    ) -> Result<(), StorageError> {
+        let record_number = self.user_number_to_record(user_number)?;
+        if record_number > self.header.num_users {
+            return Err(StorageError::BadUserNumber(user_number));
+        }
+        if record_number < self.header.num_users && self.is_deleted(record_number) {
+            return Err(StorageError::AnchorDeleted { user_number });
+        }
+        if buf.len() > self.candid_entry_size_limit() {
+            return Err(StorageError::EntrySizeLimitExceeded(buf.len()));
+        }
+        self.write_entry(record_number, buf)?;
+
+        if record_number == self.header.num_users {
+            self.allocate_record()?;
+        }
+        self.record_write(WriteEvent {
+            user_number,
+            byte_len: buf.len(),
+            timestamp_ns: now_ns(),
+        });
+        Ok(())
+    }
+
+    /// Writes the length prefix (with checksum and record version) and the encoded record `buf`
+    /// to the entry of the given record.
+    fn write_entry(&mut self, record_number: u32, buf: &[u8]) -> Result<(), StorageError> {
+        // length (with flags), optional record version and checksum preceding the candid
+        let mut len = buf.len() as u16 | ENTRY_CHECKSUM_FLAG;
+        let mut record_version = vec![];
+        if self.record_versions_enabled() {
+            len |= ENTRY_RECORD_VERSION_FLAG;
+            record_version.push(CURRENT_RECORD_VERSION);
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&record_version);
+        hasher.update(buf);
+        let mut entry_header = len.to_le_bytes().to_vec();
+        entry_header.extend_from_slice(&record_version);
+        entry_header.extend_from_slice(&hasher.finalize().to_le_bytes());
+
+        // In practice, growing the memory succeeds because the anchor range is chosen such that
+        // all entries fit into the available stable memory. If it does not, the entry is left
+        // untouched rather than having only its header written.
+        let address = self.checked_record_address(record_number)?;
+        let end_address = address
+            .checked_add((entry_header.len() + buf.len()) as u64)
+            .ok_or(StorageError::AddressOverflow { record_number })?;
+        self.ensure_entry_capacity(end_address)?;
+        let anchors = self.anchor_memory();
+        anchors.write(address, &entry_header);
+        anchors.write(address + entry_header.len() as u64, buf);
+        Ok(())
+    }
+
+    /// Returns the most recent anchor writes since this storage was created or read from memory,
+    /// oldest first.
+    pub fn recent_writes(&self) -> &[WriteEvent] {
+        &self.recent_writes
+    }
+
+    /// Sets the number of anchor writes kept by [Storage::recent_writes] (100 by default),
+    /// dropping the oldest writes beyond it. A capacity of 0 disables recording writes.
+    pub fn set_write_log_capacity(&mut self, capacity: usize) {
+        self.write_log_capacity = capacity;
+        let excess = self.recent_writes.len().saturating_sub(capacity);
+        self.recent_writes.drain(..excess);
+    }
+
+    fn record_write(&mut self, event: WriteEvent) {
+        if self.write_log_capacity == 0 {
+            return;
+        }
+        if self.recent_writes.len() == self.write_log_capacity {
+            self.recent_writes.remove(0);
+        }
+        self.recent_writes.push(event);
+    }
+
+    /// Reads the anchor record of the given user number from stable memory.
+    ///
+    /// Returns an error if the user number is out of range, if the checksum of the stored entry
+    /// does not match or if the stored entry cannot be decoded (e.g. because nothing has been
+    /// written to it yet).
+    pub fn read_anchor(&self, user_number: UserNumber) -> Result<AnchorRecord, StorageError> {
+        let record_number = self.user_number_to_record(user_number)?;
+        let mut buf = vec![0; self.header.entry_size as usize];
+        self.read_entry(record_number, &mut buf);
+        self.decode_entry(user_number, &buf)
+    }
+
+    fn hashed_placement_enabled(&self) -> bool {
+        self.header.flags & HEADER_FLAG_HASHED_PLACEMENT != 0
+    }
+
+    /// Places anchors by the hash of the salt and their user number instead of their offset in the
+    /// anchor range, so that the location of an entry does not reveal its user number.
+    ///
+    /// Hashed placement can only be enabled for a storage with a salt and without anchors, and
+    /// cannot be disabled again. Afterwards, anchors are read and written with
+    /// [Storage::read_by_hashed] and [Storage::write_by_hashed] (and allocated with
+    /// [Storage::allocate_anchor]), and [Storage::iter_anchors] and
+    /// [Storage::prune_expired_delegations] look the anchors up the same way. The other accessors
+    /// of single anchors as well as backups, re-encoding, compaction and entry size migrations fail
+    /// with [StorageError::HashedPlacementEnabled]. The salt must not change anymore.
+    #[cfg(test)]
+    pub fn enable_hashed_placement(&mut self) -> Result<(), StorageError> {
+        if self.header.num_users != 0
+            || self.salt().is_none()
+            || self.header.entry_size_migration_target != 0
+        {
+            return Err(StorageError::HashedPlacementUnavailable);
+        }
+        self.header.flags |= HEADER_FLAG_HASHED_PLACEMENT;
+        self.flush();
+        Ok(())
+    }
+
+    /// Reads the anchor record of the given user number from the entry it has been placed in by
+    /// [Storage::write_by_hashed] or [Storage::allocate_anchor].
+    ///
+    /// Returns [StorageError::BadUserNumber] if the anchor has not been allocated and fails like
+    /// [Storage::read_anchor] otherwise.
+    pub fn read_by_hashed(&self, user_number: UserNumber) -> Result<AnchorRecord, StorageError> {
+        if !self.hashed_placement_enabled() {
+            return Err(StorageError::HashedPlacementDisabled);
+        }
+        let (slot, occupied) = self.hashed_slot(user_number)?;
+        if !occupied {
+            return Err(StorageError::BadUserNumber(user_number));
+        }
+        let mut buf = vec![0; self.header.entry_size as usize];
+        self.read_entry(slot, &mut buf);
+        buf.truncate(buf.len() - HASHED_PLACEMENT_TAG_SIZE);
+        self.decode_entry(user_number, &buf)
+    }
+
+    /// Writes the anchor record of the given user number to the entry given by the hash of its
+    /// user number, or the next free entry after it.
+    ///
+    /// Like [Storage::write_anchor], this overwrites existing records in place or allocates the
+    /// next anchor, and rejects writes that would leave a gap in the allocated user numbers.
+    pub fn write_by_hashed(
+        &mut self,
+        user_number: UserNumber,
+        anchor: &AnchorRecord,
+    ) -> Result<(), StorageError> {
+        if !self.hashed_placement_enabled() {
+            return Err(StorageError::HashedPlacementDisabled);
+        }
+        let (slot, occupied) = self.hashed_slot(user_number)?;
+        if !occupied && user_number != self.header.id_range_lo + self.header.num_users as u64 {
+            return Err(StorageError::BadUserNumber(user_number));
+        }
+        let buf = self.encode_anchor(anchor)?;
+        if buf.len() > self.candid_entry_size_limit() {
+            return Err(StorageError::EntrySizeLimitExceeded(buf.len()));
+        }
+
+        // grow the memory up to the tag first, so that no write fails after the entry is written
+        let tag_address = self.slot_tag_address(slot)?;
+        self.ensure_entry_capacity(tag_address + HASHED_PLACEMENT_TAG_SIZE as u64)?;
+        self.write_entry(slot, &buf)?;
+        if !occupied {
+            self.claim_slot(slot, user_number)?;
+        }
+        self.record_write(WriteEvent {
+            user_number,
+            byte_len: buf.len(),
+            timestamp_ns: now_ns(),
+        });
+        Ok(())
+    }
+
+    /// Allocates the next user number and claims its entry, see [Storage::allocate_anchor].
+    fn allocate_hashed_anchor(&mut self) -> Result<(UserNumber, u32), StorageError> {
+        let user_number = self.header.id_range_lo + self.header.num_users as u64;
+        let (slot, _) = self.hashed_slot(user_number)?;
+        let tag_address = self.slot_tag_address(slot)?;
+        self.ensure_entry_capacity(tag_address + HASHED_PLACEMENT_TAG_SIZE as u64)?;
+        let address = self.record_address(slot);
+        self.anchor_memory().write(address, &0u16.to_le_bytes());
+        self.claim_slot(slot, user_number)?;
+        Ok((user_number, slot))
+    }
+
+    /// Writes the tag of the given user number to the free entry `slot` and allocates the anchor.
+    fn claim_slot(&mut self, slot: u32, user_number: UserNumber) -> Result<(), StorageError> {
+        let tag_address = self.slot_tag_address(slot)?;
+        self.write_anchor_memory(tag_address, &(user_number + 1).to_le_bytes());
+        self.allocate_record()?;
+        Ok(())
+    }
+
+    /// Returns the entry of the given user number and whether it is occupied by it: probing from
+    /// the entry given by the hash of the salt and the user number, this is the first entry that
+    /// either holds the user number or is free.
+    fn hashed_slot(&self, user_number: UserNumber) -> Result<(u32, bool), StorageError> {
+        let (id_range_lo, id_range_hi) = self.assigned_user_number_range();
+        if user_number < id_range_lo || user_number >= id_range_hi {
+            return Err(StorageError::UserNumberOutOfRange {
+                user_number,
+                range: (id_range_lo, id_range_hi),
+            });
+        }
+        let slots = id_range_hi - id_range_lo;
+        let start = self.hashed_slot_start(user_number);
+        let mut tag = [0; HASHED_PLACEMENT_TAG_SIZE];
+        for probe in 0..slots {
+            let slot = ((start + probe) % slots) as u32;
+            tag.fill(0);
+            let mut reader = Reader::new(self.anchor_memory(), self.slot_tag_address(slot)?);
+            reader.read(&mut tag).unwrap_or(0);
+            match u64::from_le_bytes(tag) {
+                0 => return Ok((slot, false)),
+                tag if tag == user_number + 1 => return Ok((slot, true)),
+                _ => {}
+            }
+        }
+        Err(StorageError::CapacityExhausted {
+            range: (id_range_lo, id_range_hi),
+        })
+    }
+
+    /// Returns the entry at which probing for the given user number starts.
+    fn hashed_slot_start(&self, user_number: UserNumber) -> u64 {
+        let (id_range_lo, id_range_hi) = self.assigned_user_number_range();
+        let mut hasher = Sha256::new();
+        hasher.update(self.header.salt);
+        hasher.update(user_number.to_le_bytes());
+        let hash: [u8; 32] = hasher.finalize().into();
+        let prefix: [u8; 8] = hash[..8].try_into().expect("bug: hash too short");
+        u64::from_le_bytes(prefix) % (id_range_hi - id_range_lo)
+    }
+
+    fn slot_tag_address(&self, slot: u32) -> Result<u64, StorageError> {
+        let end = self
+            .checked_record_address(slot)?
+            .checked_add(self.header.entry_size as u64)
+            .ok_or(StorageError::AddressOverflow {
+                record_number: slot,
+            })?;
+        Ok(end - HASHED_PLACEMENT_TAG_SIZE as u64)
+    }
+
+    /// Returns the SHA-256 hash of the entry of the given user number, from its length prefix to
+    /// the end of the encoded record, e.g. to verify that an upgrade left the records untouched.
+    pub fn record_fingerprint(&self, user_number: UserNumber) -> Result<[u8; 32], StorageError> {
+        let record_number = self.user_number_to_record(user_number)?;
+        let mut buf = vec![0; self.header.entry_size as usize];
+        self.read_entry(record_number, &mut buf);
+        let (_, data) = self.entry_data_range(user_number, &buf)?;
+        Ok(Sha256::digest(&buf[..data.end]).into())
+    }
+
+    /// Returns the encoded record of the given user number without decoding it, e.g. for tooling
+    /// transcoding records this version can no longer decode. Compressed records are returned as
+    /// stored, i.e. compressed.
+    ///
+    /// The length and the checksum of the entry are verified, so the returned bytes never exceed
+    /// the entry.
+    pub fn read_raw_entry(&self, user_number: UserNumber) -> Result<Vec<u8>, StorageError> {
+        let record_number = self.user_number_to_record(user_number)?;
+        let mut buf = vec![0; self.header.entry_size as usize];
+        self.read_entry(record_number, &mut buf);
+        let (_, data) = self.parse_entry(user_number, &buf)?;
+        Ok(data.to_vec())
+    }
+
+    /// Reads up to `limit` consecutive anchor records starting at `start`.
+    ///
+    /// Stops early at the highest allocated anchor, i.e. returns an empty list if `start` lies
+    /// beyond it. Returns an error if `start` is below the anchor range or if any of the records
+    /// cannot be decoded.
+    pub fn read_anchors_range(
+        &self,
+        start: UserNumber,
+        limit: usize,
+    ) -> Result<Vec<(UserNumber, AnchorRecord)>, StorageError> {
+        let (id_range_lo, id_range_hi) = self.assigned_user_number_range();
+        if start < id_range_lo {
+            return Err(StorageError::UserNumberOutOfRange {
+                user_number: start,
+                range: (id_range_lo, id_range_hi),
+            });
+        }
+        let end = id_range_lo + self.header.num_users as u64;
+        let start = start.min(end);
+        let end = end.min(start.saturating_add(limit as u64));
+
+        // a single scratch buffer is reused for all records
+        let mut buf = vec![0; self.header.entry_size as usize];
+        let mut anchors = Vec::with_capacity((end - start) as usize);
+        for user_number in start..end {
+            let record_number = (user_number - id_range_lo) as u32;
+            self.read_entry(record_number, &mut buf);
+            anchors.push((user_number, self.decode_entry(user_number, &buf)?));
+        }
+        Ok(anchors)
+    }
+
+    /// Reads the anchor records of the given user numbers.
+    ///
+    /// The records are read in the order of their addresses such that each run of adjacent
+    /// records is read with a single memory read. The results are returned in the order of
+    /// `user_numbers`.
+    pub fn read_anchors(
+        &self,
+        user_numbers: &[UserNumber],
+    ) -> Vec<Result<AnchorRecord, StorageError>> {
+        let mut results: Vec<Option<Result<AnchorRecord, StorageError>>> =
+            user_numbers.iter().map(|_| None).collect();
+        let mut records = Vec::with_capacity(user_numbers.len());
+        for (index, user_number) in user_numbers.iter().enumerate() {
+            match self.user_number_to_record(*user_number) {
+                Ok(record_number) => records.push((record_number, index)),
+                Err(err) => results[index] = Some(Err(err)),
+            }
+        }
+        records.sort_unstable();
+
+        let entry_size = self.header.entry_size as usize;
+        let mut buf = vec![];
+        let mut run_start = 0;
+        while run_start < records.len() {
+            // extend the run as long as the records are adjacent in memory
+            let first_record = records[run_start].0;
+            let mut run_end = run_start + 1;
+            while run_end < records.len() {
+                let record_number = records[run_end].0;
+                let expected_address = self.record_address(first_record)
+                    + (record_number - first_record) as u64 * entry_size as u64;
+                if record_number - records[run_end - 1].0 > 1
+                    || self.record_address(record_number) != expected_address
+                {
+                    break;
+                }
+                run_end += 1;
+            }
+
+            let run_length = (records[run_end - 1].0 - first_record) as usize + 1;
+            buf.resize(run_length * entry_size, 0);
+            self.read_entry(first_record, &mut buf);
+            for (record_number, index) in &records[run_start..run_end] {
+                let offset = (record_number - first_record) as usize * entry_size;
+                let user_number = user_numbers[*index];
+                results[*index] =
+                    Some(self.decode_entry(user_number, &buf[offset..offset + entry_size]));
+            }
+            run_start = run_end;
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.expect("bug: anchor not read"))
+            .collect()
+    }
+
+    /// Returns an iterator over all allocated anchors in ascending order.
+    ///
+    /// Records are read and decoded lazily, one at a time. Every record yields its own result
+    /// so that a single corrupted entry does not abort the iteration.
+    pub fn iter_anchors(&self) -> AnchorIterator<'_, M> {
+        self.iter_anchors_from(self.header.id_range_lo)
+    }
+
+    /// Returns an iterator over all allocated anchors starting at the given user number, e.g. to
+    /// resume a paginated iteration. User numbers below the anchor range start the iteration at
+    /// the first anchor.
+    pub fn iter_anchors_from(&self, user_number: UserNumber) -> AnchorIterator<'_, M> {
+        let next_record = user_number
+            .saturating_sub(self.header.id_range_lo)
+            .min(self.header.num_users as u64) as u32;
+        AnchorIterator {
+            storage: self,
+            next_record,
+            buf: vec![0; self.header.entry_size as usize],
+        }
+    }
+
+    /// Returns up to `max` allocated anchors in ascending order, starting at the user number
+    /// `cursor`. Unlike [Storage::iter_anchors_from], the returned page does not borrow the storage:
+    /// its `next_cursor` resumes the scan in a later call, even across upgrades. Cursors below the
+    /// anchor range start the scan at the first anchor.
+    pub fn scan_from(&self, cursor: UserNumber, max: usize) -> ScanPage {
+        let mut anchors = self.iter_anchors_from(cursor);
+        let page: Vec<_> = anchors.by_ref().take(max).collect();
+        let next_cursor = (anchors.next_record < self.header.num_users)
+            .then(|| self.header.id_range_lo + anchors.next_record as u64);
+        ScanPage {
+            anchors: page,
+            next_cursor,
+        }
+    }
+
+    /// Returns up to `limit` allocated anchors in ascending order, starting at the given user
+    /// number, for [Storage::import_anchors] into a storage of a possibly different layout.
+    ///
+    /// Fails if any of the anchors cannot be read.
+    pub fn export_anchor_range(
+        &self,
+        start: UserNumber,
+        limit: u16,
+    ) -> Result<Vec<(UserNumber, AnchorRecord)>, StorageError> {
+        self.iter_anchors_from(start)
+            .take(limit as usize)
+            .map(|(user_number, anchor)| anchor.map(|anchor| (user_number, anchor)))
+            .collect()
+    }
+
+    /// Writes the anchors exported by [Storage::export_anchor_range] to their user numbers.
+    ///
+    /// Together with the anchors already allocated, the user numbers must form a contiguous
+    /// sequence within the anchor range, i.e. the anchors must not be allocated yet and continue
+    /// right after the allocated ones. Nothing is written unless all anchors can be imported.
+    pub fn import_anchors(
+        &mut self,
+        mut anchors: Vec<(UserNumber, AnchorRecord)>,
+    ) -> Result<(), StorageError> {
+        if self.header.entry_size_migration_target != 0 {
+            return Err(StorageError::MigrationInProgress);
+        }
+        anchors.sort_unstable_by_key(|(user_number, _)| *user_number);
+
+        let mut entries = Vec::with_capacity(anchors.len());
+        for (user_number, anchor) in &anchors {
+            let record_number = self.user_number_to_record(*user_number)?;
+            if record_number < self.header.num_users {
+                return Err(StorageError::AnchorOccupied {
+                    user_number: *user_number,
+                });
+            }
+            if record_number as usize != self.header.num_users as usize + entries.len() {
+                return Err(StorageError::BadUserNumber(*user_number));
+            }
+            let buf = self.encode_anchor(anchor)?;
+            if buf.len() > self.candid_entry_size_limit() {
+                return Err(StorageError::EntrySizeLimitExceeded(buf.len()));
+            }
+            entries.push((*user_number, buf));
+        }
+
+        // grow the memory for all entries up front, so that no write fails after the first one
+        let end = self.checked_record_address(self.header.num_users + entries.len() as u32)?;
+        self.ensure_entry_capacity(end)?;
+        for (user_number, buf) in entries {
+            self.write_raw_entry(user_number, &buf)?;
+        }
+        Ok(())
+    }
+
+    /// Re-encodes the anchors of up to `batch` records starting at record number `start_record`
+    /// with the current candid types (and compression setting), which drops the bytes of fields
+    /// that are no longer known. An anchor is only written back if its encoding shrank. Records
+    /// that cannot be decoded (e.g. of deleted anchors) are skipped.
+    ///
+    /// Returns the record number to continue with, which is the number of allocated anchors once
+    /// all records have been processed. Every batch reads the records it processes anew, so
+    /// anchors may be changed between batches.
+    ///
+    /// Fails with [StorageError::HashedPlacementEnabled] if hashed placement is enabled.
+    pub fn reencode_anchors(&mut self, start_record: u32, batch: u32) -> Result<u32, StorageError> {
+        if self.hashed_placement_enabled() {
+            return Err(StorageError::HashedPlacementEnabled);
+        }
+        let end = start_record
+            .saturating_add(batch)
+            .min(self.header.num_users);
+        for record_number in start_record..end {
+            let user_number = self.header.id_range_lo + record_number as u64;
+            let stored_len = match self.read_raw_entry(user_number) {
+                Ok(buf) => buf.len(),
+                Err(_) => continue,
+            };
+            let buf = match self
+                .read_anchor(user_number)
+                .and_then(|anchor| self.encode_anchor(&anchor))
+            {
+                Ok(buf) => buf,
+                Err(_) => continue,
+            };
+            if buf.len() < stored_len && self.write_raw_entry(user_number, &buf).is_ok() {
+                self.reencoding_freed_bytes += (stored_len - buf.len()) as u64;
+            }
+        }
+        Ok(end)
+    }
+
+    /// Returns the number of bytes freed by [Storage::reencode_anchors] since this storage was
+    /// created or read from memory.
+    pub fn reencoding_freed_bytes(&self) -> u64 {
+        self.reencoding_freed_bytes
+    }
+
+    /// Removes all delegations that expired before `now_ns` from the stored records.
+    ///
+    /// Records that cannot be decoded are skipped. Returns the number of pruned delegations.
+    pub fn prune_expired_delegations(&mut self, now_ns: u64) -> u64 {
+        let id_range_lo = self.header.id_range_lo;
+        let hashed = self.hashed_placement_enabled();
+        let mut pruned = 0;
+        for offset in 0..self.header.num_users {
+            let user_number = id_range_lo + offset as u64;
+            let anchor = if hashed {
+                self.read_by_hashed(user_number)
+            } else {
+                self.read_anchor(user_number)
+            };
+            let mut anchor = match anchor {
+                Ok(anchor) => anchor,
+                Err(_) => continue,
+            };
+            let delegations = match anchor.delegations.as_mut() {
+                Some(delegations) => delegations,
+                None => continue,
+            };
+
+            let count = delegations.len();
+            delegations.retain(|delegation| delegation.expiration >= now_ns);
+            let removed = (count - delegations.len()) as u64;
+            if removed == 0 {
+                continue;
+            }
+            let written = if hashed {
+                self.write_by_hashed(user_number, &anchor)
+            } else {
+                self.write_anchor(user_number, &anchor)
+            };
+            if written.is_ok() {
+                pruned += removed;
+            }
+        }
+        pruned
+    }
+
+    /// Decodes the records of all allocated anchors, e.g. to check the health of the storage after
+    /// an upgrade, and reports the records that fail to decode instead of stopping at the first.
+    ///
+    /// Deleted anchors and anchors that have been allocated but never written are skipped.
+    pub fn verify_all(&self) -> VerifyReport {
+        let mut report = VerifyReport {
+            ok_count: 0,
+            failures: vec![],
+            failure_count: 0,
+        };
+        let entry_size = self.header.entry_size as usize;
+        let entry_len = if self.hashed_placement_enabled() {
+            entry_size - HASHED_PLACEMENT_TAG_SIZE
+        } else {
+            entry_size
+        };
+        let mut buf = vec![0; entry_size];
+        for offset in 0..self.header.num_users {
+            let user_number = self.header.id_range_lo + offset as u64;
+            let record_number = if self.hashed_placement_enabled() {
+                self.hashed_slot(user_number).map(|(slot, _)| slot)
+            } else {
+                Ok(offset)
+            };
+            let result = record_number.and_then(|record_number| {
+                self.read_entry(record_number, &mut buf);
+                let entry = &buf[..entry_len];
+                match self.parse_entry(user_number, entry) {
+                    Ok((_, [])) | Err(StorageError::AnchorDeleted { .. }) => Ok(false),
+                    _ => self.decode_entry(user_number, entry).map(|_| true),
+                }
+            });
+            match result {
+                Ok(true) => report.ok_count += 1,
+                Ok(false) => {}
+                Err(err) => {
+                    report.failure_count += 1;
+                    if report.failures.len() < MAX_VERIFY_FAILURES {
+                        report.failures.push((user_number, err.to_string()));
+                    }
+                }
+            }
+        }
+        report
+    }
+
+    /// Deallocates the anchors at the end of the allocated anchors that have never been written,
+    /// so that their user numbers are assigned again by [Storage::allocate_anchor]. Their
+    /// principals are removed from the principal index.
+    ///
+    /// Anchors that have been written are never deallocated, even if they hold no devices,
+    /// delegations or metadata anymore, as their user number has been handed out. Neither are
+    /// deleted anchors, see [Storage::delete_anchor]. Only trailing records are removed to keep the
+    /// allocated anchors contiguous, compaction stops at the first record that has been written.
+    ///
+    /// Fails with [StorageError::HashedPlacementEnabled] if hashed placement is enabled, as the
+    /// trailing records are not those of the highest user numbers then.
+    pub fn compact(&mut self) -> Result<CompactionReport, StorageError> {
+        if self.hashed_placement_enabled() {
+            return Err(StorageError::HashedPlacementEnabled);
+        }
+        let used_before = self.unused_memory_start();
+        let mut buf = vec![0; self.header.entry_size as usize];
+        let mut reclaimed_records = 0;
+        while self.header.num_users > 0 {
+            let record_number = self.header.num_users - 1;
+            let user_number = self.header.id_range_lo + record_number as u64;
+            self.read_entry(record_number, &mut buf);
+            if !matches!(self.parse_entry(user_number, &buf), Ok((_, []))) {
+                break;
+            }
+            self.header.num_users -= 1;
+            reclaimed_records += 1;
+        }
+
+        if reclaimed_records > 0 {
+            let first_reclaimed = self.header.id_range_lo + self.header.num_users as u64;
+            if self
+                .principal_index
+                .values()
+                .any(|indexed| *indexed >= first_reclaimed)
+            {
+                let mut principal_index = self.principal_index.clone();
+                principal_index.retain(|_, indexed| *indexed < first_reclaimed);
+                // also writes the number of users with the header
+                self.write_principal_index(principal_index)?;
+            } else {
+                self.flush_counters();
+            }
+        }
+        Ok(CompactionReport {
+            reclaimed_records,
+            bytes_reclaimed: used_before - self.unused_memory_start(),
+        })
+    }
+
+    /// Reads the entry of the given record into `buf` using a single memory read.
+    ///
+    /// Entries beyond the end of the memory have never been written and read as zeros,
+    /// just like freshly grown stable memory.
+    fn read_entry(&self, record_number: u32, buf: &mut [u8]) {
+        buf.fill(0);
+        let mut reader = Reader::new(self.anchor_memory(), self.record_address(record_number));
+        reader.read(buf).unwrap_or(0);
+    }
+
+    /// Decodes the anchor record from a buffer holding an entire entry.
+    fn decode_entry(
+        &self,
+        user_number: UserNumber,
+        entry: &[u8],
+    ) -> Result<AnchorRecord, StorageError> {
+        let (record_version, data) = self.parse_entry(user_number, entry)?;
+        if record_version > CURRENT_RECORD_VERSION {
+            return Err(StorageError::UnsupportedRecordVersion {
+                user_number,
+                version: record_version,
+            });
+        }
+        let decompressed;
+        let data = match split_compressed_record(data) {
+            Some((len, frame)) => {
+                decompressed = decompress_frame(len, frame)
+                    .ok_or(StorageError::BadCompressedRecord { user_number })?;
+                &decompressed
+            }
+            None => data,
+        };
+
+        // Records that have not been migrated yet may also have been overwritten using the new
+        // layout in the meantime, so the new layout is tried as well.
+        if self.is_legacy_record(user_number) {
+            if let Ok(devices) = candid::decode_one::<Vec<DeviceData>>(data) {
+                return Ok(AnchorRecord {
+                    devices,
+                    delegations: None,
+                    metadata: None,
+                });
+            }
+        }
+        candid::decode_one(data).map_err(StorageError::DeserializationError)
+    }
+
+    /// Splits a buffer holding an entire entry into the record version and the encoded record,
+    /// verifying the length and the checksum of the entry.
+    fn parse_entry<'a>(
+        &self,
+        user_number: UserNumber,
+        entry: &'a [u8],
+    ) -> Result<(u8, &'a [u8]), StorageError> {
+        let (record_version, data) = self.entry_data_range(user_number, entry)?;
+        Ok((record_version, &entry[data]))
+    }
+
+    /// Like [Storage::parse_entry], but returns the range of the data within `entry`.
+    fn entry_data_range(
+        &self,
+        user_number: UserNumber,
+        entry: &[u8],
+    ) -> Result<(u8, Range<usize>), StorageError> {
+        let mut flags = ENTRY_CHECKSUM_FLAG;
+        if self.record_versions_enabled() {
+            flags |= ENTRY_RECORD_VERSION_FLAG;
+        }
+        let len_field = u16::from_le_bytes([entry[0], entry[1]]);
+        if len_field == ENTRY_TOMBSTONE {
+            return Err(StorageError::AnchorDeleted { user_number });
+        }
+        let len = (len_field & !flags) as usize;
+
+        let mut data_start = std::mem::size_of::<u16>();
+        let mut record_version = 0;
+        if len_field & flags & ENTRY_RECORD_VERSION_FLAG != 0 {
+            record_version = entry[data_start];
+            data_start += 1;
+        }
+        let checksum_start = data_start;
+        if len_field & ENTRY_CHECKSUM_FLAG != 0 {
+            data_start += ENTRY_CHECKSUM_SIZE;
+        }
+        // A length prefix larger than the entry can only be the result of corruption. Reading
+        // it would yield data belonging to the next entry.
+        if len > entry.len() - data_start {
+            return Err(StorageError::BadEntryLength {
+                user_number,
+                length: len,
+            });
+        }
+
+        let data_range = data_start..data_start + len;
+        let data = &entry[data_range.clone()];
+        if len_field & ENTRY_CHECKSUM_FLAG != 0 {
+            let checksum =
+                u32::from_le_bytes(entry[checksum_start..data_start].try_into().unwrap());
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&entry[2..checksum_start]);
+            hasher.update(data);
+            if checksum != hasher.finalize() {
+                return Err(StorageError::ChecksumMismatch { user_number });
+            }
+        }
+        Ok((record_version, data_range))
+    }
+
+    /// Returns the record version of the entry of the given user number.
+    pub fn record_version(&self, user_number: UserNumber) -> Result<u8, StorageError> {
+        let record_number = self.user_number_to_record(user_number)?;
+        let mut buf = vec![0; self.header.entry_size as usize];
+        self.read_entry(record_number, &mut buf);
+        self.parse_entry(user_number, &buf)
+            .map(|(record_version, _)| record_version)
+    }
+
+    fn compression_enabled(&self) -> bool {
+        self.header.flags & HEADER_FLAG_COMPRESSION != 0
+    }
+
+    /// Enables or disables the compression of the anchor records written from now on. Records
+    /// are read regardless of whether they were compressed, so this can be changed at any time.
+    pub fn set_compression(&mut self, enabled: bool) {
+        if enabled {
+            self.header.flags |= HEADER_FLAG_COMPRESSION;
+        } else {
+            self.header.flags &= !HEADER_FLAG_COMPRESSION;
+        }
+        self.flush();
+    }
+
+    fn record_versions_enabled(&self) -> bool {
+        self.header.flags & HEADER_FLAG_RECORD_VERSIONS != 0
+    }
+
+    /// Enables record versions: all entries written from now on start with a record version
+    /// byte, so that [Storage::read_anchor] can decode different record encodings side by side.
+    ///
+    /// Record versions cannot be disabled again and require an entry size of at most 16 KiB.
+    pub fn enable_record_versions(&mut self) -> Result<(), StorageError> {
+        let entry_size = u16::max(
+            self.header.entry_size,
+            self.header.entry_size_migration_target,
+        );
+        if entry_size > MAX_RECORD_VERSION_ENTRY_SIZE {
+            return Err(StorageError::InvalidEntrySize(entry_size));
+        }
+        self.header.flags |= HEADER_FLAG_RECORD_VERSIONS;
+        self.flush();
+        Ok(())
+    }
+
+    pub fn backup_mode(&self) -> bool {
+        self.header.flags & HEADER_FLAG_BACKUP_MODE != 0
+    }
+
+    /// Enables or disables [Storage::restore_anchors]. Anchors should not be registered while
+    /// the backup mode is enabled, as restored entries may overwrite them.
+    pub fn set_backup_mode(&mut self, enabled: bool) {
+        if enabled {
+            self.header.flags |= HEADER_FLAG_BACKUP_MODE;
+        } else {
+            self.header.flags &= !HEADER_FLAG_BACKUP_MODE;
+        }
+        self.flush();
+    }
+
+    /// Marks the start of an upgrade that changes the stored state in several steps (e.g. writing
+    /// the persistent state and migrating the anchors). Until [Storage::end_upgrade] is called,
+    /// reading the memory fails with [HeaderError::UpgradeNotFinalized], so that an interrupted
+    /// upgrade is noticed instead of its partial state being used.
+    pub fn begin_upgrade(&mut self) {
+        self.header.flags |= HEADER_FLAG_UPGRADE_IN_PROGRESS;
+        self.flush();
+    }
+
+    /// Marks the end of the upgrade started with [Storage::begin_upgrade].
+    pub fn end_upgrade(&mut self) {
+        self.header.flags &= !HEADER_FLAG_UPGRADE_IN_PROGRESS;
+        self.flush();
+    }
+
+    /// Returns the raw entries of up to `count` allocated anchors, starting with the entry of
+    /// record number `offset`, and at most [MAX_BACKUP_CHUNK_SIZE] bytes. Returns no bytes once
+    /// `offset` reaches the number of allocated anchors.
+    pub fn backup_anchors(&self, offset: u32, count: u32) -> Result<Vec<u8>, StorageError> {
+        if self.hashed_placement_enabled() {
+            return Err(StorageError::HashedPlacementEnabled);
+        }
+        if self.header.entry_size_migration_target != 0 {
+            return Err(StorageError::MigrationInProgress);
+        }
+        let entry_size = self.header.entry_size as usize;
+        let count = (count as usize)
+            .min(self.header.num_users.saturating_sub(offset) as usize)
+            .min(MAX_BACKUP_CHUNK_SIZE / entry_size);
+        let mut buf = vec![0; count * entry_size];
+        let mut reader = Reader::new(self.anchor_memory(), self.checked_record_address(offset)?);
+        // entries beyond the end of the region have never been written and read as zeros
+        reader.read(&mut buf).unwrap_or(0);
+        Ok(buf)
+    }
+
+    /// Writes back the raw entries returned by [Storage::backup_anchors], starting with the entry
+    /// of record number `offset`, and updates the number of allocated anchors accordingly. Only
+    /// allowed in backup mode, see [Storage::set_backup_mode].
+    ///
+    /// The entries are not validated: they must have been backed up from a storage with the same
+    /// entry size and record versions setting.
+    pub fn restore_anchors(&mut self, offset: u32, bytes: &[u8]) -> Result<(), StorageError> {
+        if !self.backup_mode() {
+            return Err(StorageError::BackupModeDisabled);
+        }
+        if self.hashed_placement_enabled() {
+            return Err(StorageError::HashedPlacementEnabled);
+        }
+        if self.header.entry_size_migration_target != 0 {
+            return Err(StorageError::MigrationInProgress);
+        }
+        let entry_size = self.header.entry_size as usize;
+        if !bytes.len().is_multiple_of(entry_size) || bytes.len() > MAX_BACKUP_CHUNK_SIZE {
+            return Err(StorageError::BadBackupChunk {
+                length: bytes.len(),
+            });
+        }
+        // restoring must not leave unallocated entries between the allocated ones
+        if offset > self.header.num_users {
+            return Err(StorageError::BadUserNumber(
+                self.header.id_range_lo + offset as u64,
+            ));
+        }
+        let count = (bytes.len() / entry_size) as u32;
+        let end = offset + count;
+        let (id_range_lo, id_range_hi) = self.assigned_user_number_range();
+        if id_range_lo + end as u64 > id_range_hi {
+            return Err(StorageError::UserNumberOutOfRange {
+                user_number: id_range_lo + end as u64 - 1,
+                range: (id_range_lo, id_range_hi),
+            });
+        }
+
+        let address = self.checked_record_address(offset)?;
+        let end_address = address
+            .checked_add(bytes.len() as u64)
+            .ok_or(StorageError::AddressOverflow { record_number: end })?;
+        self.ensure_entry_capacity(end_address)?;
+        self.anchor_memory().write(address, bytes);
+        self.header.num_users = self.header.num_users.max(end);
+        self.flush_counters();
+        Ok(())
+    }
+
+    /// Returns whether the record of the given user number might still be in the vec<device>
+    /// layout of versions 3 and 4, see [HEADER_FLAG_LEGACY_RECORDS].
+    fn is_legacy_record(&self, user_number: UserNumber) -> bool {
+        let record_number = user_number - self.header.id_range_lo;
+        self.legacy_records()
+            && (self.header.new_layout_start == 0
+                || record_number < self.header.new_layout_start as u64)
+    }
+
+    /// Returns whether records may still be in the vec<device> layout: all of them if the
+    /// migration has not been started yet (`new_layout_start` is 0), otherwise the ones below
+    /// `new_layout_start`.
+    fn legacy_records(&self) -> bool {
+        self.header.flags & HEADER_FLAG_LEGACY_RECORDS != 0
+    }
+
+    /// Sets the number of records converted per call of [Storage::migrate_batch].
+    pub fn set_migration_batch_size(&mut self, batch_size: u32) {
+        self.header.migration_batch_size = batch_size;
+        self.flush();
+    }
+
+    /// Converts the next `migration_batch_size` records from the vec<device> layout to the
+    /// candid anchor record layout, starting with the highest record number below
+    /// `new_layout_start`. A migration that has not been started (layout version 3 before layout
+    /// version 16) starts with the highest allocated record. Once all records have been converted,
+    /// the records are no longer marked as legacy records (layout version 5 before layout version
+    /// 16).
+    ///
+    /// Records that cannot be decoded or no longer fit into their entry are left as they are.
+    ///
+    /// Returns an error if the batch size has not been set.
+    pub fn migrate_batch(&mut self) -> Result<MigrationProgress, StorageError> {
+        if !self.legacy_records() {
+            return Ok(MigrationProgress {
+                migrated: 0,
+                remaining: 0,
+                done: true,
+            });
+        }
+        if self.header.migration_batch_size == 0 {
+            return Err(StorageError::InvalidMigrationBatchSize(0));
+        }
+        if self.header.new_layout_start == 0 {
+            self.header.new_layout_start = self.header.num_users;
+        }
+
+        let new_layout_start = self.header.new_layout_start;
+        let batch_start = new_layout_start.saturating_sub(self.header.migration_batch_size);
+        for record_number in (batch_start..new_layout_start).rev() {
+            self.migrate_record(record_number).unwrap_or(());
+            self.header.new_layout_start = record_number;
+        }
+
+        let remaining = self.header.new_layout_start;
+        if remaining == 0 {
+            self.header.flags &= !HEADER_FLAG_LEGACY_RECORDS;
+        }
+        self.flush();
+        Ok(MigrationProgress {
+            migrated: new_layout_start - batch_start,
+            remaining,
+            done: remaining == 0,
+        })
+    }
+
+    /// Converts all remaining records from the vec<device> layout to the candid anchor record
+    /// layout at once. Does nothing if the storage already uses the candid layout.
+    ///
+    /// Like [Storage::migrate_batch], this continues a migration started before at
+    /// `new_layout_start` and leaves records that cannot be decoded (e.g. because nothing has been
+    /// written to them yet) as they are. If a record can no longer be written, the progress made
+    /// so far is persisted and the error is returned, so the upgrade can be resumed later.
+    pub fn upgrade_layout(&mut self) -> Result<(), StorageError> {
+        if !self.legacy_records() {
+            return Ok(());
+        }
+        if self.header.new_layout_start == 0 {
+            self.header.new_layout_start = self.header.num_users;
+        }
+
+        while self.header.new_layout_start > 0 {
+            let record_number = self.header.new_layout_start - 1;
+            if let Err(err) = self.migrate_record(record_number) {
+                self.flush();
+                return Err(err);
+            }
+            self.header.new_layout_start = record_number;
+        }
+        self.header.flags &= !HEADER_FLAG_LEGACY_RECORDS;
+        self.flush();
+        Ok(())
+    }
+
+    /// Rewrites a record of the vec<device> layout in the candid anchor record layout. Records
+    /// that cannot be decoded are skipped.
+    fn migrate_record(&mut self, record_number: u32) -> Result<(), StorageError> {
+        let user_number = self.header.id_range_lo + record_number as u64;
+        match self.read_anchor(user_number) {
+            Ok(anchor) => self.write_anchor(user_number, &anchor),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Returns the state of the migration from the vec<device> to the candid anchor record layout.
+    pub fn layout_migration_state(&self) -> MigrationState {
+        if !self.legacy_records() {
+            return MigrationState::Finished;
+        }
+        match self.header.new_layout_start {
+            0 => MigrationState::NotStarted,
+            anchors_left => MigrationState::Started {
+                anchors_left: anchors_left as u64,
+                batch_size: self.header.migration_batch_size as u64,
+            },
+        }
+    }
+
+    fn user_number_to_record(&self, user_number: UserNumber) -> Result<u32, StorageError> {
+        if self.hashed_placement_enabled() {
+            return Err(StorageError::HashedPlacementEnabled);
+        }
+        let (id_range_lo, id_range_hi) = (self.header.id_range_lo, self.header.id_range_hi);
+        if user_number < id_range_lo || user_number >= id_range_hi {
+            return Err(StorageError::UserNumberOutOfRange {
+                user_number,
+                range: (id_range_lo, id_range_hi),
+            });
+        }
+        u32::try_from(user_number - id_range_lo)
+            .map_err(|_| StorageError::BadUserNumber(user_number))
+    }
+
+    /// Starts growing the entry size of all records to `new_entry_size`.
+    ///
+    /// The records are moved to their new location in batches using [Storage::migrate_entry_size].
+    /// Until the migration is complete, the candid size limit of all entries remains the one of
+    /// the old entry size.
+    pub fn start_entry_size_migration(&mut self, new_entry_size: u16) -> Result<(), StorageError> {
+        if self.hashed_placement_enabled() {
+            return Err(StorageError::HashedPlacementEnabled);
+        }
+        if self.header.entry_size_migration_target != 0 {
+            return Err(StorageError::MigrationInProgress);
+        }
+        if new_entry_size <= self.header.entry_size
+            || !new_entry_size.is_power_of_two()
+            || new_entry_size > MAX_ENTRY_SIZE
+            || (self.record_versions_enabled() && new_entry_size > MAX_RECORD_VERSION_ENTRY_SIZE)
+        {
+            return Err(StorageError::InvalidEntrySize(new_entry_size));
+        }
+        let (id_range_lo, id_range_hi) = (self.header.id_range_lo, self.header.id_range_hi);
+        let max_size = max_range_size_in(
+            new_entry_size,
+            self.stable_memory_size,
+            self.stable_memory_reserve(),
+        );
+        if id_range_hi - id_range_lo > max_size {
+            return Err(StorageError::RangeTooLarge {
+                range: (id_range_lo, id_range_hi),
+                max_size,
+            });
+        }
+
+        self.header.entry_size_migration_target = new_entry_size;
+        self.header.entry_size_migration_cursor = self.header.num_users;
+        self.migrate_entry_size(0);
+        Ok(())
+    }
+
+    /// Moves up to `batch` records to the location given by the new entry size, starting with
+    /// the highest record number so that no record is overwritten before it has been moved.
+    ///
+    /// Returns the number of records that still need to be moved.
+    pub fn migrate_entry_size(&mut self, batch: u32) -> u32 {
+        if self.header.entry_size_migration_target == 0 {
+            return 0;
+        }
+
+        let cursor = self.header.entry_size_migration_cursor;
+        let mut buf = vec![0; self.header.entry_size as usize];
+        for record_number in (cursor.saturating_sub(batch)..cursor).rev() {
+            let old_address = self.record_address(record_number);
+            let mut reader = Reader::new(self.anchor_memory(), old_address);
+            buf.fill(0);
+            reader.read(&mut buf).unwrap_or(0);
+
+            self.header.entry_size_migration_cursor = record_number;
+            let new_address = self.record_address(record_number);
+            self.write_anchor_memory(new_address, &buf);
+        }
+
+        if self.header.entry_size_migration_cursor == 0 {
+            self.header.entry_size = self.header.entry_size_migration_target;
+            self.header.entry_size_migration_target = 0;
+        }
+        self.flush();
+        self.header.entry_size_migration_cursor
+    }
+
+    /// Returns the offset of the entry of the given anchor in the region of the anchors, or
+    /// [StorageError::UserNumberOutOfRange] if the user number is outside of the assigned range.
+    ///
+    /// The anchor does not need to be allocated: the offset is where its entry is (or will be)
+    /// written, taking an ongoing entry size migration into account.
+    pub fn try_record_address(&self, user_number: UserNumber) -> Result<u64, StorageError> {
+        let record_number = self.user_number_to_record(user_number)?;
+        self.checked_record_address(record_number)
+    }
+
+    /// Like [Storage::checked_record_address], but traps if the offset overflows.
+    fn record_address(&self, record_number: u32) -> u64 {
+        self.checked_record_address(record_number)
+            .unwrap_or_else(|err| trap(&err.to_string()))
+    }
+
+    /// Returns the offset of the given record in the region of the anchors or
+    /// [StorageError::AddressOverflow] if it does not fit into a `u64`.
+    fn checked_record_address(&self, record_number: u32) -> Result<u64, StorageError> {
+        let entry_size = if self.header.entry_size_migration_target != 0
+            && record_number >= self.header.entry_size_migration_cursor
+        {
+            self.header.entry_size_migration_target
+        } else {
+            self.header.entry_size
+        };
+        (record_number as u64)
+            .checked_mul(entry_size as u64)
+            .ok_or(StorageError::AddressOverflow { record_number })
+    }
+
+    /// The anchor space is divided into the following parts:
+    /// * 2 bytes of candid length (u16 little endian)
+    /// * 1 byte of record version (only if record versions are enabled)
+    /// * 4 bytes of checksum (CRC32 of the record version and the encoded candid, little endian)
+    /// * length bytes of encoded candid
+    /// * 8 bytes of user number tag at the end (only if hashed placement is enabled)
+    ///
+    /// This function returns the length limit of the candid part. During an entry size
+    /// migration, the limit is based on the old (smaller) entry size.
+    pub fn candid_entry_size_limit(&self) -> usize {
+        let record_version_size = if self.record_versions_enabled() { 1 } else { 0 };
+        let tag_size = if self.hashed_placement_enabled() {
+            HASHED_PLACEMENT_TAG_SIZE
+        } else {
+            0
+        };
+        self.header.entry_size as usize
+            - std::mem::size_of::<u16>()
+            - record_version_size
+            - ENTRY_CHECKSUM_SIZE
+            - tag_size
+    }
+
+    /// Returns the offset of the first byte of the region of the anchors not yet allocated to a
+    /// user.
+    fn unused_memory_start(&self) -> u64 {
+        self.record_address(self.header.num_users)
+    }
+
+    /// Returns the number of whole WASM pages of the region of the anchors that lie after the last
+    /// allocated entry, i.e. pages grown for entries that have not been allocated yet.
+    ///
+    /// Stable memory cannot shrink, so this only helps deciding whether migrating to a smaller
+    /// canister is worthwhile.
+    pub fn trailing_free_pages(&self) -> u64 {
+        let start = self.unused_memory_start();
+        self.anchor_memory()
+            .size()
+            .saturating_sub(start.div_ceil(WASM_PAGE_SIZE))
+    }
+
+    /// Returns the address the entries of the whole anchor range would end at if they followed
+    /// [ENTRY_OFFSET] without gaps. The stable memory reserve is accounted for after this
+    /// address, see [Storage::available_reserve].
+    fn anchor_range_end(&self) -> u64 {
+        let (id_range_lo, id_range_hi) = self.assigned_user_number_range();
+        let entry_size = u16::max(
+            self.header.entry_size,
+            self.header.entry_size_migration_target,
+        );
+        ENTRY_OFFSET + (id_range_hi - id_range_lo) * entry_size as u64
+    }
+
+    /// Grows the memory in one step such that it covers all addresses below `up_to_address`, so
+    /// that a write consisting of multiple parts cannot fail halfway through.
+    ///
+    /// Returns [StorageError::MemoryExhausted] with the number of missing pages if the memory
+    /// cannot be grown.
+    fn ensure_capacity(&self, up_to_address: u64) -> Result<(), StorageError> {
+        let required_pages = up_to_address.div_ceil(WASM_PAGE_SIZE);
+        let size = self.memory.size();
+        if required_pages <= size {
+            return Ok(());
+        }
+        let needed_pages = required_pages - size;
+        if self.memory.grow(needed_pages) < 0 {
+            return Err(StorageError::MemoryExhausted { needed_pages });
+        }
+        Ok(())
+    }
+
+    /// Grows `region` such that it covers all offsets below `up_to`. The memory manager traps if
+    /// it cannot grow the stable memory for the buckets it allocates, so the stable memory is grown
+    /// for them first (see [Storage::ensure_capacity]).
+    fn ensure_region_capacity(&self, region: &Region<M>, up_to: u64) -> Result<(), StorageError> {
+        let required_pages = up_to.div_ceil(WASM_PAGE_SIZE);
+        let size = region.size();
+        if required_pages <= size {
+            return Ok(());
+        }
+        let new_buckets =
+            required_pages.div_ceil(BUCKET_SIZE_IN_PAGES) - size.div_ceil(BUCKET_SIZE_IN_PAGES);
+        let buckets = self.regions().allocated_buckets() + new_buckets;
+        self.ensure_capacity(ENTRY_OFFSET + buckets * BUCKET_SIZE_IN_PAGES * WASM_PAGE_SIZE)?;
+        let needed_pages = required_pages - size;
+        if region.grow(needed_pages) < 0 {
+            return Err(StorageError::MemoryExhausted { needed_pages });
+        }
+        Ok(())
+    }
+
+    /// Like [Storage::ensure_region_capacity] for the region of the anchors, but first checks that
+    /// entries ending at `up_to` leave room for the stable memory reserve below the stable memory
+    /// limit.
+    fn ensure_entry_capacity(&self, up_to: u64) -> Result<(), StorageError> {
+        let available = self
+            .stable_memory_size
+            .saturating_sub(self.stable_memory_reserve());
+        if ENTRY_OFFSET + up_to > available {
+            return Err(StorageError::OutOfReserve {
+                needed: ENTRY_OFFSET + up_to,
+                available,
+            });
+        }
+        self.ensure_region_capacity(self.anchor_memory(), up_to)
+    }
+
+    /// Writes `bytes` at `offset` to the region of the anchors, trapping if it cannot be grown.
+    fn write_anchor_memory(&self, offset: u64, bytes: &[u8]) {
+        let region = self.anchor_memory();
+        self.ensure_region_capacity(region, offset + bytes.len() as u64)
+            .unwrap_or_else(|err| trap(&err.to_string()));
+        region.write(offset, bytes);
+    }
+
+    /// Writes the persistent state to the slot that does not hold the last written state and then
+    /// records its slot in the header. This is only used to _temporarily_ save state during
+    /// upgrades.
+    ///
+    /// Every write increments the persistent state epoch recorded in the header and next to the
+    /// magic, so that an older state found in the same slot is never mistaken for the current one.
+    ///
+    /// Returns the number of bytes written (including magic, epoch and size) or an error if the
+    /// memory could not be grown. The header is only updated if the state was written completely.
+    pub fn write_persistent_state(
+        &mut self,
+        state: &PersistentState,
+    ) -> Result<u64, PersistentStateError> {
+        self.write_persistent_state_chunked(state, PERSISTENT_STATE_CHUNK_SIZE)
+    }
+
+    /// Like [Storage::write_persistent_state] but streams the candid encoded state to stable memory
+    /// in chunks of `chunk_size` bytes, without assembling the whole encoding in a buffer first.
+    ///
+    /// Returns an error if the encoded state exceeds the configured maximum size (see
+    /// [Storage::set_max_persistent_state_size]) or does not fit into the stable memory reserve.
+    /// Both are checked before the bytes exceeding them are written.
+    pub fn write_persistent_state_chunked(
+        &mut self,
+        state: &PersistentState,
+        chunk_size: usize,
+    ) -> Result<u64, PersistentStateError> {
+        self.write_persistent_value(state, chunk_size)
+    }
+
+    /// Writes the persistent value to the slot that does not hold the last written state and then
+    /// commits it by recording the slot in the header.
+    fn write_persistent_value<T: CandidType>(
+        &mut self,
+        value: &T,
+        chunk_size: usize,
+    ) -> Result<u64, PersistentStateError> {
+        let written = self.write_persistent_slot(value, chunk_size)?;
+        // Until the header is written, it still points to the previous state in the other slot,
+        // so a state that was not written completely is never read.
+        self.header.persistent_state_epoch = written.epoch;
+        self.header.persistent_state_length = written.length;
+        self.header.active_slot = written.slot;
+        self.header.flags |= HEADER_FLAG_PERSISTENT_STATE_VERSION;
+        self.flush();
+        Ok(written.length)
+    }
+
+    /// Sets the maximum size of the candid encoded persistent state (2 GB by default).
+    pub fn set_max_persistent_state_size(&mut self, max_size: u64) {
+        self.max_persistent_state_size = max_size;
+    }
+
+    /// Sets the stable memory limit of the canister (32 GB by default).
+    ///
+    /// Unlike [StorageBuilder::stable_memory_size], this does not validate the range of the
+    /// storage: writes that would not fit below the limit fail with an `OutOfReserve` error.
+    pub fn set_stable_memory_size(&mut self, bytes: u64) {
+        self.stable_memory_size = bytes;
+    }
+
+    /// Returns the number of bytes of the stable memory reserve that fit below the stable memory
+    /// limit.
+    pub fn available_reserve(&self) -> u64 {
+        self.stable_memory_size
+            .saturating_sub(self.anchor_range_end())
+            .min(self.stable_memory_reserve())
+    }
+
+    /// The slots of the persistent state each take a quarter of the stable memory reserve without
+    /// the archive buffer.
+    fn persistent_state_slot_size(&self) -> u64 {
+        (self.stable_memory_reserve() - ARCHIVE_BUFFER_REGION_SIZE) / 4
+    }
+
+    /// Writes the persistent value with the next epoch to the slot that does not hold the last
+    /// written state, without updating the header. The first state is written to slot 0.
+    ///
+    /// The slots are accounted for at the start of the stable memory reserve: a value must fit
+    /// below the stable memory limit as if slot 1 followed slot 0 there.
+    fn write_persistent_slot<T: CandidType>(
+        &self,
+        value: &T,
+        chunk_size: usize,
+    ) -> Result<PersistentSlotWrite, PersistentStateError> {
+        let slot = match self.header.persistent_state_epoch {
+            0 => 0,
+            _ => 1 - self.header.active_slot,
+        };
+        let slot_size = self.persistent_state_slot_size();
+        let region = &self.regions().persistent_state[slot as usize];
+        let epoch = self.header.persistent_state_epoch + 1;
+
+        // The value goes behind the prefix, which is written last. A value exceeding a limit is
+        // left incomplete in the slot, which is never read without a prefix.
+        let available = self
+            .stable_memory_size
+            .saturating_sub(self.anchor_range_end() + slot as u64 * slot_size);
+        let mut writer = PersistentValueWriter {
+            max_size: slot_size,
+            max_state_size: self.max_persistent_state_size,
+            available,
+            storage: self,
+            region,
+            offset: PERSISTENT_STATE_PREFIX_SIZE,
+            chunk: Vec::with_capacity(chunk_size.max(1)),
+            chunk_size: chunk_size.max(1),
+            size: 0,
+            error: None,
+        };
+        let mut builder = candid::ser::IDLBuilder::new();
+        if let Err(err) = builder
+            .arg(value)
+            .and_then(|builder| builder.serialize(&mut writer))
+        {
+            return Err(writer
+                .error
+                .take()
+                .unwrap_or(PersistentStateError::CandidError(err)));
+        }
+        let size = writer.finish()?;
+
+        // The prefix fits into the region grown for the value behind it.
+        region.write(
+            0,
+            &persistent_state_prefix(CURRENT_PERSISTENT_STATE_VERSION, epoch, size),
+        );
+
+        Ok(PersistentSlotWrite {
+            slot,
+            epoch,
+            length: PERSISTENT_STATE_PREFIX_SIZE + size,
+        })
+    }
+
+    /// Returns the region holding the credential index, see [credential_index::CredentialIndex].
+    pub fn credential_index_memory(&self) -> Region<M> {
+        self.regions().credential_index.clone()
+    }
+
+    /// Returns the region holding the revoked delegations, see [revocations::RevocationList].
+    pub fn revocations_memory(&self) -> Region<M> {
+        self.regions().revocations.clone()
+    }
+
+    /// Replaces the archive entries kept in the archive region.
+    ///
+    /// Fails without changing the entries if their encoding does not fit into
+    /// [ARCHIVE_BUFFER_REGION_SIZE] bytes.
+    pub fn write_archive_buffer(&mut self, entries: &[ArchiveEntry]) -> Result<(), StorageError> {
+        let encoded = candid::encode_one(entries).map_err(StorageError::SerializationError)?;
+        let max = ARCHIVE_BUFFER_REGION_SIZE - ARCHIVE_BUFFER_PREFIX_SIZE;
+        if encoded.len() as u64 > max {
+            return Err(StorageError::ArchiveBufferFull {
+                size: encoded.len() as u64,
+                max,
+            });
+        }
+        self.write_archive_bytes(&encoded)
+    }
+
+    /// Writes the candid encoded archive entries with their prefix to the archive region.
+    fn write_archive_bytes(&self, encoded: &[u8]) -> Result<(), StorageError> {
+        let region = &self.regions().archive_buffer;
+        self.ensure_region_capacity(region, ARCHIVE_BUFFER_PREFIX_SIZE + encoded.len() as u64)?;
+        let mut bytes = Vec::with_capacity(ARCHIVE_BUFFER_PREFIX_SIZE as usize + encoded.len());
+        bytes.extend_from_slice(&ARCHIVE_BUFFER_MAGIC);
+        bytes.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(encoded);
+        region.write(0, &bytes);
+        Ok(())
+    }
+
+    /// Reads the archive entries written by [Storage::write_archive_buffer], if any.
+    pub fn read_archive_buffer(&self) -> Result<Vec<ArchiveEntry>, StorageError> {
+        let region = &self.regions().archive_buffer;
+        let region_bytes = region.size() * WASM_PAGE_SIZE;
+        if ARCHIVE_BUFFER_PREFIX_SIZE > region_bytes {
+            return Ok(vec![]);
+        }
+        let mut prefix = [0; ARCHIVE_BUFFER_PREFIX_SIZE as usize];
+        region.read(0, &mut prefix);
+        if prefix[..4] != ARCHIVE_BUFFER_MAGIC {
+            return Ok(vec![]);
+        }
+        let size = u64::from_le_bytes(prefix[4..].try_into().unwrap());
+        if size > ARCHIVE_BUFFER_REGION_SIZE - ARCHIVE_BUFFER_PREFIX_SIZE
+            || ARCHIVE_BUFFER_PREFIX_SIZE + size > region_bytes
+        {
+            return Err(StorageError::BadArchiveBuffer { size });
+        }
+        let mut buf = vec![0; size as usize];
+        region.read(ARCHIVE_BUFFER_PREFIX_SIZE, &mut buf);
+        candid::decode_one(&buf).map_err(StorageError::DeserializationError)
+    }
+
+    /// Reads the persistent state from the slot recorded in the header. This is only used to
+    /// restore state in `post_upgrade`.
+    ///
+    /// Returns [PersistentStateError::UnsupportedVersion] if the state was written by a newer
+    /// version of this canister.
+    pub fn read_persistent_state(&self) -> Result<PersistentState, PersistentStateError> {
+        let (version, data) = self.read_persistent_bytes()?;
+        decode_persistent_state(version, &data)
+    }
+
+    /// Reads the version and the candid encoded data of the persistent state.
+    pub fn read_persistent_bytes(&self) -> Result<(u8, Vec<u8>), PersistentStateError> {
+        let epoch = self.header.persistent_state_epoch;
+        if epoch == 0 {
+            // no state has been written yet
+            return Err(PersistentStateError::NotFound);
+        }
+        let slot = self.header.active_slot as usize;
+        match self.read_persistent_slot(slot, epoch) {
+            // The active slot does not hold the committed state, so the previous state is read
+            // from the other slot instead. States that can be found but not read are not replaced
+            // by older ones.
+            Err(
+                err @ (PersistentStateError::NotFound
+                | PersistentStateError::StaleState { .. }
+                | PersistentStateError::CorruptedSize(_)
+                | PersistentStateError::ReadError(_)),
+            ) if epoch > 1 => self
+                .read_persistent_slot(1 - slot, epoch - 1)
+                .map_err(|_| err),
+            result => result,
+        }
+    }
+
+    /// Reads the version and the candid encoded data of the persistent state in the given slot,
+    /// which must have been written with the `expected_epoch`.
+    fn read_persistent_slot(
+        &self,
+        slot: usize,
+        expected_epoch: u64,
+    ) -> Result<(u8, Vec<u8>), PersistentStateError> {
+        let region = &self.regions().persistent_state[slot];
+        let mut reader = Reader::new(region, 0);
+        let mut prefix = [0; PERSISTENT_STATE_PREFIX_SIZE as usize];
+        let bytes_read = reader
+            .read(&mut prefix)
+            // if we hit out of bounds here, the slot has never been written
+            .map_err(|_| PersistentStateError::NotFound)?;
+        if bytes_read != prefix.len() || prefix[..4] != PERSISTENT_STATE_MAGIC {
+            // less than the expected number of bytes were read or the magic does not match
+            // --> this is not the persistent state
+            return Err(PersistentStateError::NotFound);
+        }
+
+        let version = prefix[4];
+        let found = u64::from_le_bytes(prefix[5..13].try_into().unwrap());
+        if found != expected_epoch {
+            return Err(PersistentStateError::StaleState {
+                expected: expected_epoch,
+                found,
+            });
+        }
+        let size = u64::from_le_bytes(prefix[13..].try_into().unwrap());
+        // no state extends beyond the region it was written to
+        if size > (region.size() * WASM_PAGE_SIZE).saturating_sub(PERSISTENT_STATE_PREFIX_SIZE) {
+            return Err(PersistentStateError::CorruptedSize(size));
+        }
+        if size > self.max_persistent_state_size {
+            return Err(PersistentStateError::StateTooLarge {
+                max_size: self.max_persistent_state_size,
+            });
+        }
+        let mut data_buf = vec![0; size as usize];
+        for chunk in data_buf.chunks_mut(PERSISTENT_STATE_CHUNK_SIZE) {
+            reader
+                .read(chunk)
+                .map_err(PersistentStateError::ReadError)?;
+        }
+
+        Ok((version, data_buf))
+    }
+
+    pub fn version(&self) -> u8 {
+        self.header.version
+    }
+}
+
+/// Reads the principal index written by [Storage::put_principal_index] at `offset`. An index
+/// running past the end of the memory reads as zeros, which fail to decode.
+fn read_principal_index<M: Memory>(
+    memory: &M,
+    offset: u64,
+) -> Result<BTreeMap<Principal, UserNumber>, HeaderError> {
+    let mut reader = Reader::new(memory, offset);
+    let mut len_buf = [0; 4];
+    reader.read(&mut len_buf).ok();
+    let len = u32::from_le_bytes(len_buf);
+    if len as u64 > MAX_PRINCIPAL_INDEX_SIZE {
+        return Err(HeaderError::PrincipalIndexTooLarge(len));
+    }
+    let mut buf = vec![0; len as usize];
+    reader.read(&mut buf).ok();
+    candid::decode_one(&buf).map_err(HeaderError::BadPrincipalIndex)
+}
+
+/// Returns the magic, version, epoch and size preceding a candid encoded persistent state of `size`
+/// bytes.
+fn persistent_state_prefix(version: u8, epoch: u64, size: u64) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(PERSISTENT_STATE_PREFIX_SIZE as usize);
+    prefix.extend_from_slice(&PERSISTENT_STATE_MAGIC);
+    prefix.push(version);
+    prefix.extend_from_slice(&epoch.to_le_bytes());
+    prefix.extend_from_slice(&size.to_le_bytes());
+    prefix
+}
+
+fn now_ns() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        ic_cdk::api::time()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
 
 #[derive(Debug)]
 pub enum PersistentStateError {
     CandidError(candid::error::Error),
     NotFound,
     ReadError(OutOfBounds),
+    WriteError(GrowFailed),
+    StaleState {
+        expected: u64,
+        found: u64,
+    },
+    StateTooLarge {
+        max_size: u64,
+    },
+    /// The state written when there were `anchor_count` anchors has been (partly) overwritten by
+    /// the anchors written since, of which there are `num_users`. Only states written before
+    /// layout version 16 can be overwritten, see [legacy].
+    Overwritten {
+        anchor_count: u32,
+        num_users: u32,
+    },
+    UnsupportedVersion(u8),
+    MemoryExhausted {
+        needed_pages: u64,
+    },
+    /// The encoded state (of at least `size` bytes) and its prefix do not fit into the `max` bytes
+    /// of the stable memory reserve.
+    TooLarge {
+        size: u64,
+        max: u64,
+    },
+    /// The (at least) `needed` bytes of the state and its prefix exceed the `available` bytes
+    /// between the start of the stable memory reserve and the stable memory limit.
+    OutOfReserve {
+        needed: u64,
+        available: u64,
+    },
+    /// The size recorded in front of the state exceeds the slot it was written to.
+    CorruptedSize(u64),
+}
+
+/// Location of a persistent state written by [Storage::write_persistent_slot].
+struct PersistentSlotWrite {
+    slot: u8,
+    epoch: u64,
+    length: u64,
+}
+
+/// Streams the candid encoding of a persistent value to stable memory in chunks, failing as soon
+/// as the bytes written exceed the limits of [Storage::write_persistent_slot].
+struct PersistentValueWriter<'a, M: Memory> {
+    storage: &'a Storage<M>,
+    // slot the value is written to
+    region: &'a Region<M>,
+    // offset of the next chunk in the slot
+    offset: u64,
+    chunk: Vec<u8>,
+    chunk_size: usize,
+    // number of bytes written so far, including the ones in the chunk
+    size: u64,
+    max_size: u64,
+    max_state_size: u64,
+    available: u64,
+    // the limit exceeded or the growth failure that made the last write fail
+    error: Option<PersistentStateError>,
+}
+
+impl<M: Memory + Clone> PersistentValueWriter<'_, M> {
+    fn check_size(&self, size: u64) -> Result<(), PersistentStateError> {
+        if size + PERSISTENT_STATE_PREFIX_SIZE > self.max_size {
+            return Err(PersistentStateError::TooLarge {
+                size,
+                max: self.max_size,
+            });
+        }
+        if size > self.max_state_size {
+            return Err(PersistentStateError::StateTooLarge {
+                max_size: self.max_state_size,
+            });
+        }
+        if size + PERSISTENT_STATE_PREFIX_SIZE > self.available {
+            return Err(PersistentStateError::OutOfReserve {
+                needed: size + PERSISTENT_STATE_PREFIX_SIZE,
+                available: self.available,
+            });
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered chunk to stable memory, growing it if needed.
+    fn write_chunk(&mut self) -> Result<(), PersistentStateError> {
+        let end = self.offset + self.chunk.len() as u64;
+        self.storage
+            .ensure_region_capacity(self.region, end)
+            .map_err(|err| match err {
+                StorageError::MemoryExhausted { needed_pages } => {
+                    PersistentStateError::MemoryExhausted { needed_pages }
+                }
+                err => unreachable!("unexpected error: {}", err),
+            })?;
+        self.region.write(self.offset, &self.chunk);
+        self.offset = end;
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// Writes the last chunk (if any) and returns the size of the encoded value.
+    fn finish(mut self) -> Result<u64, PersistentStateError> {
+        if !self.chunk.is_empty() {
+            self.write_chunk()?;
+        }
+        Ok(self.size)
+    }
+}
+
+impl<M: Memory + Clone> Write for PersistentValueWriter<'_, M> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self
+            .check_size(self.size + buf.len() as u64)
+            .and_then(|()| {
+                let mut bytes = buf;
+                while !bytes.is_empty() {
+                    let len = bytes.len().min(self.chunk_size - self.chunk.len());
+                    self.chunk.extend_from_slice(&bytes[..len]);
+                    bytes = &bytes[len..];
+                    if self.chunk.len() == self.chunk_size {
+                        self.write_chunk()?;
+                    }
+                }
+                Ok(())
+            });
+        match result {
+            Ok(()) => {
+                self.size += buf.len() as u64;
+                Ok(buf.len())
+            }
+            Err(err) => {
+                let message = format!("{:?}", err);
+                self.error = Some(err);
+                Err(io::Error::other(message))
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decodes a candid encoded persistent state of the given persistent state version.
+fn decode_persistent_state(
+    version: u8,
+    data: &[u8],
+) -> Result<PersistentState, PersistentStateError> {
+    match version {
+        0..=CURRENT_PERSISTENT_STATE_VERSION => {
+            candid::decode_one(data).map_err(PersistentStateError::CandidError)
+        }
+        version => Err(PersistentStateError::UnsupportedVersion(version)),
+    }
+}
+
+/// Compresses a candid encoded record: the LEB128 encoded length of the record is followed by the
+/// record compressed with zstd.
+fn compress_record(record: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    let mut len = record.len() as u64;
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            buf.push(byte);
+            break;
+        }
+        buf.push(byte | 0x80);
+    }
+    buf.extend(compress_to_vec(record, CompressionLevel::Fastest));
+    buf
+}
+
+/// Splits a record produced by [compress_record] into the length of the decompressed record and
+/// the zstd frame. Returns `None` if `data` is not a compressed record.
+fn split_compressed_record(data: &[u8]) -> Option<(u64, &[u8])> {
+    let mut len = 0u64;
+    let mut shift = 0;
+    let mut rest = data;
+    loop {
+        let (&byte, tail) = rest.split_first()?;
+        rest = tail;
+        len |= ((byte & 0x7f) as u64).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    rest.starts_with(&ZSTD_MAGIC).then_some((len, rest))
+}
+
+/// Decompresses a zstd frame that must yield exactly `len` bytes.
+fn decompress_frame(len: u64, frame: &[u8]) -> Option<Vec<u8>> {
+    if len > MAX_DECOMPRESSED_RECORD_SIZE {
+        return None;
+    }
+    let decoder = StreamingDecoder::new(frame).ok()?;
+    let mut record = Vec::with_capacity(len as usize);
+    decoder.take(len + 1).read_to_end(&mut record).ok()?;
+    (record.len() as u64 == len).then_some(record)
 }
 
 #[derive(Debug)]
@@ -358,6 +3285,95 @@ pub enum StorageError {
     DeserializationError(candid::error::Error),
     SerializationError(candid::error::Error),
     EntrySizeLimitExceeded(usize),
+    BadEntryLength {
+        user_number: UserNumber,
+        length: usize,
+    },
+    InvalidRange {
+        range: (UserNumber, UserNumber),
+    },
+    RangeTooLarge {
+        range: (UserNumber, UserNumber),
+        max_size: u64,
+    },
+    InvalidEntrySize(u16),
+    MigrationInProgress,
+    RangeShrinkNotAllowed {
+        range: (UserNumber, UserNumber),
+        new_hi: UserNumber,
+    },
+    ChecksumMismatch {
+        user_number: UserNumber,
+    },
+    RangeShrinkWouldOrphanRecords {
+        range: (UserNumber, UserNumber),
+        new_hi: UserNumber,
+        num_users: u32,
+    },
+    UnsupportedRecordVersion {
+        user_number: UserNumber,
+        version: u8,
+    },
+    InvalidMigrationBatchSize(u32),
+    MemoryExhausted {
+        needed_pages: u64,
+    },
+    BadCompressedRecord {
+        user_number: UserNumber,
+    },
+    /// The encoded principal index of `size` bytes would exceed the `max` bytes before the first
+    /// entry.
+    PrincipalIndexFull {
+        size: u64,
+        max: u64,
+    },
+    /// The encoded archive entries of `size` bytes would exceed the `max` bytes of their region.
+    ArchiveBufferFull {
+        size: u64,
+        max: u64,
+    },
+    /// The size of the archive entries exceeds their region.
+    BadArchiveBuffer {
+        size: u64,
+    },
+    /// Anchor entries can only be restored in backup mode.
+    BackupModeDisabled,
+    /// The anchor to be imported is already allocated.
+    AnchorOccupied {
+        user_number: UserNumber,
+    },
+    /// The raw anchor entries to restore are not a whole number of entries or exceed
+    /// [MAX_BACKUP_CHUNK_SIZE].
+    BadBackupChunk {
+        length: usize,
+    },
+    /// The stable memory address of the record does not fit into a `u64`.
+    AddressOverflow {
+        record_number: u32,
+    },
+    /// The anchor has been deleted, see [Storage::delete_anchor].
+    AnchorDeleted {
+        user_number: UserNumber,
+    },
+    /// All user numbers of the range have been allocated.
+    CapacityExhausted {
+        range: (UserNumber, UserNumber),
+    },
+    /// The entries would extend to the first `needed` bytes of stable memory, but only the first
+    /// `available` bytes are in front of the stable memory reserve.
+    OutOfReserve {
+        needed: u64,
+        available: u64,
+    },
+    /// Anchors are placed by their hashed user number, see [Storage::read_by_hashed].
+    HashedPlacementEnabled,
+    /// Anchors are placed by their offset in the anchor range.
+    HashedPlacementDisabled,
+    /// Hashed placement requires a salt and a storage without anchors.
+    #[cfg(test)]
+    HashedPlacementUnavailable,
+    /// The stable memory reserve is smaller than `MIN_STABLE_MEMORY_RESERVE` bytes.
+    InvalidReserve(u64),
 }
 
 impl fmt::Display for StorageError {
@@ -381,6 +3397,230 @@ impl fmt::Display for StorageError {
                  which is larger then the max allowed entry size",
                 n
             ),
+            Self::BadEntryLength {
+                user_number,
+                length,
+            } => write!(
+                f,
+                "entry of Identity Anchor {} has a length prefix of {} \
+                 which is larger than the max allowed entry size",
+                user_number, length
+            ),
+            Self::InvalidRange { range } => write!(
+                f,
+                "improper Identity Anchor range: [{}, {})",
+                range.0, range.1
+            ),
+            Self::RangeTooLarge { range, max_size } => write!(
+                f,
+                "id range [{}, {}) is too large for a single canister (max {} entries)",
+                range.0, range.1, max_size
+            ),
+            Self::InvalidEntrySize(size) => write!(
+                f,
+                "invalid entry size {}: must be a power of two between {} and {}",
+                size, MIN_ENTRY_SIZE, MAX_ENTRY_SIZE
+            ),
+            Self::MigrationInProgress => {
+                write!(f, "an entry size migration is already in progress")
+            }
+            Self::RangeShrinkNotAllowed { range, new_hi } => write!(
+                f,
+                "cannot shrink Identity Anchor range [{}, {}) to [{}, {})",
+                range.0, range.1, range.0, new_hi
+            ),
+            Self::ChecksumMismatch { user_number } => write!(
+                f,
+                "entry of Identity Anchor {} does not match its checksum",
+                user_number
+            ),
+            Self::RangeShrinkWouldOrphanRecords {
+                range,
+                new_hi,
+                num_users,
+            } => write!(
+                f,
+                "cannot shrink Identity Anchor range [{}, {}) to [{}, {}): {} anchors are allocated",
+                range.0, range.1, range.0, new_hi, num_users
+            ),
+            Self::UnsupportedRecordVersion {
+                user_number,
+                version,
+            } => write!(
+                f,
+                "entry of Identity Anchor {} has unsupported record version {}",
+                user_number, version
+            ),
+            Self::InvalidMigrationBatchSize(batch_size) => {
+                write!(f, "invalid migration batch size {}", batch_size)
+            }
+            Self::MemoryExhausted { needed_pages } => write!(
+                f,
+                "failed to grow stable memory by {} pages",
+                needed_pages
+            ),
+            Self::BadCompressedRecord { user_number } => write!(
+                f,
+                "entry of Identity Anchor {} holds an invalid compressed record",
+                user_number
+            ),
+            Self::PrincipalIndexFull { size, max } => write!(
+                f,
+                "principal index of {} bytes exceeds the max size of {} bytes",
+                size, max
+            ),
+            Self::ArchiveBufferFull { size, max } => write!(
+                f,
+                "archive entries of {} bytes exceed the max size of {} bytes",
+                size, max
+            ),
+            Self::BadArchiveBuffer { size } => write!(
+                f,
+                "archive entries have a size of {} bytes which exceeds their region",
+                size
+            ),
+            Self::BackupModeDisabled => write!(f, "anchors can only be restored in backup mode"),
+            Self::AnchorOccupied { user_number } => {
+                write!(f, "Identity Anchor {} is already allocated", user_number)
+            }
+            Self::BadBackupChunk { length } => write!(
+                f,
+                "{} bytes are not a valid chunk of anchor entries",
+                length
+            ),
+            Self::AddressOverflow { record_number } => write!(
+                f,
+                "the stable memory address of record {} overflows",
+                record_number
+            ),
+            Self::AnchorDeleted { user_number } => {
+                write!(f, "Identity Anchor {} has been deleted", user_number)
+            }
+            Self::CapacityExhausted { range } => write!(
+                f,
+                "all Identity Anchors in range [{}, {}) have been allocated",
+                range.0, range.1
+            ),
+            Self::OutOfReserve { needed, available } => write!(
+                f,
+                "writing the entry requires {} bytes of stable memory but only {} bytes are available in front of the stable memory reserve",
+                needed, available
+            ),
+            Self::HashedPlacementEnabled => {
+                write!(f, "anchors are placed by their hashed user number")
+            }
+            Self::HashedPlacementDisabled => {
+                write!(f, "anchors are not placed by their hashed user number")
+            }
+            #[cfg(test)]
+            Self::HashedPlacementUnavailable => write!(
+                f,
+                "placing anchors by their hashed user number requires a salt and no anchors"
+            ),
+            Self::InvalidReserve(reserve) => write!(
+                f,
+                "invalid stable memory reserve of {} bytes: must be at least {} bytes",
+                reserve, MIN_STABLE_MEMORY_RESERVE
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HeaderError {
+    TooShort(usize),
+    InvalidMagic([u8; 3]),
+    UnsupportedVersion(u8),
+    VersionTooOld(u8),
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    UpgradeNotFinalized,
+    /// The entries described by the header cannot be located: the entry size is zero or the
+    /// entries do not start at [ENTRY_OFFSET].
+    InconsistentLayout {
+        entry_size: u16,
+        first_entry_offset: u64,
+    },
+    PrincipalIndexTooLarge(u32),
+    BadPrincipalIndex(candid::error::Error),
+    /// The stable memory reserve recorded in the header is smaller than
+    /// `MIN_STABLE_MEMORY_RESERVE` bytes.
+    InvalidReserve(u64),
+    /// The slot of the persistent state recorded in the header is neither 0 nor 1.
+    InvalidActiveSlot(u8),
+    /// The persistent state of a layout before version 16 was found but could not be read, so it
+    /// could not be migrated, see [legacy].
+    LegacyPersistentState(PersistentStateError),
+    /// A layout before version 16 could not be migrated to the regions of the memory manager.
+    MigrationFailed(StorageError),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort(len) => write!(
+                f,
+                "stable memory header: expected at least {} bytes, got {}",
+                HEADER_SIZE, len
+            ),
+            Self::InvalidMagic(magic) => {
+                write!(f, "stable memory header: invalid magic: {:?}", magic)
+            }
+            Self::VersionTooOld(version) => {
+                write!(f, "stable memory layout version {} is no longer supported:\nEither reinstall (wiping stable memory) or migrate using a previous II version", version)
+            }
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported header version: {}", version)
+            }
+            Self::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "stable memory header: checksum mismatch: expected {:#010x}, got {:#010x}:\nThe \
+                 header was modified outside of Internet Identity, most likely by the last install \
+                 or upgrade of a different wasm module into this canister",
+                expected, actual
+            ),
+            Self::UpgradeNotFinalized => write!(
+                f,
+                "stable memory header: an upgrade was started but never finalized, the stored \
+                 state may be inconsistent"
+            ),
+            Self::InconsistentLayout {
+                entry_size,
+                first_entry_offset,
+            } => write!(
+                f,
+                "stable memory header: inconsistent layout: entry size {} with entries starting \
+                 at {} (expected {})",
+                entry_size, first_entry_offset, ENTRY_OFFSET
+            ),
+            Self::PrincipalIndexTooLarge(len) => write!(
+                f,
+                "principal index: length {} exceeds the max size of {} bytes",
+                len, MAX_PRINCIPAL_INDEX_SIZE
+            ),
+            Self::BadPrincipalIndex(err) => {
+                write!(f, "principal index: failed to decode: {}", err)
+            }
+            Self::InvalidReserve(reserve) => write!(
+                f,
+                "stable memory header: invalid stable memory reserve of {} bytes (min {} bytes)",
+                reserve, MIN_STABLE_MEMORY_RESERVE
+            ),
+            Self::InvalidActiveSlot(slot) => write!(
+                f,
+                "stable memory header: invalid persistent state slot {}",
+                slot
+            ),
+            Self::LegacyPersistentState(err) => write!(
+                f,
+                "stable memory migration: failed to read the persistent state: {:?}",
+                err
+            ),
+            Self::MigrationFailed(err) => {
+                write!(f, "stable memory migration: {}", err)
+            }
         }
     }
 }
@@ -0,0 +1,376 @@
+//! Index from the credential IDs of WebAuthn devices to the anchors holding them, so that clients
+//! which only know the credential ID of a passkey can find its anchor.
+//!
+//! The index is a [StableBTreeMap] of keys made of the SHA-256 hash of a credential ID followed by
+//! the user number of an anchor holding a device with this credential ID. Credential IDs are
+//! chosen by the clients, so the same credential ID may be indexed for several anchors. Lookups
+//! therefore check the candidates against the anchor records, which also makes entries left behind
+//! by failed writes harmless.
+//!
+//! The index is kept up to date by writing anchors through an [IndexedStorage]. An index that is
+//! created in a fresh region (e.g. after migrating a layout before version 16, see
+//! [crate::storage]) is empty and has to be rebuilt from the anchors with
+//! [CredentialIndex::rebuild_batch] before it can be used for lookups.
+//!
+//! ## Credential Index Layout
+//!
+//! ```text
+//! Page 0:                 magic "IICI" | tag (8 bytes) | complete (1 byte)
+//!                         | rebuild cursor (8 bytes)
+//! Page 1 onwards:         StableBTreeMap<CredentialKey, ()>
+//! ```
+//!
+//! The tag identifies the region the index was created for, an index found with a different tag
+//! is discarded.
+
+use std::borrow::Cow;
+use std::convert::TryInto;
+
+use candid::Principal;
+use ic_cdk::api::trap;
+use ic_stable_structures::{BoundedStorable, Memory, RestrictedMemory, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+
+use crate::state::PersistentState;
+use crate::storage::{PersistentStateError, Salt, ScanPage, StorageError};
+use crate::storage::record_storage::RecordStorage;
+use crate::types::{AnchorRecord, ArchiveEntry, CredentialId, UserNumber};
+
+#[cfg(test)]
+mod tests;
+
+const CREDENTIAL_INDEX_MAGIC: [u8; 4] = *b"IICI";
+const PREFIX_SIZE: usize = 4 + 8 + 1 + 8;
+const HASH_SIZE: usize = 32;
+const KEY_SIZE: usize = HASH_SIZE + std::mem::size_of::<UserNumber>();
+const WASM_PAGE_SIZE: u64 = 65_536;
+/// Upper bound of the pages of the map, the actual size is limited by the memory of the index.
+const MAX_MAP_PAGES: u64 = u64::MAX / WASM_PAGE_SIZE - 1;
+
+/// Key of the [CredentialIndex]: the SHA-256 hash of a credential ID followed by the big endian
+/// user number, so that all anchors indexed for a credential ID share a key prefix.
+struct CredentialKey([u8; KEY_SIZE]);
+
+impl CredentialKey {
+    fn new(credential_id: &[u8], user_number: UserNumber) -> Self {
+        let mut key = [0; KEY_SIZE];
+        key[..HASH_SIZE].copy_from_slice(&credential_hash(credential_id));
+        key[HASH_SIZE..].copy_from_slice(&user_number.to_be_bytes());
+        Self(key)
+    }
+
+    fn user_number(&self) -> UserNumber {
+        UserNumber::from_be_bytes(self.0[HASH_SIZE..].try_into().unwrap())
+    }
+}
+
+impl Storable for CredentialKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(
+            bytes
+                .try_into()
+                .expect("bug: credential index key of unexpected size"),
+        )
+    }
+}
+
+impl BoundedStorable for CredentialKey {
+    fn max_size() -> u32 {
+        KEY_SIZE as u32
+    }
+}
+
+fn credential_hash(credential_id: &[u8]) -> [u8; HASH_SIZE] {
+    Sha256::digest(credential_id).into()
+}
+
+fn credential_ids(anchor: &AnchorRecord) -> Vec<&CredentialId> {
+    anchor
+        .devices
+        .iter()
+        .filter_map(|device| device.credential_id.as_ref())
+        .collect()
+}
+
+/// Index from credential IDs to anchors in a memory of its own, see the
+/// [module documentation](self).
+pub struct CredentialIndex<M: Memory + Clone> {
+    memory: M,
+    map: StableBTreeMap<RestrictedMemory<M>, CredentialKey, ()>,
+    tag: u64,
+    // user number to continue the rebuild with, None once the index is complete
+    rebuild_cursor: Option<UserNumber>,
+}
+
+impl<M: Memory + Clone> CredentialIndex<M> {
+    /// Loads the index from `memory` if it was created there with the same `tag`. Otherwise, an
+    /// empty index is created that needs to be rebuilt starting with user number 0.
+    pub fn init(memory: M, tag: u64) -> Self {
+        let map_memory = RestrictedMemory::new(memory.clone(), 1..MAX_MAP_PAGES);
+        let mut prefix = [0; PREFIX_SIZE];
+        if memory.size() > 0 {
+            memory.read(0, &mut prefix);
+        }
+        if prefix[..4] == CREDENTIAL_INDEX_MAGIC
+            && u64::from_le_bytes(prefix[4..12].try_into().unwrap()) == tag
+        {
+            let rebuild_cursor = match prefix[12] {
+                0 => Some(u64::from_le_bytes(prefix[13..21].try_into().unwrap())),
+                _ => None,
+            };
+            return Self {
+                memory,
+                map: StableBTreeMap::load(map_memory),
+                tag,
+                rebuild_cursor,
+            };
+        }
+
+        let index = Self {
+            memory,
+            map: StableBTreeMap::new(map_memory),
+            tag,
+            rebuild_cursor: Some(0),
+        };
+        index.write_prefix();
+        index
+    }
+
+    /// Returns true if all anchors have been indexed, see [CredentialIndex::rebuild_batch].
+    pub fn is_complete(&self) -> bool {
+        self.rebuild_cursor.is_none()
+    }
+
+    /// Indexes the anchors starting with `user_number` again, e.g. after anchors were imported
+    /// without going through an [IndexedStorage]. Lookups are unavailable until the rebuild is
+    /// complete.
+    pub fn rebuild_from(&mut self, user_number: UserNumber) {
+        self.rebuild_cursor = Some(
+            self.rebuild_cursor
+                .map_or(user_number, |cursor| cursor.min(user_number)),
+        );
+        self.write_prefix();
+    }
+
+    /// Indexes the credential IDs of up to `batch` anchors in the order of their user numbers.
+    /// Returns true if the index is complete.
+    ///
+    /// Anchors indexed before are indexed again, which does not change the index. Records that
+    /// cannot be read are skipped.
+    pub fn rebuild_batch<S: RecordStorage + ?Sized>(&mut self, storage: &S, batch: usize) -> bool {
+        let Some(cursor) = self.rebuild_cursor else {
+            return true;
+        };
+        let page = storage.scan_from(cursor, batch);
+        for (user_number, anchor) in page.anchors {
+            if let Ok(anchor) = anchor {
+                for credential_id in credential_ids(&anchor) {
+                    self.insert(credential_id, user_number);
+                }
+            }
+        }
+        self.rebuild_cursor = page.next_cursor;
+        self.write_prefix();
+        self.is_complete()
+    }
+
+    /// Records that the anchor has a device with the given credential ID.
+    ///
+    /// Traps if the memory of the index is exhausted.
+    pub fn insert(&mut self, credential_id: &[u8], user_number: UserNumber) {
+        self.map
+            .insert(CredentialKey::new(credential_id, user_number), ())
+            .expect("bug: credential index key exceeds the max size");
+    }
+
+    /// Removes the credential ID indexed for the anchor, if any.
+    pub fn remove(&mut self, credential_id: &[u8], user_number: UserNumber) {
+        self.map
+            .remove(&CredentialKey::new(credential_id, user_number));
+    }
+
+    /// Removes the entries of all anchors with a user number of at least `user_number`, e.g. after
+    /// the anchors have been deallocated by [crate::storage::Storage::compact]. Scans the whole
+    /// index.
+    pub fn remove_anchors_from(&mut self, user_number: UserNumber) {
+        let stale: Vec<_> = self
+            .map
+            .iter()
+            .map(|(key, ())| key)
+            .filter(|key| key.user_number() >= user_number)
+            .collect();
+        for key in stale {
+            self.map.remove(&key);
+        }
+    }
+
+    /// Returns the anchor holding a device with the given credential ID if `caller` is the
+    /// principal of that device. Returns `None` otherwise, so that the index does not reveal
+    /// anchors to anyone but their devices.
+    pub fn lookup<S: RecordStorage + ?Sized>(
+        &self,
+        storage: &S,
+        credential_id: &[u8],
+        caller: Principal,
+    ) -> Option<UserNumber> {
+        self.map
+            .range(credential_hash(credential_id).to_vec(), None)
+            .map(|(key, ())| key.user_number())
+            .find(|user_number| {
+                storage.read_anchor(*user_number).is_ok_and(|anchor| {
+                    anchor.devices.iter().any(|device| {
+                        device
+                            .credential_id
+                            .as_ref()
+                            .is_some_and(|id| id.as_slice() == credential_id)
+                            && Principal::self_authenticating(&device.pubkey) == caller
+                    })
+                })
+            })
+    }
+
+    fn write_prefix(&self) {
+        if self.memory.size() == 0 && self.memory.grow(1) < 0 {
+            trap("failed to grow the memory of the credential index");
+        }
+        let mut prefix = [0; PREFIX_SIZE];
+        prefix[..4].copy_from_slice(&CREDENTIAL_INDEX_MAGIC);
+        prefix[4..12].copy_from_slice(&self.tag.to_le_bytes());
+        prefix[12] = self.rebuild_cursor.is_none() as u8;
+        prefix[13..21].copy_from_slice(&self.rebuild_cursor.unwrap_or(0).to_le_bytes());
+        self.memory.write(0, &prefix);
+    }
+}
+
+/// A [RecordStorage] that keeps a [CredentialIndex] up to date with the devices of the anchors
+/// written to it.
+///
+/// The entries of removed credential IDs are removed before the anchor is written and the entries
+/// of added credential IDs are added afterwards. A device that is gone can therefore never be
+/// found through the index, even if the write fails or traps halfway through.
+pub struct IndexedStorage<'a, S: ?Sized, M: Memory + Clone> {
+    storage: &'a mut S,
+    index: &'a mut CredentialIndex<M>,
+}
+
+impl<'a, S: RecordStorage + ?Sized, M: Memory + Clone> IndexedStorage<'a, S, M> {
+    pub fn new(storage: &'a mut S, index: &'a mut CredentialIndex<M>) -> Self {
+        Self { storage, index }
+    }
+
+    /// Removes the entries of the given anchor from the index and runs `delete`, which is expected
+    /// to delete the anchor from the storage. The entries are added back if `delete` fails.
+    pub fn delete_with<R, E>(
+        &mut self,
+        user_number: UserNumber,
+        delete: impl FnOnce(&mut S) -> Result<R, E>,
+    ) -> Result<R, E> {
+        let anchor = self.storage.read_anchor(user_number).unwrap_or_default();
+        let credential_ids = credential_ids(&anchor);
+        for credential_id in &credential_ids {
+            self.index.remove(credential_id, user_number);
+        }
+        let result = delete(self.storage);
+        if result.is_err() {
+            for credential_id in &credential_ids {
+                self.index.insert(credential_id, user_number);
+            }
+        }
+        result
+    }
+}
+
+impl<S: RecordStorage + ?Sized, M: Memory + Clone> RecordStorage for IndexedStorage<'_, S, M> {
+    fn assigned_user_number_range(&self) -> (UserNumber, UserNumber) {
+        self.storage.assigned_user_number_range()
+    }
+
+    fn user_count(&self) -> usize {
+        self.storage.user_count()
+    }
+
+    fn allocate_anchor(&mut self) -> Option<UserNumber> {
+        self.storage.allocate_anchor()
+    }
+
+    fn read_anchor(&self, user_number: UserNumber) -> Result<AnchorRecord, StorageError> {
+        self.storage.read_anchor(user_number)
+    }
+
+    fn read_anchors(&self, user_numbers: &[UserNumber]) -> Vec<Result<AnchorRecord, StorageError>> {
+        self.storage.read_anchors(user_numbers)
+    }
+
+    fn write_anchor(
+        &mut self,
+        user_number: UserNumber,
+        anchor: &AnchorRecord,
+    ) -> Result<(), StorageError> {
+        // anchors that were allocated but never written have no devices yet
+        let previous = self.storage.read_anchor(user_number).unwrap_or_default();
+        let previous_ids = credential_ids(&previous);
+        let ids = credential_ids(anchor);
+        let removed: Vec<_> = previous_ids
+            .into_iter()
+            .filter(|id| !ids.contains(id))
+            .collect();
+        for credential_id in &removed {
+            self.index.remove(credential_id, user_number);
+        }
+        if let Err(err) = self.storage.write_anchor(user_number, anchor) {
+            // the previous record is still in place
+            for credential_id in &removed {
+                self.index.insert(credential_id, user_number);
+            }
+            return Err(err);
+        }
+        for credential_id in ids {
+            self.index.insert(credential_id, user_number);
+        }
+        Ok(())
+    }
+
+    fn scan_from(&self, cursor: UserNumber, max: usize) -> ScanPage {
+        self.storage.scan_from(cursor, max)
+    }
+
+    fn write_persistent_state(
+        &mut self,
+        state: &PersistentState,
+    ) -> Result<u64, PersistentStateError> {
+        self.storage.write_persistent_state(state)
+    }
+
+    fn read_persistent_bytes(&self) -> Result<(u8, Vec<u8>), PersistentStateError> {
+        self.storage.read_persistent_bytes()
+    }
+
+    fn salt(&self) -> Option<&Salt> {
+        self.storage.salt()
+    }
+
+    fn set_salt_if_empty(&mut self, salt: Salt) -> bool {
+        self.storage.set_salt_if_empty(salt)
+    }
+
+    /// Deletes the anchor and removes its entries from the index, see [IndexedStorage::delete_with].
+    fn delete_anchor(&mut self, user_number: UserNumber) -> Result<(), StorageError> {
+        self.delete_with(user_number, |storage| storage.delete_anchor(user_number))
+    }
+
+    fn deleted_count(&self) -> usize {
+        self.storage.deleted_count()
+    }
+
+    fn write_archive_buffer(&mut self, entries: &[ArchiveEntry]) -> Result<(), StorageError> {
+        self.storage.write_archive_buffer(entries)
+    }
+
+    fn read_archive_buffer(&self) -> Result<Vec<ArchiveEntry>, StorageError> {
+        self.storage.read_archive_buffer()
+    }
+}
@@ -0,0 +1,359 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use candid::Principal;
+use ic_stable_structures::VectorMemory;
+use serde_bytes::ByteBuf;
+
+use crate::anchor_management;
+use crate::state::PersistentState;
+use crate::storage::{PersistentStateError, Salt, ScanPage, Storage, StorageError};
+use crate::storage::credential_index::{CredentialIndex, CredentialKey, IndexedStorage};
+use crate::storage::record_storage::RecordStorage;
+use crate::testing;
+use crate::types::{AnchorRecord, ArchiveEntry, DeviceData, KeyType, UserNumber};
+
+const RANGE: (u64, u64) = (10_000, 10_010);
+
+fn device(key: u8) -> DeviceData {
+    DeviceData {
+        credential_id: Some(credential_id(key)),
+        key_type: KeyType::CrossPlatform,
+        ..testing::device(key)
+    }
+}
+
+fn credential_id(key: u8) -> ByteBuf {
+    ByteBuf::from(vec![key; 16])
+}
+
+fn principal(key: u8) -> Principal {
+    Principal::self_authenticating(device(key).pubkey)
+}
+
+fn anchor(keys: &[u8]) -> AnchorRecord {
+    AnchorRecord {
+        devices: keys.iter().map(|key| device(*key)).collect(),
+        ..AnchorRecord::default()
+    }
+}
+
+fn complete_index() -> CredentialIndex<VectorMemory> {
+    let storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let mut index = CredentialIndex::init(VectorMemory::default(), 0);
+    assert!(index.rebuild_batch(&storage, 10));
+    index
+}
+
+/// Registers an anchor with the given devices through an [IndexedStorage].
+fn register(
+    storage: &mut Storage<VectorMemory>,
+    index: &mut CredentialIndex<VectorMemory>,
+    keys: &[u8],
+) -> UserNumber {
+    let mut storage = IndexedStorage::new(storage, index);
+    let user_number = RecordStorage::allocate_anchor(&mut storage).unwrap();
+    storage.write_anchor(user_number, &anchor(keys)).unwrap();
+    user_number
+}
+
+/// A storage trapping on every anchor write.
+struct TrappingStorage<'a>(&'a mut Storage<VectorMemory>);
+
+impl RecordStorage for TrappingStorage<'_> {
+    fn assigned_user_number_range(&self) -> (UserNumber, UserNumber) {
+        self.0.assigned_user_number_range()
+    }
+
+    fn user_count(&self) -> usize {
+        self.0.user_count()
+    }
+
+    fn allocate_anchor(&mut self) -> Option<UserNumber> {
+        RecordStorage::allocate_anchor(self.0)
+    }
+
+    fn read_anchor(&self, user_number: UserNumber) -> Result<AnchorRecord, StorageError> {
+        self.0.read_anchor(user_number)
+    }
+
+    fn write_anchor(&mut self, _: UserNumber, _: &AnchorRecord) -> Result<(), StorageError> {
+        panic!("trapped while writing the anchor")
+    }
+
+    fn scan_from(&self, cursor: UserNumber, max: usize) -> ScanPage {
+        self.0.scan_from(cursor, max)
+    }
+
+    fn write_persistent_state(
+        &mut self,
+        state: &PersistentState,
+    ) -> Result<u64, PersistentStateError> {
+        self.0.write_persistent_state(state)
+    }
+
+    fn read_persistent_bytes(&self) -> Result<(u8, Vec<u8>), PersistentStateError> {
+        self.0.read_persistent_bytes()
+    }
+
+    fn salt(&self) -> Option<&Salt> {
+        self.0.salt()
+    }
+
+    fn set_salt_if_empty(&mut self, salt: Salt) -> bool {
+        self.0.set_salt_if_empty(salt)
+    }
+
+    fn delete_anchor(&mut self, user_number: UserNumber) -> Result<(), StorageError> {
+        self.0.delete_anchor(user_number)
+    }
+
+    fn deleted_count(&self) -> usize {
+        self.0.deleted_count()
+    }
+
+    fn write_archive_buffer(&mut self, entries: &[ArchiveEntry]) -> Result<(), StorageError> {
+        self.0.write_archive_buffer(entries)
+    }
+
+    fn read_archive_buffer(&self) -> Result<Vec<ArchiveEntry>, StorageError> {
+        self.0.read_archive_buffer()
+    }
+}
+
+#[test]
+fn should_find_anchor_by_credential_id() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let mut index = complete_index();
+    let first = register(&mut storage, &mut index, &[1]);
+    let second = register(&mut storage, &mut index, &[2, 3]);
+
+    assert_eq!(
+        index.lookup(&storage, &credential_id(1), principal(1)),
+        Some(first)
+    );
+    assert_eq!(
+        index.lookup(&storage, &credential_id(3), principal(3)),
+        Some(second)
+    );
+    assert_eq!(
+        index.lookup(&storage, &credential_id(4), principal(4)),
+        None
+    );
+    assert_eq!(index.map.len(), 3);
+}
+
+#[test]
+fn should_only_reveal_anchor_to_its_device() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let mut index = complete_index();
+    let user_number = register(&mut storage, &mut index, &[1]);
+    // another anchor claiming the same credential ID for a device with a different key
+    let other = register(&mut storage, &mut index, &[3]);
+    let impostor = DeviceData {
+        credential_id: Some(credential_id(1)),
+        ..device(2)
+    };
+    anchor_management::add(
+        &mut IndexedStorage::new(&mut storage, &mut index),
+        other,
+        impostor,
+    )
+    .unwrap();
+
+    assert_eq!(
+        index.lookup(&storage, &credential_id(1), principal(1)),
+        Some(user_number)
+    );
+    assert_eq!(
+        index.lookup(&storage, &credential_id(1), principal(2)),
+        Some(other)
+    );
+    assert_eq!(
+        index.lookup(&storage, &credential_id(1), principal(3)),
+        None
+    );
+}
+
+#[test]
+fn should_update_index_on_device_changes() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let mut index = complete_index();
+    let user_number = register(&mut storage, &mut index, &[1, 2]);
+
+    anchor_management::remove(
+        &mut IndexedStorage::new(&mut storage, &mut index),
+        user_number,
+        principal(1),
+        device(2).pubkey,
+    )
+    .unwrap();
+    assert_eq!(
+        index.lookup(&storage, &credential_id(2), principal(2)),
+        None
+    );
+
+    anchor_management::replace(
+        &mut IndexedStorage::new(&mut storage, &mut index),
+        user_number,
+        principal(1),
+        device(1).pubkey,
+        device(3),
+    )
+    .unwrap();
+    assert_eq!(
+        index.lookup(&storage, &credential_id(1), principal(1)),
+        None
+    );
+    assert_eq!(
+        index.lookup(&storage, &credential_id(3), principal(3)),
+        Some(user_number)
+    );
+    assert_eq!(index.map.len(), 1);
+}
+
+#[test]
+fn should_remove_index_entry_before_writing_the_anchor() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let mut index = complete_index();
+    let user_number = register(&mut storage, &mut index, &[1, 2]);
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        anchor_management::remove(
+            &mut IndexedStorage::new(&mut TrappingStorage(&mut storage), &mut index),
+            user_number,
+            principal(1),
+            device(2).pubkey,
+        )
+    }));
+    assert!(result.is_err());
+    // the device is still there, but no longer indexed
+    assert_eq!(storage.read_anchor(user_number).unwrap(), anchor(&[1, 2]));
+    assert_eq!(
+        index.lookup(&storage, &credential_id(2), principal(2)),
+        None
+    );
+    assert_eq!(
+        index.lookup(&storage, &credential_id(1), principal(1)),
+        Some(user_number)
+    );
+}
+
+#[test]
+fn should_keep_index_entries_if_the_anchor_write_fails() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let mut index = complete_index();
+    let user_number = register(&mut storage, &mut index, &[1]);
+
+    let too_large = DeviceData {
+        alias: "a".repeat(5_000),
+        ..device(2)
+    };
+    let result = anchor_management::replace(
+        &mut IndexedStorage::new(&mut storage, &mut index),
+        user_number,
+        principal(1),
+        device(1).pubkey,
+        too_large,
+    );
+    assert!(result.is_err());
+    assert_eq!(
+        index.lookup(&storage, &credential_id(1), principal(1)),
+        Some(user_number)
+    );
+    assert_eq!(index.map.len(), 1);
+}
+
+#[test]
+fn should_remove_index_entries_of_deallocated_anchors() {
+    let mut index = complete_index();
+    index.insert(&credential_id(1), RANGE.0);
+    index.insert(&credential_id(2), RANGE.0 + 1);
+    index.insert(&credential_id(1), RANGE.0 + 2);
+
+    index.remove_anchors_from(RANGE.0 + 1);
+    assert_eq!(index.map.len(), 1);
+    assert!(index
+        .map
+        .contains_key(&CredentialKey::new(&credential_id(1), RANGE.0)));
+}
+
+#[test]
+fn should_remove_index_entries_of_deleted_anchors() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let mut index = complete_index();
+    let user_number = register(&mut storage, &mut index, &[1, 2]);
+
+    IndexedStorage::new(&mut storage, &mut index)
+        .delete_with(user_number, |storage| storage.delete_anchor(user_number))
+        .unwrap();
+    assert_eq!(
+        index.lookup(&storage, &credential_id(1), principal(1)),
+        None
+    );
+    assert_eq!(index.map.len(), 0);
+
+    // the entries are kept if the anchor cannot be deleted
+    let user_number = register(&mut storage, &mut index, &[3]);
+    let result: Result<(), StorageError> = IndexedStorage::new(&mut storage, &mut index)
+        .delete_with(user_number, |_| {
+            Err(StorageError::BadUserNumber(user_number))
+        });
+    assert!(result.is_err());
+    assert_eq!(
+        index.lookup(&storage, &credential_id(3), principal(3)),
+        Some(user_number)
+    );
+}
+
+#[test]
+fn should_rebuild_index_in_batches() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for key in 0..5 {
+        let (user_number, _) = storage.allocate_anchor().unwrap();
+        storage.write_anchor(user_number, &anchor(&[key])).unwrap();
+    }
+
+    let mut index = CredentialIndex::init(VectorMemory::default(), 0);
+    assert!(!index.is_complete());
+    assert!(!index.rebuild_batch(&storage, 2));
+    assert!(!index.rebuild_batch(&storage, 2));
+    assert!(index.rebuild_batch(&storage, 2));
+    assert!(index.is_complete());
+    for key in 0..5 {
+        assert_eq!(
+            index.lookup(&storage, &credential_id(key), principal(key)),
+            Some(RANGE.0 + key as u64)
+        );
+    }
+
+    // anchors written without the index are picked up by a partial rebuild
+    storage.write_anchor(RANGE.0 + 3, &anchor(&[7])).unwrap();
+    index.rebuild_from(RANGE.0 + 3);
+    assert!(!index.is_complete());
+    assert!(index.rebuild_batch(&storage, 10));
+    assert_eq!(
+        index.lookup(&storage, &credential_id(7), principal(7)),
+        Some(RANGE.0 + 3)
+    );
+}
+
+#[test]
+fn should_only_reload_index_with_the_same_tag() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let memory = VectorMemory::default();
+    let mut index = CredentialIndex::init(memory.clone(), 42);
+    index.rebuild_batch(&storage, 10);
+    let user_number = register(&mut storage, &mut index, &[1]);
+
+    let index = CredentialIndex::init(memory.clone(), 42);
+    assert!(index.is_complete());
+    assert_eq!(
+        index.lookup(&storage, &credential_id(1), principal(1)),
+        Some(user_number)
+    );
+
+    // e.g. the region of the index moved
+    let index = CredentialIndex::init(memory, 43);
+    assert!(!index.is_complete());
+    assert_eq!(index.map.len(), 0);
+}
@@ -0,0 +1,370 @@
+//! Migration of the layouts before version 16, which kept all data in a single region of the stable
+//! memory, to the regions of the memory manager (see the [module documentation](super)).
+//!
+//! ## Legacy Layout
+//!
+//! ```text
+//! ------------------------------------------- <- Address 0
+//! Header                      ↕ HEADER_SIZE bytes
+//! -------------------------------------------
+//! Reserved space              ↕ (512 - HEADER_SIZE) bytes
+//! ------------------------------------------- <- 512
+//! Principal index length      ↕ 4 bytes
+//! -------------------------------------------
+//! Candid encoded principal index
+//! ------------------------------------------- <- ENTRY_OFFSET
+//! Entries of the anchor range (A_MAX - A_0) * SIZE_MAX bytes
+//! ------------------------------------------- <- reserve start
+//! Persistent state slot 0     ↕ (reserve - archive) / 4 bytes
+//! -------------------------------------------
+//! Persistent state slot 1     ↕ (reserve - archive) / 4 bytes
+//! -------------------------------------------
+//! Credential index            ↕ whole pages up to the revocations
+//! -------------------------------------------
+//! Revocations                 ↕ REVOCATIONS_REGION_SIZE bytes of whole pages
+//! ------------------------------------------- <- reserve start + reserve - ARCHIVE_BUFFER_REGION_SIZE
+//! Archive buffer              ↕ ARCHIVE_BUFFER_REGION_SIZE bytes
+//! ------------------------------------------- <- reserve start + reserve
+//! ```
+//!
+//! Layouts before version 15 wrote the persistent state to a single location recorded in the
+//! header, and layouts before version 10 right after the entry of the last allocated anchor, where
+//! the anchors allocated since may have overwritten it.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::ops::Range;
+
+use candid::Principal;
+use ic_stable_structures::reader::Reader;
+use ic_stable_structures::Memory;
+
+use super::{
+    persistent_state_prefix, read_principal_index, HeaderError, PersistentStateError, Storage,
+    StorageError, ARCHIVE_BUFFER_MAGIC, ARCHIVE_BUFFER_PREFIX_SIZE, ARCHIVE_BUFFER_REGION_SIZE,
+    CURRENT_LAYOUT_VERSION, ENTRY_OFFSET, HEADER_FLAG_LEGACY_RECORDS,
+    HEADER_FLAG_PERSISTENT_STATE_VERSION, HEADER_FLAG_PRINCIPAL_INDEX, PERSISTENT_STATE_CHUNK_SIZE,
+    PERSISTENT_STATE_MAGIC, PRINCIPAL_INDEX_OFFSET, WASM_PAGE_SIZE,
+};
+use crate::types::UserNumber;
+
+/// Size of the region holding the revoked delegations in front of the archive buffer.
+const REVOCATIONS_REGION_SIZE: u64 = 4 * 1024 * 1024;
+/// Magic and tag starting the revoked delegations, see [super::revocations].
+const REVOCATION_LIST_MAGIC: [u8; 4] = *b"IIRL";
+const REVOCATION_LIST_TAG: Range<usize> = 4..12;
+/// Address of the memory manager, which may hold the end of the principal index in these layouts.
+const MEMORY_MANAGER_ADDRESS: u64 = WASM_PAGE_SIZE;
+
+/// Data of a legacy layout that is copied to the regions of the memory manager.
+struct LegacyData {
+    principal_index: Option<BTreeMap<Principal, UserNumber>>,
+    persistent_state: Option<(u8, Vec<u8>)>,
+    archive_buffer: Option<Vec<u8>>,
+    revocations: Option<Vec<u8>>,
+}
+
+/// Migrates a storage of a layout before version 16 to the regions of the memory manager and
+/// upgrades the header to the current layout version.
+///
+/// The data is read before the memory manager is created, as it overwrites the end of the
+/// principal index. The entries of the anchors are not copied: the first buckets of the memory
+/// manager are allocated to the anchors, so they start at [ENTRY_OFFSET] like before.
+pub(super) fn migrate<M: Memory + Clone>(storage: &mut Storage<M>) -> Result<(), HeaderError> {
+    let data = read_legacy_data(storage)?;
+    if storage.memory.size() > 1 {
+        storage.memory.write(MEMORY_MANAGER_ADDRESS, &[0; 3]);
+    }
+
+    let anchors = storage.anchor_memory();
+    storage
+        .ensure_region_capacity(anchors, legacy_entries_end(storage))
+        .map_err(HeaderError::MigrationFailed)?;
+    if let Some(principal_index) = data.principal_index {
+        let encoded =
+            candid::encode_one(&principal_index).map_err(StorageError::SerializationError);
+        encoded
+            .and_then(|encoded| storage.write_principal_index_region(&encoded))
+            .map_err(HeaderError::MigrationFailed)?;
+        storage.principal_index = principal_index;
+    }
+    if let Some((version, state)) = data.persistent_state {
+        let epoch = storage.header.persistent_state_epoch + 1;
+        let region = &storage.regions().persistent_state[0];
+        let mut bytes = persistent_state_prefix(version, epoch, state.len() as u64);
+        bytes.extend_from_slice(&state);
+        storage
+            .ensure_region_capacity(region, bytes.len() as u64)
+            .map_err(HeaderError::MigrationFailed)?;
+        region.write(0, &bytes);
+        storage.header.persistent_state_epoch = epoch;
+        storage.header.persistent_state_length = bytes.len() as u64;
+    }
+    if let Some(encoded) = data.archive_buffer {
+        storage
+            .write_archive_bytes(&encoded)
+            .map_err(HeaderError::MigrationFailed)?;
+    }
+    if let Some(mut revocations) = data.revocations {
+        // the list was created for the pages it was kept in, its region never moves now
+        revocations[REVOCATION_LIST_TAG].fill(0);
+        let region = &storage.regions().revocations;
+        storage
+            .ensure_region_capacity(region, revocations.len() as u64)
+            .map_err(HeaderError::MigrationFailed)?;
+        region.write(0, &revocations);
+    }
+
+    let header = &mut storage.header;
+    match header.version {
+        3 => {
+            header.flags |= HEADER_FLAG_LEGACY_RECORDS;
+            header.new_layout_start = 0;
+        }
+        4 if header.new_layout_start > 0 => header.flags |= HEADER_FLAG_LEGACY_RECORDS,
+        _ => {}
+    }
+    header.flags |= HEADER_FLAG_PERSISTENT_STATE_VERSION;
+    header.persistent_state_address = 0;
+    header.persistent_state_anchor_count = 0;
+    header.active_slot = 0;
+    header.version = CURRENT_LAYOUT_VERSION;
+    storage.header_written = false;
+    storage.flush();
+    Ok(())
+}
+
+fn read_legacy_data<M: Memory + Clone>(storage: &Storage<M>) -> Result<LegacyData, HeaderError> {
+    let principal_index = if storage.header.flags & HEADER_FLAG_PRINCIPAL_INDEX != 0 {
+        Some(read_principal_index(
+            &storage.memory,
+            PRINCIPAL_INDEX_OFFSET,
+        )?)
+    } else {
+        None
+    };
+    let persistent_state = match read_persistent_bytes(storage) {
+        Ok(state) => Some(state),
+        // not saved by the canister this one is upgraded from
+        Err(PersistentStateError::NotFound) => None,
+        Err(err) => return Err(HeaderError::LegacyPersistentState(err)),
+    };
+    Ok(LegacyData {
+        principal_index,
+        persistent_state,
+        archive_buffer: read_archive_bytes(storage).map_err(HeaderError::MigrationFailed)?,
+        revocations: read_revocations(storage),
+    })
+}
+
+/// Returns the offset in the region of the anchors up to which entries may have been written.
+fn legacy_entries_end<M: Memory + Clone>(storage: &Storage<M>) -> u64 {
+    if storage.hashed_placement_enabled() {
+        // entries are spread over the whole range, but never beyond the memory grown for them
+        let memory_end = (storage.memory.size() * WASM_PAGE_SIZE).saturating_sub(ENTRY_OFFSET);
+        return memory_end.min(storage.anchor_range_end() - ENTRY_OFFSET);
+    }
+    let entry_size = u16::max(
+        storage.header.entry_size,
+        storage.header.entry_size_migration_target,
+    );
+    storage.header.num_users as u64 * entry_size as u64
+}
+
+/// Returns the address of the first byte after the entries of the allocated anchors.
+fn unused_memory_start<M: Memory + Clone>(storage: &Storage<M>) -> u64 {
+    ENTRY_OFFSET + storage.unused_memory_start()
+}
+
+fn archive_buffer_address<M: Memory + Clone>(storage: &Storage<M>) -> u64 {
+    storage.anchor_range_end() + storage.stable_memory_reserve() - ARCHIVE_BUFFER_REGION_SIZE
+}
+
+/// Returns the bytes of the stable memory in the given range that have been grown.
+fn read_allocated<M: Memory>(memory: &M, range: Range<u64>) -> Vec<u8> {
+    let end = range.end.min(memory.size() * WASM_PAGE_SIZE);
+    let mut buf = vec![0; end.saturating_sub(range.start) as usize];
+    if !buf.is_empty() {
+        memory.read(range.start, &mut buf);
+    }
+    buf
+}
+
+/// Reads the candid encoded archive entries kept at the end of the stable memory reserve, if any.
+fn read_archive_bytes<M: Memory + Clone>(
+    storage: &Storage<M>,
+) -> Result<Option<Vec<u8>>, StorageError> {
+    let address = archive_buffer_address(storage);
+    let prefix = read_allocated(
+        &storage.memory,
+        address..address + ARCHIVE_BUFFER_PREFIX_SIZE,
+    );
+    if prefix.len() as u64 != ARCHIVE_BUFFER_PREFIX_SIZE || prefix[..4] != ARCHIVE_BUFFER_MAGIC {
+        return Ok(None);
+    }
+    let size = u64::from_le_bytes(prefix[4..].try_into().unwrap());
+    let data_address = address + ARCHIVE_BUFFER_PREFIX_SIZE;
+    let data = read_allocated(
+        &storage.memory,
+        data_address..data_address.saturating_add(size),
+    );
+    if size > ARCHIVE_BUFFER_REGION_SIZE - ARCHIVE_BUFFER_PREFIX_SIZE || data.len() as u64 != size {
+        return Err(StorageError::BadArchiveBuffer { size });
+    }
+    Ok(Some(data))
+}
+
+/// Reads the pages of the revoked delegations in front of the archive buffer, if the list has been
+/// created.
+fn read_revocations<M: Memory + Clone>(storage: &Storage<M>) -> Option<Vec<u8>> {
+    let end = archive_buffer_address(storage) / WASM_PAGE_SIZE * WASM_PAGE_SIZE;
+    let start = end.saturating_sub(REVOCATIONS_REGION_SIZE);
+    let pages = read_allocated(&storage.memory, start..end);
+    if pages.len() < REVOCATION_LIST_TAG.end || pages[..4] != REVOCATION_LIST_MAGIC {
+        return None;
+    }
+    Some(pages)
+}
+
+/// Returns true if anchors were allocated on top of the last written persistent state, i.e. if
+/// it may have been overwritten by anchor writes since.
+///
+/// This can only happen if the anchor range was extended after the state was written, because
+/// the extended range then covers the former stable memory reserve.
+fn persistent_state_is_stale<M: Memory + Clone>(storage: &Storage<M>) -> bool {
+    let address = storage.header.persistent_state_address;
+    address != 0
+        && storage.header.num_users > storage.header.persistent_state_anchor_count
+        && address < unused_memory_start(storage)
+}
+
+/// Reads the version and the candid encoded data of the persistent state from the location
+/// recorded in the header, or just outside of the space allocated to the highest user number if it
+/// was written by a layout before version 10.
+fn read_persistent_bytes<M: Memory + Clone>(
+    storage: &Storage<M>,
+) -> Result<(u8, Vec<u8>), PersistentStateError> {
+    let header = &storage.header;
+    if persistent_state_is_stale(storage) {
+        return Err(PersistentStateError::Overwritten {
+            anchor_count: header.persistent_state_anchor_count,
+            num_users: header.num_users,
+        });
+    }
+
+    let address = header.persistent_state_address;
+    let epoch = header.persistent_state_epoch;
+    if address == 0 {
+        return read_persistent_bytes_at(storage, unused_memory_start(storage), epoch);
+    }
+    match read_persistent_bytes_at(storage, address, epoch) {
+        // The active slot does not hold the committed state, so the previous state is read from
+        // the other slot instead. States that can be found but not read are not replaced by older
+        // ones.
+        Err(
+            err @ (PersistentStateError::NotFound
+            | PersistentStateError::StaleState { .. }
+            | PersistentStateError::Overwritten { .. }
+            | PersistentStateError::CorruptedSize(_)
+            | PersistentStateError::ReadError(_)),
+        ) if epoch > 1 => {
+            let slot_size = storage.persistent_state_slot_size();
+            let other_address = match header.active_slot {
+                0 => address + slot_size,
+                _ => address - slot_size,
+            };
+            read_persistent_bytes_at(storage, other_address, epoch - 1).map_err(|_| err)
+        }
+        result => result,
+    }
+}
+
+/// Reads the version and the candid encoded data of the persistent state at `address`, which
+/// must have been written with the `expected_epoch` (unless it is 0).
+fn read_persistent_bytes_at<M: Memory + Clone>(
+    storage: &Storage<M>,
+    address: u64,
+    expected_epoch: u64,
+) -> Result<(u8, Vec<u8>), PersistentStateError> {
+    let header = &storage.header;
+    let memory_end = storage.memory.size() * WASM_PAGE_SIZE;
+    let overwritten = || PersistentStateError::Overwritten {
+        anchor_count: header.persistent_state_anchor_count,
+        num_users: header.num_users,
+    };
+
+    if address > memory_end {
+        // the address where the persistent state would be is not allocated yet
+        return Err(PersistentStateError::NotFound);
+    }
+
+    let mut reader = Reader::new(&storage.memory, address);
+    let mut magic_buf: [u8; 4] = [0; 4];
+    let bytes_read = reader
+        .read(&mut magic_buf)
+        // if we hit out of bounds here, this means that the persistent state has not been
+        // written at the expected location and thus cannot be found
+        .map_err(|_| PersistentStateError::NotFound)?;
+
+    if bytes_read != 4 || magic_buf != PERSISTENT_STATE_MAGIC {
+        // less than the expected number of bytes were read or the magic does not match
+        // --> this is not the persistent state
+        return Err(PersistentStateError::NotFound);
+    }
+
+    // states written before the version was introduced are not preceded by a version
+    let mut version = 0;
+    let mut version_len = 0;
+    if header.flags & HEADER_FLAG_PERSISTENT_STATE_VERSION != 0 {
+        let mut version_buf: [u8; 1] = [0];
+        version_len = reader
+            .read(&mut version_buf)
+            .map_err(PersistentStateError::ReadError)? as u64;
+        version = version_buf[0];
+    }
+
+    // states written before the epoch was introduced are not preceded by an epoch
+    let mut epoch_len = 0;
+    if expected_epoch != 0 {
+        let mut epoch_buf: [u8; 8] = [0; 8];
+        epoch_len = reader
+            .read(&mut epoch_buf)
+            .map_err(PersistentStateError::ReadError)? as u64;
+        let found = u64::from_le_bytes(epoch_buf);
+        if found != expected_epoch {
+            return Err(PersistentStateError::StaleState {
+                expected: expected_epoch,
+                found,
+            });
+        }
+    }
+
+    // From here on, the magic has been found: a size or data running past the allocated
+    // memory means that the state was written, but an anchor has overwritten part of it.
+    let mut size_buf: [u8; 8] = [0; 8];
+    let bytes_read = reader.read(&mut size_buf).map_err(|_| overwritten())?;
+    if bytes_read != 8 {
+        return Err(overwritten());
+    }
+
+    let size = u64::from_le_bytes(size_buf);
+    let data_address = address + 4 + version_len + epoch_len + 8;
+    // no state extends beyond the stable memory limit, whatever has been written after it
+    if size > storage.stable_memory_size.saturating_sub(data_address) {
+        return Err(PersistentStateError::CorruptedSize(size));
+    }
+    if data_address.saturating_add(size) > memory_end {
+        return Err(overwritten());
+    }
+    if size > storage.max_persistent_state_size {
+        return Err(PersistentStateError::StateTooLarge {
+            max_size: storage.max_persistent_state_size,
+        });
+    }
+    let mut data_buf = vec![0; size as usize];
+    for chunk in data_buf.chunks_mut(PERSISTENT_STATE_CHUNK_SIZE) {
+        reader.read(chunk).map_err(|_| overwritten())?;
+    }
+
+    Ok((version, data_buf))
+}
@@ -0,0 +1,586 @@
+//! Storage of the anchor records behind the [RecordStorage] trait.
+//!
+//! Besides the fixed-slot layout of [Storage], anchor records can be kept in a [MapStorage]: a
+//! [StableBTreeMap] from user numbers to candid encoded records in a region handed out by a
+//! [MemoryManager]. Its range of user numbers is not limited by the size of the stable memory and
+//! the maximum size of a record is chosen independently of the range.
+//!
+//! Note that the map of `ic-stable-structures` 0.3 reserves the maximum record size for every
+//! record, so the map does not save space compared to fixed slots of the same size.
+//!
+//! The layout is chosen when the canister is installed (see
+//! [crate::types::AnchorStorageLayout]) and the canister accesses the anchors of either layout
+//! through [RecordStorage]. The principal index, layout migrations, compression, compaction and
+//! the backups are only available with the fixed-slot layout.
+//!
+//! ## Map Storage Layout
+//!
+//! The memory manager hands out six regions:
+//!
+//! ```text
+//! Region 0 (config):      magic "IIM" | version (1 byte) | A_0 (8 bytes) | A_MAX (8 bytes)
+//!                         | maximum record size (4 bytes) | salt (32 bytes)
+//!                         | deleted anchors (8 bytes)
+//! Region 1 (anchors):     StableBTreeMap<UserNumber, StorableAnchor>
+//! Region 2 (persistent):  magic "IIPS" | version (1 byte) | size (8 bytes) | candid encoded state
+//! Region 3 (archive):     magic "IIAB" | size (8 bytes) | candid encoded archive entries
+//! Region 4 (credentials): credential index, see [crate::storage::credential_index]
+//! Region 5 (revocations): revoked delegations, see [crate::storage::revocations]
+//! ```
+//!
+//! Like in [Storage], anchors are allocated in ascending order without gaps, so the number of
+//! entries of the map is the number of allocated anchors. Allocated anchors that have not been
+//! written yet map to an empty record, deleted anchors to the single byte 0xFF, which does not
+//! start any candid encoding. A salt of zeros has not been set yet.
+
+use std::borrow::Cow;
+use std::convert::TryInto;
+
+use ic_stable_structures::{Memory, StableBTreeMap, Storable};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+
+use crate::state::PersistentState;
+use crate::storage::{
+    decode_persistent_state, HeaderError, PersistentStateError, Salt, ScanPage, Storage,
+    StorageError, ARCHIVE_BUFFER_MAGIC, ARCHIVE_BUFFER_PREFIX_SIZE, ARCHIVE_BUFFER_REGION_SIZE,
+    CURRENT_PERSISTENT_STATE_VERSION, EMPTY_SALT, PERSISTENT_STATE_MAGIC,
+};
+use crate::types::{AnchorRecord, ArchiveEntry, UserNumber};
+
+#[cfg(test)]
+pub(crate) mod tests;
+
+const MAP_STORAGE_MAGIC: [u8; 3] = *b"IIM";
+const MEMORY_MANAGER_MAGIC: [u8; 3] = *b"MGR";
+// version   0: invalid
+// version   1: anchor records in a StableBTreeMap
+// version  2+: invalid
+const MAP_STORAGE_VERSION: u8 = 1;
+const CONFIG_SIZE: usize = 3 + 1 + 8 + 8 + 4 + 32 + 8;
+const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(0);
+const ANCHOR_MEMORY_ID: MemoryId = MemoryId::new(1);
+const PERSISTENT_STATE_MEMORY_ID: MemoryId = MemoryId::new(2);
+const ARCHIVE_BUFFER_MEMORY_ID: MemoryId = MemoryId::new(3);
+const CREDENTIAL_INDEX_MEMORY_ID: MemoryId = MemoryId::new(4);
+const REVOCATIONS_MEMORY_ID: MemoryId = MemoryId::new(5);
+/// Record of a deleted anchor in a [MapStorage].
+const TOMBSTONE: [u8; 1] = [0xFF];
+/// Size of the magic, version and size preceding the candid encoded persistent state.
+const PERSISTENT_STATE_PREFIX_SIZE: u64 = 4 + 1 + 8;
+const WASM_PAGE_SIZE: u64 = 65_536;
+
+/// Operations shared by the storage layouts. Code that only needs these should use this trait rather
+/// than a specific layout.
+pub trait RecordStorage {
+    /// Returns the range of user numbers `[lo, hi)` that can be allocated.
+    fn assigned_user_number_range(&self) -> (UserNumber, UserNumber);
+
+    /// Returns the number of allocated anchors.
+    fn user_count(&self) -> usize;
+
+    /// Allocates the next anchor, returning its user number or `None` if the range is exhausted.
+    fn allocate_anchor(&mut self) -> Option<UserNumber>;
+
+    /// Reads the record of an allocated anchor.
+    fn read_anchor(&self, user_number: UserNumber) -> Result<AnchorRecord, StorageError>;
+
+    /// Reads the records of the given user numbers, in the same order.
+    fn read_anchors(&self, user_numbers: &[UserNumber]) -> Vec<Result<AnchorRecord, StorageError>> {
+        user_numbers
+            .iter()
+            .map(|user_number| self.read_anchor(*user_number))
+            .collect()
+    }
+
+    /// Writes the record of an allocated anchor, or of the next anchor which allocates it.
+    fn write_anchor(
+        &mut self,
+        user_number: UserNumber,
+        anchor: &AnchorRecord,
+    ) -> Result<(), StorageError>;
+
+    /// Returns up to `max` allocated anchors in ascending order, starting at the user number
+    /// `cursor`, see [Storage::scan_from].
+    fn scan_from(&self, cursor: UserNumber, max: usize) -> ScanPage;
+
+    /// Writes the persistent state, returning the number of bytes written.
+    fn write_persistent_state(
+        &mut self,
+        state: &PersistentState,
+    ) -> Result<u64, PersistentStateError>;
+
+    /// Reads the persistent state written last.
+    fn read_persistent_state(&self) -> Result<PersistentState, PersistentStateError> {
+        let (version, data) = self.read_persistent_bytes()?;
+        decode_persistent_state(version, &data)
+    }
+
+    /// Reads the version and the candid encoded data of the persistent state written last.
+    fn read_persistent_bytes(&self) -> Result<(u8, Vec<u8>), PersistentStateError>;
+
+    /// Returns the salt, `None` if it has not been set yet.
+    fn salt(&self) -> Option<&Salt>;
+
+    /// Sets the salt unless it has been set before, returning whether it was set.
+    fn set_salt_if_empty(&mut self, salt: Salt) -> bool;
+
+    /// Deletes an allocated anchor, see [Storage::delete_anchor].
+    fn delete_anchor(&mut self, user_number: UserNumber) -> Result<(), StorageError>;
+
+    /// Returns the number of allocated anchors that have been deleted.
+    fn deleted_count(&self) -> usize;
+
+    /// Replaces the archive entries spilled by [crate::archive], see
+    /// [Storage::write_archive_buffer].
+    fn write_archive_buffer(&mut self, entries: &[ArchiveEntry]) -> Result<(), StorageError>;
+
+    /// Reads the archive entries written last.
+    fn read_archive_buffer(&self) -> Result<Vec<ArchiveEntry>, StorageError>;
+}
+
+impl<M: Memory + Clone> RecordStorage for Storage<M> {
+    fn assigned_user_number_range(&self) -> (UserNumber, UserNumber) {
+        Storage::assigned_user_number_range(self)
+    }
+
+    fn user_count(&self) -> usize {
+        Storage::user_count(self)
+    }
+
+    fn allocate_anchor(&mut self) -> Option<UserNumber> {
+        Storage::allocate_anchor(self).map(|(user_number, _)| user_number)
+    }
+
+    fn read_anchor(&self, user_number: UserNumber) -> Result<AnchorRecord, StorageError> {
+        Storage::read_anchor(self, user_number)
+    }
+
+    fn read_anchors(&self, user_numbers: &[UserNumber]) -> Vec<Result<AnchorRecord, StorageError>> {
+        Storage::read_anchors(self, user_numbers)
+    }
+
+    fn write_anchor(
+        &mut self,
+        user_number: UserNumber,
+        anchor: &AnchorRecord,
+    ) -> Result<(), StorageError> {
+        Storage::write_anchor(self, user_number, anchor)
+    }
+
+    fn scan_from(&self, cursor: UserNumber, max: usize) -> ScanPage {
+        Storage::scan_from(self, cursor, max)
+    }
+
+    fn write_persistent_state(
+        &mut self,
+        state: &PersistentState,
+    ) -> Result<u64, PersistentStateError> {
+        Storage::write_persistent_state(self, state)
+    }
+
+    fn read_persistent_bytes(&self) -> Result<(u8, Vec<u8>), PersistentStateError> {
+        Storage::read_persistent_bytes(self)
+    }
+
+    fn salt(&self) -> Option<&Salt> {
+        Storage::salt(self)
+    }
+
+    fn set_salt_if_empty(&mut self, salt: Salt) -> bool {
+        Storage::set_salt_if_empty(self, salt)
+    }
+
+    fn delete_anchor(&mut self, user_number: UserNumber) -> Result<(), StorageError> {
+        Storage::delete_anchor(self, user_number)
+    }
+
+    fn deleted_count(&self) -> usize {
+        Storage::deleted_count(self)
+    }
+
+    fn write_archive_buffer(&mut self, entries: &[ArchiveEntry]) -> Result<(), StorageError> {
+        Storage::write_archive_buffer(self, entries)
+    }
+
+    fn read_archive_buffer(&self) -> Result<Vec<ArchiveEntry>, StorageError> {
+        Storage::read_archive_buffer(self)
+    }
+}
+
+/// Candid encoded [AnchorRecord] stored in a [MapStorage], empty for anchors that were allocated
+/// but not written yet and [TOMBSTONE] for deleted anchors.
+pub struct StorableAnchor(Vec<u8>);
+
+impl StorableAnchor {
+    fn is_deleted(&self) -> bool {
+        self.0 == TOMBSTONE
+    }
+}
+
+impl Storable for StorableAnchor {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+/// Anchor records kept in a [StableBTreeMap] in the regions of a [MemoryManager], see the
+/// [module documentation](self).
+pub struct MapStorage<M: Memory + Clone> {
+    id_range_lo: UserNumber,
+    id_range_hi: UserNumber,
+    max_record_size: u32,
+    salt: Salt,
+    deleted_anchors: u64,
+    memory: M,
+    config: VirtualMemory<M>,
+    anchors: StableBTreeMap<VirtualMemory<M>, UserNumber, StorableAnchor>,
+    persistent_state: VirtualMemory<M>,
+    archive_buffer: VirtualMemory<M>,
+    credential_index: VirtualMemory<M>,
+    revocations: VirtualMemory<M>,
+}
+
+impl<M: Memory + Clone> MapStorage<M> {
+    /// Creates a new empty map storage in `memory` for the user numbers in `range`, with records
+    /// of at most `max_record_size` bytes.
+    pub fn new(
+        (id_range_lo, id_range_hi): (UserNumber, UserNumber),
+        memory: M,
+        max_record_size: u32,
+    ) -> Result<Self, StorageError> {
+        if id_range_hi < id_range_lo {
+            return Err(StorageError::InvalidRange {
+                range: (id_range_lo, id_range_hi),
+            });
+        }
+
+        let manager = MemoryManager::init(memory.clone());
+        let storage = Self {
+            id_range_lo,
+            id_range_hi,
+            max_record_size,
+            salt: EMPTY_SALT,
+            deleted_anchors: 0,
+            memory,
+            config: manager.get(CONFIG_MEMORY_ID),
+            anchors: StableBTreeMap::new_with_sizes(
+                manager.get(ANCHOR_MEMORY_ID),
+                std::mem::size_of::<UserNumber>() as u32,
+                max_record_size,
+            ),
+            persistent_state: manager.get(PERSISTENT_STATE_MEMORY_ID),
+            archive_buffer: manager.get(ARCHIVE_BUFFER_MEMORY_ID),
+            credential_index: manager.get(CREDENTIAL_INDEX_MEMORY_ID),
+            revocations: manager.get(REVOCATIONS_MEMORY_ID),
+        };
+        storage.write_config()?;
+        Ok(storage)
+    }
+
+    /// Initializes a map storage by reading the given memory.
+    ///
+    /// Returns `Ok(None)` if the memory is empty and an error if it does not hold a map storage,
+    /// e.g. because it holds a [Storage].
+    pub fn from_memory(memory: M) -> Result<Option<Self>, HeaderError> {
+        if memory.size() < 1 {
+            return Ok(None);
+        }
+        // the memory manager would silently replace anything that is not a memory manager
+        let mut magic = [0; 3];
+        memory.read(0, &mut magic);
+        if magic != MEMORY_MANAGER_MAGIC {
+            return Err(HeaderError::InvalidMagic(magic));
+        }
+
+        let manager = MemoryManager::init(memory.clone());
+        let config = manager.get(CONFIG_MEMORY_ID);
+        if config.size() < 1 {
+            return Err(HeaderError::TooShort(0));
+        }
+        let mut bytes = [0; CONFIG_SIZE];
+        config.read(0, &mut bytes);
+        let magic: [u8; 3] = bytes[0..3].try_into().unwrap();
+        if magic != MAP_STORAGE_MAGIC {
+            return Err(HeaderError::InvalidMagic(magic));
+        }
+        if bytes[3] != MAP_STORAGE_VERSION {
+            return Err(HeaderError::UnsupportedVersion(bytes[3]));
+        }
+        let max_record_size = u32::from_le_bytes(bytes[20..24].try_into().unwrap());
+
+        Ok(Some(Self {
+            id_range_lo: u64::from_le_bytes(bytes[4..12].try_into().unwrap()),
+            id_range_hi: u64::from_le_bytes(bytes[12..20].try_into().unwrap()),
+            max_record_size,
+            salt: bytes[24..56].try_into().unwrap(),
+            deleted_anchors: u64::from_le_bytes(bytes[56..64].try_into().unwrap()),
+            memory,
+            config,
+            anchors: StableBTreeMap::load_with_sizes(
+                manager.get(ANCHOR_MEMORY_ID),
+                std::mem::size_of::<UserNumber>() as u32,
+                max_record_size,
+            ),
+            persistent_state: manager.get(PERSISTENT_STATE_MEMORY_ID),
+            archive_buffer: manager.get(ARCHIVE_BUFFER_MEMORY_ID),
+            credential_index: manager.get(CREDENTIAL_INDEX_MEMORY_ID),
+            revocations: manager.get(REVOCATIONS_MEMORY_ID),
+        }))
+    }
+
+    /// Returns the version of the layout.
+    pub fn version(&self) -> u8 {
+        MAP_STORAGE_VERSION
+    }
+
+    /// Returns the maximum size of a candid encoded record.
+    pub fn max_record_size(&self) -> u32 {
+        self.max_record_size
+    }
+
+    /// Returns the number of WASM pages allocated for all regions.
+    pub fn total_allocated_pages(&self) -> u64 {
+        self.memory.size()
+    }
+
+    /// Returns the region of the credential index, see [crate::storage::credential_index].
+    pub fn credential_index_memory(&self) -> VirtualMemory<M> {
+        self.credential_index.clone()
+    }
+
+    /// Returns the region of the revoked delegations, see [crate::storage::revocations].
+    pub fn revocations_memory(&self) -> VirtualMemory<M> {
+        self.revocations.clone()
+    }
+
+    fn write_config(&self) -> Result<(), StorageError> {
+        let mut bytes = Vec::with_capacity(CONFIG_SIZE);
+        bytes.extend_from_slice(&MAP_STORAGE_MAGIC);
+        bytes.push(MAP_STORAGE_VERSION);
+        bytes.extend_from_slice(&self.id_range_lo.to_le_bytes());
+        bytes.extend_from_slice(&self.id_range_hi.to_le_bytes());
+        bytes.extend_from_slice(&self.max_record_size.to_le_bytes());
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&self.deleted_anchors.to_le_bytes());
+        grow_to(&self.config, CONFIG_SIZE as u64)
+            .map_err(|needed_pages| StorageError::MemoryExhausted { needed_pages })?;
+        self.config.write(0, &bytes);
+        Ok(())
+    }
+
+    fn next_user_number(&self) -> UserNumber {
+        self.id_range_lo + self.anchors.len()
+    }
+
+    fn check_range(&self, user_number: UserNumber) -> Result<(), StorageError> {
+        if user_number < self.id_range_lo || user_number >= self.id_range_hi {
+            return Err(StorageError::UserNumberOutOfRange {
+                user_number,
+                range: (self.id_range_lo, self.id_range_hi),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<M: Memory + Clone> RecordStorage for MapStorage<M> {
+    fn assigned_user_number_range(&self) -> (UserNumber, UserNumber) {
+        (self.id_range_lo, self.id_range_hi)
+    }
+
+    fn user_count(&self) -> usize {
+        self.anchors.len() as usize
+    }
+
+    fn allocate_anchor(&mut self) -> Option<UserNumber> {
+        let user_number = self.next_user_number();
+        if user_number >= self.id_range_hi {
+            return None;
+        }
+        self.anchors
+            .insert(user_number, StorableAnchor(vec![]))
+            .expect("bug: empty record too large");
+        Some(user_number)
+    }
+
+    fn read_anchor(&self, user_number: UserNumber) -> Result<AnchorRecord, StorageError> {
+        self.check_range(user_number)?;
+        let record = self
+            .anchors
+            .get(&user_number)
+            .ok_or(StorageError::BadUserNumber(user_number))?;
+        if record.is_deleted() {
+            return Err(StorageError::AnchorDeleted { user_number });
+        }
+        candid::decode_one(&record.0).map_err(StorageError::DeserializationError)
+    }
+
+    fn write_anchor(
+        &mut self,
+        user_number: UserNumber,
+        anchor: &AnchorRecord,
+    ) -> Result<(), StorageError> {
+        self.check_range(user_number)?;
+        if user_number > self.next_user_number() {
+            return Err(StorageError::BadUserNumber(user_number));
+        }
+        if self
+            .anchors
+            .get(&user_number)
+            .is_some_and(|record| record.is_deleted())
+        {
+            return Err(StorageError::AnchorDeleted { user_number });
+        }
+        let buf = candid::encode_one(anchor).map_err(StorageError::SerializationError)?;
+        if buf.len() > self.max_record_size as usize {
+            return Err(StorageError::EntrySizeLimitExceeded(buf.len()));
+        }
+        self.anchors
+            .insert(user_number, StorableAnchor(buf))
+            .expect("bug: record exceeds the maximum size");
+        Ok(())
+    }
+
+    fn scan_from(&self, cursor: UserNumber, max: usize) -> ScanPage {
+        let end = self.next_user_number();
+        let start = cursor.clamp(self.id_range_lo, end);
+        let page_end = end.min(start.saturating_add(max as u64));
+        ScanPage {
+            anchors: (start..page_end)
+                .map(|user_number| (user_number, self.read_anchor(user_number)))
+                .collect(),
+            next_cursor: (page_end < end).then_some(page_end),
+        }
+    }
+
+    fn write_persistent_state(
+        &mut self,
+        state: &PersistentState,
+    ) -> Result<u64, PersistentStateError> {
+        let data = candid::encode_one(state).map_err(PersistentStateError::CandidError)?;
+        let mut buf = Vec::with_capacity(PERSISTENT_STATE_PREFIX_SIZE as usize + data.len());
+        buf.extend_from_slice(&PERSISTENT_STATE_MAGIC);
+        buf.push(CURRENT_PERSISTENT_STATE_VERSION);
+        buf.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&data);
+
+        grow_to(&self.persistent_state, buf.len() as u64)
+            .map_err(|needed_pages| PersistentStateError::MemoryExhausted { needed_pages })?;
+        self.persistent_state.write(0, &buf);
+        Ok(buf.len() as u64)
+    }
+
+    fn read_persistent_bytes(&self) -> Result<(u8, Vec<u8>), PersistentStateError> {
+        let available = self.persistent_state.size() * WASM_PAGE_SIZE;
+        if available < PERSISTENT_STATE_PREFIX_SIZE {
+            return Err(PersistentStateError::NotFound);
+        }
+        let mut prefix = [0; PERSISTENT_STATE_PREFIX_SIZE as usize];
+        self.persistent_state.read(0, &mut prefix);
+        if prefix[0..4] != PERSISTENT_STATE_MAGIC {
+            return Err(PersistentStateError::NotFound);
+        }
+        let size = u64::from_le_bytes(prefix[5..13].try_into().unwrap());
+        if size > available - PERSISTENT_STATE_PREFIX_SIZE {
+            return Err(PersistentStateError::TooLarge {
+                size,
+                max: available - PERSISTENT_STATE_PREFIX_SIZE,
+            });
+        }
+        let mut data = vec![0; size as usize];
+        self.persistent_state
+            .read(PERSISTENT_STATE_PREFIX_SIZE, &mut data);
+        Ok((prefix[4], data))
+    }
+
+    fn salt(&self) -> Option<&Salt> {
+        (self.salt != EMPTY_SALT).then_some(&self.salt)
+    }
+
+    fn set_salt_if_empty(&mut self, salt: Salt) -> bool {
+        if self.salt != EMPTY_SALT {
+            return false;
+        }
+        self.salt = salt;
+        if self.write_config().is_err() {
+            self.salt = EMPTY_SALT;
+            return false;
+        }
+        true
+    }
+
+    fn delete_anchor(&mut self, user_number: UserNumber) -> Result<(), StorageError> {
+        self.check_range(user_number)?;
+        let record = self
+            .anchors
+            .get(&user_number)
+            .ok_or(StorageError::BadUserNumber(user_number))?;
+        if record.is_deleted() {
+            return Err(StorageError::AnchorDeleted { user_number });
+        }
+        self.anchors
+            .insert(user_number, StorableAnchor(TOMBSTONE.to_vec()))
+            .expect("bug: tombstone too large");
+        self.deleted_anchors += 1;
+        self.write_config()
+    }
+
+    fn deleted_count(&self) -> usize {
+        self.deleted_anchors as usize
+    }
+
+    fn write_archive_buffer(&mut self, entries: &[ArchiveEntry]) -> Result<(), StorageError> {
+        let encoded = candid::encode_one(entries).map_err(StorageError::SerializationError)?;
+        let max = ARCHIVE_BUFFER_REGION_SIZE - ARCHIVE_BUFFER_PREFIX_SIZE;
+        if encoded.len() as u64 > max {
+            return Err(StorageError::ArchiveBufferFull {
+                size: encoded.len() as u64,
+                max,
+            });
+        }
+        let mut buf = Vec::with_capacity(ARCHIVE_BUFFER_PREFIX_SIZE as usize + encoded.len());
+        buf.extend_from_slice(&ARCHIVE_BUFFER_MAGIC);
+        buf.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&encoded);
+
+        grow_to(&self.archive_buffer, buf.len() as u64)
+            .map_err(|needed_pages| StorageError::MemoryExhausted { needed_pages })?;
+        self.archive_buffer.write(0, &buf);
+        Ok(())
+    }
+
+    fn read_archive_buffer(&self) -> Result<Vec<ArchiveEntry>, StorageError> {
+        let available = self.archive_buffer.size() * WASM_PAGE_SIZE;
+        if available < ARCHIVE_BUFFER_PREFIX_SIZE {
+            return Ok(vec![]);
+        }
+        let mut prefix = [0; ARCHIVE_BUFFER_PREFIX_SIZE as usize];
+        self.archive_buffer.read(0, &mut prefix);
+        if prefix[..4] != ARCHIVE_BUFFER_MAGIC {
+            return Ok(vec![]);
+        }
+        let size = u64::from_le_bytes(prefix[4..].try_into().unwrap());
+        if size > available - ARCHIVE_BUFFER_PREFIX_SIZE {
+            return Err(StorageError::BadArchiveBuffer { size });
+        }
+        let mut buf = vec![0; size as usize];
+        self.archive_buffer
+            .read(ARCHIVE_BUFFER_PREFIX_SIZE, &mut buf);
+        candid::decode_one(&buf).map_err(StorageError::DeserializationError)
+    }
+}
+
+/// Grows the memory to hold at least `size` bytes. Fails with the number of missing pages if the
+/// memory cannot grow.
+fn grow_to<N: Memory>(memory: &N, size: u64) -> Result<(), u64> {
+    let required_pages = size.div_ceil(WASM_PAGE_SIZE);
+    let pages = memory.size();
+    if required_pages > pages && memory.grow(required_pages - pages) < 0 {
+        return Err(required_pages - pages);
+    }
+    Ok(())
+}
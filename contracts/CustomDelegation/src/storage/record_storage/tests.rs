@@ -0,0 +1,315 @@
+use ic_stable_structures::{Memory, VectorMemory};
+use serde_bytes::ByteBuf;
+
+use crate::state::PersistentState;
+use crate::storage::{HeaderError, PersistentStateError, Storage, StorageError};
+use crate::storage::record_storage::{MapStorage, RecordStorage};
+use crate::testing;
+use crate::types::{AnchorRecord, ArchiveEntry, StoredDelegation};
+
+pub(crate) const RANGE: (u64, u64) = (10_000, 10_010);
+
+fn sample_anchor(key: u8) -> AnchorRecord {
+    AnchorRecord {
+        devices: vec![testing::device(key)],
+        delegations: None,
+        metadata: None,
+    }
+}
+
+fn archive_entry(sequence_number: u64) -> ArchiveEntry {
+    ArchiveEntry {
+        anchor: RANGE.0,
+        timestamp: sequence_number,
+        sequence_number,
+        entry: ByteBuf::from(vec![1, 2, 3]),
+    }
+}
+
+/// An anchor whose candid encoding exceeds 4 KiB.
+fn large_anchor() -> AnchorRecord {
+    let delegation = StoredDelegation {
+        session_key: ByteBuf::from(vec![1; 64]),
+        expiration: 100,
+    };
+    AnchorRecord {
+        delegations: Some(vec![delegation; 100]),
+        ..sample_anchor(1)
+    }
+}
+
+/// Checks every [RecordStorage] has to pass, each run on an empty storage for [RANGE] with records
+/// of at most 4 KiB.
+pub(crate) const CONFORMANCE_CHECKS: [fn(&mut dyn RecordStorage); 7] = [
+    check_allocation,
+    check_reads_and_writes,
+    check_scans,
+    check_persistent_state,
+    check_salt,
+    check_deletion,
+    check_archive_buffer,
+];
+
+/// Runs the [CONFORMANCE_CHECKS]. `new_storage` creates an empty storage for [RANGE] in the given
+/// memory, with records of at most 4 KiB, and `load_storage` reads it back.
+fn check_conformance<S: RecordStorage>(
+    new_storage: impl Fn(VectorMemory) -> S,
+    load_storage: impl Fn(VectorMemory) -> S,
+) {
+    for check in CONFORMANCE_CHECKS {
+        check(&mut new_storage(VectorMemory::default()));
+    }
+
+    let memory = VectorMemory::default();
+    let mut storage = new_storage(memory.clone());
+    let state = PersistentState {
+        canister_creation_cycles_cost: 12_345,
+        ..PersistentState::default()
+    };
+    for i in 0..3 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+    storage.delete_anchor(RANGE.0 + 2).unwrap();
+    storage.write_persistent_state(&state).unwrap();
+    assert!(storage.set_salt_if_empty([7; 32]));
+    storage.write_archive_buffer(&[archive_entry(1)]).unwrap();
+    let storage = load_storage(memory);
+    assert_eq!(storage.assigned_user_number_range(), RANGE);
+    assert_eq!(storage.user_count(), 3);
+    for i in 0..2 {
+        assert_eq!(
+            storage.read_anchor(RANGE.0 + i).unwrap(),
+            sample_anchor(i as u8)
+        );
+    }
+    assert!(matches!(
+        storage.read_anchor(RANGE.0 + 2),
+        Err(StorageError::AnchorDeleted { .. })
+    ));
+    assert_eq!(storage.deleted_count(), 1);
+    assert_eq!(storage.read_persistent_state().unwrap(), state);
+    assert_eq!(storage.salt(), Some(&[7; 32]));
+    assert_eq!(
+        storage.read_archive_buffer().unwrap(),
+        vec![archive_entry(1)]
+    );
+}
+
+fn check_allocation(storage: &mut dyn RecordStorage) {
+    assert_eq!(storage.assigned_user_number_range(), RANGE);
+    assert_eq!(storage.user_count(), 0);
+    for i in 0..(RANGE.1 - RANGE.0) {
+        assert_eq!(storage.allocate_anchor(), Some(RANGE.0 + i));
+    }
+    assert_eq!(storage.allocate_anchor(), None);
+    assert_eq!(storage.user_count(), 10);
+
+    // allocated anchors can be written, but have no record before
+    assert!(storage.read_anchor(RANGE.0).is_err());
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(1));
+}
+
+fn check_reads_and_writes(storage: &mut dyn RecordStorage) {
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(2))
+        .unwrap();
+    assert_eq!(storage.user_count(), 2);
+
+    storage.write_anchor(RANGE.0, &sample_anchor(3)).unwrap();
+    assert_eq!(storage.user_count(), 2);
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(3));
+    assert_eq!(storage.read_anchor(RANGE.0 + 1).unwrap(), sample_anchor(2));
+
+    // gaps are not allowed
+    assert!(matches!(
+        storage.write_anchor(RANGE.0 + 3, &sample_anchor(4)),
+        Err(StorageError::BadUserNumber(user_number)) if user_number == RANGE.0 + 3
+    ));
+    assert!(matches!(
+        storage.write_anchor(RANGE.1, &sample_anchor(4)),
+        Err(StorageError::UserNumberOutOfRange { .. })
+    ));
+    assert!(matches!(
+        storage.read_anchor(RANGE.0 - 1),
+        Err(StorageError::UserNumberOutOfRange { .. })
+    ));
+    assert!(matches!(
+        storage.write_anchor(RANGE.0 + 2, &large_anchor()),
+        Err(StorageError::EntrySizeLimitExceeded(_))
+    ));
+    assert_eq!(storage.user_count(), 2);
+
+    let anchors = storage.read_anchors(&[RANGE.0 + 1, RANGE.1, RANGE.0]);
+    assert_eq!(anchors[0].as_ref().unwrap(), &sample_anchor(2));
+    assert!(matches!(
+        anchors[1],
+        Err(StorageError::UserNumberOutOfRange { .. })
+    ));
+    assert_eq!(anchors[2].as_ref().unwrap(), &sample_anchor(3));
+}
+
+fn check_scans(storage: &mut dyn RecordStorage) {
+    for i in 0..5 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+
+    let mut cursor = 0;
+    let mut user_numbers = vec![];
+    loop {
+        let page = storage.scan_from(cursor, 2);
+        assert!(page.anchors.len() <= 2);
+        for (user_number, anchor) in page.anchors {
+            assert_eq!(
+                anchor.unwrap(),
+                sample_anchor((user_number - RANGE.0) as u8)
+            );
+            user_numbers.push(user_number);
+        }
+        match page.next_cursor {
+            Some(next_cursor) => cursor = next_cursor,
+            None => break,
+        }
+    }
+    assert_eq!(user_numbers, (RANGE.0..RANGE.0 + 5).collect::<Vec<_>>());
+
+    let page = storage.scan_from(RANGE.0 + 3, 2);
+    assert_eq!(page.anchors.len(), 2);
+    assert_eq!(page.next_cursor, None);
+    let page = storage.scan_from(RANGE.1, 2);
+    assert!(page.anchors.is_empty());
+    assert_eq!(page.next_cursor, None);
+}
+
+fn check_persistent_state(storage: &mut dyn RecordStorage) {
+    assert!(matches!(
+        storage.read_persistent_state(),
+        Err(PersistentStateError::NotFound)
+    ));
+    let state = PersistentState {
+        canister_creation_cycles_cost: 12_345,
+        ..PersistentState::default()
+    };
+    storage.write_persistent_state(&state).unwrap();
+    assert_eq!(storage.read_persistent_state().unwrap(), state);
+
+    let state = PersistentState {
+        canister_creation_cycles_cost: 1,
+        ..state
+    };
+    storage.write_persistent_state(&state).unwrap();
+    assert_eq!(storage.read_persistent_state().unwrap(), state);
+}
+
+fn check_salt(storage: &mut dyn RecordStorage) {
+    assert_eq!(storage.salt(), None);
+    assert!(storage.set_salt_if_empty([1; 32]));
+    assert_eq!(storage.salt(), Some(&[1; 32]));
+
+    // the salt never changes once set
+    assert!(!storage.set_salt_if_empty([2; 32]));
+    assert_eq!(storage.salt(), Some(&[1; 32]));
+}
+
+fn check_deletion(storage: &mut dyn RecordStorage) {
+    for i in 0..2 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+    storage.delete_anchor(RANGE.0).unwrap();
+    assert_eq!(storage.deleted_count(), 1);
+    assert_eq!(storage.user_count(), 2);
+    assert!(matches!(
+        storage.read_anchor(RANGE.0),
+        Err(StorageError::AnchorDeleted { user_number }) if user_number == RANGE.0
+    ));
+    assert!(matches!(
+        storage.write_anchor(RANGE.0, &sample_anchor(3)),
+        Err(StorageError::AnchorDeleted { .. })
+    ));
+    assert!(matches!(
+        storage.delete_anchor(RANGE.0),
+        Err(StorageError::AnchorDeleted { .. })
+    ));
+    assert!(matches!(
+        storage.delete_anchor(RANGE.0 + 2),
+        Err(StorageError::BadUserNumber(_))
+    ));
+    assert_eq!(storage.read_anchor(RANGE.0 + 1).unwrap(), sample_anchor(1));
+
+    // the user number of the deleted anchor is not assigned again
+    assert_eq!(storage.allocate_anchor(), Some(RANGE.0 + 2));
+    assert_eq!(storage.deleted_count(), 1);
+}
+
+fn check_archive_buffer(storage: &mut dyn RecordStorage) {
+    assert_eq!(storage.read_archive_buffer().unwrap(), vec![]);
+    let entries: Vec<_> = (0..3).map(archive_entry).collect();
+    storage.write_archive_buffer(&entries).unwrap();
+    assert_eq!(storage.read_archive_buffer().unwrap(), entries);
+    storage.write_archive_buffer(&entries[2..]).unwrap();
+    assert_eq!(
+        storage.read_archive_buffer().unwrap(),
+        entries[2..].to_vec()
+    );
+}
+
+#[test]
+fn fixed_slot_storage_should_conform() {
+    check_conformance(
+        |memory| Storage::new(RANGE, memory).unwrap(),
+        |memory| Storage::from_memory(memory).unwrap(),
+    );
+}
+
+#[test]
+fn map_storage_should_conform() {
+    check_conformance(
+        |memory| MapStorage::new(RANGE, memory, 4096).unwrap(),
+        |memory| MapStorage::from_memory(memory).unwrap().unwrap(),
+    );
+}
+
+#[test]
+fn should_store_records_larger_than_entries_in_map_storage() {
+    let memory = VectorMemory::default();
+    let mut storage = MapStorage::new(RANGE, memory.clone(), 16_384).unwrap();
+    storage.write_anchor(RANGE.0, &large_anchor()).unwrap();
+
+    let storage = MapStorage::from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.max_record_size(), 16_384);
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), large_anchor());
+}
+
+#[test]
+fn should_not_read_fixed_slot_storage_as_map_storage() {
+    assert!(matches!(
+        MapStorage::from_memory(VectorMemory::default()),
+        Ok(None)
+    ));
+
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    assert!(matches!(
+        MapStorage::from_memory(memory.clone()),
+        Err(HeaderError::InvalidMagic(magic)) if &magic == b"IIC"
+    ));
+    // the fixed-slot storage is left untouched
+    let storage = Storage::from_memory(memory.clone()).unwrap();
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(1));
+
+    let memory = VectorMemory::default();
+    MapStorage::new(RANGE, memory.clone(), 4096).unwrap();
+    assert!(memory.size() > 0);
+    assert!(matches!(
+        Storage::try_from_memory(memory),
+        Err(HeaderError::InvalidMagic(magic)) if &magic == b"MGR"
+    ));
+}
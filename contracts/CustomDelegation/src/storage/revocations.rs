@@ -0,0 +1,234 @@
+//! Revoked delegations (see [crate::delegation::revoke_delegation]) kept in a memory of their own,
+//! so that they do not have to be carried in the persistent state across upgrades.
+//!
+//! A revocation is identified by a [RevocationKey]: the SHA-256 hash of the seed of the delegation
+//! followed by the SHA-256 hash of its session key. It maps to the time the revocation expires,
+//! once all delegations signed before it have expired as well. At most [MAX_REVOCATIONS]
+//! revocations are kept.
+//!
+//! The revocations are certified: a copy of them is kept in a certified map on the heap, which is
+//! loaded from the memory when the list is initialized and certified under
+//! [crate::LABEL_REVOKED] next to the HTTP responses and the signatures:
+//!
+//! ```text
+//! revoked/<seed hash><session key hash> -> expiration (8 bytes, big endian)
+//! ```
+//!
+//! Expired revocations are treated as absent, but stay in the list (and certified, with their
+//! expiration) until [RevocationList::prune_batch] removes them.
+//!
+//! ## Revocation List Layout
+//!
+//! ```text
+//! Page 0:                 magic "IIRL" | tag (8 bytes)
+//! Page 1 onwards:         StableBTreeMap<RevocationKey, Timestamp>
+//! ```
+//!
+//! The memory is only written once the first delegation is revoked. The tag identifies the region
+//! the list was created for, a list found with a different tag is discarded.
+
+use std::borrow::Cow;
+use std::convert::TryInto;
+
+use ic_cdk::api::trap;
+use ic_certified_map::{AsHashTree, Hash, HashTree, RbTree};
+use ic_stable_structures::{BoundedStorable, Memory, RestrictedMemory, StableBTreeMap, Storable};
+
+use crate::types::Timestamp;
+
+#[cfg(test)]
+mod tests;
+
+const REVOCATION_LIST_MAGIC: [u8; 4] = *b"IIRL";
+const PREFIX_SIZE: usize = 4 + 8;
+const HASH_SIZE: usize = 32;
+const KEY_SIZE: usize = 2 * HASH_SIZE;
+const WASM_PAGE_SIZE: u64 = 65_536;
+/// Upper bound of the pages of the map, the actual size is limited by the memory of the list.
+const MAX_MAP_PAGES: u64 = u64::MAX / WASM_PAGE_SIZE - 1;
+/// Maximum number of revocations that have not been pruned.
+pub const MAX_REVOCATIONS: u64 = 10_000;
+
+/// Key of the [RevocationList]: the hash of the seed of a delegation followed by the hash of its
+/// session key.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RevocationKey([u8; KEY_SIZE]);
+
+impl RevocationKey {
+    pub fn new(seed_hash: Hash, session_key_hash: Hash) -> Self {
+        let mut key = [0; KEY_SIZE];
+        key[..HASH_SIZE].copy_from_slice(&seed_hash);
+        key[HASH_SIZE..].copy_from_slice(&session_key_hash);
+        Self(key)
+    }
+}
+
+impl AsRef<[u8]> for RevocationKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Storable for RevocationKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(
+            bytes
+                .try_into()
+                .expect("bug: revocation key of unexpected size"),
+        )
+    }
+}
+
+impl BoundedStorable for RevocationKey {
+    fn max_size() -> u32 {
+        KEY_SIZE as u32
+    }
+}
+
+/// Revoked delegations in a memory of their own, see the [module documentation](self).
+pub struct RevocationList<M: Memory + Clone> {
+    memory: M,
+    // None until the first revocation is inserted, so that the memory is not grown before
+    map: Option<StableBTreeMap<RestrictedMemory<M>, RevocationKey, Timestamp>>,
+    // the revocations of the map, certified
+    certified: RbTree<RevocationKey, Vec<u8>>,
+    tag: u64,
+    // key to continue pruning with, None to start over with the first key
+    prune_cursor: Option<RevocationKey>,
+}
+
+impl<M: Memory + Clone> RevocationList<M> {
+    /// Loads the list from `memory` if it was created there with the same `tag`. Otherwise, the
+    /// list is empty and created on the first [RevocationList::insert].
+    pub fn init(memory: M, tag: u64) -> Self {
+        let mut prefix = [0; PREFIX_SIZE];
+        if memory.size() > 0 {
+            memory.read(0, &mut prefix);
+        }
+        let mut list = Self {
+            memory,
+            map: None,
+            certified: RbTree::new(),
+            tag,
+            prune_cursor: None,
+        };
+        if prefix[..4] == REVOCATION_LIST_MAGIC
+            && u64::from_le_bytes(prefix[4..12].try_into().unwrap()) == tag
+        {
+            let map: StableBTreeMap<_, RevocationKey, Timestamp> =
+                StableBTreeMap::load(list.map_memory());
+            for (key, expires_at) in map.iter() {
+                list.certified
+                    .insert(key, expires_at.to_be_bytes().to_vec());
+            }
+            list.map = Some(map);
+        }
+        list
+    }
+
+    /// Returns the number of revocations, including expired ones that have not been pruned yet.
+    pub fn len(&self) -> u64 {
+        self.map.as_ref().map_or(0, |map| map.len())
+    }
+
+    /// Revokes the delegations identified by `key` until `expires_at`. A revocation that already
+    /// exists is extended. If [MAX_REVOCATIONS] revocations exist, the expired ones are pruned
+    /// first.
+    ///
+    /// Traps if the memory of the list is exhausted.
+    pub fn insert(
+        &mut self,
+        key: RevocationKey,
+        expires_at: Timestamp,
+        now: Timestamp,
+    ) -> Result<(), String> {
+        if self.len() >= MAX_REVOCATIONS && self.expiration(&key).is_none() {
+            self.prune_cursor = None;
+            self.prune_batch(now, MAX_REVOCATIONS as usize);
+            if self.len() >= MAX_REVOCATIONS {
+                return Err(format!(
+                    "at most {} delegations can be revoked at the same time, try again later",
+                    MAX_REVOCATIONS
+                ));
+            }
+        }
+        let expires_at = self
+            .expiration(&key)
+            .map_or(expires_at, |previous| previous.max(expires_at));
+        self.map_mut()
+            .insert(key.clone(), expires_at)
+            .expect("bug: revocation key exceeds the max size");
+        self.certified
+            .insert(key, expires_at.to_be_bytes().to_vec());
+        Ok(())
+    }
+
+    /// Returns the time the revocation identified by `key` expires, if it has not been pruned.
+    pub fn expiration(&self, key: &RevocationKey) -> Option<Timestamp> {
+        self.map.as_ref()?.get(key)
+    }
+
+    /// Returns whether the delegations identified by `key` are revoked at `now`.
+    pub fn is_revoked(&self, key: &RevocationKey, now: Timestamp) -> bool {
+        self.expiration(key)
+            .is_some_and(|expires_at| expires_at > now)
+    }
+
+    /// Removes the revocations that have expired at `now` among the next `batch` revocations,
+    /// continuing where the last call stopped. Returns the number of revocations removed.
+    pub fn prune_batch(&mut self, now: Timestamp, batch: usize) -> usize {
+        let Some(map) = self.map.as_mut() else {
+            return 0;
+        };
+        let offset = self.prune_cursor.take().map(|key| key.0.to_vec());
+        let mut expired = vec![];
+        {
+            let mut entries = map.range(vec![], offset);
+            for (key, expires_at) in entries.by_ref().take(batch) {
+                if expires_at <= now {
+                    expired.push(key);
+                }
+            }
+            self.prune_cursor = entries.next().map(|(key, _)| key);
+        }
+        for key in &expired {
+            map.remove(key);
+            self.certified.delete(key.as_ref());
+        }
+        expired.len()
+    }
+
+    /// Returns the root hash of the certified revocations.
+    pub fn root_hash(&self) -> Hash {
+        self.certified.root_hash()
+    }
+
+    /// Returns the witness of the revocation identified by `key`, or of its absence.
+    pub fn witness(&self, key: &RevocationKey) -> HashTree<'_> {
+        self.certified.witness(key.as_ref())
+    }
+
+    fn map_memory(&self) -> RestrictedMemory<M> {
+        RestrictedMemory::new(self.memory.clone(), 1..MAX_MAP_PAGES)
+    }
+
+    /// Returns the map, creating it (and writing the prefix) if no revocation has been inserted
+    /// yet.
+    fn map_mut(&mut self) -> &mut StableBTreeMap<RestrictedMemory<M>, RevocationKey, Timestamp> {
+        if self.map.is_none() {
+            if self.memory.size() == 0 && self.memory.grow(1) < 0 {
+                trap("failed to grow the memory of the revocation list");
+            }
+            let mut prefix = [0; PREFIX_SIZE];
+            prefix[..4].copy_from_slice(&REVOCATION_LIST_MAGIC);
+            prefix[4..12].copy_from_slice(&self.tag.to_le_bytes());
+            self.memory.write(0, &prefix);
+            self.map = Some(StableBTreeMap::new(self.map_memory()));
+        }
+        self.map.as_mut().unwrap()
+    }
+}
@@ -0,0 +1,124 @@
+use ic_certified_map::HashTree;
+use ic_stable_structures::{Memory, VectorMemory};
+
+use crate::storage::revocations::{RevocationKey, RevocationList, MAX_REVOCATIONS};
+
+fn key(n: u8) -> RevocationKey {
+    RevocationKey::new([n; 32], [n + 1; 32])
+}
+
+/// Returns the certified expiration of the revocation, if the witness proves one.
+fn certified_expiration(list: &RevocationList<VectorMemory>, key: &RevocationKey) -> Option<u64> {
+    fn lookup<'a>(tree: &'a HashTree<'a>, label: &[u8]) -> Option<&'a HashTree<'a>> {
+        match tree {
+            HashTree::Fork(forks) => lookup(&forks.0, label).or_else(|| lookup(&forks.1, label)),
+            HashTree::Labeled(l, subtree) if *l == label => Some(subtree),
+            _ => None,
+        }
+    }
+    let witness = list.witness(key);
+    assert_eq!(witness.reconstruct(), list.root_hash());
+    match lookup(&witness, key.as_ref())? {
+        HashTree::Leaf(value) => Some(u64::from_be_bytes(value.as_ref().try_into().unwrap())),
+        _ => None,
+    }
+}
+
+#[test]
+fn should_revoke_until_expiration() {
+    let mut list = RevocationList::init(VectorMemory::default(), 0);
+    assert!(!list.is_revoked(&key(1), 1_000));
+
+    list.insert(key(1), 2_000, 1_000).unwrap();
+    assert!(list.is_revoked(&key(1), 1_999));
+    assert!(!list.is_revoked(&key(1), 2_000));
+    assert!(!list.is_revoked(&key(2), 1_000));
+
+    // revoking again extends the revocation, but never shortens it
+    list.insert(key(1), 3_000, 1_000).unwrap();
+    list.insert(key(1), 2_500, 1_000).unwrap();
+    assert_eq!(list.len(), 1);
+    assert!(list.is_revoked(&key(1), 2_999));
+    assert_eq!(certified_expiration(&list, &key(1)), Some(3_000));
+}
+
+#[test]
+fn should_certify_revocations_and_their_absence() {
+    let mut list = RevocationList::init(VectorMemory::default(), 0);
+    let empty_root = list.root_hash();
+    assert_eq!(certified_expiration(&list, &key(1)), None);
+
+    list.insert(key(1), 2_000, 1_000).unwrap();
+    list.insert(key(3), 4_000, 1_000).unwrap();
+    assert_ne!(list.root_hash(), empty_root);
+    assert_eq!(certified_expiration(&list, &key(1)), Some(2_000));
+    assert_eq!(certified_expiration(&list, &key(3)), Some(4_000));
+    assert_eq!(certified_expiration(&list, &key(2)), None);
+}
+
+#[test]
+fn should_only_write_memory_once_a_delegation_is_revoked() {
+    let memory = VectorMemory::default();
+    let mut list = RevocationList::init(memory.clone(), 0);
+    assert_eq!(list.prune_batch(1_000, 10), 0);
+    assert_eq!(memory.size(), 0);
+
+    list.insert(key(1), 2_000, 1_000).unwrap();
+    assert!(memory.size() > 0);
+}
+
+#[test]
+fn should_load_revocations_created_with_the_same_tag() {
+    let memory = VectorMemory::default();
+    let mut list = RevocationList::init(memory.clone(), 7);
+    list.insert(key(1), 2_000, 1_000).unwrap();
+    let root_hash = list.root_hash();
+
+    let list = RevocationList::init(memory.clone(), 7);
+    assert!(list.is_revoked(&key(1), 1_000));
+    assert_eq!(list.root_hash(), root_hash);
+
+    let list = RevocationList::init(memory, 8);
+    assert_eq!(list.len(), 0);
+    assert!(!list.is_revoked(&key(1), 1_000));
+}
+
+#[test]
+fn should_prune_expired_revocations_in_batches() {
+    let mut list = RevocationList::init(VectorMemory::default(), 0);
+    for n in 0..5 {
+        // revocations 0, 2 and 4 expire at 2_000
+        list.insert(key(n), 2_000 + (n % 2) as u64 * 1_000, 1_000)
+            .unwrap();
+    }
+    assert_eq!(list.prune_batch(1_500, 5), 0);
+
+    assert_eq!(list.prune_batch(2_000, 2), 1);
+    assert_eq!(list.prune_batch(2_000, 2), 1);
+    assert_eq!(list.prune_batch(2_000, 2), 1);
+    assert_eq!(list.len(), 2);
+    // starts over once all revocations have been checked
+    assert_eq!(list.prune_batch(3_000, 2), 2);
+    assert_eq!(list.len(), 0);
+    assert_eq!(
+        list.root_hash(),
+        RevocationList::init(VectorMemory::default(), 0).root_hash()
+    );
+}
+
+#[test]
+fn should_limit_number_of_revocations() {
+    let mut list = RevocationList::init(VectorMemory::default(), 0);
+    for n in 0..MAX_REVOCATIONS {
+        let key = RevocationKey::new([7; 32], crate::deps::hash::hash_bytes(n.to_le_bytes()));
+        list.insert(key, 2_000, 1_000).unwrap();
+    }
+    assert!(list.insert(key(1), 2_000, 1_000).is_err());
+    // existing revocations can still be extended
+    let existing = RevocationKey::new([7; 32], crate::deps::hash::hash_bytes(0u64.to_le_bytes()));
+    list.insert(existing, 3_000, 1_000).unwrap();
+
+    // expired revocations make room for new ones
+    list.insert(key(1), 4_000, 2_000).unwrap();
+    assert_eq!(list.len(), 2);
+}
@@ -0,0 +1,3775 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use candid::{CandidType, Principal};
+use ic_stable_structures::{Memory, VectorMemory};
+use serde_bytes::ByteBuf;
+
+use crate::rate_limit::TokenBucket;
+use crate::state::PersistentState;
+use crate::storage::{
+    check_layout_version, Header, HeaderError, PersistentStateError, Storage, StorageBuilder,
+    StorageError, WriteEvent, CURRENT_LAYOUT_VERSION, CURRENT_PERSISTENT_STATE_VERSION,
+    DEFAULT_ENTRY_SIZE, DEFAULT_RANGE_SIZE, ENTRY_OFFSET, HEADER_FLAG_PERSISTENT_STATE_VERSION,
+    HEADER_FLAG_PRINCIPAL_INDEX, HEADER_SIZE, MAX_BACKUP_CHUNK_SIZE, MAX_VERIFY_FAILURES,
+    PRINCIPAL_INDEX_OFFSET, STABLE_MEMORY_RESERVE, WASM_PAGE_SIZE,
+};
+use crate::testing;
+use crate::types::{
+    AnchorRecord, ArchiveConfig, ArchiveEntry, CredentialId, DeviceData, DeviceKey, KeyType,
+    MetadataEntry, MigrationState, Purpose, RateLimitConfig, StoredDelegation, UserNumber,
+};
+
+const RANGE: (u64, u64) = (10_000, 10_010);
+
+fn sample_anchor(key: u8) -> AnchorRecord {
+    AnchorRecord {
+        devices: vec![testing::device(key)],
+        delegations: None,
+        metadata: None,
+    }
+}
+
+/// Turns the memory of a storage that has not written anything but anchors into an image of the
+/// given layout version before 16: without the memory manager, the entries start at
+/// [ENTRY_OFFSET] like in those layouts.
+fn into_legacy_layout(mut storage: Storage<VectorMemory>, version: u8) -> VectorMemory {
+    storage.header.version = version;
+    storage.header.clear_newer_fields();
+    storage.header_written = false;
+    storage.flush();
+    storage
+        .memory
+        .write(WASM_PAGE_SIZE, &[0; WASM_PAGE_SIZE as usize]);
+    storage.memory
+}
+
+#[test]
+fn should_read_and_write_first_anchor() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(1));
+}
+
+#[test]
+fn should_read_and_write_last_anchor_in_range() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for user_number in RANGE.0..RANGE.1 {
+        storage
+            .write_anchor(user_number, &sample_anchor(user_number as u8))
+            .unwrap();
+    }
+
+    assert_eq!(
+        storage.read_anchor(RANGE.1 - 1).unwrap(),
+        sample_anchor((RANGE.1 - 1) as u8)
+    );
+}
+
+#[test]
+fn should_increment_user_count_on_new_anchor() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(2))
+        .unwrap();
+    assert_eq!(storage.user_count(), 2);
+
+    // overwriting an existing anchor does not allocate
+    storage.write_anchor(RANGE.0, &sample_anchor(3)).unwrap();
+    assert_eq!(storage.user_count(), 2);
+
+    // the header is flushed
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.user_count(), 2);
+}
+
+#[test]
+fn should_reject_writes_leaving_a_gap() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+
+    assert!(matches!(
+        storage.write_anchor(RANGE.0 + 2, &sample_anchor(2)),
+        Err(StorageError::BadUserNumber(10_002))
+    ));
+    assert_eq!(storage.user_count(), 1);
+}
+
+#[test]
+fn should_keep_persistent_state_when_writing_new_anchors() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    let state = PersistentState {
+        canister_creation_cycles_cost: 12_345,
+        ..PersistentState::default()
+    };
+    storage.write_persistent_state(&state).unwrap();
+
+    for i in 1..10 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+    assert_eq!(storage.read_persistent_state().unwrap(), state);
+}
+
+#[test]
+fn should_round_trip_persistent_state_across_upgrades() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    for i in 0..3 {
+        let state = PersistentState {
+            canister_creation_cycles_cost: i,
+            ..PersistentState::default()
+        };
+        // pre_upgrade
+        storage.write_persistent_state(&state).unwrap();
+        // post_upgrade
+        storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+        assert_eq!(storage.read_persistent_state().unwrap(), state);
+
+        storage.allocate_anchor().unwrap();
+    }
+}
+
+#[test]
+fn should_move_persistent_state_of_old_layout_to_its_region() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.allocate_anchor().unwrap();
+    let address = ENTRY_OFFSET + storage.unused_memory_start();
+    let memory = into_legacy_layout(storage, 9);
+    // persistent state written by a layout version before 10
+    let state = PersistentState {
+        canister_creation_cycles_cost: 12_345,
+        ..PersistentState::default()
+    };
+    let encoded_state = candid::encode_one(&state).unwrap();
+    memory.write(address, b"IIPS");
+    memory.write(address + 4, &(encoded_state.len() as u64).to_le_bytes());
+    memory.write(address + 12, &encoded_state);
+
+    let mut storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+    assert_eq!(storage.read_persistent_state().unwrap(), state);
+    storage.allocate_anchor().unwrap();
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.version(), CURRENT_LAYOUT_VERSION);
+    assert_eq!(storage.read_persistent_state().unwrap(), state);
+}
+
+#[test]
+fn should_keep_most_recent_writes() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.set_write_log_capacity(3);
+    for i in 0..5 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(1))
+            .unwrap();
+    }
+
+    let byte_len = candid::encode_one(sample_anchor(1)).unwrap().len();
+    assert_eq!(
+        storage.recent_writes(),
+        (2..5)
+            .map(|i| WriteEvent {
+                user_number: RANGE.0 + i,
+                byte_len,
+                timestamp_ns: 0,
+            })
+            .collect::<Vec<_>>()
+    );
+
+    storage.set_write_log_capacity(1);
+    assert_eq!(storage.recent_writes().len(), 1);
+    assert_eq!(storage.recent_writes()[0].user_number, RANGE.0 + 4);
+}
+
+#[test]
+fn should_reject_out_of_range_anchor() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+
+    for user_number in [RANGE.0 - 1, RANGE.1] {
+        assert!(matches!(
+            storage.write_anchor(user_number, &sample_anchor(1)),
+            Err(StorageError::UserNumberOutOfRange { .. })
+        ));
+        assert!(matches!(
+            storage.read_anchor(user_number),
+            Err(StorageError::UserNumberOutOfRange { .. })
+        ));
+    }
+}
+
+#[test]
+fn should_not_write_entries_exceeding_the_size_limit() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let anchor = AnchorRecord {
+        devices: (0..100).map(testing::device).collect(),
+        delegations: None,
+        metadata: None,
+    };
+
+    assert!(matches!(
+        storage.write_anchor(RANGE.0, &anchor),
+        Err(StorageError::EntrySizeLimitExceeded(_))
+    ));
+    assert!(matches!(
+        storage.read_anchor(RANGE.0),
+        Err(StorageError::DeserializationError(_))
+    ));
+}
+
+#[test]
+fn should_not_overwrite_neighbouring_entries() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(2))
+        .unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(3)).unwrap();
+
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(3));
+    assert_eq!(storage.read_anchor(RANGE.0 + 1).unwrap(), sample_anchor(2));
+}
+
+#[test]
+fn should_fail_to_decode_unwritten_anchor() {
+    let storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+
+    assert!(matches!(
+        storage.read_anchor(RANGE.0 + 5),
+        Err(StorageError::DeserializationError(_))
+    ));
+}
+
+#[test]
+fn should_report_length_prefix_larger_than_entry() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(2))
+        .unwrap();
+
+    let address = storage.record_address(0);
+    // u16::MAX marks deleted anchors
+    storage
+        .anchor_memory()
+        .write(address, &(u16::MAX - 1).to_le_bytes());
+
+    assert!(matches!(
+        storage.read_anchor(RANGE.0),
+        Err(StorageError::BadEntryLength {
+            user_number: 10_000,
+            length: 32_766
+        })
+    ));
+    assert_eq!(storage.read_anchor(RANGE.0 + 1).unwrap(), sample_anchor(2));
+}
+
+#[test]
+fn should_detect_corrupted_entry() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+
+    // flip a bit in the candid payload (after the length and the checksum)
+    let address = storage.record_address(0) + 10;
+    let mut byte = [0];
+    storage.anchor_memory().read(address, &mut byte);
+    storage.anchor_memory().write(address, &[byte[0] ^ 0x01]);
+
+    assert!(matches!(
+        storage.read_anchor(RANGE.0),
+        Err(StorageError::ChecksumMismatch {
+            user_number: 10_000
+        })
+    ));
+}
+
+#[test]
+fn should_report_corrupted_candid_of_entry_without_checksum() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.allocate_anchor().unwrap();
+
+    let address = storage.record_address(0);
+    storage.anchor_memory().write(address, &4u16.to_le_bytes());
+    storage.anchor_memory().write(address + 2, b"XXXX");
+
+    assert!(matches!(
+        storage.read_anchor(RANGE.0),
+        Err(StorageError::DeserializationError(_))
+    ));
+}
+
+#[test]
+fn should_read_entry_without_checksum_and_add_it_on_write() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.allocate_anchor().unwrap();
+
+    // entry in the layout of version 6 and before
+    let buf = candid::encode_one(sample_anchor(1)).unwrap();
+    let address = storage.record_address(0);
+    storage
+        .anchor_memory()
+        .write(address, &(buf.len() as u16).to_le_bytes());
+    storage.anchor_memory().write(address + 2, &buf);
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(1));
+
+    storage.write_anchor(RANGE.0, &sample_anchor(2)).unwrap();
+    let mut len_buf = [0; 2];
+    storage.anchor_memory().read(address, &mut len_buf);
+    assert_ne!(u16::from_le_bytes(len_buf) & 0x8000, 0);
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(2));
+}
+
+#[test]
+fn should_allocate_anchors_until_range_is_exhausted() {
+    let mut storage = Storage::new((10, 13), VectorMemory::default()).unwrap();
+
+    assert_eq!(storage.allocate_anchor(), Some((10, 0)));
+    assert_eq!(storage.allocate_anchor(), Some((11, 1)));
+    assert_eq!(storage.allocate_anchor(), Some((12, 2)));
+    assert_eq!(storage.allocate_anchor(), None);
+    assert_eq!(storage.user_count(), 3);
+}
+
+#[test]
+fn should_fill_tiny_range_to_capacity() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10, 13), memory.clone()).unwrap();
+    for user_number in 10..13 {
+        storage
+            .write_anchor(user_number, &sample_anchor(user_number as u8))
+            .unwrap();
+    }
+    assert_eq!(storage.user_count(), 3);
+
+    assert!(matches!(
+        storage.allocate_record(),
+        Err(StorageError::CapacityExhausted { range: (10, 13) })
+    ));
+    assert_eq!(storage.allocate_anchor(), None);
+    assert!(matches!(
+        storage.write_anchor(13, &sample_anchor(13)),
+        Err(StorageError::UserNumberOutOfRange { .. })
+    ));
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.user_count(), 3);
+
+    // a range ending at the largest user number
+    let mut storage = Storage::new((u64::MAX - 1, u64::MAX), VectorMemory::default()).unwrap();
+    assert_eq!(storage.allocate_record().unwrap(), 0);
+    assert!(matches!(
+        storage.allocate_record(),
+        Err(StorageError::CapacityExhausted { .. })
+    ));
+}
+
+#[test]
+fn should_report_corrupted_records() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    for i in 0..5 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+    storage.delete_anchor(RANGE.0 + 4).unwrap();
+    storage.allocate_anchor().unwrap();
+    let report = storage.verify_all();
+    assert_eq!(report.ok_count, 4);
+    assert_eq!(report.failure_count, 0);
+
+    // flip a byte of the candid encoded record of the second anchor
+    let address = storage.try_record_address(RANGE.0 + 1).unwrap() + 10;
+    let mut byte = [0];
+    storage.anchor_memory().read(address, &mut byte);
+    storage.anchor_memory().write(address, &[byte[0] ^ 0xff]);
+
+    let report = Storage::from_memory(memory).unwrap().verify_all();
+    assert_eq!(report.ok_count, 3);
+    assert_eq!(report.failure_count, 1);
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].0, RANGE.0 + 1);
+    assert!(report.failures[0].1.contains("checksum"));
+}
+
+#[test]
+fn should_cap_reported_failures() {
+    let memory = VectorMemory::default();
+    let range = (0, MAX_VERIFY_FAILURES as u64 + 10);
+    let mut storage = Storage::new(range, memory.clone()).unwrap();
+    for user_number in range.0..range.1 {
+        storage
+            .write_anchor(user_number, &sample_anchor(1))
+            .unwrap();
+        let address = storage.try_record_address(user_number).unwrap() + 10;
+        storage.anchor_memory().write(address, &[0xff]);
+    }
+
+    let report = storage.verify_all();
+    assert_eq!(report.ok_count, 0);
+    assert_eq!(report.failure_count, MAX_VERIFY_FAILURES as u32 + 10);
+    assert_eq!(report.failures.len(), MAX_VERIFY_FAILURES);
+}
+
+#[test]
+fn should_describe_header_without_salt() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.update_salt([0xab; 32]).unwrap();
+    storage.allocate_anchor().unwrap();
+    storage.allocate_anchor().unwrap();
+
+    let description = storage.describe();
+    assert!(description.contains(&format!("version: {}", CURRENT_LAYOUT_VERSION)));
+    assert!(description.contains("num_users: 2"));
+    assert!(description.contains("salt_set: true"));
+    assert!(!description.contains("171, 171"));
+}
+
+fn hashed_storage(memory: VectorMemory) -> Storage<VectorMemory> {
+    let mut storage = Storage::new(RANGE, memory).unwrap();
+    storage.update_salt([5; 32]).unwrap();
+    storage.enable_hashed_placement().unwrap();
+    storage
+}
+
+#[test]
+fn should_place_anchors_by_hashed_user_number() {
+    let memory = VectorMemory::default();
+    let mut storage = hashed_storage(memory.clone());
+    let (user_number, slot) = storage.allocate_anchor().unwrap();
+    assert_eq!(user_number, RANGE.0);
+    assert_eq!(slot as u64, storage.hashed_slot_start(RANGE.0));
+    for user_number in RANGE.0..RANGE.1 {
+        storage
+            .write_by_hashed(user_number, &sample_anchor(user_number as u8))
+            .unwrap();
+    }
+    assert_eq!(storage.user_count(), 10);
+    assert_eq!(storage.allocate_anchor(), None);
+
+    // the entries are not in the order of the user numbers
+    let slots: Vec<u32> = (RANGE.0..RANGE.1)
+        .map(|user_number| storage.hashed_slot(user_number).unwrap().0)
+        .collect();
+    assert_ne!(slots, (0..10).collect::<Vec<u32>>());
+
+    let storage = Storage::from_memory(memory).unwrap();
+    for user_number in RANGE.0..RANGE.1 {
+        assert_eq!(
+            storage.read_by_hashed(user_number).unwrap(),
+            sample_anchor(user_number as u8)
+        );
+    }
+    assert!(matches!(
+        storage.read_anchor(RANGE.0),
+        Err(StorageError::HashedPlacementEnabled)
+    ));
+    let anchors: Vec<_> = storage
+        .iter_anchors()
+        .map(|(user_number, anchor)| (user_number, anchor.unwrap()))
+        .collect();
+    assert_eq!(
+        anchors,
+        (RANGE.0..RANGE.1)
+            .map(|user_number| (user_number, sample_anchor(user_number as u8)))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn should_not_compact_or_reencode_with_hashed_placement() {
+    let mut storage = hashed_storage(VectorMemory::default());
+    for user_number in RANGE.0..RANGE.0 + 3 {
+        storage
+            .write_by_hashed(user_number, &sample_anchor(user_number as u8))
+            .unwrap();
+    }
+
+    assert!(matches!(
+        storage.compact(),
+        Err(StorageError::HashedPlacementEnabled)
+    ));
+    assert!(matches!(
+        storage.reencode_anchors(0, 10),
+        Err(StorageError::HashedPlacementEnabled)
+    ));
+    // no live anchor was deallocated
+    assert_eq!(storage.user_count(), 3);
+    assert_eq!(storage.allocate_anchor().unwrap().0, RANGE.0 + 3);
+    for user_number in RANGE.0..RANGE.0 + 3 {
+        assert_eq!(
+            storage.read_by_hashed(user_number).unwrap(),
+            sample_anchor(user_number as u8)
+        );
+    }
+}
+
+#[test]
+fn should_not_change_salt_with_hashed_placement() {
+    let memory = VectorMemory::default();
+    let mut storage = hashed_storage(memory.clone());
+    let salt = *storage.salt().unwrap();
+
+    assert!(matches!(
+        storage.update_salt([6; 32]),
+        Err(StorageError::HashedPlacementEnabled)
+    ));
+    assert!(matches!(
+        storage.rotate_salt([6; 32]),
+        Err(StorageError::HashedPlacementEnabled)
+    ));
+    assert!(!storage.set_salt_if_empty([6; 32]));
+    // setting the same salt again is fine
+    storage.update_salt(salt).unwrap();
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.salt(), Some(&salt));
+    assert_eq!(storage.previous_salt(), None);
+}
+
+#[test]
+fn should_prune_expired_delegations_with_hashed_placement() {
+    let mut storage = hashed_storage(VectorMemory::default());
+    let anchor = AnchorRecord {
+        delegations: Some(vec![
+            StoredDelegation {
+                session_key: ByteBuf::from(vec![1; 32]),
+                expiration: 100,
+            },
+            StoredDelegation {
+                session_key: ByteBuf::from(vec![2; 32]),
+                expiration: 300,
+            },
+        ]),
+        ..sample_anchor(1)
+    };
+    storage.write_by_hashed(RANGE.0, &sample_anchor(0)).unwrap();
+    storage.write_by_hashed(RANGE.0 + 1, &anchor).unwrap();
+
+    assert_eq!(storage.prune_expired_delegations(200), 1);
+    assert_eq!(
+        storage.read_by_hashed(RANGE.0 + 1).unwrap().delegations,
+        Some(vec![StoredDelegation {
+            session_key: ByteBuf::from(vec![2; 32]),
+            expiration: 300,
+        }])
+    );
+    assert_eq!(storage.read_by_hashed(RANGE.0).unwrap(), sample_anchor(0));
+    assert_eq!(storage.user_count(), 2);
+}
+
+#[test]
+fn should_probe_next_entry_on_hashed_placement_collision() {
+    let mut storage = hashed_storage(VectorMemory::default());
+    let starts: Vec<u64> = (RANGE.0..RANGE.1)
+        .map(|user_number| storage.hashed_slot_start(user_number))
+        .collect();
+    // with the salt above, two of the ten user numbers start probing at the same entry
+    let (first, second) = (0..10)
+        .flat_map(|i| (i + 1..10).map(move |j| (i, j)))
+        .find(|(i, j)| starts[*i] == starts[*j])
+        .expect("no collision");
+    let (first, second) = (RANGE.0 + first as u64, RANGE.0 + second as u64);
+
+    for user_number in RANGE.0..=second {
+        storage
+            .write_by_hashed(user_number, &sample_anchor(user_number as u8))
+            .unwrap();
+    }
+    let (first_slot, _) = storage.hashed_slot(first).unwrap();
+    let (second_slot, occupied) = storage.hashed_slot(second).unwrap();
+    assert!(occupied);
+    assert_eq!(first_slot as u64, storage.hashed_slot_start(first));
+    assert_ne!(second_slot, first_slot);
+
+    // overwriting either anchor leaves the other one untouched
+    storage.write_by_hashed(first, &sample_anchor(100)).unwrap();
+    assert_eq!(storage.read_by_hashed(first).unwrap(), sample_anchor(100));
+    assert_eq!(
+        storage.read_by_hashed(second).unwrap(),
+        sample_anchor(second as u8)
+    );
+    storage
+        .write_by_hashed(second, &sample_anchor(101))
+        .unwrap();
+    assert_eq!(storage.read_by_hashed(first).unwrap(), sample_anchor(100));
+    assert_eq!(storage.read_by_hashed(second).unwrap(), sample_anchor(101));
+}
+
+#[test]
+fn should_only_enable_hashed_placement_for_empty_storage_with_salt() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    assert!(matches!(
+        storage.enable_hashed_placement(),
+        Err(StorageError::HashedPlacementUnavailable)
+    ));
+    assert!(matches!(
+        storage.write_by_hashed(RANGE.0, &sample_anchor(1)),
+        Err(StorageError::HashedPlacementDisabled)
+    ));
+    storage.update_salt([5; 32]).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    assert!(matches!(
+        storage.enable_hashed_placement(),
+        Err(StorageError::HashedPlacementUnavailable)
+    ));
+
+    let mut storage = hashed_storage(VectorMemory::default());
+    let limit = storage.candid_entry_size_limit();
+    assert_eq!(limit, DEFAULT_ENTRY_SIZE as usize - 2 - 4 - 8);
+    // no gaps are allowed, just like with the linear placement
+    assert!(matches!(
+        storage.write_by_hashed(RANGE.0 + 1, &sample_anchor(1)),
+        Err(StorageError::BadUserNumber(user_number)) if user_number == RANGE.0 + 1
+    ));
+    assert!(matches!(
+        storage.read_by_hashed(RANGE.0),
+        Err(StorageError::BadUserNumber(_))
+    ));
+    assert!(matches!(
+        storage.write_by_hashed(RANGE.1, &sample_anchor(1)),
+        Err(StorageError::UserNumberOutOfRange { .. })
+    ));
+}
+
+#[test]
+fn should_clear_persistent_state_magic_on_allocation() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.allocate_anchor().unwrap();
+    // persistent state at the location used by previous layout versions
+    let address = storage.unused_memory_start();
+    storage.anchor_memory().write(address, b"IIPS");
+
+    let mut magic = [0; 4];
+
+    let (user_number, _) = storage.allocate_anchor().unwrap();
+    storage.anchor_memory().read(address, &mut magic);
+    assert_ne!(&magic, b"IIPS");
+    assert!(matches!(
+        storage.read_anchor(user_number),
+        Err(StorageError::DeserializationError(_))
+    ));
+}
+
+#[test]
+fn should_reject_inverted_range() {
+    assert!(matches!(
+        Storage::new((10, 5), VectorMemory::default()),
+        Err(StorageError::InvalidRange { range: (10, 5) })
+    ));
+}
+
+#[test]
+fn should_reject_too_large_range() {
+    assert!(matches!(
+        Storage::new((0, DEFAULT_RANGE_SIZE + 1), VectorMemory::default()),
+        Err(StorageError::RangeTooLarge { .. })
+    ));
+}
+
+#[test]
+fn should_build_storage() {
+    let memory = VectorMemory::default();
+    let storage = StorageBuilder::new()
+        .range(RANGE.0, RANGE.1)
+        .entry_size(1024)
+        .memory(memory.clone())
+        .build()
+        .unwrap();
+    assert_eq!(storage.assigned_user_number_range(), RANGE);
+    assert_eq!(storage.max_entry_size(), 1024);
+
+    let storage = StorageBuilder::new().memory(memory).build().unwrap();
+    assert_eq!(
+        storage.assigned_user_number_range(),
+        (0, DEFAULT_RANGE_SIZE)
+    );
+}
+
+#[test]
+fn should_not_build_storage_with_inverted_range() {
+    assert!(matches!(
+        StorageBuilder::new()
+            .range(10, 5)
+            .memory(VectorMemory::default())
+            .build(),
+        Err(StorageError::InvalidRange { range: (10, 5) })
+    ));
+}
+
+#[test]
+fn should_not_build_storage_with_too_large_range() {
+    assert!(matches!(
+        StorageBuilder::new()
+            .range(0, DEFAULT_RANGE_SIZE + 1)
+            .memory(VectorMemory::default())
+            .build(),
+        Err(StorageError::RangeTooLarge { max_size, .. }) if max_size == DEFAULT_RANGE_SIZE
+    ));
+    // the maximum size of the range depends on the entry size
+    assert!(matches!(
+        StorageBuilder::new()
+            .range(0, DEFAULT_RANGE_SIZE)
+            .entry_size(2 * DEFAULT_ENTRY_SIZE)
+            .memory(VectorMemory::default())
+            .build(),
+        Err(StorageError::RangeTooLarge { max_size, .. }) if max_size == DEFAULT_RANGE_SIZE / 2
+    ));
+}
+
+#[test]
+fn should_fit_more_anchors_with_smaller_reserve() {
+    let reserve = STABLE_MEMORY_RESERVE / 4;
+    let storage = StorageBuilder::new()
+        .stable_memory_reserve(reserve)
+        .memory(VectorMemory::default())
+        .build()
+        .unwrap();
+    let (id_range_lo, id_range_hi) = storage.assigned_user_number_range();
+    assert!(id_range_hi - id_range_lo > DEFAULT_RANGE_SIZE);
+    assert_eq!(
+        id_range_hi - id_range_lo,
+        ((32 << 30) - ENTRY_OFFSET - reserve) / DEFAULT_ENTRY_SIZE as u64
+    );
+
+    // the reserve is kept in the header, so the range can be extended after an upgrade
+    let memory = VectorMemory::default();
+    let mut storage = StorageBuilder::new()
+        .range(0, 10)
+        .stable_memory_reserve(reserve)
+        .memory(memory.clone())
+        .build()
+        .unwrap();
+    storage.flush();
+    let mut storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.stable_memory_reserve(), reserve);
+    assert_eq!(storage.memory_stats().bytes_reserved, reserve);
+    storage.extend_range(id_range_hi).unwrap();
+    assert!(matches!(
+        storage.extend_range(id_range_hi + 1),
+        Err(StorageError::RangeTooLarge { .. })
+    ));
+}
+
+#[test]
+fn should_use_default_reserve_for_headers_without_reserve() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.flush();
+    let mut bytes = [0u8; 8];
+    memory.read(Header::STABLE_MEMORY_RESERVE.start as u64, &mut bytes);
+    assert_eq!(bytes, [0; 8]);
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.stable_memory_reserve(), STABLE_MEMORY_RESERVE);
+}
+
+#[test]
+fn should_not_build_storage_with_too_small_reserve() {
+    assert!(matches!(
+        StorageBuilder::new()
+            .range(RANGE.0, RANGE.1)
+            .stable_memory_reserve(1024)
+            .memory(VectorMemory::default())
+            .build(),
+        Err(StorageError::InvalidReserve(1024))
+    ));
+}
+
+#[test]
+fn should_not_build_storage_with_invalid_entry_size() {
+    assert!(matches!(
+        StorageBuilder::new()
+            .entry_size(0)
+            .memory(VectorMemory::default())
+            .build(),
+        Err(StorageError::InvalidEntrySize(0))
+    ));
+}
+
+#[test]
+fn should_return_none_for_empty_memory() {
+    assert!(matches!(
+        Storage::try_from_memory(VectorMemory::default()),
+        Ok(None)
+    ));
+}
+
+#[test]
+fn should_reject_bad_magic() {
+    let memory = VectorMemory::default();
+    Storage::new(RANGE, memory.clone()).unwrap().flush();
+    memory.write(0, b"ABC");
+
+    assert!(matches!(
+        Storage::try_from_memory(memory),
+        Err(HeaderError::InvalidMagic(magic)) if &magic == b"ABC"
+    ));
+}
+
+#[test]
+fn should_reject_too_old_versions() {
+    for version in [0, 1, 2] {
+        let memory = VectorMemory::default();
+        Storage::new(RANGE, memory.clone()).unwrap().flush();
+        memory.write(3, &[version]);
+
+        assert!(matches!(
+            Storage::try_from_memory(memory),
+            Err(HeaderError::VersionTooOld(v)) if v == version
+        ));
+    }
+}
+
+#[test]
+fn should_reject_unsupported_versions() {
+    for version in [CURRENT_LAYOUT_VERSION + 1, u8::MAX] {
+        let memory = VectorMemory::default();
+        Storage::new(RANGE, memory.clone()).unwrap().flush();
+        memory.write(3, &[version]);
+
+        assert!(matches!(
+            Storage::try_from_memory(memory),
+            Err(HeaderError::UnsupportedVersion(v)) if v == version
+        ));
+    }
+}
+
+#[test]
+fn should_read_fields_newer_than_the_layout_version_as_zero() {
+    let fixture = include_bytes!("fixtures/header_v12.bin");
+    let memory = VectorMemory::default();
+    memory.grow(1);
+    memory.write(0, fixture);
+    // bytes at the offsets of the fields added after layout version 12, which the writer of the
+    // fixture did not cover with the checksum
+    let newer_fields = Header::DELETED_ANCHORS.start..Header::ACTIVE_SLOT.end;
+    memory.write(newer_fields.start as u64, &vec![0xff; newer_fields.len()]);
+
+    // the header is upgraded when the layout is migrated
+    let storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+    assert_eq!(storage.version(), CURRENT_LAYOUT_VERSION);
+    assert_eq!(storage.salt(), Some(&[8; 32]));
+    assert_eq!(storage.previous_salt(), Some(&[7; 32]));
+    assert_eq!(storage.deleted_count(), 0);
+    assert_eq!(storage.header.stable_memory_reserve, 0);
+    assert_eq!(storage.header.active_slot, 0);
+
+    let mut bytes = vec![0; newer_fields.len()];
+    memory.read(newer_fields.start as u64, &mut bytes);
+    assert_eq!(bytes, vec![0; newer_fields.len()]);
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.version(), CURRENT_LAYOUT_VERSION);
+    assert_eq!(storage.previous_salt(), Some(&[7; 32]));
+    assert_eq!(storage.deleted_count(), 0);
+}
+
+#[test]
+fn should_reject_upgraded_header_in_older_layout_versions() {
+    let memory = VectorMemory::default();
+    memory.grow(1);
+    memory.write(0, include_bytes!("fixtures/header_v12.bin"));
+    let mut storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+    storage.flush();
+
+    // a rollback to a release supporting only an older layout version must not read the header,
+    // as it would drop the fields it does not know
+    let version = Header::read_from(&memory).unwrap().version;
+    for supported_version in 10..CURRENT_LAYOUT_VERSION {
+        assert!(matches!(
+            check_layout_version(version, 3..=supported_version),
+            Err(HeaderError::UnsupportedVersion(v)) if v == CURRENT_LAYOUT_VERSION
+        ));
+    }
+    assert!(check_layout_version(version, 3..=CURRENT_LAYOUT_VERSION).is_ok());
+}
+
+#[test]
+fn should_halve_capacity_with_double_entry_size() {
+    assert!(Storage::new_with_entry_size(
+        (0, DEFAULT_RANGE_SIZE / 2),
+        VectorMemory::default(),
+        8192
+    )
+    .is_ok());
+    assert!(matches!(
+        Storage::new_with_entry_size((0, DEFAULT_RANGE_SIZE), VectorMemory::default(), 8192),
+        Err(StorageError::RangeTooLarge { max_size, .. }) if max_size == DEFAULT_RANGE_SIZE / 2
+    ));
+}
+
+#[test]
+fn should_use_configured_entry_size() {
+    let mut storage = Storage::new_with_entry_size(RANGE, VectorMemory::default(), 8192).unwrap();
+    assert_eq!(storage.record_address(1) - storage.record_address(0), 8192);
+
+    // fits into an 8KB entry but not into a 4KB one
+    let anchor = AnchorRecord {
+        devices: (0..100).map(testing::device).collect(),
+        delegations: None,
+        metadata: None,
+    };
+    storage.write_anchor(RANGE.0, &anchor).unwrap();
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), anchor);
+}
+
+#[test]
+fn should_reject_invalid_entry_sizes() {
+    for entry_size in [0, 2, 256, 1000, 4095, u16::MAX] {
+        assert!(matches!(
+            Storage::new_with_entry_size(RANGE, VectorMemory::default(), entry_size),
+            Err(StorageError::InvalidEntrySize(size)) if size == entry_size
+        ));
+    }
+}
+
+#[test]
+fn should_respect_configured_entry_size_in_layout() {
+    for entry_size in [512, 2048, 4096, 32_768] {
+        let storage =
+            Storage::new_with_entry_size(RANGE, VectorMemory::default(), entry_size).unwrap();
+        assert_eq!(
+            storage.record_address(3),
+            storage.record_address(0) + 3 * entry_size as u64
+        );
+        assert_eq!(storage.candid_entry_size_limit(), entry_size as usize - 6);
+    }
+}
+
+#[test]
+fn should_iterate_over_all_anchors_in_order() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    for i in 0..3 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+    // corrupt the middle record
+    storage
+        .anchor_memory()
+        .write(storage.record_address(1) + 6, b"XXXX");
+
+    let anchors: Vec<_> = storage.iter_anchors().collect();
+    assert_eq!(anchors.len(), 3);
+    assert_eq!(anchors[0].0, RANGE.0);
+    assert_eq!(anchors[0].1.as_ref().unwrap(), &sample_anchor(0));
+    assert_eq!(anchors[1].0, RANGE.0 + 1);
+    assert!(matches!(
+        anchors[1].1,
+        Err(StorageError::ChecksumMismatch { .. })
+    ));
+    assert_eq!(anchors[2].0, RANGE.0 + 2);
+    assert_eq!(anchors[2].1.as_ref().unwrap(), &sample_anchor(2));
+}
+
+#[test]
+fn should_detect_corrupted_header() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.allocate_anchor().unwrap();
+
+    // flip a bit in num_users
+    let mut byte = [0];
+    memory.read(4, &mut byte);
+    memory.write(4, &[byte[0] ^ 0x02]);
+
+    assert!(matches!(
+        Storage::try_from_memory(memory),
+        Err(HeaderError::ChecksumMismatch { .. })
+    ));
+}
+
+#[test]
+fn should_detect_corruption_of_each_header_field() {
+    // written before the number of deleted anchors was added to the header
+    let fixture = include_bytes!("fixtures/header_v12.bin");
+    let fields = [
+        Header::MAGIC,
+        Header::VERSION,
+        Header::NUM_USERS,
+        Header::ID_RANGE_LO,
+        Header::ID_RANGE_HI,
+        Header::ENTRY_SIZE,
+        Header::SALT,
+        Header::FIRST_ENTRY_OFFSET,
+        Header::NEW_LAYOUT_START,
+        Header::MIGRATION_BATCH_SIZE,
+        Header::CHECKSUM,
+        Header::ENTRY_SIZE_MIGRATION_TARGET,
+        Header::ENTRY_SIZE_MIGRATION_CURSOR,
+        Header::PERSISTENT_STATE_EPOCH,
+        Header::FLAGS,
+        Header::PERSISTENT_STATE_ADDRESS,
+        Header::PERSISTENT_STATE_LENGTH,
+        Header::PERSISTENT_STATE_ANCHOR_COUNT,
+        Header::PREVIOUS_SALT,
+    ];
+
+    let memory = VectorMemory::default();
+    memory.grow(1);
+    memory.write(0, fixture);
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.salt(), Some(&[8; 32]));
+    assert_eq!(storage.user_count(), 2);
+    assert_eq!(storage.deleted_count(), 0);
+
+    for field in fields {
+        for bit in [0x01, 0x80] {
+            let mut bytes = fixture.to_vec();
+            bytes[field.end - 1] ^= bit;
+            let memory = VectorMemory::default();
+            memory.grow(1);
+            memory.write(0, &bytes);
+
+            let result = Storage::try_from_memory(memory);
+            if field == Header::MAGIC {
+                assert!(matches!(result, Err(HeaderError::InvalidMagic(_))));
+            } else if field == Header::VERSION {
+                assert!(matches!(
+                    result,
+                    Err(HeaderError::UnsupportedVersion(_) | HeaderError::ChecksumMismatch { .. })
+                ));
+            } else {
+                assert!(
+                    matches!(result, Err(HeaderError::ChecksumMismatch { .. })),
+                    "undetected corruption of header bytes {:?}",
+                    field
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn should_detect_interrupted_upgrade() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.allocate_anchor().unwrap();
+
+    storage.begin_upgrade();
+    storage
+        .write_persistent_state(&PersistentState::default())
+        .unwrap();
+    // the upgrade is interrupted before it is finalized
+    assert!(matches!(
+        Storage::try_from_memory(memory.clone()),
+        Err(HeaderError::UpgradeNotFinalized)
+    ));
+
+    storage.end_upgrade();
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.user_count(), 1);
+}
+
+#[test]
+fn should_reject_header_with_zero_entry_size() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+
+    // a header with a valid checksum describing entries that cannot be located
+    storage.header.entry_size = 0;
+    storage.flush();
+    let result = Storage::try_from_memory(memory.clone());
+    assert!(matches!(
+        result,
+        Err(HeaderError::InconsistentLayout {
+            entry_size: 0,
+            first_entry_offset: ENTRY_OFFSET
+        })
+    ));
+    assert_eq!(
+        result.err().unwrap().to_string(),
+        "stable memory header: inconsistent layout: entry size 0 with entries starting at 131072 \
+         (expected 131072)"
+    );
+
+    storage.header.entry_size = DEFAULT_ENTRY_SIZE;
+    storage.header.first_entry_offset = ENTRY_OFFSET + 1;
+    storage.flush();
+    assert!(matches!(
+        Storage::try_from_memory(memory),
+        Err(HeaderError::InconsistentLayout {
+            entry_size: DEFAULT_ENTRY_SIZE,
+            first_entry_offset
+        }) if first_entry_offset == ENTRY_OFFSET + 1
+    ));
+}
+
+#[test]
+fn should_accept_and_upgrade_v5_header_without_checksum() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.allocate_anchor().unwrap();
+    let memory = into_legacy_layout(storage, 5);
+    memory.write(Header::CHECKSUM.start as u64, &[0; 4]);
+
+    let storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+    assert_eq!(storage.version(), CURRENT_LAYOUT_VERSION);
+    assert_eq!(storage.user_count(), 1);
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.version(), CURRENT_LAYOUT_VERSION);
+    assert_eq!(storage.user_count(), 1);
+}
+
+#[test]
+fn should_upgrade_layout_version_before_writing_entry_checksums() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.header.version = 6;
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.version(), CURRENT_LAYOUT_VERSION);
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(1));
+}
+
+#[test]
+fn should_grow_entry_size_in_batches() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    for i in 0..7 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+
+    storage.start_entry_size_migration(8192).unwrap();
+    assert_eq!(storage.migrate_entry_size(3), 4);
+
+    // interleave writes to both migrated and not yet migrated records
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(11))
+        .unwrap();
+    storage
+        .write_anchor(RANGE.0 + 6, &sample_anchor(16))
+        .unwrap();
+    storage
+        .write_anchor(RANGE.0 + 7, &sample_anchor(17))
+        .unwrap();
+    assert_eq!(storage.migrate_entry_size(3), 1);
+
+    // progress survives an upgrade
+    let mut storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(10)).unwrap();
+    assert_eq!(storage.migrate_entry_size(3), 0);
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.record_address(1) - storage.record_address(0), 8192);
+    let expected = [10, 11, 2, 3, 4, 5, 16, 17];
+    assert_eq!(storage.user_count(), expected.len());
+    for (i, key) in expected.iter().enumerate() {
+        assert_eq!(
+            storage.read_anchor(RANGE.0 + i as u64).unwrap(),
+            sample_anchor(*key)
+        );
+    }
+}
+
+#[test]
+fn should_return_record_addresses_in_range() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    assert_eq!(storage.try_record_address(RANGE.0).unwrap(), 0);
+    assert_eq!(
+        storage.try_record_address(RANGE.1 - 1).unwrap(),
+        (RANGE.1 - RANGE.0 - 1) * DEFAULT_ENTRY_SIZE as u64
+    );
+    for user_number in [RANGE.0 - 1, RANGE.1, u64::MAX] {
+        assert!(matches!(
+            storage.try_record_address(user_number),
+            Err(StorageError::UserNumberOutOfRange { .. })
+        ));
+    }
+
+    // the moved entries are located according to the new entry size
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(2))
+        .unwrap();
+    storage.start_entry_size_migration(8192).unwrap();
+    let unmoved = storage.try_record_address(RANGE.0 + 1).unwrap();
+    assert_eq!(storage.migrate_entry_size(10), 0);
+    assert_ne!(storage.try_record_address(RANGE.0 + 1).unwrap(), unmoved);
+    assert_eq!(storage.try_record_address(RANGE.0 + 1).unwrap(), 8192);
+}
+
+#[test]
+fn should_export_and_import_anchors_between_entry_sizes() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for i in 0..(RANGE.1 - RANGE.0) {
+        let (user_number, _) = storage.allocate_anchor().unwrap();
+        storage
+            .write_anchor(user_number, &sample_anchor(i as u8))
+            .unwrap();
+    }
+
+    let mut imported = Storage::new_with_entry_size(
+        (RANGE.0, RANGE.0 + 100),
+        VectorMemory::default(),
+        2 * DEFAULT_ENTRY_SIZE,
+    )
+    .unwrap();
+    let mut start = RANGE.0;
+    loop {
+        let anchors = storage.export_anchor_range(start, 3).unwrap();
+        let Some((last, _)) = anchors.last() else {
+            break;
+        };
+        start = last + 1;
+        imported.import_anchors(anchors).unwrap();
+    }
+
+    assert_eq!(imported.user_count(), storage.user_count());
+    assert_eq!(
+        imported
+            .iter_anchors()
+            .map(|(user_number, anchor)| (user_number, anchor.unwrap()))
+            .collect::<Vec<_>>(),
+        storage.export_anchor_range(RANGE.0, u16::MAX).unwrap()
+    );
+}
+
+#[test]
+fn should_reject_import_of_anchors_atomically() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(0)).unwrap();
+    let import = |user_numbers: &[UserNumber]| {
+        user_numbers
+            .iter()
+            .map(|user_number| (*user_number, sample_anchor(*user_number as u8)))
+            .collect::<Vec<_>>()
+    };
+
+    assert!(matches!(
+        storage.import_anchors(import(&[RANGE.0 + 1, RANGE.0])),
+        Err(StorageError::AnchorOccupied { user_number }) if user_number == RANGE.0
+    ));
+    assert!(matches!(
+        storage.import_anchors(import(&[RANGE.0 + 1, RANGE.0 + 3])),
+        Err(StorageError::BadUserNumber(user_number)) if user_number == RANGE.0 + 3
+    ));
+    assert!(matches!(
+        storage.import_anchors(import(&[RANGE.0 + 1, RANGE.0 + 1])),
+        Err(StorageError::BadUserNumber(_))
+    ));
+    assert!(matches!(
+        storage.import_anchors(import(&[RANGE.0 + 1, RANGE.1])),
+        Err(StorageError::UserNumberOutOfRange { .. })
+    ));
+    let mut anchors = import(&[RANGE.0 + 1, RANGE.0 + 2]);
+    anchors[1].1.devices = (0..100).map(testing::device).collect();
+    assert!(matches!(
+        storage.import_anchors(anchors),
+        Err(StorageError::EntrySizeLimitExceeded(_))
+    ));
+    assert_eq!(storage.user_count(), 1);
+
+    storage
+        .import_anchors(import(&[RANGE.0 + 2, RANGE.0 + 1]))
+        .unwrap();
+    assert_eq!(storage.user_count(), 3);
+    assert_eq!(
+        storage.read_anchor(RANGE.0 + 2).unwrap(),
+        import(&[RANGE.0 + 2])[0].1
+    );
+}
+
+#[test]
+fn should_restore_backed_up_anchors() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for i in 0..(RANGE.1 - RANGE.0) {
+        let (user_number, _) = storage.allocate_anchor().unwrap();
+        storage
+            .write_anchor(user_number, &sample_anchor(i as u8))
+            .unwrap();
+    }
+
+    let mut restored = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    restored.set_backup_mode(true);
+    let mut offset = 0;
+    loop {
+        let chunk = storage.backup_anchors(offset, 3).unwrap();
+        if chunk.is_empty() {
+            break;
+        }
+        restored.restore_anchors(offset, &chunk).unwrap();
+        offset += (chunk.len() / DEFAULT_ENTRY_SIZE as usize) as u32;
+    }
+    restored.set_backup_mode(false);
+
+    assert_eq!(restored.user_count(), storage.user_count());
+    for user_number in RANGE.0..RANGE.1 {
+        assert_eq!(
+            restored.read_anchor(user_number).unwrap(),
+            storage.read_anchor(user_number).unwrap()
+        );
+    }
+}
+
+#[test]
+fn should_limit_backup_chunk_size() {
+    let mut storage =
+        Storage::new_with_entry_size((0, 100), VectorMemory::default(), 32_768).unwrap();
+    for _ in 0..100 {
+        storage.allocate_anchor().unwrap();
+    }
+    let chunk = storage.backup_anchors(0, u32::MAX).unwrap();
+    assert_eq!(chunk.len(), MAX_BACKUP_CHUNK_SIZE / 32_768 * 32_768);
+    assert_eq!(storage.backup_anchors(95, 10).unwrap().len(), 5 * 32_768);
+    assert!(storage.backup_anchors(100, 10).unwrap().is_empty());
+}
+
+#[test]
+fn should_only_restore_anchors_in_backup_mode() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let entry = vec![0; DEFAULT_ENTRY_SIZE as usize];
+    assert!(matches!(
+        storage.restore_anchors(0, &entry),
+        Err(StorageError::BackupModeDisabled)
+    ));
+
+    storage.set_backup_mode(true);
+    assert!(matches!(
+        storage.restore_anchors(0, &entry[1..]),
+        Err(StorageError::BadBackupChunk { length }) if length == entry.len() - 1
+    ));
+    // no gaps between the allocated anchors
+    assert!(matches!(
+        storage.restore_anchors(1, &entry),
+        Err(StorageError::BadUserNumber(_))
+    ));
+    assert!(matches!(
+        storage.restore_anchors(0, &entry.repeat(11)),
+        Err(StorageError::UserNumberOutOfRange { .. })
+    ));
+    assert_eq!(storage.user_count(), 0);
+
+    storage.restore_anchors(0, &entry).unwrap();
+    assert_eq!(storage.user_count(), 1);
+}
+
+#[test]
+fn should_allow_large_entries_after_entry_size_migration() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    let anchor = AnchorRecord {
+        devices: (0..100).map(testing::device).collect(),
+        delegations: None,
+        metadata: None,
+    };
+
+    storage.start_entry_size_migration(8192).unwrap();
+    assert!(matches!(
+        storage.write_anchor(RANGE.0, &anchor),
+        Err(StorageError::EntrySizeLimitExceeded(_))
+    ));
+    assert_eq!(storage.migrate_entry_size(10), 0);
+
+    storage.write_anchor(RANGE.0, &anchor).unwrap();
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), anchor);
+}
+
+#[test]
+fn should_reject_invalid_entry_size_migrations() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    assert!(matches!(
+        storage.start_entry_size_migration(2048),
+        Err(StorageError::InvalidEntrySize(2048))
+    ));
+    assert!(matches!(
+        storage.start_entry_size_migration(6000),
+        Err(StorageError::InvalidEntrySize(6000))
+    ));
+
+    let mut storage = Storage::new((0, DEFAULT_RANGE_SIZE), VectorMemory::default()).unwrap();
+    assert!(matches!(
+        storage.start_entry_size_migration(8192),
+        Err(StorageError::RangeTooLarge { .. })
+    ));
+}
+
+#[test]
+fn should_report_range_and_remaining_capacity() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    assert_eq!(storage.assigned_user_number_range(), RANGE);
+    assert_eq!(storage.remaining_capacity(), 10);
+
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    assert_eq!(storage.remaining_capacity(), 9);
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(2))
+        .unwrap();
+    assert_eq!(storage.remaining_capacity(), 8);
+
+    // overwriting does not use up capacity
+    storage.write_anchor(RANGE.0, &sample_anchor(3)).unwrap();
+    assert_eq!(storage.remaining_capacity(), 8);
+}
+
+#[test]
+fn should_extend_range() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10, 12), memory.clone()).unwrap();
+    storage.allocate_anchor().unwrap();
+    storage.allocate_anchor().unwrap();
+    assert_eq!(storage.allocate_anchor(), None);
+
+    storage.extend_range(13).unwrap();
+    assert_eq!(storage.allocate_anchor(), Some((12, 2)));
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.assigned_user_number_range(), (10, 13));
+}
+
+#[test]
+fn should_reject_shrinking_range() {
+    let mut storage = Storage::new((10, 20), VectorMemory::default()).unwrap();
+    assert!(matches!(
+        storage.extend_range(15),
+        Err(StorageError::RangeShrinkNotAllowed {
+            range: (10, 20),
+            new_hi: 15
+        })
+    ));
+    assert_eq!(storage.assigned_user_number_range(), (10, 20));
+}
+
+#[test]
+fn should_reject_extending_range_into_the_reserve() {
+    let mut storage = Storage::new((0, 10), VectorMemory::default()).unwrap();
+    storage.extend_range(DEFAULT_RANGE_SIZE).unwrap();
+    assert!(matches!(
+        storage.extend_range(DEFAULT_RANGE_SIZE + 1),
+        Err(StorageError::RangeTooLarge { .. })
+    ));
+}
+
+#[test]
+fn should_prune_expired_delegations() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let delegation = |key: u8, expiration| StoredDelegation {
+        session_key: ByteBuf::from(vec![key; 32]),
+        expiration,
+    };
+    let anchor = AnchorRecord {
+        devices: vec![testing::device(1)],
+        delegations: Some(vec![delegation(1, 100), delegation(2, 300)]),
+        metadata: None,
+    };
+    storage.write_anchor(RANGE.0, &anchor).unwrap();
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(2))
+        .unwrap();
+
+    assert_eq!(storage.prune_expired_delegations(200), 1);
+
+    let pruned = storage.read_anchor(RANGE.0).unwrap();
+    assert_eq!(pruned.devices, anchor.devices);
+    assert_eq!(pruned.delegations, Some(vec![delegation(2, 300)]));
+    assert_eq!(storage.read_anchor(RANGE.0 + 1).unwrap(), sample_anchor(2));
+    assert_eq!(storage.user_count(), 2);
+    assert_eq!(storage.prune_expired_delegations(200), 0);
+}
+
+#[test]
+fn should_skip_undecodable_records_when_pruning() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    let anchor = AnchorRecord {
+        devices: vec![],
+        delegations: Some(vec![StoredDelegation {
+            session_key: ByteBuf::from(vec![1; 32]),
+            expiration: 100,
+        }]),
+        metadata: None,
+    };
+    storage.write_anchor(RANGE.0, &anchor).unwrap();
+    storage.write_anchor(RANGE.0 + 1, &anchor).unwrap();
+    storage
+        .anchor_memory()
+        .write(storage.record_address(0) + 6, b"XXXX");
+
+    assert_eq!(storage.prune_expired_delegations(200), 1);
+    assert!(storage.read_anchor(RANGE.0).is_err());
+    assert_eq!(
+        storage.read_anchor(RANGE.0 + 1).unwrap().delegations,
+        Some(vec![])
+    );
+}
+
+#[test]
+fn should_read_anchors_range_with_partial_last_page() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for i in 0..5 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+
+    let page = storage.read_anchors_range(RANGE.0, 3).unwrap();
+    assert_eq!(
+        page,
+        (0..3)
+            .map(|i| (RANGE.0 + i, sample_anchor(i as u8)))
+            .collect::<Vec<_>>()
+    );
+    let page = storage.read_anchors_range(RANGE.0 + 3, 3).unwrap();
+    assert_eq!(
+        page,
+        vec![
+            (RANGE.0 + 3, sample_anchor(3)),
+            (RANGE.0 + 4, sample_anchor(4))
+        ]
+    );
+    assert!(storage
+        .read_anchors_range(RANGE.0 + 5, 3)
+        .unwrap()
+        .is_empty());
+    assert!(storage.read_anchors_range(u64::MAX, 3).unwrap().is_empty());
+}
+
+#[test]
+fn should_read_empty_anchors_range() {
+    let storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    assert!(storage.read_anchors_range(RANGE.0, 10).unwrap().is_empty());
+    assert!(matches!(
+        storage.read_anchors_range(RANGE.0 - 1, 10),
+        Err(StorageError::UserNumberOutOfRange { .. })
+    ));
+}
+
+/// Writes an entry in the vec<device> layout of versions 3 and 4.
+fn write_legacy_entry<M: Memory + Clone>(storage: &Storage<M>, record_number: u32) {
+    let buf = candid::encode_one(vec![testing::device(record_number as u8)]).unwrap();
+    let address = storage.record_address(record_number);
+    storage
+        .anchor_memory()
+        .write(address, &(buf.len() as u16).to_le_bytes());
+    storage.anchor_memory().write(address + 2, &buf);
+}
+
+fn legacy_memory(num_users: u32) -> VectorMemory {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for record_number in 0..num_users {
+        storage.allocate_anchor().unwrap();
+        write_legacy_entry(&storage, record_number);
+    }
+    into_legacy_layout(storage, 3)
+}
+
+#[test]
+fn should_read_v3_records_transparently() {
+    let storage = Storage::try_from_memory(legacy_memory(3)).unwrap().unwrap();
+    assert_eq!(storage.version(), CURRENT_LAYOUT_VERSION);
+    assert_eq!(storage.layout_migration_state(), MigrationState::NotStarted);
+    for i in 0..3 {
+        assert_eq!(
+            storage.read_anchor(RANGE.0 + i).unwrap(),
+            sample_anchor(i as u8)
+        );
+    }
+}
+
+#[test]
+fn should_not_flush_header_when_writing_v3_records() {
+    let memory = legacy_memory(3);
+    let mut storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+
+    // a flush would write the batch size held only in memory
+    let batch_size = storage.header.migration_batch_size;
+    storage.header.migration_batch_size = batch_size + 1;
+    storage.write_anchor(RANGE.0, &sample_anchor(7)).unwrap();
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(8))
+        .unwrap();
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.layout_migration_state(), MigrationState::NotStarted);
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(7));
+    assert_eq!(storage.read_anchor(RANGE.0 + 1).unwrap(), sample_anchor(8));
+    assert_eq!(storage.header.migration_batch_size, batch_size);
+}
+
+#[test]
+fn should_migrate_v3_records_in_batches() {
+    let memory = legacy_memory(5);
+    let mut storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+    storage.set_migration_batch_size(2);
+
+    assert_eq!(storage.migrate_batch().unwrap().remaining, 3);
+    assert_eq!(
+        storage.layout_migration_state(),
+        MigrationState::Started {
+            anchors_left: 3,
+            batch_size: 2
+        }
+    );
+    // records are readable in both layouts while the migration is in progress
+    for i in 0..5 {
+        assert_eq!(
+            storage.read_anchor(RANGE.0 + i).unwrap(),
+            sample_anchor(i as u8)
+        );
+    }
+
+    // the migration state survives reloading the storage
+    let mut storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+    assert_eq!(storage.migrate_batch().unwrap().remaining, 1);
+    assert_eq!(storage.migrate_batch().unwrap().remaining, 0);
+    assert_eq!(storage.layout_migration_state(), MigrationState::Finished);
+    assert!(storage.migrate_batch().unwrap().done);
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.version(), CURRENT_LAYOUT_VERSION);
+    for i in 0..5 {
+        assert_eq!(
+            storage.read_anchor(RANGE.0 + i).unwrap(),
+            sample_anchor(i as u8)
+        );
+    }
+}
+
+/// Memory that records the highest address read so far and the number of reads.
+#[derive(Clone, Default)]
+struct ReadTrackingMemory {
+    inner: VectorMemory,
+    read_end: Rc<Cell<u64>>,
+    reads: Rc<Cell<u64>>,
+}
+
+impl Memory for ReadTrackingMemory {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        self.inner.grow(pages)
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        let end = offset + dst.len() as u64;
+        self.read_end.set(self.read_end.get().max(end));
+        self.reads.set(self.reads.get() + 1);
+        self.inner.read(offset, dst)
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        self.inner.write(offset, src)
+    }
+}
+
+#[test]
+fn should_iterate_over_many_anchors_without_reading_unused_memory() {
+    let memory = ReadTrackingMemory::default();
+    let mut storage = Storage::new((0, 2_000), memory.clone()).unwrap();
+    for i in 0..1_000 {
+        storage.write_anchor(i, &sample_anchor(i as u8)).unwrap();
+    }
+    memory.read_end.set(0);
+
+    let mut count = 0;
+    for (i, (user_number, anchor)) in storage.iter_anchors().enumerate() {
+        assert_eq!(user_number, i as u64);
+        assert_eq!(anchor.unwrap(), sample_anchor(i as u8));
+        count += 1;
+    }
+    assert_eq!(count, 1_000);
+    // the region of the anchors is the only one allocated, so its offsets follow ENTRY_OFFSET
+    assert!(memory.read_end.get() <= ENTRY_OFFSET + storage.unused_memory_start());
+}
+
+#[test]
+fn should_resume_iteration_from_user_number() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for i in 0..5 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+
+    let user_numbers: Vec<_> = storage
+        .iter_anchors_from(RANGE.0 + 3)
+        .map(|(user_number, _)| user_number)
+        .collect();
+    assert_eq!(user_numbers, vec![RANGE.0 + 3, RANGE.0 + 4]);
+    assert_eq!(storage.iter_anchors_from(0).count(), 5);
+    assert_eq!(storage.iter_anchors_from(RANGE.0 + 5).count(), 0);
+    assert_eq!(storage.iter_anchors_from(u64::MAX).count(), 0);
+}
+
+#[test]
+fn should_scan_anchors_in_pages() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for i in 0..5 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+
+    let mut cursor = 0;
+    let mut pages = vec![];
+    loop {
+        let page = storage.scan_from(cursor, 2);
+        pages.push(
+            page.anchors
+                .into_iter()
+                .map(|(user_number, anchor)| {
+                    assert_eq!(
+                        anchor.unwrap(),
+                        sample_anchor((user_number - RANGE.0) as u8)
+                    );
+                    user_number
+                })
+                .collect::<Vec<_>>(),
+        );
+        match page.next_cursor {
+            Some(next_cursor) => cursor = next_cursor,
+            None => break,
+        }
+    }
+    assert_eq!(
+        pages,
+        vec![
+            vec![RANGE.0, RANGE.0 + 1],
+            vec![RANGE.0 + 2, RANGE.0 + 3],
+            vec![RANGE.0 + 4]
+        ]
+    );
+}
+
+#[test]
+fn should_end_scan_on_last_anchor() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for i in 0..4 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+
+    // a page ending exactly at the last anchor is the final page
+    let page = storage.scan_from(RANGE.0 + 2, 2);
+    assert_eq!(page.anchors.len(), 2);
+    assert_eq!(page.next_cursor, None);
+
+    let page = storage.scan_from(RANGE.0 + 1, 2);
+    assert_eq!(page.next_cursor, Some(RANGE.0 + 3));
+
+    let page = storage.scan_from(RANGE.0 + 4, 2);
+    assert!(page.anchors.is_empty());
+    assert_eq!(page.next_cursor, None);
+
+    let page = storage.scan_from(RANGE.0, 0);
+    assert!(page.anchors.is_empty());
+    assert_eq!(page.next_cursor, Some(RANGE.0));
+}
+
+#[test]
+fn should_set_range_upper() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10, 12), memory.clone()).unwrap();
+    storage.allocate_anchor().unwrap();
+
+    storage.set_range_upper(20).unwrap();
+    assert_eq!(storage.assigned_user_number_range(), (10, 20));
+    assert_eq!(storage.remaining_capacity(), 9);
+    // shrinking is fine as long as all allocated anchors stay within the range
+    storage.set_range_upper(11).unwrap();
+    assert_eq!(storage.remaining_capacity(), 0);
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.assigned_user_number_range(), (10, 11));
+}
+
+#[test]
+fn should_reject_range_upper_orphaning_records() {
+    let mut storage = Storage::new((10, 20), VectorMemory::default()).unwrap();
+    storage.allocate_anchor().unwrap();
+    storage.allocate_anchor().unwrap();
+
+    assert!(matches!(
+        storage.set_range_upper(11),
+        Err(StorageError::RangeShrinkWouldOrphanRecords {
+            range: (10, 20),
+            new_hi: 11,
+            num_users: 2
+        })
+    ));
+    assert!(matches!(
+        storage.set_range_upper(DEFAULT_RANGE_SIZE + 11),
+        Err(StorageError::RangeTooLarge { .. })
+    ));
+    assert_eq!(storage.assigned_user_number_range(), (10, 20));
+}
+
+#[test]
+fn should_report_memory_stats() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for i in 0..3 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+
+    let stats = storage.memory_stats();
+    assert_eq!(stats.num_users, 3);
+    assert_eq!(stats.entry_size, DEFAULT_ENTRY_SIZE);
+    assert_eq!(stats.bytes_used, 3 * DEFAULT_ENTRY_SIZE as u64);
+    // the pages of the header and the memory manager, followed by the bucket of the anchors
+    assert_eq!(stats.total_allocated_pages, 2 + 1024);
+    assert_eq!(stats.remaining_capacity, 7);
+}
+
+#[test]
+fn should_sum_the_payload_of_all_anchors() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    assert_eq!(storage.total_payload_bytes(), 0);
+    let large_anchor = AnchorRecord {
+        devices: vec![testing::device(1), testing::device(2), testing::device(3)],
+        ..sample_anchor(1)
+    };
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    storage.write_anchor(RANGE.0 + 1, &large_anchor).unwrap();
+    storage
+        .write_anchor(RANGE.0 + 2, &sample_anchor(3))
+        .unwrap();
+    // allocated, but not written
+    storage.allocate_anchor().unwrap();
+
+    let sample_len = candid::encode_one(sample_anchor(1)).unwrap().len() as u64;
+    let large_len = candid::encode_one(&large_anchor).unwrap().len() as u64;
+    assert_eq!(storage.total_payload_bytes(), 2 * sample_len + large_len);
+
+    storage.delete_anchor(RANGE.0 + 1).unwrap();
+    assert_eq!(storage.total_payload_bytes(), 2 * sample_len);
+}
+
+#[test]
+fn should_read_anchors_in_requested_order() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for i in 0..5 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+
+    let user_numbers = [
+        RANGE.0 + 4,
+        RANGE.0,
+        5,
+        RANGE.0 + 1,
+        RANGE.0 + 4,
+        RANGE.0 + 3,
+    ];
+    let results = storage.read_anchors(&user_numbers);
+    assert_eq!(results.len(), user_numbers.len());
+    assert_eq!(results[0].as_ref().unwrap(), &sample_anchor(4));
+    assert_eq!(results[1].as_ref().unwrap(), &sample_anchor(0));
+    assert!(matches!(
+        results[2],
+        Err(StorageError::UserNumberOutOfRange { .. })
+    ));
+    assert_eq!(results[3].as_ref().unwrap(), &sample_anchor(1));
+    assert_eq!(results[4].as_ref().unwrap(), &sample_anchor(4));
+    assert_eq!(results[5].as_ref().unwrap(), &sample_anchor(3));
+}
+
+#[test]
+fn should_read_adjacent_anchors_with_fewer_memory_reads() {
+    let memory = ReadTrackingMemory::default();
+    let mut storage = Storage::new((0, 200), memory.clone()).unwrap();
+    for i in 0..100 {
+        storage.write_anchor(i, &sample_anchor(i as u8)).unwrap();
+    }
+    let user_numbers: Vec<_> = (0..100).rev().collect();
+
+    memory.reads.set(0);
+    let naive: Vec<_> = user_numbers
+        .iter()
+        .map(|user_number| storage.read_anchor(*user_number).unwrap())
+        .collect();
+    let naive_reads = memory.reads.get();
+
+    memory.reads.set(0);
+    let batched: Vec<_> = storage
+        .read_anchors(&user_numbers)
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    let batched_reads = memory.reads.get();
+
+    assert_eq!(batched, naive);
+    assert_eq!(naive_reads, 100);
+    assert_eq!(batched_reads, 1);
+}
+
+#[test]
+fn should_reject_stale_persistent_state() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let previous_state = PersistentState {
+        canister_creation_cycles_cost: 1,
+        ..PersistentState::default()
+    };
+    storage.write_persistent_state(&previous_state).unwrap();
+    let previous_slot = storage.regions().persistent_state[0].clone();
+    let mut stale_state = vec![0; 64];
+    previous_slot.read(0, &mut stale_state);
+
+    // register an anchor and save the state again
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    storage
+        .write_persistent_state(&PersistentState::default())
+        .unwrap();
+    assert!(storage.read_persistent_state().is_ok());
+
+    // an older state ending up in the current slot must not be read as the current one, the
+    // previous state is read from the other slot instead
+    assert_eq!(storage.header.active_slot, 1);
+    storage.regions().persistent_state[1].write(0, &stale_state);
+    assert_eq!(storage.read_persistent_state().unwrap(), previous_state);
+
+    previous_slot.write(0, b"XXXX");
+    assert!(matches!(
+        storage.read_persistent_state(),
+        Err(PersistentStateError::StaleState {
+            expected: 2,
+            found: 1
+        })
+    ));
+}
+
+#[test]
+fn should_alternate_persistent_state_slots() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for (i, slot) in [0, 1, 0].into_iter().enumerate() {
+        let state = PersistentState {
+            canister_creation_cycles_cost: i as u64,
+            ..PersistentState::default()
+        };
+        storage.write_persistent_state(&state).unwrap();
+        assert_eq!(storage.header.active_slot, slot);
+        let mut magic = [0; 4];
+        storage.regions().persistent_state[slot as usize].read(0, &mut magic);
+        assert_eq!(&magic, b"IIPS");
+        assert_eq!(storage.read_persistent_state().unwrap(), state);
+    }
+}
+
+#[test]
+fn should_recover_previous_persistent_state_after_crash_before_commit() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    let state = PersistentState {
+        canister_creation_cycles_cost: 1,
+        ..PersistentState::default()
+    };
+    storage.write_persistent_state(&state).unwrap();
+
+    // the new state is written to the other slot, but the header is never flipped to it
+    let written = storage
+        .write_persistent_slot(
+            &PersistentState {
+                canister_creation_cycles_cost: 2,
+                ..PersistentState::default()
+            },
+            1024,
+        )
+        .unwrap();
+    assert_eq!(written.slot, 1);
+
+    let mut storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.header.active_slot, 0);
+    assert_eq!(storage.read_persistent_state().unwrap(), state);
+
+    // the next write replaces the uncommitted state and keeps the recovered one as previous state
+    let next_state = PersistentState {
+        canister_creation_cycles_cost: 3,
+        ..PersistentState::default()
+    };
+    storage.write_persistent_state(&next_state).unwrap();
+    assert_eq!(storage.header.active_slot, 1);
+    assert_eq!(storage.header.persistent_state_epoch, 2);
+    assert_eq!(storage.read_persistent_state().unwrap(), next_state);
+}
+
+#[test]
+fn should_reject_header_with_invalid_active_slot() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.header.active_slot = 2;
+    storage.flush();
+
+    assert!(matches!(
+        Storage::try_from_memory(memory),
+        Err(HeaderError::InvalidActiveSlot(2))
+    ));
+}
+
+#[test]
+fn should_read_persistent_state_written_without_epoch() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.allocate_anchor().unwrap();
+    let address = ENTRY_OFFSET + storage.unused_memory_start();
+    let memory = into_legacy_layout(storage, 7);
+    let encoded_state = candid::encode_one(PersistentState::default()).unwrap();
+    memory.write(address, b"IIPS");
+    memory.write(address + 4, &(encoded_state.len() as u64).to_le_bytes());
+    memory.write(address + 12, &encoded_state);
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert!(storage.read_persistent_state().is_ok());
+}
+
+#[test]
+fn should_read_records_of_mixed_record_versions() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(0)).unwrap();
+    storage.enable_record_versions().unwrap();
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(1))
+        .unwrap();
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.record_version(RANGE.0).unwrap(), 0);
+    assert_eq!(storage.record_version(RANGE.0 + 1).unwrap(), 1);
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(0));
+    assert_eq!(storage.read_anchor(RANGE.0 + 1).unwrap(), sample_anchor(1));
+    assert_eq!(
+        storage.candid_entry_size_limit(),
+        DEFAULT_ENTRY_SIZE as usize - 7
+    );
+}
+
+#[test]
+fn should_detect_corrupted_record_version() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.enable_record_versions().unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(0)).unwrap();
+
+    storage
+        .anchor_memory()
+        .write(storage.record_address(0) + 2, &[2]);
+    assert!(matches!(
+        storage.read_anchor(RANGE.0),
+        Err(StorageError::ChecksumMismatch { .. })
+    ));
+}
+
+#[test]
+fn should_reject_unsupported_record_version() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.enable_record_versions().unwrap();
+    storage.allocate_anchor().unwrap();
+
+    let buf = candid::encode_one(sample_anchor(0)).unwrap();
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[2]);
+    hasher.update(&buf);
+    let address = storage.record_address(0);
+    storage
+        .anchor_memory()
+        .write(address, &(buf.len() as u16 | 0xC000).to_le_bytes());
+    storage.anchor_memory().write(address + 2, &[2]);
+    storage
+        .anchor_memory()
+        .write(address + 3, &hasher.finalize().to_le_bytes());
+    storage.anchor_memory().write(address + 7, &buf);
+
+    assert_eq!(storage.record_version(RANGE.0).unwrap(), 2);
+    assert!(matches!(
+        storage.read_anchor(RANGE.0),
+        Err(StorageError::UnsupportedRecordVersion {
+            user_number: 10_000,
+            version: 2
+        })
+    ));
+}
+
+#[test]
+fn should_only_enable_record_versions_for_small_entries() {
+    let mut storage = Storage::new_with_entry_size(RANGE, VectorMemory::default(), 32_768).unwrap();
+    assert!(matches!(
+        storage.enable_record_versions(),
+        Err(StorageError::InvalidEntrySize(32_768))
+    ));
+
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.enable_record_versions().unwrap();
+    assert!(matches!(
+        storage.start_entry_size_migration(32_768),
+        Err(StorageError::InvalidEntrySize(32_768))
+    ));
+    storage.start_entry_size_migration(16_384).unwrap();
+}
+
+#[test]
+fn should_complete_migration_in_batches() {
+    let mut storage = Storage::try_from_memory(legacy_memory(10))
+        .unwrap()
+        .unwrap();
+    storage.set_migration_batch_size(3);
+
+    let mut steps = vec![];
+    loop {
+        let progress = storage.migrate_batch().unwrap();
+        steps.push((progress.migrated, progress.remaining));
+        if progress.done {
+            break;
+        }
+    }
+    assert_eq!(steps, vec![(3, 7), (3, 4), (3, 1), (1, 0)]);
+    for i in 0..10 {
+        assert_eq!(
+            storage.read_anchor(RANGE.0 + i).unwrap(),
+            sample_anchor(i as u8)
+        );
+    }
+}
+
+#[test]
+fn should_upgrade_v3_layout_at_once() {
+    let memory = legacy_memory(4);
+    let mut storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+    storage.upgrade_layout().unwrap();
+    assert_eq!(storage.layout_migration_state(), MigrationState::Finished);
+
+    let mut storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.version(), CURRENT_LAYOUT_VERSION);
+    for i in 0..4 {
+        assert_eq!(
+            storage.read_anchor(RANGE.0 + i).unwrap(),
+            sample_anchor(i as u8)
+        );
+    }
+    // upgrading again is a no-op
+    storage.upgrade_layout().unwrap();
+    assert_eq!(storage.version(), CURRENT_LAYOUT_VERSION);
+}
+
+#[test]
+fn should_resume_started_migration_on_upgrade() {
+    let mut storage = Storage::try_from_memory(legacy_memory(5)).unwrap().unwrap();
+    storage.set_migration_batch_size(2);
+    assert_eq!(storage.migrate_batch().unwrap().remaining, 3);
+
+    storage.upgrade_layout().unwrap();
+    assert_eq!(storage.layout_migration_state(), MigrationState::Finished);
+    for i in 0..5 {
+        assert_eq!(
+            storage.read_anchor(RANGE.0 + i).unwrap(),
+            sample_anchor(i as u8)
+        );
+    }
+}
+
+#[test]
+fn should_require_migration_batch_size() {
+    let mut storage = Storage::try_from_memory(legacy_memory(3)).unwrap().unwrap();
+    assert!(matches!(
+        storage.migrate_batch(),
+        Err(StorageError::InvalidMigrationBatchSize(0))
+    ));
+    assert_eq!(storage.layout_migration_state(), MigrationState::NotStarted);
+}
+
+/// Memory that refuses to grow beyond a fixed number of pages.
+#[derive(Clone, Default)]
+struct LimitedMemory {
+    inner: VectorMemory,
+    max_pages: u64,
+}
+
+impl Memory for LimitedMemory {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        if self.inner.size() + pages > self.max_pages {
+            return -1;
+        }
+        self.inner.grow(pages)
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        self.inner.read(offset, dst)
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        self.inner.write(offset, src)
+    }
+}
+
+#[test]
+fn should_return_persisted_length() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let state = PersistentState::default();
+    let encoded_state = candid::encode_one(&state).unwrap();
+
+    let length = storage.write_persistent_state(&state).unwrap();
+    assert_eq!(length, 4 + 1 + 8 + 8 + encoded_state.len() as u64);
+}
+
+#[test]
+fn should_report_failure_to_grow_memory_for_persistent_state() {
+    let memory = LimitedMemory {
+        inner: VectorMemory::default(),
+        max_pages: 2,
+    };
+    let mut storage = Storage::new(RANGE, memory).unwrap();
+    storage.flush();
+
+    assert!(matches!(
+        storage.write_persistent_state(&PersistentState::default()),
+        Err(PersistentStateError::MemoryExhausted { needed_pages: 1024 })
+    ));
+    assert!(matches!(
+        storage.read_persistent_state(),
+        Err(PersistentStateError::NotFound)
+    ));
+}
+
+#[test]
+fn should_not_write_anchor_if_memory_cannot_grow() {
+    let memory = LimitedMemory {
+        inner: VectorMemory::default(),
+        max_pages: 2,
+    };
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.flush();
+    // the memory manager is created on first use
+    storage.regions();
+    let contents = memory.inner.borrow().clone();
+
+    assert!(matches!(
+        storage.write_anchor(RANGE.0, &sample_anchor(1)),
+        Err(StorageError::MemoryExhausted { needed_pages: 1024 })
+    ));
+    assert_eq!(*memory.inner.borrow(), contents);
+    assert_eq!(storage.user_count(), 0);
+}
+
+#[test]
+fn should_round_trip_large_persistent_value_in_chunks() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    let value = ByteBuf::from((0..5 * 1024 * 1024).map(|i| i as u8).collect::<Vec<u8>>());
+
+    let length = storage.write_persistent_value(&value, 64 * 1024).unwrap();
+    assert!(length > 5 * 1024 * 1024);
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    let (_, data) = storage.read_persistent_bytes().unwrap();
+    let read_value: ByteBuf = candid::decode_one(&data).unwrap();
+    assert_eq!(read_value, value);
+}
+
+#[test]
+fn should_write_persistent_state_in_small_chunks() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let state = PersistentState {
+        canister_creation_cycles_cost: 12_345,
+        ..PersistentState::default()
+    };
+    storage.write_persistent_state_chunked(&state, 3).unwrap();
+    assert_eq!(storage.read_persistent_state().unwrap(), state);
+}
+
+#[test]
+fn should_reject_persistent_state_exceeding_the_size_limit() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.set_max_persistent_state_size(1024);
+
+    let value = ByteBuf::from(vec![0; 2048]);
+    assert!(matches!(
+        storage.write_persistent_value(&value, 256),
+        Err(PersistentStateError::StateTooLarge { max_size: 1024 })
+    ));
+    assert!(matches!(
+        storage.read_persistent_state(),
+        Err(PersistentStateError::NotFound)
+    ));
+
+    storage.set_max_persistent_state_size(4096);
+    storage.write_persistent_value(&value, 256).unwrap();
+    storage.set_max_persistent_state_size(1024);
+    assert!(matches!(
+        storage.read_persistent_bytes(),
+        Err(PersistentStateError::StateTooLarge { max_size: 1024 })
+    ));
+}
+
+#[test]
+fn should_keep_previous_persistent_state_when_exceeding_the_size_limit() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    let state = PersistentState {
+        canister_creation_cycles_cost: 12_345,
+        ..PersistentState::default()
+    };
+    storage.write_persistent_state(&state).unwrap();
+
+    // a state exceeding the limit is not committed, whatever part of it has been streamed
+    storage.set_max_persistent_state_size(1024);
+    let values = (0..100)
+        .map(|i| ByteBuf::from(vec![i; 20]))
+        .collect::<Vec<_>>();
+    assert!(matches!(
+        storage.write_persistent_value(&values, 16),
+        Err(PersistentStateError::StateTooLarge { max_size: 1024 })
+    ));
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.read_persistent_state().unwrap(), state);
+}
+
+#[test]
+fn should_reject_persistent_state_exceeding_the_reserve() {
+    // the smallest reserve leaves slots of 4 MiB next to the archive buffer of 16 MiB
+    let mut storage = StorageBuilder::new()
+        .range(RANGE.0, RANGE.1)
+        .stable_memory_reserve(32 << 20)
+        .memory(VectorMemory::default())
+        .build()
+        .unwrap();
+    storage.allocate_anchor().unwrap();
+
+    let value = ByteBuf::from(vec![1; 4 << 20]);
+    let encoded_size = candid::encode_one(&value).unwrap().len() as u64;
+    assert!(matches!(
+        storage.write_persistent_value(&value, 1 << 20),
+        Err(PersistentStateError::TooLarge { size, max }) if size == encoded_size && max == 4 << 20
+    ));
+    assert!(matches!(
+        storage.read_persistent_state(),
+        Err(PersistentStateError::NotFound)
+    ));
+
+    storage
+        .write_persistent_value(&ByteBuf::from(vec![1; 2 << 20]), 1 << 20)
+        .unwrap();
+}
+
+#[test]
+fn should_reject_persistent_state_exceeding_the_stable_memory_size() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.allocate_anchor().unwrap();
+    // only 100 bytes of the reserve are left below the simulated stable memory limit
+    storage.set_stable_memory_size(ENTRY_OFFSET + 10 * DEFAULT_ENTRY_SIZE as u64 + 100);
+    assert_eq!(storage.available_reserve(), 100);
+    let size_before = memory.size();
+    let snapshot = memory.borrow().clone();
+
+    let value = ByteBuf::from(vec![1; 200]);
+    assert!(matches!(
+        storage.write_persistent_value(&value, 256),
+        Err(PersistentStateError::OutOfReserve { needed, available: 100 }) if needed > 200
+    ));
+    assert_eq!(memory.size(), size_before);
+    assert_eq!(*memory.borrow(), snapshot);
+
+    storage
+        .write_persistent_value(&ByteBuf::from(vec![1; 10]), 256)
+        .unwrap();
+}
+
+#[test]
+fn should_reject_entries_overlapping_the_reserve() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    for i in 0..5 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+    // simulate a stable memory limit that only leaves room for the first 5 entries
+    let available = ENTRY_OFFSET + 5 * DEFAULT_ENTRY_SIZE as u64;
+    storage.set_stable_memory_size(available + STABLE_MEMORY_RESERVE);
+    assert_eq!(
+        storage.available_reserve(),
+        STABLE_MEMORY_RESERVE - 5 * DEFAULT_ENTRY_SIZE as u64
+    );
+
+    assert!(matches!(
+        storage.write_anchor(RANGE.0 + 5, &sample_anchor(5)),
+        Err(StorageError::OutOfReserve { needed, available: a }) if a == available && needed > available
+    ));
+    assert_eq!(storage.user_count(), 5);
+    // existing anchors can still be updated
+    storage
+        .write_anchor(RANGE.0 + 4, &sample_anchor(9))
+        .unwrap();
+    assert_eq!(storage.read_anchor(RANGE.0 + 4).unwrap(), sample_anchor(9));
+}
+
+#[test]
+fn should_keep_persistent_state_after_range_extension() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let state = PersistentState {
+        canister_creation_cycles_cost: 12_345,
+        ..PersistentState::default()
+    };
+    storage.write_persistent_state(&state).unwrap();
+    storage.extend_range(RANGE.1 + 10).unwrap();
+
+    for _ in RANGE.0..=RANGE.1 {
+        storage.allocate_anchor().unwrap();
+    }
+    storage.write_anchor(RANGE.1, &sample_anchor(1)).unwrap();
+
+    assert_eq!(storage.read_persistent_state().unwrap(), state);
+}
+
+#[test]
+fn should_report_persistent_state_of_old_layout_overwritten_after_range_extension() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.extend_range(RANGE.1 + 10).unwrap();
+    for _ in RANGE.0..=RANGE.1 {
+        storage.allocate_anchor().unwrap();
+    }
+    storage.write_anchor(RANGE.1, &sample_anchor(1)).unwrap();
+    // the persistent state was written to the reserve before the range was extended, where the
+    // first anchor of the extended range is located
+    storage.header.persistent_state_epoch = 1;
+    storage.header.persistent_state_address = ENTRY_OFFSET + storage.record_address(10);
+    storage.header.flags |= HEADER_FLAG_PERSISTENT_STATE_VERSION;
+    let memory = into_legacy_layout(storage, 15);
+
+    assert!(matches!(
+        Storage::try_from_memory(memory),
+        Err(HeaderError::LegacyPersistentState(
+            PersistentStateError::Overwritten {
+                anchor_count: 0,
+                num_users: 11
+            }
+        ))
+    ));
+}
+
+#[test]
+fn should_report_partly_overwritten_persistent_state_of_old_layout() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.allocate_anchor().unwrap();
+    let address = storage.anchor_range_end();
+    storage.header.persistent_state_epoch = 1;
+    storage.header.persistent_state_address = address;
+    storage.header.persistent_state_anchor_count = 1;
+    storage.header.flags |= HEADER_FLAG_PERSISTENT_STATE_VERSION;
+    let memory = into_legacy_layout(storage, 15);
+    let encoded_state = candid::encode_one(PersistentState::default()).unwrap();
+    memory.write(address, b"IIPS");
+    memory.write(address + 4, &[CURRENT_PERSISTENT_STATE_VERSION]);
+    memory.write(address + 5, &1u64.to_le_bytes());
+    memory.write(address + 13, &(encoded_state.len() as u64).to_le_bytes());
+    memory.write(address + 21, &encoded_state);
+
+    // an anchor written over the persistent state, leaving only its magic, version and epoch
+    let mut entry = candid::encode_one(sample_anchor(1)).unwrap();
+    entry.splice(0..0, (entry.len() as u16).to_le_bytes());
+    memory.write(address + 13, &entry);
+
+    // the size field now holds the length and the start of the candid encoded anchor
+    assert!(matches!(
+        Storage::try_from_memory(memory),
+        Err(HeaderError::LegacyPersistentState(
+            PersistentStateError::CorruptedSize(_)
+        ))
+    ));
+}
+
+#[test]
+fn should_read_persistent_state_v0_fixture() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.allocate_anchor().unwrap();
+    let address = ENTRY_OFFSET + storage.unused_memory_start();
+    let memory = into_legacy_layout(storage, 7);
+    memory.write(address, include_bytes!("fixtures/persistent_state_v0.bin"));
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(
+        storage.read_persistent_state().unwrap(),
+        PersistentState {
+            canister_creation_cycles_cost: 100_000_000_000,
+            ..PersistentState::default()
+        }
+    );
+}
+
+/// Reads a persistent state written to slot 0, as recorded in the header by a previous write.
+fn read_persistent_state_fixture(fixture: &[u8]) -> PersistentState {
+    try_read_persistent_state_fixture(fixture).unwrap()
+}
+
+fn try_read_persistent_state_fixture(
+    fixture: &[u8],
+) -> Result<PersistentState, PersistentStateError> {
+    storage_with_persistent_state_fixture(fixture).read_persistent_state()
+}
+
+fn storage_with_persistent_state_fixture(fixture: &[u8]) -> Storage<VectorMemory> {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage
+        .write_persistent_state(&PersistentState::default())
+        .unwrap();
+    let slot = &storage.regions().persistent_state[0];
+    // clear the state written above, so that no bytes of it follow shorter fixtures
+    slot.write(0, &vec![0; 4096]);
+    slot.write(0, fixture);
+
+    Storage::try_from_memory(memory).unwrap().unwrap()
+}
+
+/// The v1 fixture with its size field replaced by `size`.
+fn persistent_state_fixture_with_size(size: u64) -> Vec<u8> {
+    let mut fixture = include_bytes!("fixtures/persistent_state_v1.bin").to_vec();
+    fixture[13..21].copy_from_slice(&size.to_le_bytes());
+    fixture
+}
+
+#[test]
+fn should_reject_persistent_state_with_corrupted_size() {
+    assert!(matches!(
+        try_read_persistent_state_fixture(&persistent_state_fixture_with_size(u64::MAX)),
+        Err(PersistentStateError::CorruptedSize(u64::MAX))
+    ));
+    // the state could at most extend to the end of the single page grown for it
+    let max_size = WASM_PAGE_SIZE - 21;
+    assert!(matches!(
+        try_read_persistent_state_fixture(&persistent_state_fixture_with_size(max_size + 1)),
+        Err(PersistentStateError::CorruptedSize(size)) if size == max_size + 1
+    ));
+    // within the slot, but covering the zeros behind the state
+    assert!(matches!(
+        try_read_persistent_state_fixture(&persistent_state_fixture_with_size(max_size)),
+        Err(PersistentStateError::CandidError(_))
+    ));
+}
+
+#[test]
+fn should_reject_truncated_persistent_state() {
+    let fixture = include_bytes!("fixtures/persistent_state_v1.bin");
+    // the candid encoded state is cut off, the size field still covers the missing bytes
+    assert!(matches!(
+        try_read_persistent_state_fixture(&fixture[..fixture.len() / 2]),
+        Err(PersistentStateError::CandidError(_))
+    ));
+}
+
+#[test]
+fn should_read_persistent_state_v1_fixture() {
+    assert_eq!(
+        read_persistent_state_fixture(include_bytes!("fixtures/persistent_state_v1.bin")),
+        PersistentState {
+            canister_creation_cycles_cost: 100_000_000_000,
+            ..PersistentState::default()
+        }
+    );
+}
+
+#[test]
+fn should_read_persistent_state_v12_fixture() {
+    let state = PersistentState {
+        canister_creation_cycles_cost: 100_000_000_000,
+        max_delegation_ttl: Some(7 * 24 * 60 * 60 * 1_000_000_000),
+        max_signatures_to_prune: Some(50),
+        derivation_origins: Some(vec!["https://app.example.com".to_string()]),
+        disable_registration_challenge: Some(true),
+        registration_rate_limit: Some(TokenBucket {
+            config: RateLimitConfig {
+                max_tokens: 100,
+                time_per_token_ns: 1_000_000_000,
+            },
+            tokens: 42,
+            last_refill: 1_620_328_630_192_441_513,
+        }),
+        delegation_rate_limit: None,
+        archive_config: Some(ArchiveConfig {
+            archive_canister: Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1]),
+            expected_module_hash: [7; 32],
+            max_entries_per_call: 100,
+        }),
+        archive_sequence_number: Some(1_234),
+        archive_entries: Some(vec![ArchiveEntry {
+            anchor: 10_000,
+            timestamp: 1_620_328_630_192_441_513,
+            sequence_number: 1_233,
+            entry: ByteBuf::from(vec![1, 2, 3]),
+        }]),
+        scheduled_anchor_deletions: Some(vec![(10_001, 1_620_328_630_192_441_513)]),
+        origin_delegation_ttls: Some(vec![(
+            "https://app.example.com".to_string(),
+            3_600_000_000_000,
+        )]),
+        revoked_delegations: Some(vec![([1; 32], [2; 32], 1_620_328_630_192_441_513)]),
+    };
+    assert_eq!(
+        read_persistent_state_fixture(include_bytes!("fixtures/persistent_state_v12.bin")),
+        state
+    );
+}
+
+#[test]
+fn should_reject_persistent_state_of_unsupported_version() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage
+        .write_persistent_state(&PersistentState::default())
+        .unwrap();
+    storage.regions().persistent_state[0].write(4, &[15]);
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert!(matches!(
+        storage.read_persistent_state(),
+        Err(PersistentStateError::UnsupportedVersion(15))
+    ));
+}
+
+#[test]
+fn should_keep_previous_salt_on_rotation() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.update_salt([1; 32]).unwrap();
+    assert_eq!(storage.previous_salt(), None);
+
+    storage.rotate_salt([2; 32]).unwrap();
+    assert_eq!(storage.salt(), Some(&[2; 32]));
+    assert_eq!(storage.previous_salt(), Some(&[1; 32]));
+
+    let mut storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.salt(), Some(&[2; 32]));
+    assert_eq!(storage.previous_salt(), Some(&[1; 32]));
+
+    storage.rotate_salt([3; 32]).unwrap();
+    assert_eq!(storage.salt(), Some(&[3; 32]));
+    assert_eq!(storage.previous_salt(), Some(&[2; 32]));
+}
+
+#[test]
+fn should_round_trip_header_on_mock_memory() {
+    let memory = testing::VectorMemory::with_pages(1);
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.update_salt([5; 32]).unwrap();
+    storage.allocate_anchor().unwrap();
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.assigned_user_number_range(), RANGE);
+    assert_eq!(storage.user_count(), 1);
+    assert_eq!(storage.salt(), Some(&[5; 32]));
+    assert_eq!(storage.version(), CURRENT_LAYOUT_VERSION);
+}
+
+#[test]
+fn should_round_trip_all_header_fields() {
+    let header = Header {
+        magic: *b"IIC",
+        version: 7,
+        num_users: 0x0102_0304,
+        id_range_lo: 0x0506_0708_090a_0b0c,
+        id_range_hi: 0x0d0e_0f10_1112_1314,
+        entry_size: 0x1516,
+        salt: [0x17; 32],
+        first_entry_offset: 0x1819_1a1b_1c1d_1e1f,
+        new_layout_start: 0x2021_2223,
+        migration_batch_size: 0x2425_2627,
+        checksum: 0x2829_2a2b,
+        entry_size_migration_target: 0x2c2d,
+        entry_size_migration_cursor: 0x2e2f_3031,
+        persistent_state_epoch: 0x3233_3435_3637_3839,
+        flags: 0x3a3b_3c3d,
+        persistent_state_address: 0x3e3f_4041_4243_4445,
+        persistent_state_length: 0x4647_4849_4a4b_4c4d,
+        persistent_state_anchor_count: 0x4e4f_5051,
+        previous_salt: [0x52; 32],
+        deleted_anchors: 0x5354_5556,
+        stable_memory_reserve: 0x5758_595a_5b5c_5d5e,
+        active_slot: 1,
+    };
+
+    let bytes = header.serialize_header();
+    assert!(bytes[HEADER_SIZE..].iter().all(|b| *b == 0));
+    assert_eq!(bytes[Header::CHECKSUM], 0x2829_2a2bu32.to_le_bytes());
+
+    let decoded = Header::deserialize_header(&bytes).unwrap();
+    assert_eq!(decoded.serialize_header(), bytes);
+    assert_eq!(decoded.num_users, header.num_users);
+    assert_eq!(decoded.id_range_lo, header.id_range_lo);
+    assert_eq!(decoded.id_range_hi, header.id_range_hi);
+    assert_eq!(decoded.entry_size, header.entry_size);
+    assert_eq!(decoded.salt, header.salt);
+    assert_eq!(decoded.first_entry_offset, header.first_entry_offset);
+    assert_eq!(decoded.new_layout_start, header.new_layout_start);
+    assert_eq!(decoded.migration_batch_size, header.migration_batch_size);
+    assert_eq!(decoded.checksum, header.checksum);
+    assert_eq!(
+        decoded.entry_size_migration_target,
+        header.entry_size_migration_target
+    );
+    assert_eq!(
+        decoded.entry_size_migration_cursor,
+        header.entry_size_migration_cursor
+    );
+    assert_eq!(
+        decoded.persistent_state_epoch,
+        header.persistent_state_epoch
+    );
+    assert_eq!(decoded.flags, header.flags);
+    assert_eq!(
+        decoded.persistent_state_address,
+        header.persistent_state_address
+    );
+    assert_eq!(
+        decoded.persistent_state_length,
+        header.persistent_state_length
+    );
+    assert_eq!(
+        decoded.persistent_state_anchor_count,
+        header.persistent_state_anchor_count
+    );
+    assert_eq!(decoded.previous_salt, header.previous_salt);
+    assert_eq!(decoded.deleted_anchors, header.deleted_anchors);
+    assert_eq!(decoded.stable_memory_reserve, header.stable_memory_reserve);
+    assert_eq!(decoded.active_slot, header.active_slot);
+
+    assert!(matches!(
+        Header::deserialize_header(&bytes[..HEADER_SIZE - 1]),
+        Err(HeaderError::TooShort(len)) if len == HEADER_SIZE - 1
+    ));
+}
+
+#[test]
+fn should_match_documented_header_layout() {
+    assert_eq!(Header::MAGIC, 0..3);
+    assert_eq!(Header::VERSION, 3..4);
+    assert_eq!(Header::NUM_USERS, 4..8);
+    assert_eq!(Header::ID_RANGE_LO, 8..16);
+    assert_eq!(Header::ID_RANGE_HI, 16..24);
+    assert_eq!(Header::ENTRY_SIZE, 24..26);
+    assert_eq!(Header::SALT, 26..58);
+    assert_eq!(Header::FIRST_ENTRY_OFFSET, 58..66);
+    assert_eq!(Header::NEW_LAYOUT_START, 66..70);
+    assert_eq!(Header::MIGRATION_BATCH_SIZE, 70..74);
+    assert_eq!(Header::CHECKSUM, 74..78);
+    assert_eq!(Header::ENTRY_SIZE_MIGRATION_TARGET, 78..80);
+    assert_eq!(Header::ENTRY_SIZE_MIGRATION_CURSOR, 80..84);
+    assert_eq!(Header::PERSISTENT_STATE_EPOCH, 84..92);
+    assert_eq!(Header::FLAGS, 92..96);
+    assert_eq!(Header::PERSISTENT_STATE_ADDRESS, 96..104);
+    assert_eq!(Header::PERSISTENT_STATE_LENGTH, 104..112);
+    assert_eq!(Header::PERSISTENT_STATE_ANCHOR_COUNT, 112..116);
+    assert_eq!(Header::PREVIOUS_SALT, 116..148);
+    assert_eq!(Header::DELETED_ANCHORS, 148..152);
+    assert_eq!(Header::STABLE_MEMORY_RESERVE, 152..160);
+    assert_eq!(Header::ACTIVE_SLOT, 160..161);
+    assert_eq!(HEADER_SIZE, 161);
+}
+
+#[test]
+fn should_not_rewrite_salt_when_flushing_metadata() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.update_salt([5; 32]).unwrap();
+
+    // only [Storage::flush_salt] may touch the salt bytes once the header has been written
+    storage.header.salt = [6; 32];
+    storage.allocate_anchor().unwrap();
+    storage.flush();
+
+    let mut salt = [0; 32];
+    memory.read(Header::SALT.start as u64, &mut salt);
+    assert_eq!(salt, [5; 32]);
+    let mut num_users = [0; 4];
+    memory.read(Header::NUM_USERS.start as u64, &mut num_users);
+    assert_eq!(u32::from_le_bytes(num_users), 1);
+}
+
+#[test]
+fn should_write_salt_and_counters_granularly() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.flush();
+
+    storage.rotate_salt([7; 32]).unwrap();
+    storage.allocate_anchor().unwrap();
+    storage.rotate_salt([8; 32]).unwrap();
+    storage.allocate_anchor().unwrap();
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.salt(), Some(&[8; 32]));
+    assert_eq!(storage.previous_salt(), Some(&[7; 32]));
+    assert_eq!(storage.user_count(), 2);
+    assert_eq!(storage.assigned_user_number_range(), RANGE);
+}
+
+/// The header of [should_read_header_from_documented_layout], encoded by hand following the
+/// layout documented in the storage module.
+fn golden_header_bytes() -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(b"IIC");
+    bytes.push(8);
+    bytes.extend_from_slice(&3u32.to_le_bytes());
+    bytes.extend_from_slice(&RANGE.0.to_le_bytes());
+    bytes.extend_from_slice(&RANGE.1.to_le_bytes());
+    bytes.extend_from_slice(&4096u16.to_le_bytes());
+    bytes.extend_from_slice(&[0xaa; 32]);
+    bytes.extend_from_slice(&ENTRY_OFFSET.to_le_bytes());
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(&10u32.to_le_bytes());
+    bytes.extend_from_slice(&0xdead_beefu32.to_le_bytes());
+    bytes.extend_from_slice(&8192u16.to_le_bytes());
+    bytes.extend_from_slice(&2u32.to_le_bytes());
+    bytes.extend_from_slice(&42u64.to_le_bytes());
+    bytes.extend_from_slice(&0b1_0001u32.to_le_bytes());
+    bytes.extend_from_slice(&0x0123_4567_89ab_cdefu64.to_le_bytes());
+    bytes.extend_from_slice(&512u64.to_le_bytes());
+    bytes.extend_from_slice(&3u32.to_le_bytes());
+    bytes.extend_from_slice(&[0xbb; 32]);
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(&(64u64 << 20).to_le_bytes());
+    bytes.push(1);
+    bytes
+}
+
+#[test]
+fn should_read_header_from_documented_layout() {
+    let bytes = golden_header_bytes();
+    assert_eq!(bytes.len(), HEADER_SIZE);
+    let memory = VectorMemory::default();
+    memory.grow(1);
+    memory.write(0, &bytes);
+
+    let header = Header::read_from(&memory).unwrap();
+    assert_eq!(&header.magic, b"IIC");
+    assert_eq!(header.version, 8);
+    assert_eq!(header.num_users, 3);
+    assert_eq!(header.id_range_lo, RANGE.0);
+    assert_eq!(header.id_range_hi, RANGE.1);
+    assert_eq!(header.entry_size, 4096);
+    assert_eq!(header.salt, [0xaa; 32]);
+    assert_eq!(header.first_entry_offset, ENTRY_OFFSET);
+    assert_eq!(header.new_layout_start, 1);
+    assert_eq!(header.migration_batch_size, 10);
+    assert_eq!(header.checksum, 0xdead_beef);
+    assert_eq!(header.entry_size_migration_target, 8192);
+    assert_eq!(header.entry_size_migration_cursor, 2);
+    assert_eq!(header.persistent_state_epoch, 42);
+    assert_eq!(header.flags, 0b1_0001);
+    assert_eq!(header.persistent_state_address, 0x0123_4567_89ab_cdef);
+    assert_eq!(header.persistent_state_length, 512);
+    assert_eq!(header.persistent_state_anchor_count, 3);
+    assert_eq!(header.previous_salt, [0xbb; 32]);
+    assert_eq!(header.deleted_anchors, 1);
+    assert_eq!(header.stable_memory_reserve, 64 << 20);
+    assert_eq!(header.active_slot, 1);
+
+    let mut written = vec![];
+    header.write_to(&mut written).unwrap();
+    assert_eq!(written, bytes);
+}
+
+#[test]
+fn should_round_trip_pseudo_random_headers() {
+    // xorshift, so that the test is deterministic without pulling in a property testing crate
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    for _ in 0..1_000 {
+        let mut bytes = [0u8; HEADER_SIZE];
+        for chunk in bytes.chunks_mut(8) {
+            chunk.copy_from_slice(&next().to_le_bytes()[..chunk.len()]);
+        }
+        let memory = VectorMemory::default();
+        memory.grow(1);
+        memory.write(0, &bytes);
+
+        let header = Header::read_from(&memory).unwrap();
+        let mut written = vec![];
+        header.write_to(&mut written).unwrap();
+        assert_eq!(written, bytes);
+        assert_eq!(
+            Header::deserialize_header(&written)
+                .unwrap()
+                .serialize_header(),
+            header.serialize_header()
+        );
+    }
+}
+
+#[test]
+fn should_count_trailing_free_pages() {
+    let mut storage = Storage::new((0, 100), VectorMemory::default()).unwrap();
+    storage.flush();
+    assert_eq!(storage.trailing_free_pages(), 0);
+
+    // 20 entries end at 1.25 pages, the region of the anchors was grown to cover them
+    for i in 0..20 {
+        storage.write_anchor(i, &sample_anchor(i as u8)).unwrap();
+    }
+    assert_eq!(storage.anchor_memory().size(), 2);
+    assert_eq!(storage.trailing_free_pages(), 0);
+
+    storage.anchor_memory().grow(2);
+    assert_eq!(storage.trailing_free_pages(), 2);
+
+    // pages of the other regions are never counted
+    storage
+        .write_persistent_state(&PersistentState::default())
+        .unwrap();
+    assert_eq!(storage.trailing_free_pages(), 2);
+}
+
+#[test]
+fn should_change_fingerprint_only_of_modified_records() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(2))
+        .unwrap();
+    let untouched = storage.record_fingerprint(RANGE.0).unwrap();
+    let modified = storage.record_fingerprint(RANGE.0 + 1).unwrap();
+    assert_ne!(untouched, modified);
+
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(3))
+        .unwrap();
+    assert_eq!(storage.record_fingerprint(RANGE.0).unwrap(), untouched);
+    assert_ne!(storage.record_fingerprint(RANGE.0 + 1).unwrap(), modified);
+
+    // writing the same record again yields the same fingerprint
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(2))
+        .unwrap();
+    assert_eq!(storage.record_fingerprint(RANGE.0 + 1).unwrap(), modified);
+
+    assert!(matches!(
+        storage.record_fingerprint(RANGE.1),
+        Err(StorageError::UserNumberOutOfRange { .. })
+    ));
+}
+
+#[test]
+fn should_delete_anchor_with_tombstone() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    for i in 0..3 {
+        storage
+            .write_anchor(RANGE.0 + i, &sample_anchor(i as u8))
+            .unwrap();
+    }
+    storage
+        .put_principal_index(Principal::anonymous(), RANGE.0 + 1)
+        .unwrap();
+
+    storage.delete_anchor(RANGE.0 + 1).unwrap();
+    assert_eq!(storage.user_count(), 3);
+    assert_eq!(storage.deleted_count(), 1);
+    assert_eq!(storage.lookup_by_principal(&Principal::anonymous()), None);
+    assert!(matches!(
+        storage.read_anchor(RANGE.0 + 1),
+        Err(StorageError::AnchorDeleted { user_number }) if user_number == RANGE.0 + 1
+    ));
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(0));
+    assert_eq!(storage.read_anchor(RANGE.0 + 2).unwrap(), sample_anchor(2));
+
+    // the entry is zeroed apart from the tombstone
+    let mut entry = vec![0; DEFAULT_ENTRY_SIZE as usize];
+    storage
+        .anchor_memory()
+        .read(storage.record_address(1), &mut entry);
+    assert_eq!(entry[..2], [0xff, 0xff]);
+    assert!(entry[2..].iter().all(|b| *b == 0));
+
+    assert!(matches!(
+        storage.delete_anchor(RANGE.0 + 1),
+        Err(StorageError::AnchorDeleted { .. })
+    ));
+    assert!(matches!(
+        storage.write_anchor(RANGE.0 + 1, &sample_anchor(1)),
+        Err(StorageError::AnchorDeleted { .. })
+    ));
+    assert!(matches!(
+        storage.delete_anchor(RANGE.0 + 3),
+        Err(StorageError::BadUserNumber(_))
+    ));
+
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.deleted_count(), 1);
+    assert!(matches!(
+        storage.read_anchor(RANGE.0 + 1),
+        Err(StorageError::AnchorDeleted { .. })
+    ));
+}
+
+#[test]
+fn should_never_reallocate_deleted_anchors() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    let (user_number, _) = storage.allocate_anchor().unwrap();
+    storage.delete_anchor(user_number).unwrap();
+
+    // the tombstone is neither reclaimed by compaction nor handed out again
+    assert_eq!(storage.compact().unwrap().reclaimed_records, 0);
+    assert_eq!(storage.allocate_anchor().unwrap().0, user_number + 1);
+}
+
+#[test]
+fn should_reencode_padded_anchors() {
+    // a record of a former schema with a field that is no longer used
+    #[derive(CandidType)]
+    struct PaddedAnchorRecord {
+        devices: Vec<DeviceData>,
+        delegations: Option<Vec<StoredDelegation>>,
+        unused: String,
+    }
+
+    let padded = |key: u8| {
+        candid::encode_one(PaddedAnchorRecord {
+            devices: vec![testing::device(key)],
+            delegations: None,
+            unused: "x".repeat(100),
+        })
+        .unwrap()
+    };
+    let unpadded = |key: u8| candid::encode_one(sample_anchor(key)).unwrap();
+
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for i in 0..4 {
+        storage
+            .write_raw_entry(RANGE.0 + i as u64, &padded(i))
+            .unwrap();
+    }
+    storage.delete_anchor(RANGE.0 + 1).unwrap();
+    let freed: usize = [0, 2, 3]
+        .into_iter()
+        .map(|key| padded(key).len() - unpadded(key).len())
+        .sum();
+
+    assert_eq!(storage.reencode_anchors(0, 2).unwrap(), 2);
+    assert_eq!(storage.reencode_anchors(2, 10).unwrap(), 4);
+    assert_eq!(storage.reencode_anchors(4, 10).unwrap(), 4);
+    assert_eq!(storage.reencoding_freed_bytes(), freed as u64);
+    for key in [0, 2, 3] {
+        assert_eq!(
+            storage.read_raw_entry(RANGE.0 + key as u64).unwrap(),
+            unpadded(key)
+        );
+    }
+
+    // nothing left to shrink
+    storage.reencode_anchors(0, 10).unwrap();
+    assert_eq!(storage.reencoding_freed_bytes(), freed as u64);
+}
+
+#[test]
+fn should_check_whether_anchor_would_fit() {
+    let memory = VectorMemory::default();
+    let storage = Storage::new(RANGE, memory.clone()).unwrap();
+    let anchor = sample_anchor(1);
+    assert_eq!(
+        storage.would_fit(&anchor).unwrap(),
+        candid::encode_one(&anchor).unwrap().len()
+    );
+
+    let mut device = testing::device(2);
+    device.alias = "a".repeat(DEFAULT_ENTRY_SIZE as usize);
+    let too_large = AnchorRecord {
+        devices: vec![device],
+        ..sample_anchor(2)
+    };
+    assert!(matches!(
+        storage.would_fit(&too_large),
+        Err(StorageError::EntrySizeLimitExceeded(len)) if len > DEFAULT_ENTRY_SIZE as usize
+    ));
+    // nothing was written
+    assert_eq!(storage.user_count(), 0);
+    assert_eq!(memory.size(), 0);
+}
+
+#[test]
+fn should_count_anchors_in_range() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for _ in 0..5 {
+        storage.allocate_anchor().unwrap();
+    }
+    let lo = RANGE.0;
+
+    // fully inside
+    assert_eq!(storage.count_anchors_in_range(lo..=lo + 4), 5);
+    assert_eq!(storage.count_anchors_in_range(lo + 1..=lo + 2), 2);
+    assert_eq!(storage.count_anchors_in_range(lo + 3..=lo + 3), 1);
+
+    // partially overlapping
+    assert_eq!(storage.count_anchors_in_range(0..=lo + 1), 2);
+    assert_eq!(storage.count_anchors_in_range(lo + 3..=RANGE.1), 2);
+    assert_eq!(storage.count_anchors_in_range(0..=u64::MAX), 5);
+
+    // disjoint
+    assert_eq!(storage.count_anchors_in_range(0..=lo - 1), 0);
+    assert_eq!(storage.count_anchors_in_range(lo + 5..=RANGE.1), 0);
+    #[allow(clippy::reversed_empty_ranges)]
+    let empty = lo + 3..=lo + 1;
+    assert_eq!(storage.count_anchors_in_range(empty), 0);
+}
+
+#[test]
+fn should_check_whether_anchor_exists() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    assert!(!storage.anchor_exists(RANGE.0));
+
+    for _ in 0..3 {
+        storage.allocate_anchor().unwrap();
+    }
+    let num_users = storage.user_count() as u64;
+    assert!(!storage.anchor_exists(RANGE.0 - 1));
+    assert!(storage.anchor_exists(RANGE.0));
+    assert!(storage.anchor_exists(RANGE.0 + num_users - 1));
+    assert!(!storage.anchor_exists(RANGE.0 + num_users));
+    assert!(!storage.anchor_exists(RANGE.1));
+}
+
+#[test]
+fn should_reclaim_trailing_never_written_records() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    for key in 0..3 {
+        let (user_number, _) = storage.allocate_anchor().unwrap();
+        storage
+            .write_anchor(user_number, &sample_anchor(key))
+            .unwrap();
+    }
+    // allocated but never written
+    storage.allocate_anchor().unwrap();
+    storage.allocate_anchor().unwrap();
+
+    let report = storage.compact().unwrap();
+    assert_eq!(report.reclaimed_records, 2);
+    assert_eq!(report.bytes_reclaimed, 2 * DEFAULT_ENTRY_SIZE as u64);
+    assert_eq!(storage.user_count(), 3);
+    assert_eq!(storage.read_anchor(RANGE.0 + 2).unwrap(), sample_anchor(2));
+    assert_eq!(storage.compact().unwrap().reclaimed_records, 0);
+
+    // reclaimed user numbers are assigned again
+    assert_eq!(storage.allocate_anchor().unwrap().0, RANGE.0 + 3);
+}
+
+#[test]
+fn should_not_reclaim_written_empty_records() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    let (user_number, _) = storage.allocate_anchor().unwrap();
+    storage
+        .write_anchor(user_number, &AnchorRecord::default())
+        .unwrap();
+
+    // the user number has been handed out, so it is not assigned again
+    assert_eq!(storage.compact().unwrap().reclaimed_records, 0);
+    assert_eq!(storage.allocate_anchor().unwrap().0, user_number + 1);
+}
+
+#[test]
+fn should_not_reclaim_records_holding_only_metadata() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let (user_number, _) = storage.allocate_anchor().unwrap();
+    let anchor = AnchorRecord {
+        devices: vec![],
+        delegations: Some(vec![]),
+        metadata: Some(HashMap::from([(
+            "recovery_phrase_hint".to_string(),
+            MetadataEntry::String("blue".to_string()),
+        )])),
+    };
+    storage.write_anchor(user_number, &anchor).unwrap();
+
+    assert_eq!(storage.compact().unwrap().reclaimed_records, 0);
+    assert_eq!(storage.read_anchor(user_number).unwrap(), anchor);
+}
+
+#[test]
+fn should_remove_reclaimed_records_from_principal_index() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    let (user_number, _) = storage.allocate_anchor().unwrap();
+    storage
+        .put_principal_index(sample_principal(1), RANGE.0)
+        .unwrap();
+    storage
+        .put_principal_index(sample_principal(2), user_number)
+        .unwrap();
+
+    assert_eq!(storage.compact().unwrap().reclaimed_records, 1);
+    assert_eq!(
+        storage.lookup_by_principal(&sample_principal(1)),
+        Some(RANGE.0)
+    );
+    assert_eq!(storage.lookup_by_principal(&sample_principal(2)), None);
+
+    // the index is written along with the number of users
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.user_count(), 1);
+    assert_eq!(storage.lookup_by_principal(&sample_principal(2)), None);
+}
+
+#[test]
+fn should_detect_corruption_of_last_byte_of_full_entry() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    let limit = storage.candid_entry_size_limit();
+    let mut anchor = sample_anchor(1);
+    let mut alias_len = 0;
+    while candid::encode_one(&anchor).unwrap().len() < limit {
+        alias_len += 1;
+        anchor.devices[0].alias = "a".repeat(alias_len);
+    }
+    assert_eq!(candid::encode_one(&anchor).unwrap().len(), limit);
+    storage.write_anchor(RANGE.0, &anchor).unwrap();
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), anchor);
+
+    // the payload fills the entry up to its last byte
+    let address = storage.record_address(1) - 1;
+    let mut byte = [0];
+    storage.anchor_memory().read(address, &mut byte);
+    storage.anchor_memory().write(address, &[byte[0] ^ 0x80]);
+
+    assert!(matches!(
+        storage.read_anchor(RANGE.0),
+        Err(StorageError::ChecksumMismatch {
+            user_number: 10_000
+        })
+    ));
+}
+
+#[test]
+fn should_read_raw_entry() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    storage.enable_record_versions().unwrap();
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(2))
+        .unwrap();
+
+    assert_eq!(
+        storage.read_raw_entry(RANGE.0).unwrap(),
+        candid::encode_one(sample_anchor(1)).unwrap()
+    );
+    assert_eq!(
+        storage.read_raw_entry(RANGE.0 + 1).unwrap(),
+        candid::encode_one(sample_anchor(2)).unwrap()
+    );
+    assert!(matches!(
+        storage.read_raw_entry(RANGE.1),
+        Err(StorageError::UserNumberOutOfRange { .. })
+    ));
+}
+
+#[test]
+fn should_round_trip_raw_entry() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    let encoded = candid::encode_one(sample_anchor(1)).unwrap();
+    storage.write_raw_entry(RANGE.0, &encoded).unwrap();
+    assert_eq!(storage.user_count(), 1);
+    assert_eq!(storage.read_raw_entry(RANGE.0).unwrap(), encoded);
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(1));
+
+    // existing records are overwritten in place
+    let encoded = candid::encode_one(sample_anchor(2)).unwrap();
+    storage.write_raw_entry(RANGE.0, &encoded).unwrap();
+    assert_eq!(storage.user_count(), 1);
+    assert_eq!(storage.read_raw_entry(RANGE.0).unwrap(), encoded);
+
+    // the bytes are not required to be decodable
+    storage.write_raw_entry(RANGE.0 + 1, b"opaque").unwrap();
+    assert_eq!(storage.read_raw_entry(RANGE.0 + 1).unwrap(), b"opaque");
+
+    let too_large = vec![0; storage.candid_entry_size_limit() + 1];
+    assert!(matches!(
+        storage.write_raw_entry(RANGE.0, &too_large),
+        Err(StorageError::EntrySizeLimitExceeded(_))
+    ));
+    assert!(matches!(
+        storage.write_raw_entry(RANGE.0 + 3, &encoded),
+        Err(StorageError::BadUserNumber(_))
+    ));
+    assert!(matches!(
+        storage.write_raw_entry(RANGE.1, &encoded),
+        Err(StorageError::UserNumberOutOfRange { .. })
+    ));
+}
+
+#[test]
+fn should_read_devices_stored_without_protection() {
+    #[derive(CandidType)]
+    struct DeviceDataWithoutProtection {
+        pubkey: DeviceKey,
+        alias: String,
+        credential_id: Option<CredentialId>,
+        purpose: Purpose,
+        key_type: KeyType,
+    }
+    #[derive(CandidType)]
+    struct AnchorRecordWithoutProtection {
+        devices: Vec<DeviceDataWithoutProtection>,
+        delegations: Option<Vec<StoredDelegation>>,
+    }
+
+    let device = testing::device(1);
+    let record = AnchorRecordWithoutProtection {
+        devices: vec![DeviceDataWithoutProtection {
+            pubkey: device.pubkey.clone(),
+            alias: device.alias.clone(),
+            credential_id: None,
+            purpose: Purpose::Authentication,
+            key_type: KeyType::Unknown,
+        }],
+        delegations: None,
+    };
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage
+        .write_raw_entry(RANGE.0, &candid::encode_one(record).unwrap())
+        .unwrap();
+    let device = storage.read_anchor(RANGE.0).unwrap().devices.remove(0);
+    assert_eq!(device.protection, None);
+    assert!(!device.is_protected());
+}
+
+fn compressible_anchor() -> AnchorRecord {
+    let delegation = StoredDelegation {
+        session_key: ByteBuf::from(vec![1; 32]),
+        expiration: 100,
+    };
+    AnchorRecord {
+        delegations: Some(vec![delegation; 200]),
+        ..sample_anchor(1)
+    }
+}
+
+#[test]
+fn should_fit_large_records_into_entry_with_compression() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    let anchor = compressible_anchor();
+    let encoded_len = candid::encode_one(&anchor).unwrap().len();
+    assert!(encoded_len > DEFAULT_ENTRY_SIZE as usize);
+    assert!(matches!(
+        storage.write_anchor(RANGE.0, &anchor),
+        Err(StorageError::EntrySizeLimitExceeded(len)) if len == encoded_len
+    ));
+
+    storage.set_compression(true);
+    storage.write_anchor(RANGE.0, &anchor).unwrap();
+    assert!(storage.read_raw_entry(RANGE.0).unwrap().len() < DEFAULT_ENTRY_SIZE as usize);
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), anchor);
+
+    // the setting is persisted
+    let mut storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    storage.write_anchor(RANGE.0 + 1, &anchor).unwrap();
+    assert_eq!(storage.read_anchor(RANGE.0 + 1).unwrap(), anchor);
+}
+
+#[test]
+fn should_read_compressed_and_uncompressed_records_side_by_side() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    storage.set_compression(true);
+    storage
+        .write_anchor(RANGE.0 + 1, &sample_anchor(2))
+        .unwrap();
+    storage.set_compression(false);
+    storage
+        .write_anchor(RANGE.0 + 2, &sample_anchor(3))
+        .unwrap();
+
+    assert_eq!(
+        storage.read_raw_entry(RANGE.0 + 2).unwrap(),
+        candid::encode_one(sample_anchor(3)).unwrap()
+    );
+    for i in 0..3 {
+        assert_eq!(
+            storage.read_anchor(RANGE.0 + i).unwrap(),
+            sample_anchor(i as u8 + 1)
+        );
+    }
+}
+
+/// Bytes that zstd cannot compress.
+fn pseudo_random_bytes(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// A [compressible_anchor] with an additional incompressible session key of `len` bytes.
+fn partially_compressible_anchor(len: usize) -> AnchorRecord {
+    let mut anchor = compressible_anchor();
+    anchor.delegations.as_mut().unwrap().push(StoredDelegation {
+        session_key: ByteBuf::from(pseudo_random_bytes(len)),
+        expiration: 100,
+    });
+    anchor
+}
+
+#[test]
+fn should_store_incompressible_records_uncompressed() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.set_compression(true);
+    let anchor = AnchorRecord {
+        delegations: Some(vec![StoredDelegation {
+            session_key: ByteBuf::from(pseudo_random_bytes(1000)),
+            expiration: 100,
+        }]),
+        ..sample_anchor(1)
+    };
+    storage.write_anchor(RANGE.0, &anchor).unwrap();
+
+    assert_eq!(
+        storage.read_raw_entry(RANGE.0).unwrap(),
+        candid::encode_one(&anchor).unwrap()
+    );
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), anchor);
+}
+
+#[test]
+fn should_apply_entry_size_limit_to_compressed_records() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.set_compression(true);
+    let limit = storage.candid_entry_size_limit();
+    let encoded_len = |len| match storage.would_fit(&partially_compressible_anchor(len)) {
+        Ok(len) | Err(StorageError::EntrySizeLimitExceeded(len)) => len,
+        Err(err) => panic!("unexpected error: {}", err),
+    };
+    let exact_fit = (0..limit)
+        .find(|&len| encoded_len(len) == limit)
+        .expect("no anchor compressing to exactly the limit");
+    let too_large = (exact_fit..limit)
+        .find(|&len| encoded_len(len) == limit + 1)
+        .expect("no anchor compressing to one byte more than the limit");
+
+    let anchor = partially_compressible_anchor(exact_fit);
+    assert!(candid::encode_one(&anchor).unwrap().len() > limit);
+    storage.write_anchor(RANGE.0, &anchor).unwrap();
+    let raw = storage.read_raw_entry(RANGE.0).unwrap();
+    assert_eq!(raw.len(), limit);
+    // compressed records are marked by the zstd magic number instead of the candid magic
+    assert!(!raw.starts_with(b"DIDL"));
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), anchor);
+
+    assert!(matches!(
+        storage.write_anchor(RANGE.0, &partially_compressible_anchor(too_large)),
+        Err(StorageError::EntrySizeLimitExceeded(len)) if len == limit + 1
+    ));
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), anchor);
+}
+
+#[test]
+fn should_report_corrupted_compressed_record() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.set_compression(true);
+    storage
+        .write_anchor(RANGE.0, &compressible_anchor())
+        .unwrap();
+
+    // rewrite the entry (with a valid checksum) claiming a different decompressed length
+    let mut data = storage.read_raw_entry(RANGE.0).unwrap();
+    data[0] ^= 0x01;
+    storage.write_raw_entry(RANGE.0, &data).unwrap();
+    assert!(matches!(
+        storage.read_anchor(RANGE.0),
+        Err(StorageError::BadCompressedRecord {
+            user_number: 10_000
+        })
+    ));
+}
+
+fn sample_principal(n: u32) -> Principal {
+    Principal::self_authenticating(n.to_le_bytes())
+}
+
+#[test]
+fn should_put_and_lookup_principals() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    for i in 0..3 {
+        storage
+            .put_principal_index(sample_principal(i), RANGE.0 + i as u64)
+            .unwrap();
+    }
+    storage
+        .put_principal_index(sample_principal(3), RANGE.0)
+        .unwrap();
+    // a principal can be moved to another anchor
+    storage
+        .put_principal_index(sample_principal(1), RANGE.0 + 5)
+        .unwrap();
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(
+        storage.lookup_by_principal(&sample_principal(0)),
+        Some(RANGE.0)
+    );
+    assert_eq!(
+        storage.lookup_by_principal(&sample_principal(1)),
+        Some(RANGE.0 + 5)
+    );
+    assert_eq!(
+        storage.lookup_by_principal(&sample_principal(2)),
+        Some(RANGE.0 + 2)
+    );
+    assert_eq!(
+        storage.lookup_by_principal(&sample_principal(3)),
+        Some(RANGE.0)
+    );
+    assert_eq!(storage.lookup_by_principal(&sample_principal(4)), None);
+    assert_eq!(storage.lookup_by_principal(&Principal::anonymous()), None);
+}
+
+#[test]
+fn should_reject_principal_of_out_of_range_anchor() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    assert!(matches!(
+        storage.put_principal_index(sample_principal(0), RANGE.1),
+        Err(StorageError::UserNumberOutOfRange { .. })
+    ));
+    assert_eq!(storage.lookup_by_principal(&sample_principal(0)), None);
+}
+
+#[test]
+fn should_bound_principal_index_by_entry_offset() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    let (user_number, _) = storage.allocate_anchor().unwrap();
+    storage
+        .write_anchor(user_number, &sample_anchor(1))
+        .unwrap();
+
+    let mut count = 0;
+    let err = loop {
+        match storage.put_principal_index(sample_principal(count), user_number) {
+            Ok(()) => count += 1,
+            Err(err) => break err,
+        }
+    };
+    assert!(matches!(
+        err,
+        StorageError::PrincipalIndexFull { size, max }
+            if size > max && PRINCIPAL_INDEX_OFFSET + 4 + max == ENTRY_OFFSET
+    ));
+    assert_eq!(storage.lookup_by_principal(&sample_principal(count)), None);
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(
+        storage.lookup_by_principal(&sample_principal(count - 1)),
+        Some(user_number)
+    );
+    assert_eq!(storage.read_anchor(user_number).unwrap(), sample_anchor(1));
+}
+
+#[test]
+fn should_fail_to_load_corrupted_principal_index() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage
+        .put_principal_index(sample_principal(0), RANGE.0)
+        .unwrap();
+    storage.regions().principal_index.write(4, b"DIDX");
+
+    assert!(matches!(
+        Storage::try_from_memory(memory),
+        Err(HeaderError::BadPrincipalIndex(_))
+    ));
+}
+
+/// Grows `memory` such that it covers all addresses below `end`.
+fn grow_to(memory: &VectorMemory, end: u64) {
+    let pages = end.div_ceil(WASM_PAGE_SIZE);
+    if pages > memory.size() {
+        memory.grow(pages - memory.size());
+    }
+}
+
+#[test]
+fn should_migrate_principal_index_of_old_layout() {
+    let mut storage = Storage::new(RANGE, VectorMemory::default()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    storage.header.flags |= HEADER_FLAG_PRINCIPAL_INDEX;
+    let memory = into_legacy_layout(storage, 15);
+    // an index running into the page the memory manager is created in
+    let mut principal_index = BTreeMap::new();
+    let mut encoded = vec![];
+    for n in 0.. {
+        principal_index.insert(sample_principal(n), RANGE.0);
+        encoded = candid::encode_one(&principal_index).unwrap();
+        if PRINCIPAL_INDEX_OFFSET + 4 + encoded.len() as u64 > WASM_PAGE_SIZE + 3 {
+            break;
+        }
+    }
+    memory.write(
+        PRINCIPAL_INDEX_OFFSET,
+        &(encoded.len() as u32).to_le_bytes(),
+    );
+    memory.write(PRINCIPAL_INDEX_OFFSET + 4, &encoded);
+
+    for _ in 0..2 {
+        let storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+        assert_eq!(storage.version(), CURRENT_LAYOUT_VERSION);
+        for principal in principal_index.keys() {
+            assert_eq!(storage.lookup_by_principal(principal), Some(RANGE.0));
+        }
+        assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(1));
+    }
+}
+
+#[test]
+fn should_migrate_archive_buffer_and_revocations_of_old_layout() {
+    let mut storage = StorageBuilder::new()
+        .range(RANGE.0, RANGE.1)
+        .stable_memory_reserve(32 << 20)
+        .memory(VectorMemory::default())
+        .build()
+        .unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    // the archive buffer takes the last 16 MiB of the reserve, the revocations the 4 MiB of whole
+    // pages in front of it
+    let archive_address = storage.anchor_range_end() + (16 << 20);
+    let revocations_address = archive_address / WASM_PAGE_SIZE * WASM_PAGE_SIZE - (4 << 20);
+    let memory = into_legacy_layout(storage, 15);
+    grow_to(&memory, archive_address + (16 << 20));
+
+    let entries = vec![ArchiveEntry {
+        anchor: RANGE.0,
+        timestamp: 1_620_328_630_192_441_513,
+        sequence_number: 1,
+        entry: ByteBuf::from(vec![1, 2, 3]),
+    }];
+    let encoded = candid::encode_one(&entries).unwrap();
+    memory.write(archive_address, b"IIAB");
+    memory.write(archive_address + 4, &(encoded.len() as u64).to_le_bytes());
+    memory.write(archive_address + 12, &encoded);
+    memory.write(revocations_address, b"IIRL");
+    memory.write(revocations_address + 4, &[9; 8]);
+    memory.write(revocations_address + 12, &[1, 2, 3]);
+
+    for _ in 0..2 {
+        let storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+        assert_eq!(storage.read_archive_buffer().unwrap(), entries);
+        let mut revocations = [0; 15];
+        storage.revocations_memory().read(0, &mut revocations);
+        // the tag of the pages the list was kept in is cleared
+        assert_eq!(&revocations[..4], b"IIRL");
+        assert_eq!(revocations[4..12], [0; 8]);
+        assert_eq!(revocations[12..], [1, 2, 3]);
+        assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(1));
+    }
+}
+
+#[test]
+fn should_migrate_persistent_state_of_old_layout_from_its_active_slot() {
+    let mut storage = StorageBuilder::new()
+        .range(RANGE.0, RANGE.1)
+        .stable_memory_reserve(32 << 20)
+        .memory(VectorMemory::default())
+        .build()
+        .unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    // slot 1 follows slot 0 of 4 MiB at the start of the reserve
+    let address = storage.anchor_range_end();
+    storage.header.persistent_state_epoch = 2;
+    storage.header.persistent_state_address = address + (4 << 20);
+    storage.header.persistent_state_anchor_count = 1;
+    storage.header.active_slot = 1;
+    storage.header.flags |= HEADER_FLAG_PERSISTENT_STATE_VERSION;
+    let memory = into_legacy_layout(storage, 15);
+    grow_to(&memory, address + (8 << 20));
+    for (slot, cost) in [(0, 1), (1, 2)] {
+        let encoded = candid::encode_one(PersistentState {
+            canister_creation_cycles_cost: cost,
+            ..PersistentState::default()
+        })
+        .unwrap();
+        let slot_address = address + slot * (4 << 20);
+        memory.write(slot_address, b"IIPS");
+        memory.write(slot_address + 4, &[CURRENT_PERSISTENT_STATE_VERSION]);
+        memory.write(slot_address + 5, &(slot + 1).to_le_bytes());
+        memory.write(slot_address + 13, &(encoded.len() as u64).to_le_bytes());
+        memory.write(slot_address + 21, &encoded);
+    }
+
+    let mut storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+    let state = storage.read_persistent_state().unwrap();
+    assert_eq!(state.canister_creation_cycles_cost, 2);
+    assert_eq!(storage.header.persistent_state_epoch, 3);
+
+    // the next write goes to the other slot of the memory manager
+    let next_state = PersistentState {
+        canister_creation_cycles_cost: 3,
+        ..PersistentState::default()
+    };
+    storage.write_persistent_state(&next_state).unwrap();
+    let storage = Storage::try_from_memory(memory).unwrap().unwrap();
+    assert_eq!(storage.header.active_slot, 1);
+    assert_eq!(storage.read_persistent_state().unwrap(), next_state);
+}
+
+#[test]
+fn should_migrate_hashed_placement_of_old_layout() {
+    let mut storage = hashed_storage(VectorMemory::default());
+    for user_number in RANGE.0..RANGE.1 {
+        storage
+            .write_by_hashed(user_number, &sample_anchor(user_number as u8))
+            .unwrap();
+    }
+    let memory = into_legacy_layout(storage, 15);
+
+    for _ in 0..2 {
+        let storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+        for user_number in RANGE.0..RANGE.1 {
+            assert_eq!(
+                storage.read_by_hashed(user_number).unwrap(),
+                sample_anchor(user_number as u8)
+            );
+        }
+    }
+}
+
+#[test]
+fn should_not_migrate_managed_layout_again() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(RANGE, memory.clone()).unwrap();
+    storage.write_anchor(RANGE.0, &sample_anchor(1)).unwrap();
+    storage
+        .put_principal_index(sample_principal(0), RANGE.0)
+        .unwrap();
+    let pages = memory.size();
+
+    let storage = Storage::try_from_memory(memory.clone()).unwrap().unwrap();
+    assert_eq!(memory.size(), pages);
+    assert_eq!(storage.read_anchor(RANGE.0).unwrap(), sample_anchor(1));
+    assert_eq!(
+        storage.lookup_by_principal(&sample_principal(0)),
+        Some(RANGE.0)
+    );
+}
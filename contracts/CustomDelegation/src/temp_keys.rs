@@ -0,0 +1,211 @@
+//! Temporary keys ("temp keys") authenticating calls for an anchor on behalf of one of its devices.
+//!
+//! Browsers without durable WebAuthn storage (e.g. in mobile web flows) would otherwise prompt the
+//! user for the device on every call. When registering an anchor or adding a device, the client
+//! can pass the public key of a short-lived session key, which then authenticates calls for the
+//! anchor like the device does, until it expires or the device is removed.
+//!
+//! A temp key never counts as the device itself though: protected devices can still only be
+//! changed by calls signed by the device (see [crate::anchor_management::remove]).
+
+use std::collections::HashMap;
+
+use candid::Principal;
+
+use crate::secs_to_nanos;
+use crate::types::{DeviceKey, SessionKey, Timestamp, UserNumber};
+
+/// Time after which a temp key no longer authenticates calls.
+pub const TEMP_KEY_EXPIRATION_NS: u64 = secs_to_nanos(10 * 60);
+/// Maximum number of temp keys that can be valid at the same time.
+pub const MAX_TEMP_KEYS: usize = 10_000;
+
+struct TempKey {
+    user_number: UserNumber,
+    device_key: DeviceKey,
+    expiration: Timestamp,
+}
+
+/// The temp keys by the principal they authenticate, NOT persisted across upgrades.
+#[derive(Default)]
+pub struct TempKeys {
+    keys: HashMap<Principal, TempKey>,
+}
+
+impl TempKeys {
+    /// Adds a temp key standing in for the given device until [TEMP_KEY_EXPIRATION_NS] from `now`,
+    /// pruning the expired keys first.
+    ///
+    /// Returns false if [MAX_TEMP_KEYS] keys are still valid, in which case the client has to
+    /// authenticate with the device itself.
+    pub fn add(
+        &mut self,
+        user_number: UserNumber,
+        device_key: DeviceKey,
+        temp_key: &SessionKey,
+        now: Timestamp,
+    ) -> bool {
+        self.keys.retain(|_, key| key.expiration > now);
+        if self.keys.len() >= MAX_TEMP_KEYS {
+            return false;
+        }
+        self.keys.insert(
+            Principal::self_authenticating(temp_key),
+            TempKey {
+                user_number,
+                device_key,
+                expiration: now.saturating_add(TEMP_KEY_EXPIRATION_NS),
+            },
+        );
+        true
+    }
+
+    /// Returns the key of the device `caller` stands in for if it is a temp key of the given
+    /// anchor that has not expired yet.
+    pub fn device_key(
+        &self,
+        user_number: UserNumber,
+        caller: Principal,
+        now: Timestamp,
+    ) -> Option<&DeviceKey> {
+        self.keys
+            .get(&caller)
+            .filter(|key| key.user_number == user_number && key.expiration > now)
+            .map(|key| &key.device_key)
+    }
+
+    /// Removes the temp keys of the given device, e.g. because it was removed from the anchor.
+    pub fn remove_device(&mut self, user_number: UserNumber, device_key: &DeviceKey) {
+        self.keys
+            .retain(|_, key| key.user_number != user_number || key.device_key != *device_key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+
+    use crate::anchor_management;
+    use crate::storage::Storage;
+    use crate::testing::{self, VectorMemory};
+    use crate::types::{AnchorRecord, DeviceData, DeviceError, DeviceProtection, KeyType, Purpose};
+
+    use super::*;
+
+    const ANCHOR: UserNumber = 10_000;
+
+    fn key(byte: u8) -> ByteBuf {
+        ByteBuf::from(vec![byte; 32])
+    }
+
+    fn principal(byte: u8) -> Principal {
+        Principal::self_authenticating(key(byte))
+    }
+
+    #[test]
+    fn should_authenticate_until_expiration() {
+        let mut temp_keys = TempKeys::default();
+        assert!(temp_keys.add(ANCHOR, key(1), &key(100), 0));
+
+        assert_eq!(
+            temp_keys.device_key(ANCHOR, principal(100), 1),
+            Some(&key(1))
+        );
+        assert_eq!(
+            temp_keys.device_key(ANCHOR, principal(100), TEMP_KEY_EXPIRATION_NS - 1),
+            Some(&key(1))
+        );
+        assert_eq!(
+            temp_keys.device_key(ANCHOR, principal(100), TEMP_KEY_EXPIRATION_NS),
+            None
+        );
+    }
+
+    #[test]
+    fn should_only_authenticate_for_own_anchor() {
+        let mut temp_keys = TempKeys::default();
+        temp_keys.add(ANCHOR, key(1), &key(100), 0);
+
+        assert_eq!(temp_keys.device_key(ANCHOR + 1, principal(100), 1), None);
+        assert_eq!(temp_keys.device_key(ANCHOR, principal(1), 1), None);
+    }
+
+    #[test]
+    fn should_invalidate_temp_keys_of_removed_device() {
+        let mut temp_keys = TempKeys::default();
+        temp_keys.add(ANCHOR, key(1), &key(100), 0);
+        temp_keys.add(ANCHOR, key(2), &key(101), 0);
+        temp_keys.add(ANCHOR + 1, key(1), &key(102), 0);
+
+        temp_keys.remove_device(ANCHOR, &key(1));
+        assert_eq!(temp_keys.device_key(ANCHOR, principal(100), 1), None);
+        assert_eq!(
+            temp_keys.device_key(ANCHOR, principal(101), 1),
+            Some(&key(2))
+        );
+        assert_eq!(
+            temp_keys.device_key(ANCHOR + 1, principal(102), 1),
+            Some(&key(1))
+        );
+    }
+
+    #[test]
+    fn should_cap_temp_keys_and_prune_expired_ones() {
+        let mut temp_keys = TempKeys::default();
+        for i in 0..MAX_TEMP_KEYS as u64 {
+            let temp_key = ByteBuf::from(i.to_le_bytes().to_vec());
+            assert!(temp_keys.add(ANCHOR, key(1), &temp_key, i));
+        }
+        assert!(!temp_keys.add(ANCHOR, key(1), &key(100), MAX_TEMP_KEYS as u64));
+
+        // the first key expires
+        assert!(temp_keys.add(ANCHOR, key(1), &key(100), TEMP_KEY_EXPIRATION_NS));
+        assert_eq!(temp_keys.keys.len(), MAX_TEMP_KEYS);
+        assert!(!temp_keys.add(ANCHOR, key(1), &key(101), TEMP_KEY_EXPIRATION_NS));
+    }
+
+    #[test]
+    fn should_not_authorize_protected_device_mutations() {
+        let device = |byte: u8, protection: DeviceProtection| DeviceData {
+            purpose: Purpose::Recovery,
+            key_type: KeyType::SeedPhrase,
+            protection: Some(protection),
+            ..testing::device(byte)
+        };
+        let mut storage = Storage::new((ANCHOR, ANCHOR + 10), VectorMemory::new()).unwrap();
+        let anchor = AnchorRecord {
+            devices: vec![
+                device(1, DeviceProtection::Protected),
+                device(2, DeviceProtection::Unprotected),
+            ],
+            delegations: None,
+            metadata: None,
+        };
+        storage.write_anchor(ANCHOR, &anchor).unwrap();
+
+        // a temp key of the protected device authenticates calls for the anchor ...
+        let mut temp_keys = TempKeys::default();
+        temp_keys.add(ANCHOR, key(1), &key(100), 0);
+        assert_eq!(
+            temp_keys.device_key(ANCHOR, principal(100), 1),
+            Some(&key(1))
+        );
+
+        // ... but cannot change the protected device
+        assert_eq!(
+            anchor_management::remove(&mut storage, ANCHOR, principal(100), key(1)),
+            Err(DeviceError::ProtectedDevice)
+        );
+        assert_eq!(
+            anchor_management::replace(
+                &mut storage,
+                ANCHOR,
+                principal(100),
+                key(1),
+                device(3, DeviceProtection::Unprotected)
+            ),
+            Err(DeviceError::ProtectedDevice)
+        );
+        assert_eq!(storage.read_anchor(ANCHOR).unwrap(), anchor);
+    }
+}
@@ -0,0 +1,189 @@
+//! Test doubles for running the canister logic outside of a canister.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use ic_stable_structures::Memory;
+use serde_bytes::ByteBuf;
+
+use crate::types::{DeviceData, DeviceProtection, KeyType, Purpose};
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// [Memory] backed by a growable `Vec<u8>`, behaving like the stable memory of a canister: it
+/// grows in pages of 64 KiB which are zero-filled, and reads or writes beyond its size panic.
+///
+/// Clones share the same underlying buffer, so a memory handed to a [crate::storage::Storage]
+/// can still be inspected by the test.
+#[derive(Clone, Default)]
+pub struct VectorMemory {
+    buf: Rc<RefCell<Vec<u8>>>,
+}
+
+impl VectorMemory {
+    /// Creates an empty memory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a memory of `pages` zero-filled pages.
+    pub fn with_pages(pages: u64) -> Self {
+        let memory = Self::new();
+        memory.grow(pages);
+        memory
+    }
+}
+
+impl Memory for VectorMemory {
+    fn size(&self) -> u64 {
+        self.buf.borrow().len() as u64 / WASM_PAGE_SIZE
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        let old_size = self.size();
+        let new_len = match pages
+            .checked_add(old_size)
+            .and_then(|new_size| new_size.checked_mul(WASM_PAGE_SIZE))
+            .and_then(|new_len| usize::try_from(new_len).ok())
+        {
+            Some(new_len) => new_len,
+            None => return -1,
+        };
+        self.buf.borrow_mut().resize(new_len, 0);
+        old_size as i64
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        let buf = self.buf.borrow();
+        let start = offset as usize;
+        let end = start
+            .checked_add(dst.len())
+            .filter(|end| *end <= buf.len())
+            .unwrap_or_else(|| panic!("read out of bounds: offset {} len {}", offset, dst.len()));
+        dst.copy_from_slice(&buf[start..end]);
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        let mut buf = self.buf.borrow_mut();
+        let start = offset as usize;
+        let end = start
+            .checked_add(src.len())
+            .filter(|end| *end <= buf.len())
+            .unwrap_or_else(|| panic!("write out of bounds: offset {} len {}", offset, src.len()));
+        buf[start..end].copy_from_slice(src);
+    }
+}
+
+/// Management canister answering `raw_rand` calls with the given responses in order. Every
+/// response is only returned after the call has been polled once, so that tests can interleave
+/// concurrent calls like the canister's message executions.
+pub struct MockManagementCanister {
+    responses: RefCell<VecDeque<Result<Vec<u8>, String>>>,
+    calls: Cell<usize>,
+}
+
+impl MockManagementCanister {
+    pub fn new(responses: Vec<Result<Vec<u8>, String>>) -> Self {
+        Self {
+            responses: RefCell::new(responses.into()),
+            calls: Cell::new(0),
+        }
+    }
+
+    /// Number of `raw_rand` calls made so far.
+    pub fn calls(&self) -> usize {
+        self.calls.get()
+    }
+
+    pub async fn raw_rand(&self) -> Result<Vec<u8>, String> {
+        self.calls.set(self.calls.get() + 1);
+        YieldOnce(false).await;
+        self.responses
+            .borrow_mut()
+            .pop_front()
+            .expect("unexpected raw_rand call")
+    }
+}
+
+/// Future that is pending when polled for the first time, like an inter-canister call.
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        Poll::Pending
+    }
+}
+
+/// Polls the future once.
+pub fn poll_once<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+    future.poll(&mut Context::from_waker(Waker::noop()))
+}
+
+/// Polls the future until it completes. Only suitable for futures that make progress on every
+/// poll, such as the calls of [MockManagementCanister].
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = poll_once(future.as_mut()) {
+            return output;
+        }
+    }
+}
+
+/// Unprotected authentication device with a public key of 32 `key` bytes and no credential ID.
+pub fn device(key: u8) -> DeviceData {
+    DeviceData {
+        pubkey: ByteBuf::from(vec![key; 32]),
+        alias: format!("device {}", key),
+        credential_id: None,
+        purpose: Purpose::Authentication,
+        key_type: KeyType::Unknown,
+        protection: Some(DeviceProtection::Unprotected),
+        metadata: None,
+        last_usage_timestamp: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_grow_in_zero_filled_pages() {
+        let memory = VectorMemory::new();
+        assert_eq!(memory.size(), 0);
+        assert_eq!(memory.grow(2), 0);
+        assert_eq!(memory.grow(1), 2);
+        assert_eq!(memory.size(), 3);
+
+        let mut buf = vec![1; 3 * WASM_PAGE_SIZE as usize];
+        memory.read(0, &mut buf);
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn should_share_buffer_between_clones() {
+        let memory = VectorMemory::with_pages(1);
+        memory.clone().write(WASM_PAGE_SIZE - 3, b"abc");
+
+        let mut buf = [0; 3];
+        memory.read(WASM_PAGE_SIZE - 3, &mut buf);
+        assert_eq!(&buf, b"abc");
+    }
+
+    #[test]
+    #[should_panic(expected = "write out of bounds")]
+    fn should_panic_on_write_beyond_size() {
+        VectorMemory::with_pages(1).write(WASM_PAGE_SIZE - 1, b"ab");
+    }
+}
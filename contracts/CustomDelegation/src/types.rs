@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
 
 use candid::{CandidType, Deserialize, Func, Principal};
 use serde_bytes::{ByteBuf, Bytes};
@@ -15,6 +16,13 @@ pub type Timestamp = u64; // in nanos since epoch
 pub type Signature = ByteBuf;
 pub type DeviceVerificationCode = String;
 pub type FailedAttemptsCounter = u8;
+// frontend, session key, maximum time to live and targets of a delegation prepared in a batch
+pub type DelegationRequest = (
+    FrontendHostname,
+    SessionKey,
+    Option<u64>,
+    Option<Vec<Principal>>,
+);
 
 pub struct Base64(pub String);
 
@@ -25,7 +33,54 @@ pub struct DeviceData {
     pub credential_id: Option<CredentialId>,
     pub purpose: Purpose,
     pub key_type: KeyType,
-    pub protection: DeviceProtection,
+    // optional for compatibility with devices stored before protection was introduced, which are
+    // unprotected
+    pub protection: Option<DeviceProtection>,
+    pub metadata: Option<HashMap<String, MetadataEntry>>,
+    // when the device last authenticated an update call, recorded at most once per day
+    pub last_usage_timestamp: Option<Timestamp>,
+}
+
+impl DeviceData {
+    /// Returns whether the device can only be removed or replaced by itself.
+    pub fn is_protected(&self) -> bool {
+        self.protection == Some(DeviceProtection::Protected)
+    }
+}
+
+/// The data stored in stable memory for each anchor.
+#[derive(Eq, PartialEq, Clone, Debug, Default, CandidType, Deserialize)]
+pub struct AnchorRecord {
+    pub devices: Vec<DeviceData>,
+    // optional for compatibility with records written before delegations were stored
+    pub delegations: Option<Vec<StoredDelegation>>,
+    pub metadata: Option<HashMap<String, MetadataEntry>>,
+}
+
+/// A value of the metadata attached to devices and anchors by integrators. Metadata is stored
+/// with the anchor record, so it counts against the size limit of the anchor's entry.
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub enum MetadataEntry {
+    #[serde(rename = "string")]
+    String(String),
+    #[serde(rename = "bytes")]
+    Bytes(ByteBuf),
+    #[serde(rename = "map")]
+    Map(HashMap<String, MetadataEntry>),
+}
+
+/// The devices and the metadata of an anchor.
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub struct AnchorInfo {
+    pub devices: Vec<DeviceData>,
+    pub metadata: Option<HashMap<String, MetadataEntry>>,
+}
+
+/// A delegation issued for an anchor, kept until it expires.
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub struct StoredDelegation {
+    pub session_key: SessionKey,
+    pub expiration: Timestamp,
 }
 
 #[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
@@ -44,6 +99,25 @@ pub enum RegisterResponse {
     CanisterFull,
     #[serde(rename = "bad_challenge")]
     BadChallenge,
+    #[serde(rename = "rate_limit_exceeded")]
+    RateLimitExceeded { retry_after_ns: u64 },
+}
+
+/// Why a change to the devices of an anchor was rejected.
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub enum DeviceError {
+    #[serde(rename = "device_not_found")]
+    DeviceNotFound,
+    #[serde(rename = "duplicate_device")]
+    DuplicateDevice,
+    #[serde(rename = "last_device")]
+    LastDevice,
+    #[serde(rename = "protected_device")]
+    ProtectedDevice,
+    #[serde(rename = "entry_size_limit_exceeded")]
+    EntrySizeLimitExceeded { length: u64 },
+    #[serde(rename = "storage_error")]
+    StorageError(String),
 }
 
 #[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
@@ -58,18 +132,21 @@ pub enum KeyType {
     SeedPhrase,
 }
 
-#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+/// Protected devices can only be removed or replaced by themselves.
+#[derive(Eq, PartialEq, Clone, Debug, Default, CandidType, Deserialize)]
 pub enum DeviceProtection {
     #[serde(rename = "protected")]
     Protected,
     #[serde(rename = "unprotected")]
+    #[default]
     Unprotected,
 }
 
+/// A proof-of-work challenge, see [crate::challenge].
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct Challenge {
-    pub png_base64: String,
     pub challenge_key: ChallengeKey,
+    pub difficulty: u8,
 }
 
 pub type ChallengeKey = String;
@@ -102,6 +179,19 @@ pub enum GetDelegationResponse {
     NoSuchDelegation,
 }
 
+/// Whether delegations have been revoked, see [crate::delegation::is_delegation_revoked].
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RevocationStatus {
+    pub revoked: bool,
+    // time the revocation expires, if it has not been pruned yet
+    pub expires_at: Option<Timestamp>,
+    // certificate of the certified data, only available in query calls
+    pub certificate: Option<ByteBuf>,
+    // CBOR encoded witness of the revocation or its absence, with the rest of the certified tree
+    // pruned
+    pub witness: ByteBuf,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum AddTentativeDeviceResponse {
     #[serde(rename = "added_tentatively")]
@@ -127,16 +217,18 @@ pub enum VerifyTentativeDeviceResponse {
     NoDeviceToVerify,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
 pub struct DeviceRegistrationInfo {
     pub expiration: Timestamp,
     pub tentative_device: Option<DeviceData>,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
+/// Everything needed to manage an anchor, see [crate::anchor_management::anchor_info].
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
 pub struct IdentityAnchorInfo {
     pub devices: Vec<DeviceData>,
     pub device_registration: Option<DeviceRegistrationInfo>,
+    pub metadata: Option<HashMap<String, MetadataEntry>>,
 }
 
 pub type HeaderField = (String, String);
@@ -177,6 +269,39 @@ pub struct InternetIdentityInit {
     pub archive_module_hash: Option<[u8; 32]>,
     pub canister_creation_cycles_cost: Option<u64>,
     pub layout_migration_batch_size: Option<u32>,
+    pub entry_size: Option<u16>,
+    pub max_delegation_ttl: Option<u64>,
+    pub max_signatures_to_prune: Option<u64>,
+    pub disable_registration_challenge: Option<bool>,
+    pub registration_rate_limit: Option<RateLimitConfig>,
+    pub delegation_rate_limit: Option<RateLimitConfig>,
+    pub archive_config: Option<ArchiveConfig>,
+    pub compress_anchor_records: Option<bool>,
+    pub anchor_storage_layout: Option<AnchorStorageLayout>,
+    /// Size of the stable memory reserve of the fixed slots layout in bytes, ~800 MB by default.
+    /// A smaller reserve leaves room for more anchors. Only taken into account on install.
+    pub stable_memory_reserve: Option<u64>,
+}
+
+/// Layout of the anchor records in stable memory, chosen at install time, see
+/// [crate::storage::record_storage].
+#[derive(Clone, Copy, Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AnchorStorageLayout {
+    /// Fixed-size entries at offsets derived from the user numbers, see [crate::storage].
+    #[default]
+    #[serde(rename = "fixed_slots")]
+    FixedSlots,
+    /// A stable map from user numbers to records.
+    #[serde(rename = "map")]
+    Map,
+}
+
+/// Allows bursts of up to `max_tokens` calls and one call per `time_per_token_ns` nanoseconds on
+/// average, see [crate::rate_limit].
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct RateLimitConfig {
+    pub max_tokens: u64,
+    pub time_per_token_ns: u64,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
@@ -197,6 +322,27 @@ pub struct InternetIdentityStats {
     pub canister_creation_cycles_cost: u64,
     pub storage_layout_version: u8,
     pub layout_migration_state: Option<MigrationState>,
+    pub max_entry_size: u16,
+    pub signature_map_size: u64,
+    pub stable_memory_pages: u64,
+    pub heap_memory_bytes: u64,
+    pub last_upgrade_timestamp: Timestamp,
+    // delegations prepared since the last upgrade
+    pub delegations_prepared: u64,
+    // anchor delegations prepared since the last upgrade by frontend, see [crate::metrics]
+    pub anchor_delegations_by_frontend: Vec<(FrontendHostname, u64)>,
+    // audit log entries waiting to be pushed to the archive, see [crate::archive]
+    pub buffered_archive_entries: u64,
+    // registered anchors that have not been deleted
+    pub live_anchors: u64,
+    // deleted anchors, which are still counted by users_registered
+    pub deleted_anchors: u64,
+    // bytes freed by re-encoding anchors since the last upgrade, see [crate::recompress_anchors]
+    pub reencoding_freed_bytes: u64,
+    // bytes of the stable memory reserve below the stable memory limit, see [crate::storage]
+    pub available_reserve_bytes: u64,
+    // layout of the anchor records, which the layout version and the entry size refer to
+    pub anchor_storage_layout: AnchorStorageLayout,
 }
 
 // Archive specific types
@@ -214,6 +360,8 @@ pub enum Operation {
     },
     #[serde(rename = "remove_device")]
     RemoveDevice { device: PublicKey },
+    #[serde(rename = "prepare_delegation")]
+    PrepareDelegation { frontend: FrontendHostname },
 }
 
 #[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
@@ -227,6 +375,16 @@ pub struct Entry {
     pub sequence_number: u64,
 }
 
+/// Entry as pushed to the archive canister by `write_entries`, see [crate::archive].
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub struct ArchiveEntry {
+    pub anchor: Anchor,
+    pub timestamp: Timestamp,
+    pub sequence_number: u64,
+    // candid encoded Entry, so that the archive can store entries of newer versions
+    pub entry: ByteBuf,
+}
+
 #[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
 pub struct DeviceDataWithoutAlias {
     pub pubkey: DeviceKey,
@@ -243,7 +401,7 @@ impl From<DeviceData> for DeviceDataWithoutAlias {
             credential_id: device_data.credential_id,
             purpose: device_data.purpose,
             key_type: device_data.key_type,
-            protection: device_data.protection,
+            protection: device_data.protection.unwrap_or_default(),
         }
     }
 }
@@ -297,6 +455,15 @@ pub struct ArchiveInfo {
     pub expected_wasm_hash: Option<[u8; 32]>,
 }
 
+/// The archive canister the audit log is pushed to, see [crate::archive].
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct ArchiveConfig {
+    pub archive_canister: Principal,
+    // module hash the archive canister must have, checked before the first push
+    pub expected_module_hash: [u8; 32],
+    pub max_entries_per_call: u16,
+}
+
 /// Init arguments of the archive canister.
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct ArchiveInit {